@@ -0,0 +1,659 @@
+//! Sources of serial ports that a `SerialThread` can be connected to.
+//!
+//! A `SerialThread` never talks to the operating system directly. Instead it opens and enumerates
+//! ports through a `Backend`, which makes it possible to run the entire command/response protocol
+//! against something other than real hardware.
+//!
+//! Ports are waited on until they can be read from or written to, which is only supported for the
+//! serial ports of Unix systems. On other platforms the `SerialPortBackend`'s ports fall back to
+//! polling every 10ms.
+
+use std::collections::VecDeque;
+use std::io;
+use std::io::prelude::*;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serialport;
+use serialport::prelude::*;
+
+use PortInfo;
+use wakeup::Wakeup;
+
+/// What a port is ready for once `Port::wait()` returns.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Readiness {
+    /// Whether the port should be read from
+    pub readable: bool,
+    /// Whether the port can take more data to send
+    pub writable: bool,
+}
+
+/// An open serial port as returned by `Backend::open()`.
+///
+/// Writes must not block. Data the port can't take right away is to be refused by writing less
+/// than asked for, or failing with `io::ErrorKind::WouldBlock` or `io::ErrorKind::TimedOut`, and
+/// is written again once `wait()` reports that the port is writable.
+pub trait Port: Read + Write + Send {
+    /// Returns the name of this port if it has one.
+    fn name(&self) -> Option<String>;
+
+    /// Sets the baud rate of the port.
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()>;
+
+    /// Sets the number of data bits per character.
+    fn set_data_bits(&mut self, data_bits: DataBits) -> serialport::Result<()>;
+
+    /// Sets the flow control mode.
+    fn set_flow_control(&mut self, flow_control: FlowControl) -> serialport::Result<()>;
+
+    /// Sets the parity-checking mode.
+    fn set_parity(&mut self, parity: Parity) -> serialport::Result<()>;
+
+    /// Sets the number of stop bits.
+    fn set_stop_bits(&mut self, stop_bits: StopBits) -> serialport::Result<()>;
+
+    /// Sets the level of the RTS (Request To Send) line.
+    fn write_request_to_send(&mut self, level: bool) -> serialport::Result<()>;
+
+    /// Sets the level of the DTR (Data Terminal Ready) line.
+    fn write_data_terminal_ready(&mut self, level: bool) -> serialport::Result<()>;
+
+    /// Reads the level of the CTS (Clear To Send) line.
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool>;
+
+    /// Reads the level of the DSR (Data Set Ready) line.
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool>;
+
+    /// Reads the level of the RI (Ring Indicator) line.
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool>;
+
+    /// Reads the level of the CD (Carrier Detect) line.
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool>;
+
+    /// Starts transmitting a break condition, which continues until `clear_break()` is called.
+    ///
+    /// The default implementation reports that breaks aren't supported.
+    fn set_break(&mut self) -> serialport::Result<()> {
+        Err(serialport::Error::new(serialport::ErrorKind::Unknown,
+                                   "sending a break isn't supported by this port"))
+    }
+
+    /// Stops transmitting a break condition.
+    fn clear_break(&mut self) -> serialport::Result<()> {
+        Err(serialport::Error::new(serialport::ErrorKind::Unknown,
+                                   "sending a break isn't supported by this port"))
+    }
+
+    /// Blocks until data may be available to read from the port, the port can take more data if
+    /// `write` is set, `wakeup` is signalled, or `timeout` elapses.
+    ///
+    /// The default implementation can't tell when either happens, so it waits at most 10ms and
+    /// then always asks for the port to be read, and written if there's anything to write. This is
+    /// what the ports of the `SerialPortBackend` do on platforms other than Unix, which aren't
+    /// supported by the event-driven port thread.
+    fn wait(&mut self,
+            wakeup: &Wakeup,
+            timeout: Option<Duration>,
+            write: bool)
+            -> io::Result<Readiness> {
+        let interval = Duration::from_millis(10);
+        wakeup.wait(Some(timeout.map_or(interval, |t| t.min(interval))))?;
+        Ok(Readiness {
+               readable: true,
+               writable: write,
+           })
+    }
+}
+
+/// Opens and enumerates serial ports for a `SerialThread`.
+pub trait Backend: Send + 'static {
+    /// The type of port returned by `open()`.
+    type Port: Port;
+
+    /// Opens the port called `name` and configures it with `settings`.
+    ///
+    /// Errors keep the OS error number where there is one, so that e.g. a port that's in use can
+    /// be told apart from one that doesn't exist. Settings the port doesn't support are reported
+    /// as `io::ErrorKind::InvalidInput`.
+    fn open(&mut self, name: &str, settings: &SerialPortSettings) -> io::Result<Self::Port>;
+
+    /// Returns all ports that are currently available.
+    fn list_ports(&mut self) -> serialport::Result<Vec<PortInfo>>;
+}
+
+/// The default backend, which uses the operating system's serial ports via serialport-rs.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SerialPortBackend;
+
+#[cfg(unix)]
+impl Backend for SerialPortBackend {
+    type Port = serialport::posix::TTYPort;
+
+    /// Opens the port in non-blocking mode, so that writing to a port that's stopped taking data
+    /// doesn't hold up the port thread.
+    fn open(&mut self, name: &str, settings: &SerialPortSettings) -> io::Result<Self::Port> {
+        use std::os::unix::io::AsRawFd;
+        use nix::fcntl::{fcntl, FcntlArg, OFlag};
+        use error::os_error;
+        use wakeup::nix_to_io;
+
+        let port = serialport::posix::TTYPort::open(::std::path::Path::new(name), settings)
+            .map_err(|e| match os_error(&e) {
+                         Some(code) => io::Error::from_raw_os_error(code),
+                         None => io::Error::from(e),
+                     })?;
+        fcntl(port.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK)).map_err(nix_to_io)?;
+        Ok(port)
+    }
+
+    fn list_ports(&mut self) -> serialport::Result<Vec<PortInfo>> {
+        ::list_ports()
+    }
+}
+
+#[cfg(windows)]
+impl Backend for SerialPortBackend {
+    type Port = serialport::windows::COMPort;
+
+    fn open(&mut self, name: &str, settings: &SerialPortSettings) -> io::Result<Self::Port> {
+        Ok(serialport::windows::COMPort::open(name, settings)?)
+    }
+
+    fn list_ports(&mut self) -> serialport::Result<Vec<PortInfo>> {
+        ::list_ports()
+    }
+}
+
+impl Port for Box<dyn SerialPort> {
+    fn name(&self) -> Option<String> {
+        SerialPort::name(self.as_ref())
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        SerialPort::set_baud_rate(self.as_mut(), baud_rate)
+    }
+
+    fn set_data_bits(&mut self, data_bits: DataBits) -> serialport::Result<()> {
+        SerialPort::set_data_bits(self.as_mut(), data_bits)
+    }
+
+    fn set_flow_control(&mut self, flow_control: FlowControl) -> serialport::Result<()> {
+        SerialPort::set_flow_control(self.as_mut(), flow_control)
+    }
+
+    fn set_parity(&mut self, parity: Parity) -> serialport::Result<()> {
+        SerialPort::set_parity(self.as_mut(), parity)
+    }
+
+    fn set_stop_bits(&mut self, stop_bits: StopBits) -> serialport::Result<()> {
+        SerialPort::set_stop_bits(self.as_mut(), stop_bits)
+    }
+
+    fn write_request_to_send(&mut self, level: bool) -> serialport::Result<()> {
+        SerialPort::write_request_to_send(self.as_mut(), level)
+    }
+
+    fn write_data_terminal_ready(&mut self, level: bool) -> serialport::Result<()> {
+        SerialPort::write_data_terminal_ready(self.as_mut(), level)
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        SerialPort::read_clear_to_send(self.as_mut())
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        SerialPort::read_data_set_ready(self.as_mut())
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        SerialPort::read_ring_indicator(self.as_mut())
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        SerialPort::read_carrier_detect(self.as_mut())
+    }
+}
+
+#[cfg(unix)]
+impl Port for serialport::posix::TTYPort {
+    fn name(&self) -> Option<String> {
+        SerialPort::name(self)
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        SerialPort::set_baud_rate(self, baud_rate)
+    }
+
+    fn set_data_bits(&mut self, data_bits: DataBits) -> serialport::Result<()> {
+        SerialPort::set_data_bits(self, data_bits)
+    }
+
+    fn set_flow_control(&mut self, flow_control: FlowControl) -> serialport::Result<()> {
+        SerialPort::set_flow_control(self, flow_control)
+    }
+
+    fn set_parity(&mut self, parity: Parity) -> serialport::Result<()> {
+        SerialPort::set_parity(self, parity)
+    }
+
+    fn set_stop_bits(&mut self, stop_bits: StopBits) -> serialport::Result<()> {
+        SerialPort::set_stop_bits(self, stop_bits)
+    }
+
+    fn write_request_to_send(&mut self, level: bool) -> serialport::Result<()> {
+        SerialPort::write_request_to_send(self, level)
+    }
+
+    fn write_data_terminal_ready(&mut self, level: bool) -> serialport::Result<()> {
+        SerialPort::write_data_terminal_ready(self, level)
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        SerialPort::read_clear_to_send(self)
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        SerialPort::read_data_set_ready(self)
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        SerialPort::read_ring_indicator(self)
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        SerialPort::read_carrier_detect(self)
+    }
+
+    #[cfg(any(target_os = "linux",
+              target_os = "android",
+              target_os = "macos",
+              target_os = "ios",
+              target_os = "freebsd",
+              target_os = "dragonfly"))]
+    fn set_break(&mut self) -> serialport::Result<()> {
+        use std::os::unix::io::AsRawFd;
+        use wakeup::nix_to_io;
+
+        unsafe { tiocbrk::set_break(self.as_raw_fd()) }.map_err(nix_to_io)?;
+        Ok(())
+    }
+
+    #[cfg(any(target_os = "linux",
+              target_os = "android",
+              target_os = "macos",
+              target_os = "ios",
+              target_os = "freebsd",
+              target_os = "dragonfly"))]
+    fn clear_break(&mut self) -> serialport::Result<()> {
+        use std::os::unix::io::AsRawFd;
+        use wakeup::nix_to_io;
+
+        unsafe { tiocbrk::clear_break(self.as_raw_fd()) }.map_err(nix_to_io)?;
+        Ok(())
+    }
+
+    /// Polls the port's file descriptor together with the wakeup's, so incoming data is handled
+    /// as soon as it arrives, and data waiting to be sent as soon as the port can take it.
+    fn wait(&mut self,
+            wakeup: &Wakeup,
+            timeout: Option<Duration>,
+            write: bool)
+            -> io::Result<Readiness> {
+        use std::os::unix::io::AsRawFd;
+        use nix::errno::Errno;
+        use nix::poll::{poll, EventFlags, PollFd};
+        use wakeup::{nix_to_io, poll_timeout};
+
+        let mut events = EventFlags::POLLIN;
+        if write {
+            events |= EventFlags::POLLOUT;
+        }
+        let mut fds = [PollFd::new(self.as_raw_fd(), events),
+                       PollFd::new(wakeup.as_raw_fd(), EventFlags::POLLIN)];
+        match poll(&mut fds, poll_timeout(timeout)) {
+            Ok(_) => {
+                let revents = fds[0].revents().unwrap_or_else(EventFlags::empty);
+                Ok(Readiness {
+                       // Hangups and errors are also reported here so that the following read
+                       // fails
+                       readable: !(revents - EventFlags::POLLOUT).is_empty(),
+                       writable: revents.contains(EventFlags::POLLOUT),
+                   })
+            }
+            Err(::nix::Error::Sys(Errno::EINTR)) => Ok(Readiness::default()),
+            Err(e) => Err(nix_to_io(e)),
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Port for serialport::windows::COMPort {
+    fn name(&self) -> Option<String> {
+        SerialPort::name(self)
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        SerialPort::set_baud_rate(self, baud_rate)
+    }
+
+    fn set_data_bits(&mut self, data_bits: DataBits) -> serialport::Result<()> {
+        SerialPort::set_data_bits(self, data_bits)
+    }
+
+    fn set_flow_control(&mut self, flow_control: FlowControl) -> serialport::Result<()> {
+        SerialPort::set_flow_control(self, flow_control)
+    }
+
+    fn set_parity(&mut self, parity: Parity) -> serialport::Result<()> {
+        SerialPort::set_parity(self, parity)
+    }
+
+    fn set_stop_bits(&mut self, stop_bits: StopBits) -> serialport::Result<()> {
+        SerialPort::set_stop_bits(self, stop_bits)
+    }
+
+    fn write_request_to_send(&mut self, level: bool) -> serialport::Result<()> {
+        SerialPort::write_request_to_send(self, level)
+    }
+
+    fn write_data_terminal_ready(&mut self, level: bool) -> serialport::Result<()> {
+        SerialPort::write_data_terminal_ready(self, level)
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        SerialPort::read_clear_to_send(self)
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        SerialPort::read_data_set_ready(self)
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        SerialPort::read_ring_indicator(self)
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        SerialPort::read_carrier_detect(self)
+    }
+
+    fn set_break(&mut self) -> serialport::Result<()> {
+        use std::os::windows::io::AsRawHandle;
+        use winapi::um::commapi::SetCommBreak;
+
+        if unsafe { SetCommBreak(self.as_raw_handle() as _) } == 0 {
+            Err(io::Error::last_os_error().into())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn clear_break(&mut self) -> serialport::Result<()> {
+        use std::os::windows::io::AsRawHandle;
+        use winapi::um::commapi::ClearCommBreak;
+
+        if unsafe { ClearCommBreak(self.as_raw_handle() as _) } == 0 {
+            Err(io::Error::last_os_error().into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// The ioctls to start and stop a break, which take no argument.
+#[cfg(any(target_os = "linux",
+          target_os = "android",
+          target_os = "macos",
+          target_os = "ios",
+          target_os = "freebsd",
+          target_os = "dragonfly"))]
+mod tiocbrk {
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    use nix::libc::{TIOCCBRK, TIOCSBRK};
+
+    // The libc crate doesn't define these for Linux, where they only differ on SPARC
+    #[cfg(all(any(target_os = "linux", target_os = "android"),
+              not(any(target_arch = "sparc", target_arch = "sparc64"))))]
+    const TIOCSBRK: u32 = 0x5427;
+    #[cfg(all(any(target_os = "linux", target_os = "android"),
+              not(any(target_arch = "sparc", target_arch = "sparc64"))))]
+    const TIOCCBRK: u32 = 0x5428;
+    #[cfg(all(any(target_os = "linux", target_os = "android"),
+              any(target_arch = "sparc", target_arch = "sparc64")))]
+    const TIOCSBRK: u32 = 0x2000_747b;
+    #[cfg(all(any(target_os = "linux", target_os = "android"),
+              any(target_arch = "sparc", target_arch = "sparc64")))]
+    const TIOCCBRK: u32 = 0x2000_747a;
+
+    ioctl_none_bad!(set_break, TIOCSBRK);
+    ioctl_none_bad!(clear_break, TIOCCBRK);
+}
+
+/// An in-memory backend whose ports echo back everything written to them.
+///
+/// The list of available ports is shared between all clones of a `LoopbackBackend`, so a clone
+/// kept outside of the `SerialThread` can add and remove ports to simulate devices being plugged
+/// in and unplugged. Any I/O on a port that has been removed fails.
+#[derive(Clone, Debug)]
+pub struct LoopbackBackend {
+    ports: Arc<Mutex<Vec<PortInfo>>>,
+}
+
+impl LoopbackBackend {
+    /// Creates a backend providing the given ports, which can be just their names.
+    pub fn new<I, P>(ports: I) -> Self
+        where I: IntoIterator<Item = P>,
+              P: Into<PortInfo>
+    {
+        LoopbackBackend { ports: Arc::new(Mutex::new(ports.into_iter().map(Into::into).collect())) }
+    }
+
+    /// Makes a new port available. Does nothing if a port with the same name already exists.
+    pub fn add_port<P: Into<PortInfo>>(&self, port: P) {
+        let port = port.into();
+        let mut ports = self.ports.lock().unwrap();
+        if !ports.iter().any(|p| p.name == port.name) {
+            ports.push(port);
+        }
+    }
+
+    /// Removes a port, causing all further I/O on any open instance of it to fail.
+    pub fn remove_port(&self, name: &str) {
+        self.ports.lock().unwrap().retain(|p| p.name != name);
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        self.ports.lock().unwrap().iter().any(|p| p.name == name)
+    }
+}
+
+impl Default for LoopbackBackend {
+    /// Creates a backend with a single port named "loopback".
+    fn default() -> Self {
+        LoopbackBackend::new(vec!["loopback"])
+    }
+}
+
+impl Backend for LoopbackBackend {
+    type Port = LoopbackPort;
+
+    fn open(&mut self, name: &str, settings: &SerialPortSettings) -> io::Result<Self::Port> {
+        if !self.contains(name) {
+            let description = format!("Port '{}' doesn't exist", name);
+            return Err(io::Error::new(io::ErrorKind::NotFound, description));
+        }
+        if settings.baud_rate == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid baud rate"));
+        }
+        Ok(LoopbackPort {
+            name: name.to_string(),
+            settings: *settings,
+            buffer: VecDeque::new(),
+            dtr: true,
+            rts: true,
+            in_break: false,
+            backend: self.clone(),
+        })
+    }
+
+    fn list_ports(&mut self) -> serialport::Result<Vec<PortInfo>> {
+        Ok(self.ports.lock().unwrap().clone())
+    }
+}
+
+/// A port opened by a `LoopbackBackend`. All data written to it can be read back from it.
+///
+/// The control lines are wired like a common loopback plug: RTS drives CTS, and DTR drives both DSR
+/// and CD. RI is never asserted. A break is received as a single NUL byte once it ends, which is
+/// how a terminal set up to neither ignore breaks nor signal them reports one.
+#[derive(Debug)]
+pub struct LoopbackPort {
+    name: String,
+    settings: SerialPortSettings,
+    buffer: VecDeque<u8>,
+    dtr: bool,
+    rts: bool,
+    in_break: bool,
+    backend: LoopbackBackend,
+}
+
+impl LoopbackPort {
+    /// Returns the settings the port is currently configured with.
+    pub fn settings(&self) -> SerialPortSettings {
+        self.settings
+    }
+
+    fn check_connected(&self) -> io::Result<()> {
+        if self.backend.contains(&self.name) {
+            Ok(())
+        } else {
+            Err(io::Error::new(io::ErrorKind::BrokenPipe,
+                               format!("Port '{}' was removed", &self.name)))
+        }
+    }
+}
+
+impl Read for LoopbackPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.check_connected()?;
+        if self.buffer.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Operation timed out"));
+        }
+        let len = buf.len().min(self.buffer.len());
+        for (dst, src) in buf.iter_mut().zip(self.buffer.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for LoopbackPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_connected()?;
+        self.buffer.extend(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.check_connected()
+    }
+}
+
+impl Port for LoopbackPort {
+    fn name(&self) -> Option<String> {
+        Some(self.name.clone())
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        if baud_rate == 0 {
+            return Err(serialport::Error::new(serialport::ErrorKind::InvalidInput,
+                                              "invalid baud rate"));
+        }
+        self.settings.baud_rate = baud_rate;
+        Ok(())
+    }
+
+    fn set_data_bits(&mut self, data_bits: DataBits) -> serialport::Result<()> {
+        self.settings.data_bits = data_bits;
+        Ok(())
+    }
+
+    fn set_flow_control(&mut self, flow_control: FlowControl) -> serialport::Result<()> {
+        self.settings.flow_control = flow_control;
+        Ok(())
+    }
+
+    fn set_parity(&mut self, parity: Parity) -> serialport::Result<()> {
+        self.settings.parity = parity;
+        Ok(())
+    }
+
+    fn set_stop_bits(&mut self, stop_bits: StopBits) -> serialport::Result<()> {
+        self.settings.stop_bits = stop_bits;
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, level: bool) -> serialport::Result<()> {
+        self.check_connected()?;
+        self.rts = level;
+        Ok(())
+    }
+
+    fn write_data_terminal_ready(&mut self, level: bool) -> serialport::Result<()> {
+        self.check_connected()?;
+        self.dtr = level;
+        Ok(())
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        self.check_connected()?;
+        Ok(self.rts)
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        self.check_connected()?;
+        Ok(self.dtr)
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        self.check_connected()?;
+        Ok(false)
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        self.check_connected()?;
+        Ok(self.dtr)
+    }
+
+    fn set_break(&mut self) -> serialport::Result<()> {
+        self.check_connected()?;
+        self.in_break = true;
+        Ok(())
+    }
+
+    fn clear_break(&mut self) -> serialport::Result<()> {
+        self.check_connected()?;
+        if self.in_break {
+            self.in_break = false;
+            self.buffer.push_back(0);
+        }
+        Ok(())
+    }
+
+    /// Data only ever arrives by being written from the port thread itself, so there's nothing
+    /// to wait for if the buffer is empty. Writes always succeed at once.
+    fn wait(&mut self,
+            wakeup: &Wakeup,
+            timeout: Option<Duration>,
+            write: bool)
+            -> io::Result<Readiness> {
+        if !self.buffer.is_empty() || !self.backend.contains(&self.name) || write {
+            return Ok(Readiness {
+                          readable: true,
+                          writable: write,
+                      });
+        }
+        wakeup.wait(timeout)?;
+        Ok(Readiness::default())
+    }
+}
@@ -13,9 +13,12 @@ extern crate gattii;
 
 use std::cell::RefCell;
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::rc::Rc;
 use std::string::String;
+use std::time::Duration;
 
 use clap::{Arg, App};
 use cairo::Context;
@@ -41,6 +44,9 @@ enum StatusContext {
 
 struct Ui {
     window: gtk::Window,
+    page: gtk::Box,
+    tab_label: gtk::Label,
+    tab_indicator: gtk::Label,
     text_view: gtk::TextView,
     hex_view: gtk::TextView,
     scrolled_text_view: gtk::ScrolledWindow,
@@ -48,6 +54,8 @@ struct Ui {
     text_buffer: gtk::TextBuffer,
     hex_buffer: gtk::TextBuffer,
     send_button: gtk::ToggleButton,
+    receive_button: gtk::ToggleButton,
+    queue_button: gtk::Button,
     open_button: gtk::ToggleButton,
     save_button: gtk::ToggleButton,
     status_bar: gtk::Statusbar,
@@ -59,19 +67,34 @@ struct Ui {
     flow_control_dropdown: gtk::ComboBoxText,
     flow_control_map: HashMap<String, u32>,
     baud_dropdown: gtk::ComboBoxText,
-    baud_map: HashMap<String, u32>,
+    baud_entry: gtk::Entry,
     ports_dropdown: gtk::ComboBoxText,
-    ports_map: HashMap<String, u32>,
+    /// The ports listed in `ports_dropdown`, which uses their names as ids
+    ports: Vec<PortInfo>,
     text_buffer_insert_signal: glib::SignalHandlerId,
     hex_buffer_insert_signal: glib::SignalHandlerId,
     text_buffer_delete_signal: glib::SignalHandlerId,
     hex_buffer_delete_signal: glib::SignalHandlerId,
     open_button_clicked_signal: glib::SignalHandlerId,
     send_button_toggled_signal: glib::SignalHandlerId,
+    receive_button_toggled_signal: glib::SignalHandlerId,
     save_button_toggled_signal: glib::SignalHandlerId,
     send_button_progress_icon: gtk::DrawingArea,
     send_button_static_icon: gtk::Image,
     ports_dropdown_changed_signal: glib::SignalHandlerId,
+    baud_dropdown_changed_signal: glib::SignalHandlerId,
+    data_bits_scale_changed_signal: glib::SignalHandlerId,
+    stop_bits_scale_changed_signal: glib::SignalHandlerId,
+    parity_dropdown_changed_signal: glib::SignalHandlerId,
+    flow_control_dropdown_changed_signal: glib::SignalHandlerId,
+    dtr_button: gtk::ToggleButton,
+    rts_button: gtk::ToggleButton,
+    dtr_button_toggled_signal: glib::SignalHandlerId,
+    rts_button_toggled_signal: glib::SignalHandlerId,
+    cts_indicator: gtk::Label,
+    dsr_indicator: gtk::Label,
+    ri_indicator: gtk::Label,
+    cd_indicator: gtk::Label,
 }
 
 struct State {
@@ -81,6 +104,61 @@ struct State {
     line_ending: String,
     /// The percentage completion of sending a file [0, 100]
     send_file_percentage: u8,
+    /// Whether the file being transferred is being received rather than sent
+    receiving_file: bool,
+    /// The protocol last used to send a file
+    send_protocol: Protocol,
+    /// The protocol last used to receive a file
+    receive_protocol: Protocol,
+    /// Whether the user is being asked where to receive files a ZMODEM sender started sending
+    zmodem_offered: bool,
+    /// How files are sent, as set in the send dialog
+    send_options: SendOptions,
+    /// The files last sent or edited in the queue dialog
+    send_queue: SendQueue,
+    /// How the last log file was written
+    log_options: LogOptions,
+    /// Where to log to whenever the port is opened, as a template filled in by
+    /// `capture::expand_path()`
+    auto_log: Option<String>,
+}
+
+/// How files are sent, as set in the send dialog.
+#[derive(Clone, Default)]
+struct SendOptions {
+    /// How text files are paced
+    pacing: LinePacing,
+    /// The rate raw sends are limited to in bytes per second, instead of the port's rate
+    throughput: Option<u32>,
+    /// How HEX and S-record files are sent
+    image: ImageOptions,
+    /// How images are flashed through microcontroller bootloaders
+    flash: FlashOptions,
+}
+
+/// A queue of files sent one after another, as edited in the queue dialog.
+#[derive(Clone, Default)]
+struct SendQueue {
+    files: Vec<QueuedFile>,
+    /// How long to wait after each file
+    gap: Duration,
+    repeat: Repeat,
+}
+
+/// Identifies a session, which is one tab with its own port thread.
+type SessionId = u32;
+
+/// Everything belonging to a single session.
+type Session = (Ui, SerialThread, State);
+
+/// Window-wide state that's shared between all sessions.
+struct Shared {
+    window: gtk::Window,
+    notebook: gtk::Notebook,
+    /// The id given to the next session that's created
+    next_session_id: SessionId,
+    /// Baud rates that aren't in `BAUD_RATES` but have been used successfully, oldest first
+    custom_baud_rates: Vec<u32>,
 }
 
 static BAUD_RATES: [&'static str; 6] = [
@@ -93,6 +171,9 @@ static BAUD_RATES: [&'static str; 6] = [
 ];
 static DEFAULT_BAUD: &'static str = "115200";
 
+/// How many custom baud rates are remembered.
+const MAX_CUSTOM_BAUD_RATES: usize = 10;
+
 static PARITIES: [&'static str; 3] = [
     "none",
     "odd",
@@ -107,9 +188,66 @@ static FLOW_CONTROLS: [&'static str; 3] = [
 ];
 static DEFAULT_FLOW_CONTROL: &'static str = "none";
 
-// declare a new thread local storage key
+static SEND_PROTOCOLS: [Protocol; 13] = [
+    Protocol::Raw,
+    Protocol::Xmodem,
+    Protocol::XmodemCrc,
+    Protocol::Xmodem1k,
+    Protocol::Ymodem,
+    Protocol::Zmodem { resume: false },
+    Protocol::Zmodem { resume: true },
+    Protocol::Kermit,
+    Protocol::Text,
+    Protocol::HexImage,
+    Protocol::HexRecords,
+    Protocol::Stm32,
+    Protocol::Stk500,
+];
+
+static RECEIVE_PROTOCOLS: [Protocol; 6] = [
+    Protocol::Xmodem,
+    Protocol::XmodemCrc,
+    Protocol::Ymodem,
+    Protocol::Zmodem { resume: false },
+    Protocol::Zmodem { resume: true },
+    Protocol::Kermit,
+];
+
+static LOG_FORMATS: [LogFormat; 5] = [
+    LogFormat::Raw,
+    LogFormat::Text,
+    LogFormat::Hexdump,
+    LogFormat::Csv,
+    LogFormat::Pcapng,
+];
+
+static LOG_FILE_NAMINGS: [FileNaming; 2] = [FileNaming::Numbered, FileNaming::Timestamped];
+
+/// The name of the files logged to with `--log-dir`.
+static DEFAULT_LOG_NAME: &str = "{port}_{date}_{time}.log";
+
+/// The names of `LOG_FORMATS` on the command line.
+static LOG_FORMAT_NAMES: [&str; 5] = [
+    "raw",
+    "text",
+    "hexdump",
+    "csv",
+    "pcapng",
+];
+
+/// The names of `LOG_FILE_NAMINGS` on the command line.
+static LOG_FILE_NAMING_NAMES: [&str; 2] = [
+    "numbered",
+    "timestamped",
+];
+
+/// How long a break lasts when sent from the GUI, in milliseconds.
+const BREAK_DURATION_MS: u64 = 250;
+
+// declare new thread local storage keys
 thread_local!(
-    static GLOBAL: RefCell<Option<(Ui, SerialThread, State)>> = RefCell::new(None)
+    static GLOBAL: RefCell<HashMap<SessionId, Session>> = RefCell::new(HashMap::new());
+    static SHARED: RefCell<Option<Shared>> = RefCell::new(None)
 );
 
 fn main() {
@@ -132,7 +270,7 @@ fn main() {
                           .help("The serial port baud rate")
                           .takes_value(true)
                           .requires("port")
-                          .possible_values(&BAUD_RATES))
+                          .validator(validate_baud_rate))
                       .arg(Arg::with_name("data_bits")
                           .long("data-bits")
                           .help("Number of bits per character")
@@ -161,6 +299,47 @@ fn main() {
                           .requires("port")
                           .possible_values(&FLOW_CONTROLS)
                           .default_value_if("port", None, DEFAULT_FLOW_CONTROL))
+                      .arg(Arg::with_name("log")
+                          .long("log")
+                          .help("Log to this file whenever the port is opened. {port}, {baud}, \
+                                 {date} and {time} in it are filled in.")
+                          .takes_value(true))
+                      .arg(Arg::with_name("log_dir")
+                          .long("log-dir")
+                          .help("Log to a new file in this directory whenever the port is opened")
+                          .takes_value(true)
+                          .conflicts_with("log"))
+                      .arg(Arg::with_name("log_format")
+                          .long("log-format")
+                          .help("The format data is logged in")
+                          .takes_value(true)
+                          .possible_values(&LOG_FORMAT_NAMES))
+                      .arg(Arg::with_name("log_sent")
+                          .long("log-sent")
+                          .help("Log sent data along with received data"))
+                      .arg(Arg::with_name("log_append")
+                          .long("log-append")
+                          .help("Append to existing log files instead of replacing them"))
+                      .arg(Arg::with_name("log_max_size")
+                          .long("log-max-size")
+                          .help("Start a new log file once the current one reaches this many MB")
+                          .takes_value(true)
+                          .validator(validate_positive))
+                      .arg(Arg::with_name("log_interval")
+                          .long("log-interval")
+                          .help("Start a new log file after this many minutes")
+                          .takes_value(true)
+                          .validator(validate_positive))
+                      .arg(Arg::with_name("log_naming")
+                          .long("log-naming")
+                          .help("How new log files are named")
+                          .takes_value(true)
+                          .possible_values(&LOG_FILE_NAMING_NAMES))
+                      .arg(Arg::with_name("log_keep")
+                          .long("log-keep")
+                          .help("How many log files to keep, deleting the oldest")
+                          .takes_value(true)
+                          .validator(validate_positive))
                       .get_matches();
 
     if gtk::init().is_err() {
@@ -168,10 +347,23 @@ fn main() {
         return;
     }
 
-    ui_init();
+    // The command-line arguments only apply to the first session
+    let session_id = ui_init();
+
+    GLOBAL.with(|global| {
+        if let Some(&mut (_, _, ref mut state)) = global.borrow_mut().get_mut(&session_id) {
+            state.log_options = parse_log_options(&matches);
+            state.auto_log = matches.value_of("log").map(String::from).or_else(|| {
+                matches.value_of("log_dir").map(|dir| {
+                    let dir = PathBuf::from(dir).join(DEFAULT_LOG_NAME);
+                    dir.to_string_lossy().into_owned()
+                })
+            });
+        }
+    });
 
     GLOBAL.with(|global| {
-        if let Some((ref ui, _, _)) = *global.borrow() {
+        if let Some((ui, _, _)) = global.borrow().get(&session_id) {
             if let Some(data_bits) = matches.value_of("data_bits") {
                 let data_bits_value = data_bits.parse::<u8>().unwrap() as f64;
                 ui.data_bits_scale.set_value(data_bits_value);
@@ -189,13 +381,10 @@ fn main() {
                 ui.flow_control_dropdown.set_active(*flow_control_dropdown_index);
             }
             if let Some(serial_baud) = matches.value_of("baud") {
-                let baud_dropdown_index = ui.baud_map.get(serial_baud).unwrap();
-                ui.baud_dropdown.set_active(*baud_dropdown_index);
+                set_baud_rate_text(ui, serial_baud);
             }
             if let Some(serial_port_name) = matches.value_of("port") {
-                if let Some(ports_dropdown_index) = ui.ports_map.get(serial_port_name) {
-                    ui.ports_dropdown.set_active(*ports_dropdown_index as u32);
-                } else {
+                if !ui.ports_dropdown.set_active_id(serial_port_name) {
                     error!("Invalid port name '{}' specified.", serial_port_name);
                     process::exit(ExitCode::ArgumentError as i32);
                 }
@@ -219,50 +408,85 @@ fn main() {
     gtk::main();
 }
 
-fn ui_init() {
+/// Creates the main window with a single session, returning the id of that session.
+fn ui_init() -> SessionId {
     // Create the main window
     let window = gtk::Window::new(gtk::WindowType::Toplevel);
     window.set_title("Gattii - Your Serial Terminal Interface");
     window.set_position(gtk::WindowPosition::Center);
     window.set_default_size(400, 300);
 
+    // Every session is shown in its own tab. New ones are added with the button at the end of the
+    // tab bar.
+    let notebook = gtk::Notebook::new();
+    notebook.set_scrollable(true);
+    let new_session_button = gtk::Button::new_from_icon_name("tab-new-symbolic",
+                                                             gtk::IconSize::Menu);
+    new_session_button.set_relief(gtk::ReliefStyle::None);
+    new_session_button.set_tooltip_text("New session");
+    new_session_button.connect_clicked(|_| {
+        add_session();
+    });
+    new_session_button.show();
+    notebook.set_action_widget(&new_session_button, gtk::PackType::End);
+    window.add(&notebook);
+
+    // Set CSS styles for the entire application.
+    let css_provider = gtk::CssProvider::new();
+    let display = gdk::Display::get_default().expect("Couldn't open default GDK display");
+    let screen = display.get_default_screen();
+    gtk::StyleContext::add_provider_for_screen(&screen,
+                                               &css_provider,
+                                               gtk::STYLE_PROVIDER_PRIORITY_APPLICATION);
+    css_provider.load_from_path("resources/style.css").expect("Failed to load CSS stylesheet");
+
+    let shared = Shared {
+        window: window.clone(),
+        notebook,
+        next_session_id: 0,
+        custom_baud_rates: load_custom_baud_rates(),
+    };
+    SHARED.with(move |s| *s.borrow_mut() = Some(shared));
+
+    let session_id = add_session();
+    window.show_all();
+    session_id
+}
+
+/// Adds a new session in its own tab and switches to it, returning the id of the new session.
+fn add_session() -> SessionId {
+    let (window, notebook, id, custom_baud_rates) = SHARED.with(|s| {
+        let mut shared = s.borrow_mut();
+        let shared = shared.as_mut().expect("The main window should be set up");
+        let id = shared.next_session_id;
+        shared.next_session_id += 1;
+        (shared.window.clone(), shared.notebook.clone(), id, shared.custom_baud_rates.clone())
+    });
+
     // Create the top toolbar
     let toolbar = gtk::Toolbar::new();
     toolbar.set_show_arrow(false);
 
     // Add a port selector
     let ports_dropdown = gtk::ComboBoxText::new();
-    let mut ports_dropdown_map = HashMap::new();
-    if let Ok(mut ports) = list_ports() {
-        ports.sort();
-        if !ports.is_empty() {
-            for (i, p) in (0u32..).zip(ports.into_iter()) {
-                ports_dropdown.append(None, &p);
-                ports_dropdown_map.insert(p, i);
-            }
-            ports_dropdown.set_active(0);
-        } else {
-            ports_dropdown.append(None, "No ports found");
-            ports_dropdown.set_active(0);
-            ports_dropdown.set_sensitive(false);
-        }
-    } else {
-        ports_dropdown.append(None, "No ports found");
-        ports_dropdown.set_active(0);
-        ports_dropdown.set_sensitive(false);
-    }
+    let mut ports = list_ports().unwrap_or_default();
+    ports.sort();
+    fill_ports_dropdown(&ports_dropdown, &ports);
     let ports_dropdown_container = gtk::ToolItem::new();
     ports_dropdown_container.add(&ports_dropdown);
     toolbar.add(&ports_dropdown_container);
 
-    // Add a baud rate selector
-    let mut baud_dropdown_map = HashMap::new();
-    let baud_dropdown = gtk::ComboBoxText::new();
-    for (i, b) in BAUD_RATES.iter().enumerate() {
-        baud_dropdown_map.insert(b.to_string(), i as u32);
-        baud_dropdown.append(None, b);
-    }
-    baud_dropdown.set_active(baud_dropdown_map[DEFAULT_BAUD]);
+    // Add a baud rate selector. Any rate can be typed in besides the listed ones, and typed rates
+    // are added to the list once a port has accepted them.
+    let baud_dropdown = gtk::ComboBoxText::new_with_entry();
+    fill_baud_dropdown(&baud_dropdown, &custom_baud_rates);
+    baud_dropdown.set_active_id(DEFAULT_BAUD);
+    let baud_entry = baud_dropdown.get_child()
+        .and_then(|c| c.downcast::<gtk::Entry>().ok())
+        .expect("Baud rate dropdown should have an entry");
+    baud_entry.set_width_chars(8);
+    baud_entry.set_input_purpose(gtk::InputPurpose::Digits);
+    baud_entry.set_tooltip_text("Baud rate. Select one from the list or type one and press ENTER.");
     let baud_dropdown_container = gtk::ToolItem::new();
     baud_dropdown_container.add(&baud_dropdown);
     toolbar.add(&baud_dropdown_container);
@@ -334,10 +558,37 @@ fn ui_init() {
     port_settings_button_container.add(&port_settings_button);
     toolbar.add(&port_settings_button_container);
 
+    // Add the control lines panel with toggles for the output lines and indicators for the input
+    // lines. The indicators are only lit while a port reports its lines.
+    let control_lines_box = gtk::Box::new(gtk::Orientation::Horizontal, 5);
+    let dtr_button = gtk::ToggleButton::new_with_label("DTR");
+    dtr_button.set_tooltip_text("Data Terminal Ready");
+    dtr_button.set_active(true);
+    control_lines_box.pack_start(&dtr_button, false, false, 0);
+    let rts_button = gtk::ToggleButton::new_with_label("RTS");
+    rts_button.set_tooltip_text("Request To Send");
+    rts_button.set_active(true);
+    control_lines_box.pack_start(&rts_button, false, false, 0);
+    let cts_indicator = gtk::Label::new("CTS");
+    cts_indicator.set_tooltip_text("Clear To Send");
+    let dsr_indicator = gtk::Label::new("DSR");
+    dsr_indicator.set_tooltip_text("Data Set Ready");
+    let ri_indicator = gtk::Label::new("RI");
+    ri_indicator.set_tooltip_text("Ring Indicator");
+    let cd_indicator = gtk::Label::new("CD");
+    cd_indicator.set_tooltip_text("Carrier Detect");
+    for indicator in &[&cts_indicator, &dsr_indicator, &ri_indicator, &cd_indicator] {
+        indicator.get_style_context().add_class("control-line");
+        control_lines_box.pack_start(*indicator, false, false, 0);
+    }
+    let control_lines_container = gtk::ToolItem::new();
+    control_lines_container.add(&control_lines_box);
+    toolbar.add(&control_lines_container);
+
     // Add the open button, disabling it if no ports are available
     let open_button_container = gtk::ToolItem::new();
     let open_button = gtk::ToggleButton::new_with_label("Open");
-    if ports_dropdown_map.is_empty() {
+    if ports.is_empty() {
         open_button.set_sensitive(false);
     }
     open_button_container.add(&open_button);
@@ -349,16 +600,16 @@ fn ui_init() {
     separator.set_expand(true);
     toolbar.add(&separator);
 
-    // This drawing area draws a pie-chart showing progress as stored in the
-    // `GLOBAL::state.send_file_percentage` variable.
+    // This drawing area draws a pie-chart showing progress as stored in the session's
+    // `state.send_file_percentage` variable.
     // See src/nautilus-toolbar.c, line 688, from GTK's nautilus program.
     let operations_icon = gtk::DrawingArea::new();
     operations_icon.show();
     operations_icon.set_size_request(16, 16);
     operations_icon.add_events(EventMask::BUTTON_PRESS_MASK | EventMask::BUTTON_RELEASE_MASK);
-    operations_icon.connect_draw(|w, c| {
+    operations_icon.connect_draw(move |w, c| {
         GLOBAL.with(|global| {
-            if let Some((.., ref state)) = *global.borrow() {
+            if let Some((.., state)) = global.borrow().get(&id) {
                 let style_context = w.get_style_context();
                 let foreground = style_context.get_color(w.get_state_flags());
                 let mut background = foreground.clone();
@@ -399,6 +650,28 @@ fn ui_init() {
     send_button_container.add(&send_button);
     toolbar.add(&send_button_container);
 
+    // Add receive file button
+    let receive_button = gtk::ToggleButton::new();
+    receive_button.set_tooltip_text("Receive file");
+    let receive_image = gtk::Image::new_from_icon_name("document-save-symbolic",
+                                                       gtk::IconSize::SmallToolbar);
+    receive_button.set_image(&receive_image);
+    receive_button.set_sensitive(false);
+    let receive_button_container = gtk::ToolItem::new();
+    receive_button_container.add(&receive_button);
+    toolbar.add(&receive_button_container);
+
+    // Add send queue button
+    let queue_button = gtk::Button::new();
+    queue_button.set_tooltip_text("Send a queue of files");
+    let queue_image = gtk::Image::new_from_icon_name("view-list-symbolic",
+                                                     gtk::IconSize::SmallToolbar);
+    queue_button.set_image(&queue_image);
+    queue_button.set_sensitive(false);
+    let queue_button_container = gtk::ToolItem::new();
+    queue_button_container.add(&queue_button);
+    toolbar.add(&queue_button_container);
+
     // Add save file button
     let save_button = gtk::ToggleButton::new();
     save_button.set_tooltip_text("Log to file");
@@ -458,43 +731,50 @@ fn ui_init() {
     vbox.pack_start(&scrolled_text_view, true, true, 0);
     vbox.pack_start(&scrolled_hex_view, true, true, 0);
     vbox.pack_start(&status_bar, false, false, 0);
-    window.add(&vbox);
+
+    // The tab shows which port the session is for along with an indicator that's lit while the
+    // port is open, so it's easy to see which sessions are active.
+    let tab_box = gtk::Box::new(gtk::Orientation::Horizontal, 5);
+    let tab_indicator = gtk::Label::new("\u{25CF}");
+    tab_indicator.set_tooltip_text("Port closed");
+    tab_indicator.get_style_context().add_class("session-indicator");
+    tab_box.pack_start(&tab_indicator, false, false, 0);
+    let tab_label = gtk::Label::new("Not connected");
+    tab_box.pack_start(&tab_label, true, true, 0);
+    let close_button = gtk::Button::new_from_icon_name("window-close-symbolic",
+                                                       gtk::IconSize::Menu);
+    close_button.set_relief(gtk::ReliefStyle::None);
+    close_button.set_tooltip_text("Close session");
+    close_button.connect_clicked(move |_| close_session(id));
+    tab_box.pack_start(&close_button, false, false, 0);
+    tab_box.show_all();
+    let page = notebook.append_page(&vbox, Some(&tab_box));
+    notebook.set_tab_reorderable(&vbox, true);
 
     // Make sure all desired widgets are visible.
-    window.show_all();
+    vbox.show_all();
     scrolled_hex_view.hide();
 
-    // Set CSS styles for the entire application.
-    let css_provider = gtk::CssProvider::new();
-    let display = gdk::Display::get_default().expect("Couldn't open default GDK display");
-    let screen = display.get_default_screen();
-    gtk::StyleContext::add_provider_for_screen(&screen,
-                                               &css_provider,
-                                               gtk::STYLE_PROVIDER_PRIORITY_APPLICATION);
-    css_provider.load_from_path("resources/style.css").expect("Failed to load CSS stylesheet");
-
-    baud_dropdown.connect_changed(move |s| if let Some(baud_rate) = s.get_active_text() {
-                                            GLOBAL.with(|global| {
-            if let Some((_, ref serial_thread, _)) = *global.borrow() {
-                match serial_thread.send_port_change_baud_cmd(baud_rate.to_string()) {
-                    Err(GeneralError::Parse(_)) => {
-                        error!("Invalid baud rate '{}' specified.", &baud_rate)
-                    }
-                    Err(GeneralError::Send(_)) => {
-                        error!("Error sending port_open command to child thread. \
-                                    Aborting.")
-                    }
-                    Ok(_) => (),
-                }
-            }
-        });
+    // Typing into the entry changes the dropdown as well, but typed rates are only validated then
+    // and not sent until ENTER is pressed so that partial rates don't end up on the port.
+    let entry = baud_entry.clone();
+    let baud_dropdown_changed_signal = baud_dropdown.connect_changed(move |s| {
+        let baud_rate = s.get_active_text().map(|t| t.to_string()).unwrap_or_default();
+        set_entry_error(&entry, parse_baud_rate(&baud_rate).is_none());
+        if s.get_active().is_some() {
+            change_baud_rate(id, &baud_rate);
+        }
+    });
+    baud_entry.connect_activate(move |e| {
+        change_baud_rate(id, &e.get_text().map(|t| t.to_string()).unwrap_or_default());
     });
 
     let ports_dropdown_changed_signal = ports_dropdown.connect_changed(
         move |s| if let Some(port_name) =
-        s.get_active_text() {
+        s.get_active_id() {
             GLOBAL.with(|global| {
-                if let Some((_, ref serial_thread, _)) = *global.borrow() {
+                if let Some((ui, serial_thread, _)) = global.borrow().get(&id) {
+                    set_port_tooltip(s, &ui.ports);
                     match serial_thread.send_port_change_port_cmd(port_name.to_string()) {
                         Err(GeneralError::Parse(_)) => {
                             error!("Invalid port name '{}' specified.", &port_name)
@@ -512,9 +792,17 @@ fn ui_init() {
     let open_button_clicked_signal = open_button.connect_clicked(move |s| {
         if s.get_active() {
             GLOBAL.with(|global| {
-                if let Some((ref ui, ref serial_thread, _)) = *global.borrow() {
-                    if let Some(port_name) = ui.ports_dropdown.get_active_text() {
+                if let Some((ui, serial_thread, _)) = global.borrow().get(&id) {
+                    if let Some(port_name) = ui.ports_dropdown.get_active_id() {
                         if let Some(baud_rate) = ui.baud_dropdown.get_active_text() {
+                            if parse_baud_rate(&baud_rate).is_none() {
+                                let msg = format!("Invalid baud rate '{}'", &baud_rate);
+                                log_status(ui, StatusContext::PortOperation, &msg);
+                                signal_handler_block(s, &ui.open_button_clicked_signal);
+                                s.set_active(false);
+                                signal_handler_unblock(s, &ui.open_button_clicked_signal);
+                                return;
+                            }
                             match serial_thread.send_port_open_cmd(port_name.to_string(),
                                                                    baud_rate.to_string()) {
                                 Err(GeneralError::Parse(_)) => {
@@ -535,7 +823,8 @@ fn ui_init() {
                 }
             });
         } else {
-            GLOBAL.with(|global| if let Some((ref ui, ref sthread, _)) = *global.borrow() {
+            GLOBAL.with(|global| {
+                if let Some((ui, sthread, _)) = global.borrow().get(&id) {
                     match sthread.send_port_close_cmd() {
                         Err(GeneralError::Send(_)) => {
                             error!("Error sending port_close command to child thread. \
@@ -544,22 +833,30 @@ fn ui_init() {
                         Err(_) | Ok(_) => (),
                     }
                     ui.send_button.set_image(&ui.send_button_static_icon);
-                });
+                }
+            });
         }
     });
 
     // Connect send file selector button to callback. This is left as a
     // separate function to reduce rightward drift.
     let send_button_toggled_signal = send_button
-        .connect_toggled(send_button_connect_toggled);
+        .connect_toggled(move |b| send_button_connect_toggled(id, b));
+
+    // Connect receive file selector button to callback
+    let receive_button_toggled_signal = receive_button
+        .connect_toggled(move |b| receive_button_connect_toggled(id, b));
+
+    // Connect send queue button to callback
+    queue_button.connect_clicked(move |_| queue_button_clicked(id));
 
     // Connect log file selector button to callback. This is left as a
     // separate function to reduce rightward drift.
     let save_button_toggled_signal = save_button
-        .connect_toggled(save_button_connect_toggled);
+        .connect_toggled(move |b| save_button_connect_toggled(id, b));
 
     // Configure the data bits callback
-    data_bits_scale.connect_value_changed(|s| {
+    let data_bits_scale_changed_signal = data_bits_scale.connect_value_changed(move |s| {
         let data_bits = match s.get_value() as u8 {
             5 => DataBits::Five,
             6 => DataBits::Six,
@@ -567,43 +864,47 @@ fn ui_init() {
             8 => DataBits::Eight,
             _ => unreachable!(),
         };
-        GLOBAL.with(|global| if let Some((_, ref serial_thread, _)) = *global.borrow() {
-            match serial_thread.send_port_change_data_bits_cmd(data_bits) {
-                Err(GeneralError::Parse(_)) => {
-                    unreachable!();
-                }
-                Err(GeneralError::Send(_)) => {
-                    error!("Error sending data bits change command to child thread. \
-                            Aborting.")
+        GLOBAL.with(|global| {
+            if let Some((_, serial_thread, _)) = global.borrow().get(&id) {
+                match serial_thread.send_port_change_data_bits_cmd(data_bits) {
+                    Err(GeneralError::Parse(_)) => {
+                        unreachable!();
+                    }
+                    Err(GeneralError::Send(_)) => {
+                        error!("Error sending data bits change command to child thread. \
+                                Aborting.")
+                    }
+                    Ok(_) => (),
                 }
-                Ok(_) => (),
             }
         });
     });
 
     // Configure the data bits callback
-    stop_bits_scale.connect_value_changed(|s| {
+    let stop_bits_scale_changed_signal = stop_bits_scale.connect_value_changed(move |s| {
         let stop_bits = match s.get_value() as u8 {
             1 => StopBits::One,
             2 => StopBits::Two,
             _ => unreachable!(),
         };
-        GLOBAL.with(|global| if let Some((_, ref serial_thread, _)) = *global.borrow() {
-            match serial_thread.send_port_change_stop_bits_cmd(stop_bits) {
-                Err(GeneralError::Parse(_)) => {
-                    unreachable!();
-                }
-                Err(GeneralError::Send(_)) => {
-                    error!("Error sending stop bits change command to child thread. \
-                            Aborting.")
+        GLOBAL.with(|global| {
+            if let Some((_, serial_thread, _)) = global.borrow().get(&id) {
+                match serial_thread.send_port_change_stop_bits_cmd(stop_bits) {
+                    Err(GeneralError::Parse(_)) => {
+                        unreachable!();
+                    }
+                    Err(GeneralError::Send(_)) => {
+                        error!("Error sending stop bits change command to child thread. \
+                                Aborting.")
+                    }
+                    Ok(_) => (),
                 }
-                Ok(_) => (),
             }
         });
     });
 
     // Configure the parity dropdown callback
-    parity_dropdown.connect_changed(|s| {
+    let parity_dropdown_changed_signal = parity_dropdown.connect_changed(move |s| {
         let parity = match s.get_active_text() {
             Some(ref x) if x == "none" => Some(Parity::None),
             Some(ref x) if x == "odd" => Some(Parity::Odd),
@@ -611,21 +912,23 @@ fn ui_init() {
             Some(_) | None => unreachable!(),
         };
         if let Some(parity) = parity {
-            GLOBAL.with(|global| if let Some((_, ref serial_thread, _)) = *global.borrow() {
-                match serial_thread.send_port_change_parity_cmd(parity) {
-                    Err(GeneralError::Parse(_)) => unreachable!(),
-                    Err(GeneralError::Send(_)) => {
-                        error!("Error sending parity change command \
-                            to child thread. Aborting.")
+            GLOBAL.with(|global| {
+                if let Some((_, serial_thread, _)) = global.borrow().get(&id) {
+                    match serial_thread.send_port_change_parity_cmd(parity) {
+                        Err(GeneralError::Parse(_)) => unreachable!(),
+                        Err(GeneralError::Send(_)) => {
+                            error!("Error sending parity change command \
+                                to child thread. Aborting.")
+                        }
+                        Ok(_) => (),
                     }
-                    Ok(_) => (),
                 }
             });
         }
     });
 
     // Configure the flow control dropdown callback
-    flow_control_dropdown.connect_changed(|s| {
+    let flow_control_dropdown_changed_signal = flow_control_dropdown.connect_changed(move |s| {
         let flow_control = match s.get_active_text() {
             Some(ref x) if x == "none" => Some(FlowControl::None),
             Some(ref x) if x == "software" => Some(FlowControl::Software),
@@ -633,7 +936,8 @@ fn ui_init() {
             Some(_) | None => unreachable!(),
         };
         if let Some(flow_control) = flow_control {
-            GLOBAL.with(|global| if let Some((_, ref serial_thread, _)) = *global.borrow() {
+            GLOBAL.with(|global| {
+                if let Some((_, serial_thread, _)) = global.borrow().get(&id) {
                     match serial_thread.send_port_change_flow_control_cmd(flow_control) {
                         Err(GeneralError::Parse(_)) => {
                             unreachable!();
@@ -644,18 +948,47 @@ fn ui_init() {
                         }
                         Ok(_) => (),
                     }
-                });
+                }
+            });
         }
     });
 
+    // Configure the control line toggles
+    let dtr_button_toggled_signal = dtr_button.connect_toggled(move |b| {
+        GLOBAL.with(|global| {
+            if let Some((_, serial_thread, _)) = global.borrow().get(&id) {
+                let dtr = b.get_active();
+                if serial_thread.send_port_dtr_cmd(dtr).is_err() {
+                    error!("Error sending DTR command to child thread. Aborting.")
+                }
+            }
+        });
+    });
+    let rts_button_toggled_signal = rts_button.connect_toggled(move |b| {
+        GLOBAL.with(|global| {
+            if let Some((_, serial_thread, _)) = global.borrow().get(&id) {
+                let rts = b.get_active();
+                if serial_thread.send_port_rts_cmd(rts).is_err() {
+                    error!("Error sending RTS command to child thread. Aborting.")
+                }
+            }
+        });
+    });
+
     // Configure the right-click menu for the both the text and hex view widgets
-    text_view.connect_populate_popup(view_populate_popup);
-    hex_view.connect_populate_popup(view_populate_popup);
+    text_view.connect_populate_popup(move |v, p| view_populate_popup(id, v, p));
+    hex_view.connect_populate_popup(move |v, p| view_populate_popup(id, v, p));
 
-    text_view.connect_key_press_event(|_, k| {
+    text_view.connect_key_press_event(move |_, k| {
         GLOBAL.with(|global| {
-            if let Some((_, ref serial_thread, ref state)) = *global.borrow() {
+            if let Some((_, serial_thread, state)) = global.borrow().get(&id) {
                 if state.connected_port.is_some() {
+                    // Ctrl+Pause produces the Break key
+                    if k.get_keyval() == gdk::enums::key::Break {
+                        send_break(serial_thread);
+                        return;
+                    }
+
                     let mut cmd: Option<(u8, char)> = None;
                     // Check for a backspace with no modifier keys
                     if k.get_state().is_empty() &&
@@ -695,8 +1028,10 @@ fn ui_init() {
     });
 
     // Allow the user to send data by typing/pasting it in either buffer
-    let text_buffer_insert_signal = text_buffer.connect_insert_text(buffer_insert);
-    let hex_buffer_insert_signal = hex_buffer.connect_insert_text(buffer_insert);
+    let text_buffer_insert_signal =
+        text_buffer.connect_insert_text(move |b, i, t| buffer_insert(id, b, i, t));
+    let hex_buffer_insert_signal =
+        hex_buffer.connect_insert_text(move |b, i, t| buffer_insert(id, b, i, t));
 
     // Disable deletion of characters within the textview
     let text_buffer_delete_signal = text_buffer.connect_delete_range(move |b, _, _| {
@@ -710,7 +1045,10 @@ fn ui_init() {
 
     // Set up channels for communicating with the port thread.
     let ui = Ui {
-        window: window.clone(),
+        window,
+        page: vbox.clone(),
+        tab_label,
+        tab_indicator,
         text_view: text_view.clone(),
         hex_view: hex_view.clone(),
         scrolled_text_view: scrolled_text_view.clone(),
@@ -718,6 +1056,8 @@ fn ui_init() {
         text_buffer: text_buffer.clone(),
         hex_buffer: hex_buffer.clone(),
         send_button: send_button.clone(),
+        receive_button: receive_button.clone(),
+        queue_button: queue_button.clone(),
         open_button: open_button.clone(),
         save_button: save_button.clone(),
         status_bar: status_bar.clone(),
@@ -729,32 +1069,73 @@ fn ui_init() {
         flow_control_dropdown: flow_control_dropdown.clone(),
         flow_control_map: flow_control_dropdown_map,
         baud_dropdown: baud_dropdown.clone(),
-        baud_map: baud_dropdown_map,
+        baud_entry: baud_entry.clone(),
         ports_dropdown: ports_dropdown.clone(),
-        ports_map: ports_dropdown_map,
+        ports,
         text_buffer_insert_signal: text_buffer_insert_signal,
         hex_buffer_insert_signal: hex_buffer_insert_signal,
         text_buffer_delete_signal: text_buffer_delete_signal,
         hex_buffer_delete_signal: hex_buffer_delete_signal,
         open_button_clicked_signal: open_button_clicked_signal,
         send_button_toggled_signal: send_button_toggled_signal,
+        receive_button_toggled_signal,
         save_button_toggled_signal: save_button_toggled_signal,
         send_button_progress_icon: operations_icon,
         send_button_static_icon: send_image,
         ports_dropdown_changed_signal: ports_dropdown_changed_signal,
+        baud_dropdown_changed_signal,
+        data_bits_scale_changed_signal,
+        stop_bits_scale_changed_signal,
+        parity_dropdown_changed_signal,
+        flow_control_dropdown_changed_signal,
+        dtr_button: dtr_button.clone(),
+        rts_button: rts_button.clone(),
+        dtr_button_toggled_signal,
+        rts_button_toggled_signal,
+        cts_indicator: cts_indicator.clone(),
+        dsr_indicator: dsr_indicator.clone(),
+        ri_indicator: ri_indicator.clone(),
+        cd_indicator: cd_indicator.clone(),
     };
     let state = State {
         connected_port: None,
         line_ending: "\n".to_string(),
         send_file_percentage: 0,
+        receiving_file: false,
+        zmodem_offered: false,
+        send_options: Default::default(),
+        send_queue: Default::default(),
+        log_options: Default::default(),
+        auto_log: None,
+        send_protocol: Protocol::Raw,
+        receive_protocol: Protocol::XmodemCrc,
     };
-    GLOBAL.with(move |global| {
-                    *global.borrow_mut() =
-                        Some((ui, SerialThread::new(|| { glib::idle_add(receive); }), state));
-                });
+    let serial_thread = SerialThread::new(move || { glib::idle_add(move || receive(id)); });
+    GLOBAL.with(move |global| { global.borrow_mut().insert(id, (ui, serial_thread, state)); });
+
+    notebook.set_current_page(page);
+    id
 }
 
-fn view_populate_popup(text_view: &gtk::TextView, popup: &gtk::Widget) {
+/// Closes a session's tab and stops its port thread, closing the port if it's open. Closing the
+/// last session closes the application.
+fn close_session(id: SessionId) {
+    // The session is only dropped after releasing `GLOBAL` as removing its widgets can trigger
+    // other callbacks.
+    let session = GLOBAL.with(|global| global.borrow_mut().remove(&id));
+    if let Some((ui, ..)) = session {
+        SHARED.with(|s| {
+            if let Some(ref shared) = *s.borrow() {
+                shared.notebook.remove(&ui.page);
+                if shared.notebook.get_n_pages() == 0 {
+                    gtk::main_quit();
+                }
+            }
+        });
+    }
+}
+
+fn view_populate_popup(id: SessionId, text_view: &gtk::TextView, popup: &gtk::Widget) {
     if let Ok(popup) = popup.clone().downcast::<gtk::Menu>() {
 
         // Remove the "delete" menu option as it doesn't even work
@@ -785,7 +1166,7 @@ fn view_populate_popup(text_view: &gtk::TextView, popup: &gtk::Widget) {
         newline_submenu.append(&newline_r);
         let newline_rn = gtk::RadioMenuItem::new_with_label_from_widget(&newline_n, "\\r\\n");
         newline_submenu.append(&newline_rn);
-        GLOBAL.with(|global| if let Some((.., ref state)) = *global.borrow() {
+        GLOBAL.with(|global| if let Some((.., state)) = global.borrow().get(&id) {
                         match state.line_ending.as_ref() {
                             "\n" => newline_n.activate(),
                             "\r" => newline_r.activate(),
@@ -793,9 +1174,9 @@ fn view_populate_popup(text_view: &gtk::TextView, popup: &gtk::Widget) {
                             _ => unreachable!(),
                         };
                     });
-        newline_n.connect_toggled(|w| {
+        newline_n.connect_toggled(move |w| {
             GLOBAL.with(|global| {
-                if let Some((.., ref mut state)) = *global.borrow_mut() {
+                if let Some(&mut (.., ref mut state)) = global.borrow_mut().get_mut(&id) {
                     // The toggle signal triggers on activation and deactivation, so only respond
                     // to activations here.
                     if w.get_active() {
@@ -804,9 +1185,9 @@ fn view_populate_popup(text_view: &gtk::TextView, popup: &gtk::Widget) {
                 }
             });
         });
-        newline_r.connect_toggled(|w| {
+        newline_r.connect_toggled(move |w| {
             GLOBAL.with(|global| {
-                if let Some((.., ref mut state)) = *global.borrow_mut() {
+                if let Some(&mut (.., ref mut state)) = global.borrow_mut().get_mut(&id) {
                     // The toggle signal triggers on activation and deactivation, so only respond
                     // to activations here.
                     if w.get_active() {
@@ -815,9 +1196,9 @@ fn view_populate_popup(text_view: &gtk::TextView, popup: &gtk::Widget) {
                 }
             });
         });
-        newline_rn.connect_toggled(|w| {
+        newline_rn.connect_toggled(move |w| {
             GLOBAL.with(|global| {
-                if let Some((.., ref mut state)) = *global.borrow_mut() {
+                if let Some(&mut (.., ref mut state)) = global.borrow_mut().get_mut(&id) {
                     // The toggle signal triggers on activation and deactivation, so only respond
                     // to activations here.
                     if w.get_active() {
@@ -831,6 +1212,24 @@ fn view_populate_popup(text_view: &gtk::TextView, popup: &gtk::Widget) {
         newline.set_submenu(Some(&newline_submenu));
         popup.prepend(&newline);
 
+        // Add an item to send a break, which is also available with Ctrl+Break
+        let break_item = gtk::MenuItem::new_with_label("Send break");
+        if let Some(label) = break_item.get_child()
+               .and_then(|c| c.downcast::<gtk::AccelLabel>().ok()) {
+            label.set_accel(gdk::enums::key::Break, ModifierType::CONTROL_MASK);
+        }
+        GLOBAL.with(|global| if let Some((.., state)) = global.borrow().get(&id) {
+                        break_item.set_sensitive(state.connected_port.is_some());
+                    });
+        break_item.connect_activate(move |_| {
+            GLOBAL.with(|global| {
+                if let Some((_, serial_thread, _)) = global.borrow().get(&id) {
+                    send_break(serial_thread);
+                }
+            });
+        });
+        popup.prepend(&break_item);
+
         // Add the text or Hex view selectors
         // Note: These are in reverse order because they use `prepend()`.
         let separator = gtk::SeparatorMenuItem::new();
@@ -839,16 +1238,16 @@ fn view_populate_popup(text_view: &gtk::TextView, popup: &gtk::Widget) {
         popup.prepend(&view_hex);
         let view_text = gtk::RadioMenuItem::new_with_label_from_widget(&view_hex, "Text");
         popup.prepend(&view_text);
-        GLOBAL.with(|global| if let Some((ref ui, ..)) = *global.borrow() {
+        GLOBAL.with(|global| if let Some((ui, ..)) = global.borrow().get(&id) {
                         if ui.scrolled_hex_view.get_visible() {
                             view_hex.activate();
                         } else if ui.scrolled_text_view.get_visible() {
                 view_text.activate();
             }
                     });
-        view_hex.connect_toggled(|w| {
+        view_hex.connect_toggled(move |w| {
             GLOBAL.with(|global| {
-                if let Some((ref ui, ..)) = *global.borrow() {
+                if let Some((ui, ..)) = global.borrow().get(&id) {
                     // The toggle signal triggers on activation and deactivation, so only respond
                     // to activations here.
                     if w.get_active() {
@@ -874,9 +1273,9 @@ fn view_populate_popup(text_view: &gtk::TextView, popup: &gtk::Widget) {
                 }
             });
         });
-        view_text.connect_toggled(|w| {
+        view_text.connect_toggled(move |w| {
             GLOBAL.with(|global| {
-                if let Some((ref ui, ..)) = *global.borrow() {
+                if let Some((ui, ..)) = global.borrow().get(&id) {
                     // The toggle signal triggers on activation and deactivation, so only respond
                     // to activations here.
                     if w.get_active() {
@@ -905,7 +1304,7 @@ fn view_populate_popup(text_view: &gtk::TextView, popup: &gtk::Widget) {
 
         // Only enable the Paste option if a port is open
         GLOBAL.with(|global| {
-            if let Some((_, _, ref state)) = *global.borrow() {
+            if let Some((_, _, state)) = global.borrow().get(&id) {
                 if state.connected_port.is_none() {
                     for c in popup.get_children() {
                         // Workaround for Bug 778162:
@@ -933,9 +1332,9 @@ fn view_populate_popup(text_view: &gtk::TextView, popup: &gtk::Widget) {
             if b.get_char_count() == 0 {
                 clear_all.set_sensitive(false);
             } else {
-                clear_all.connect_activate(|_| {
+                clear_all.connect_activate(move |_| {
                     GLOBAL.with(|global| {
-                        if let Some((ref ui, _, _)) = *global.borrow() {
+                        if let Some((ui, _, _)) = global.borrow().get(&id) {
                             // In order to clear the buffer we need to
                             // disable the insert-text and delete-range
                             // signal handlers.
@@ -960,29 +1359,36 @@ fn view_populate_popup(text_view: &gtk::TextView, popup: &gtk::Widget) {
     }
 }
 
-fn buffer_insert(textbuffer: &gtk::TextBuffer, _: &mut gtk::TextIter, text: &str) {
-    GLOBAL.with(|global| if let Some((_, ref serial_thread, ref state)) = *global.borrow() {
-        let text = text.replace("\n", &state.line_ending);
-        debug!("Sending {:?}", &text);
-        let text = text.as_bytes();
-        match serial_thread.send_port_data_cmd(text) {
-            Err(GeneralError::Send(_)) => {
+fn buffer_insert(id: SessionId,
+                 textbuffer: &gtk::TextBuffer,
+                 _: &mut gtk::TextIter,
+                 text: &str) {
+    GLOBAL.with(|global| {
+        if let Some((_, serial_thread, state)) = global.borrow().get(&id) {
+            let text = text.replace("\n", &state.line_ending);
+            debug!("Sending {:?}", &text);
+            let text = text.as_bytes();
+            if let Err(GeneralError::Send(_)) = serial_thread.send_port_data_cmd(text) {
                 error!("Error sending data command to child thread. Aborting.")
             }
-            Err(_) | Ok(_) => (),
         }
     });
     signal_stop_emission_by_name(textbuffer, "insert-text");
 }
 
-fn receive() -> glib::Continue {
+fn receive(id: SessionId) -> glib::Continue {
     GLOBAL.with(|global| {
-        if let Some((ref mut ui, ref serial_thread, ref mut state)) = *global.borrow_mut() {
+        let mut sessions = global.borrow_mut();
+        // Baud rates are remembered for all sessions, so that has to wait until this session's
+        // borrow has ended.
+        let mut accepted_baud_rate = None;
+        if let Some(&mut (ref mut ui, ref serial_thread, ref mut state)) = sessions.get_mut(&id) {
             let window = &ui.window;
             let view = &ui.text_view;
             let ascii_buf = &ui.text_buffer;
             let hex_buf = &ui.hex_buffer;
             let f_button = &ui.send_button;
+            let r_button = &ui.receive_button;
             let s_button = &ui.save_button;
             let o_button = &ui.open_button;
             match serial_thread.from_port_chan_rx.try_recv() {
@@ -1043,10 +1449,17 @@ fn receive() -> glib::Continue {
                     view.scroll_mark_onscreen(&mark);
                 }
                 Ok(SerialResponse::DisconnectSuccess) => {
+                    clear_input_lines(ui);
+                    show_session_connected(ui, false);
                     f_button.set_sensitive(false);
+                    ui.queue_button.set_sensitive(false);
                     signal_handler_block(f_button, &ui.send_button_toggled_signal);
                     f_button.set_active(false);
                     signal_handler_unblock(f_button, &ui.send_button_toggled_signal);
+                    r_button.set_sensitive(false);
+                    signal_handler_block(r_button, &ui.receive_button_toggled_signal);
+                    r_button.set_active(false);
+                    signal_handler_unblock(r_button, &ui.receive_button_toggled_signal);
                     s_button.set_sensitive(false);
                     signal_handler_block(s_button, &ui.save_button_toggled_signal);
                     s_button.set_active(false);
@@ -1056,13 +1469,31 @@ fn receive() -> glib::Continue {
                 }
                 Ok(SerialResponse::OpenPortSuccess(s)) => {
                     f_button.set_sensitive(true);
+                    ui.queue_button.set_sensitive(true);
+                    r_button.set_sensitive(true);
                     s_button.set_sensitive(true);
                     o_button.set_active(true);
+                    ui.tab_label.set_text(&s);
+                    show_session_connected(ui, true);
                     state.connected_port = Some(s);
                     log_status(&ui, StatusContext::PortOperation, "Port opened");
+                    let baud_rate = ui.baud_dropdown.get_active_text();
+                    accepted_baud_rate = baud_rate.and_then(|b| parse_baud_rate(&b));
+                    if let Some(ref template) = state.auto_log {
+                        let path = expand_log_path(ui, state, template);
+                        if start_logging(ui, serial_thread, path, state.log_options) {
+                            signal_handler_block(s_button, &ui.save_button_toggled_signal);
+                            s_button.set_active(true);
+                            signal_handler_unblock(s_button, &ui.save_button_toggled_signal);
+                        }
+                    }
                 }
                 Ok(SerialResponse::OpenPortError(s)) => {
+                    clear_input_lines(ui);
+                    show_session_connected(ui, false);
                     f_button.set_sensitive(false);
+                    ui.queue_button.set_sensitive(false);
+                    r_button.set_sensitive(false);
                     s_button.set_sensitive(false);
                     signal_handler_block(o_button, &ui.open_button_clicked_signal);
                     o_button.set_active(false);
@@ -1072,25 +1503,10 @@ fn receive() -> glib::Continue {
 
                     // We also rescan the ports since it was likely a disconnection that caused this
                     // error:
-                    let ports = list_ports().unwrap_or_default();
-                    ui.ports_dropdown.remove_all();
-                    ui.ports_map.clear();
-                    if ports.is_empty() {
-                        ui.ports_dropdown.append(None, "No ports found");
-                        ui.ports_dropdown.set_sensitive(false);
-                        o_button.set_sensitive(false);
-                    } else {
-                        for (i, p) in (0u32..).zip(ports.into_iter()) {
-                            ui.ports_dropdown.append(None, &p);
-                            ui.ports_map.insert(p, i);
-                        }
-                        ui.ports_dropdown.set_sensitive(true);
-                        o_button.set_sensitive(true);
-                    }
-                    signal_handler_block(&ui.ports_dropdown, &ui.ports_dropdown_changed_signal);
-                    ui.ports_dropdown.set_active(0);
-                    signal_handler_unblock(&ui.ports_dropdown,
-                                           &ui.ports_dropdown_changed_signal);
+                    let mut ports = list_ports().unwrap_or_default();
+                    ports.sort();
+                    show_ports(ui, &ports);
+                    ui.ports = ports;
 
                     let s = format!("Error opening port ({})", s);
                     log_status(&ui, StatusContext::PortOperation, &s);
@@ -1105,29 +1521,31 @@ fn receive() -> glib::Continue {
                     dialog.show_all();
                 }
                 Ok(SerialResponse::SendingFileComplete) => {
-                    signal_handler_block(&ui.send_button, &ui.send_button_toggled_signal);
-                    f_button.set_active(false);
-                    signal_handler_unblock(&ui.send_button, &ui.send_button_toggled_signal);
-                    view.set_editable(true);
-                    log_status(&ui, StatusContext::FileOperation, "Sending file finished");
-                    f_button.set_image(&ui.send_button_static_icon);
+                    show_transfer_finished(ui, true);
+                    let s = if state.receiving_file {
+                        "Receiving file finished"
+                    } else {
+                        "Sending file finished"
+                    };
+                    log_status(ui, StatusContext::FileOperation, s);
                 }
                 Ok(SerialResponse::SendingFileCanceled) => {
                     info!("Sending file complete");
-                    signal_handler_block(&ui.send_button, &ui.send_button_toggled_signal);
-                    f_button.set_active(false);
-                    signal_handler_unblock(&ui.send_button, &ui.send_button_toggled_signal);
-                    view.set_editable(true);
-                    log_status(&ui, StatusContext::FileOperation, "Sending file canceled");
-                    f_button.set_image(&ui.send_button_static_icon);
+                    show_transfer_finished(ui, true);
+                    let s = if state.receiving_file {
+                        "Receiving file canceled"
+                    } else {
+                        "Sending file canceled"
+                    };
+                    log_status(ui, StatusContext::FileOperation, s);
                 }
-                Ok(SerialResponse::SendingFileError(_)) => {
-                    signal_handler_block(&ui.send_button, &ui.send_button_toggled_signal);
-                    f_button.set_active(false);
-                    signal_handler_unblock(&ui.send_button, &ui.send_button_toggled_signal);
-                    view.set_editable(true);
-                    f_button.set_image(&ui.send_button_static_icon);
-                    let s = "Error sending file";
+                Ok(SerialResponse::SendingFileError(e)) => {
+                    show_transfer_finished(ui, state.connected_port.is_some());
+                    let s = if state.receiving_file {
+                        format!("Error receiving file ({})", e)
+                    } else {
+                        format!("Error sending file ({})", e)
+                    };
                     log_status(&ui, StatusContext::FileOperation, &s);
                     let dialog = gtk::MessageDialog::new(Some(window),
                                                          DialogFlags::DESTROY_WITH_PARENT,
@@ -1140,39 +1558,78 @@ fn receive() -> glib::Continue {
                     dialog.show_all();
                 }
                 Ok(SerialResponse::SendingFileStarted) => {
-                    f_button.set_image(&ui.send_button_progress_icon);
+                    // The size of received files isn't known, so there's no progress to show
+                    if !state.receiving_file {
+                        f_button.set_image(&ui.send_button_progress_icon);
+                    }
                     state.send_file_percentage = 0;
                 }
-                Ok(SerialResponse::SendingFileProgress(i)) => {
-                    info!("Sending file {}% complete", i);
-                    state.send_file_percentage = i;
+                Ok(SerialResponse::SendingFileProgress(report)) => {
+                    debug!("Transfer progress {:?}", report);
+                    if let Some(percentage) = report.percentage() {
+                        state.send_file_percentage = percentage;
+                    }
+                    let tooltip = describe_progress(&report);
+                    ui.send_button_progress_icon.set_tooltip_text(tooltip.as_str());
                     ui.send_button_progress_icon.queue_draw();
                 }
+                Ok(SerialResponse::SendingFileBatchProgress(file)) => {
+                    let action = if state.receiving_file { "Receiving" } else { "Sending" };
+                    let position = match file.count {
+                        Some(count) => format!(" ({} of {})", file.index + 1, count),
+                        None => String::new(),
+                    };
+                    let percentage = match file.percentage() {
+                        Some(p) => format!(": {}%", p),
+                        None => String::new(),
+                    };
+                    let s = format!("{} '{}'{}{}", action, file.name, position, percentage);
+                    log_status(ui, StatusContext::FileOperation, &s);
+                }
+                Ok(SerialResponse::SendingQueueProgress { index, count, pass, passes }) => {
+                    state.send_file_percentage = 0;
+                    ui.send_button_progress_icon.queue_draw();
+                    let name = state.send_queue.files.get(index).map_or(String::new(), |f| {
+                        f.path.file_name().map_or(String::new(), |n| {
+                            format!("'{}' ", n.to_string_lossy())
+                        })
+                    });
+                    let passes = match passes {
+                        Some(passes) => format!("pass {} of {}", pass, passes),
+                        None => format!("pass {}", pass),
+                    };
+                    let s = format!("Sending {}({} of {}, {})", name, index + 1, count, passes);
+                    log_status(ui, StatusContext::FileOperation, &s);
+                }
+                Ok(SerialResponse::SendingLineProgress { sent, total }) => {
+                    let s = format!("Sent line {} of {}", sent, total);
+                    log_status(ui, StatusContext::FileOperation, &s);
+                }
+                Ok(SerialResponse::TransferStage(stage)) => {
+                    log_status(ui, StatusContext::FileOperation, &stage);
+                }
+                Ok(SerialResponse::ZmodemDetected) => {
+                    let transferring = f_button.get_active() || r_button.get_active();
+                    if !transferring && !state.zmodem_offered {
+                        state.zmodem_offered = true;
+                        offer_zmodem_receive(id, window);
+                    }
+                }
                 Ok(SerialResponse::UnexpectedDisconnection(ports)) => {
+                    clear_input_lines(ui);
+                    show_session_connected(ui, false);
                     // Update the port listing and other UI elements
-                    ui.ports_dropdown.remove_all();
-                    ui.ports_map.clear();
-                    if ports.is_empty() {
-                        ui.ports_dropdown.append(None, "No ports found");
-                        ui.ports_dropdown.set_sensitive(false);
-                        o_button.set_sensitive(false);
-                    } else {
-                        for (i, p) in (0u32..).zip(ports.into_iter()) {
-                            ui.ports_dropdown.append(None, &p);
-                            ui.ports_map.insert(p, i);
-                        }
-                        ui.ports_dropdown.set_sensitive(true);
-                        o_button.set_sensitive(true);
-                    }
-                    signal_handler_block(&ui.ports_dropdown,
-                                         &ui.ports_dropdown_changed_signal);
-                    ui.ports_dropdown.set_active(0);
-                    signal_handler_unblock(&ui.ports_dropdown,
-                                           &ui.ports_dropdown_changed_signal);
+                    show_ports(ui, &ports);
+                    ui.ports = ports;
                     f_button.set_sensitive(false);
+                    ui.queue_button.set_sensitive(false);
                     signal_handler_block(f_button, &ui.send_button_toggled_signal);
                     f_button.set_active(false);
                     signal_handler_unblock(f_button, &ui.send_button_toggled_signal);
+                    r_button.set_sensitive(false);
+                    signal_handler_block(r_button, &ui.receive_button_toggled_signal);
+                    r_button.set_active(false);
+                    signal_handler_unblock(r_button, &ui.receive_button_toggled_signal);
                     s_button.set_sensitive(false);
                     signal_handler_block(s_button, &ui.save_button_toggled_signal);
                     s_button.set_active(false);
@@ -1181,36 +1638,65 @@ fn receive() -> glib::Continue {
                     o_button.set_active(false);
                     signal_handler_unblock(o_button, &ui.open_button_clicked_signal);
 
-                    // Save the current port name and then update internal state
-                    let name = state.connected_port.take().expect("A port should be connected here");
-                    let s = format!("Port '{}' unexpectedly closed", name);
-
-                    // Warn the user as to what happened
-                    log_status(&ui, StatusContext::PortOperation, &s);
+                    // Save the current port name and then update internal state. The port may
+                    // have been closed already, in which case resetting the UI is all there is to
+                    // do.
+                    if let Some(name) = state.connected_port.take() {
+                        let s = format!("Port '{}' unexpectedly closed", name);
+
+                        // Warn the user as to what happened
+                        log_status(&ui, StatusContext::PortOperation, &s);
+                        let dialog = gtk::MessageDialog::new(Some(window),
+                                                             DialogFlags::DESTROY_WITH_PARENT |
+                                                             DialogFlags::MODAL,
+                                                             gtk::MessageType::Error,
+                                                             gtk::ButtonsType::Ok,
+                                                             &s);
+                        dialog.connect_response(|w, _| {
+                             w.destroy();
+                        });
+                        dialog.show_all();
+                    }
+                }
+                Ok(SerialResponse::LogToFileError(e)) => {
+                    signal_handler_block(s_button, &ui.save_button_toggled_signal);
+                    s_button.set_active(false);
+                    signal_handler_unblock(s_button, &ui.save_button_toggled_signal);
+                    let s = format!("Error logging to file ({})", e);
                     let dialog = gtk::MessageDialog::new(Some(window),
-                                                         DialogFlags::DESTROY_WITH_PARENT |
-                                                         DialogFlags::MODAL,
+                                                         DialogFlags::DESTROY_WITH_PARENT,
                                                          gtk::MessageType::Error,
                                                          gtk::ButtonsType::Ok,
                                                          &s);
+                    // Running the dialog here would handle other responses while the global
+                    // state is still borrowed
                     dialog.connect_response(|w, _| {
                          w.destroy();
                     });
                     dialog.show_all();
-
+                    log_status(ui, StatusContext::FileOperation, &s);
                 }
-                Ok(SerialResponse::LogToFileError(_)) => {
-                    s_button.set_active(false);
-                    let dialog = gtk::MessageDialog::new(Some(window),
-                                                         DialogFlags::DESTROY_WITH_PARENT,
-                                                         gtk::MessageType::Error,
-                                                         gtk::ButtonsType::Ok,
-                                                         "Error logging to file");
-                    dialog.run();
-                    dialog.destroy();
-                    log_status(&ui,
-                               StatusContext::FileOperation,
-                               "Error while logging to file");
+                Ok(SerialResponse::SettingsApplied(settings)) => {
+                    if state.connected_port.is_some() {
+                        let s = format!("Port settings changed to {}", format_settings(&settings));
+                        log_status(ui, StatusContext::PortOperation, &s);
+                        accepted_baud_rate = Some(settings.baud_rate);
+                    }
+                }
+                Ok(SerialResponse::SettingsRejected { settings, error }) => {
+                    show_settings(ui, &settings);
+                    let s = format!("Error changing port settings ({})", error);
+                    log_status(ui, StatusContext::PortOperation, &s);
+                }
+                Ok(SerialResponse::BreakSent) => {
+                    log_status(ui, StatusContext::PortOperation, "Break sent");
+                }
+                Ok(SerialResponse::ControlLinesChanged(lines)) => {
+                    show_control_lines(ui, &lines);
+                }
+                Ok(SerialResponse::PortError(e)) => {
+                    error!("Port error: {}", e);
+                    log_status(ui, StatusContext::PortOperation, &e.to_string());
                 }
                 Ok(SerialResponse::LoggingFileCanceled) => {
                     info!("Logging file canceled");
@@ -1219,71 +1705,41 @@ fn receive() -> glib::Continue {
                 }
                 Ok(SerialResponse::PortsFound(ports)) => {
                     info!("Found some ports!");
-                    // Determine if the new ports match existing ones
-                    let replace = {
-                        if ports.len() != ui.ports_map.len() {
-                            true
-                        } else {
-                            ports.iter()
-                                 .enumerate()
-                                 .map(|t| ui.ports_map[t.1] != t.0 as u32)
-                                 .all(|x| x)
-                        }
-                    };
-
-                    if replace {
-                        // First save whichever the currently-selected port is
-                        let current_port = {
-                            let active_port = ui.ports_dropdown.get_active().unwrap_or(0);
-                            let mut n = None;
-                            for (p, i) in &ui.ports_map {
-                                if *i == active_port {
-                                    n = Some(p.to_owned());
-                                    break;
-                                }
-                            }
-                            n
-                        };
-
-                        ui.ports_dropdown.remove_all();
-                        ui.ports_map.clear();
-                        if ports.is_empty() {
-                            ui.ports_dropdown.append(None, "No ports found");
-                            ui.ports_dropdown.set_sensitive(false);
-                            o_button.set_sensitive(false);
-                        } else {
-                            for (i, p) in (0u32..).zip(ports.into_iter()) {
-                                ui.ports_dropdown.append(None, &p);
-                                ui.ports_map.insert(p, i);
-                            }
-                            ui.ports_dropdown.set_sensitive(true);
-                            o_button.set_sensitive(true);
-                        }
-                        signal_handler_block(&ui.ports_dropdown, &ui.ports_dropdown_changed_signal);
-                        if let Some(p) = current_port {
-                            ui.ports_dropdown.set_active(*ui.ports_map.get(&p).unwrap_or(&0));
-                        } else {
-                            ui.ports_dropdown.set_active(0);
-                        }
-                        signal_handler_unblock(&ui.ports_dropdown,
-                                               &ui.ports_dropdown_changed_signal);
+                    if ports != ui.ports {
+                        show_ports(ui, &ports);
+                        ui.ports = ports;
                     }
                 }
                 Err(_) => (),
             }
         }
+        if let Some(baud_rate) = accepted_baud_rate {
+            remember_baud_rate(&sessions, baud_rate);
+        }
     });
     glib::Continue(false)
 }
 
-fn start_file_send(filename: PathBuf) {
+fn start_file_send(id: SessionId, mut paths: Vec<PathBuf>, protocol: Protocol, receive: bool) {
     GLOBAL.with(|global| {
-        if let Some((ref ui, ref serial_thread, _)) = *global.borrow() {
-            match serial_thread.send_port_file_cmd(filename.clone()) {
+        if let Some(&mut (ref ui, ref serial_thread, ref mut state)) =
+            global.borrow_mut().get_mut(&id) {
+            let description = match paths.len() {
+                1 => format!("'{}'", paths[0].display()),
+                n => format!("{} files", n),
+            };
+            let result = if receive {
+                state.receive_protocol = protocol;
+                serial_thread.receive_port_file_cmd(paths.remove(0), protocol)
+            } else {
+                state.send_protocol = protocol;
+                serial_thread.send_port_files_cmd(paths, protocol)
+            };
+            state.receiving_file = receive;
+            match result {
                 Err(_) => {
                     error!("Error sending port_file command to child thread. Aborting.");
-                    ui.send_button.set_sensitive(true);
-                    ui.send_button.set_active(false);
+                    show_transfer_finished(ui, true);
                     log_status(&ui,
                                StatusContext::FileOperation,
                                "Error trying to send file");
@@ -1292,40 +1748,648 @@ fn start_file_send(filename: PathBuf) {
                     // TODO: Add a SerialResponse::SendingFileStarted and move this into
                     // receive()
                     ui.text_view.set_editable(false);
+                    // Only one file can be transferred at a time
+                    ui.send_button.set_sensitive(!receive);
+                    ui.receive_button.set_sensitive(receive);
+                    ui.queue_button.set_sensitive(false);
+                    let action = if receive { "receiving into" } else { "sending" };
                     log_status(&ui,
                                StatusContext::FileOperation,
-                               &format!("Started sending file '{}'",
-                                        filename.to_str().unwrap()));
+                               &format!("Started {} {} using {}", action, description, protocol));
                 }
             }
         }
     });
 }
 
-fn send_button_connect_toggled(b: &gtk::ToggleButton) {
+/// Lets the queue be edited and sends it if the user asks to.
+fn queue_button_clicked(id: SessionId) {
+    // As with the send dialog, the session isn't held on to while the dialog runs
+    let session = GLOBAL.with(|global| {
+        global.borrow().get(&id).map(|(ui, _, state)| {
+            (ui.window.clone(),
+             state.send_queue.clone(),
+             state.send_protocol,
+             state.send_options.clone())
+        })
+    });
+    let (window, mut queue, protocol, mut options) = match session {
+        Some(s) => s,
+        None => return,
+    };
+    let send = edit_send_queue(&window, &mut queue, protocol, &mut options);
+    set_send_options(id, options);
+
     GLOBAL.with(|global| {
-        if let Some((ref ui, ref serial_thread, _)) = *global.borrow() {
-            let window = &ui.window;
-            if b.get_active() {
-                let dialog = gtk::FileChooserDialog::new(Some("Send File"),
-                                                         Some(window),
-                                                         gtk::FileChooserAction::Open);
-                dialog.add_buttons(&[("Send", gtk::ResponseType::Ok.into()),
-                                     ("Cancel", gtk::ResponseType::Cancel.into())]);
-                let result = dialog.run();
-                if result == gtk::ResponseType::Ok.into() {
-                    let filename = dialog.get_filename().unwrap();
-                    start_file_send(filename);
-                } else {
-                    // Make the button look inactive if the user canceled the
-                    // file open dialog
+        if let Some(&mut (ref ui, ref serial_thread, ref mut state)) =
+            global.borrow_mut().get_mut(&id) {
+            state.send_queue = queue.clone();
+            let idle = !ui.send_button.get_active() && !ui.receive_button.get_active();
+            if !send || !idle || state.connected_port.is_none() {
+                return;
+            }
+
+            let count = queue.files.len();
+            match serial_thread.send_queue_cmd(queue.files, queue.gap, queue.repeat) {
+                Err(_) => {
+                    error!("Error sending queue command to child thread. Aborting.");
+                    log_status(ui,
+                               StatusContext::FileOperation,
+                               "Error trying to send queue");
+                }
+                Ok(_) => {
+                    state.receiving_file = false;
+                    ui.text_view.set_editable(false);
                     signal_handler_block(&ui.send_button, &ui.send_button_toggled_signal);
-                    b.set_active(false);
+                    ui.send_button.set_active(true);
                     signal_handler_unblock(&ui.send_button, &ui.send_button_toggled_signal);
+                    ui.receive_button.set_sensitive(false);
+                    ui.queue_button.set_sensitive(false);
+                    log_status(ui,
+                               StatusContext::FileOperation,
+                               &format!("Started sending a queue of {} files", count));
                 }
+            }
+        }
+    });
+}
 
-                dialog.destroy();
-            } else {
+/// Shows `queue` for editing, with files being added through the send dialog using `protocol`
+/// and `options` by default. Returns whether the queue should be sent.
+fn edit_send_queue(window: &gtk::Window,
+                   queue: &mut SendQueue,
+                   protocol: Protocol,
+                   options: &mut SendOptions)
+                   -> bool {
+    let dialog = gtk::Dialog::new_with_buttons(Some("Send Queue"),
+                                               Some(window),
+                                               DialogFlags::MODAL |
+                                               DialogFlags::DESTROY_WITH_PARENT,
+                                               &[("Send", gtk::ResponseType::Ok),
+                                                 ("Close", gtk::ResponseType::Close)]);
+    dialog.set_default_size(500, 350);
+    let content = dialog.get_content_area();
+    content.set_spacing(6);
+
+    // The files are listed with their protocol, in the order they're sent
+    let store = gtk::ListStore::new(&[glib::Type::String, glib::Type::String]);
+    let tree_view = gtk::TreeView::new_with_model(&store);
+    for (i, title) in ["File", "Protocol"].iter().enumerate() {
+        let column = gtk::TreeViewColumn::new();
+        column.set_title(title);
+        let cell = gtk::CellRendererText::new();
+        column.pack_start(&cell, true);
+        column.add_attribute(&cell, "text", i as i32);
+        column.set_expand(i == 0);
+        tree_view.append_column(&column);
+    }
+    let scrolled_window = gtk::ScrolledWindow::new(None::<&gtk::Adjustment>,
+                                                   None::<&gtk::Adjustment>);
+    scrolled_window.set_vexpand(true);
+    scrolled_window.add(&tree_view);
+    content.pack_start(&scrolled_window, true, true, 0);
+
+    let files = Rc::new(RefCell::new(queue.files.clone()));
+    let chosen_options = Rc::new(RefCell::new((protocol, options.clone())));
+    // Shows `files` in the list, selecting the file at `selected`
+    let show_files = {
+        let store = store.clone();
+        let tree_view = tree_view.clone();
+        let files = files.clone();
+        move |selected: Option<usize>| {
+            store.clear();
+            for f in files.borrow().iter() {
+                let path = f.path.display().to_string();
+                let protocol = f.protocol.to_string();
+                store.insert_with_values(None, &[0, 1], &[&path, &protocol]);
+            }
+            if let Some(i) = selected {
+                let path = gtk::TreePath::new_from_indicesv(&[i as i32]);
+                tree_view.get_selection().select_path(&path);
+            }
+        }
+    };
+    show_files(None);
+    // The position of the file that's selected
+    let selected = {
+        let tree_view = tree_view.clone();
+        move || {
+            tree_view.get_selection()
+                .get_selected()
+                .and_then(|(model, iter)| model.get_path(&iter))
+                .and_then(|path| path.get_indices().first().map(|i| *i as usize))
+        }
+    };
+
+    let buttons = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+    let add_button = gtk::Button::new_with_label("Add");
+    {
+        let dialog = dialog.clone();
+        let files = files.clone();
+        let chosen_options = chosen_options.clone();
+        let show_files = show_files.clone();
+        add_button.connect_clicked(move |_| {
+            let (protocol, send_options) = chosen_options.borrow().clone();
+            let window = dialog.clone().upcast::<gtk::Window>();
+            let chosen = choose_transfer_files(&window,
+                                               false,
+                                               &SEND_PROTOCOLS,
+                                               protocol,
+                                               &send_options);
+            if let Some((paths, protocol, send_options)) = chosen {
+                *chosen_options.borrow_mut() = (protocol, send_options);
+                let mut files = files.borrow_mut();
+                for path in paths {
+                    files.push(QueuedFile {
+                                   path,
+                                   protocol,
+                               });
+                }
+                let last = files.len() - 1;
+                drop(files);
+                show_files(Some(last));
+            }
+        });
+    }
+    buttons.pack_start(&add_button, false, false, 0);
+    let remove_button = gtk::Button::new_with_label("Remove");
+    {
+        let files = files.clone();
+        let show_files = show_files.clone();
+        let selected = selected.clone();
+        remove_button.connect_clicked(move |_| if let Some(i) = selected() {
+            files.borrow_mut().remove(i);
+            let len = files.borrow().len();
+            show_files(if len > 0 { Some(i.min(len - 1)) } else { None });
+        });
+    }
+    buttons.pack_start(&remove_button, false, false, 0);
+    for &(label, up) in &[("Move Up", true), ("Move Down", false)] {
+        let button = gtk::Button::new_with_label(label);
+        let files = files.clone();
+        let show_files = show_files.clone();
+        let selected = selected.clone();
+        button.connect_clicked(move |_| if let Some(i) = selected() {
+            let len = files.borrow().len();
+            let other = if up { i.checked_sub(1) } else { Some(i + 1).filter(|j| *j < len) };
+            if let Some(j) = other {
+                files.borrow_mut().swap(i, j);
+                show_files(Some(j));
+            }
+        });
+        buttons.pack_start(&button, false, false, 0);
+    }
+    content.pack_start(&buttons, false, false, 0);
+
+    let settings = gtk::Grid::new();
+    settings.set_row_spacing(6);
+    settings.set_column_spacing(6);
+    let gap = gtk::SpinButton::new_with_range(0.0, 3_600_000.0, 100.0);
+    gap.set_value(duration_ms(queue.gap) as f64);
+    settings.attach(&gtk::Label::new(Some("Wait after each file (ms):")), 0, 0, 1, 1);
+    settings.attach(&gap, 1, 0, 1, 1);
+    let times = gtk::SpinButton::new_with_range(0.0, 1_000_000.0, 1.0);
+    times.set_value(match queue.repeat {
+                        Repeat::Times(n) => n as f64,
+                        Repeat::Forever => 0.0,
+                    });
+    settings.attach(&gtk::Label::new(Some("Send the queue (times, 0 for forever):")),
+                    0,
+                    1,
+                    1,
+                    1);
+    settings.attach(&times, 1, 1, 1, 1);
+    content.pack_start(&settings, false, false, 0);
+
+    dialog.show_all();
+    let send = dialog.run() == gtk::ResponseType::Ok.into();
+    queue.files = files.borrow().clone();
+    queue.gap = Duration::from_millis(gap.get_value_as_int() as u64);
+    queue.repeat = match times.get_value_as_int() {
+        n if n > 0 => Repeat::Times(n as u32),
+        _ => Repeat::Forever,
+    };
+    *options = chosen_options.borrow().1.clone();
+    dialog.destroy();
+    send && !queue.files.is_empty()
+}
+
+/// Asks where to put the files that the other end has started sending using ZMODEM, and starts
+/// receiving them there. The dialog isn't modal as this is called while handling responses.
+fn offer_zmodem_receive(id: SessionId, window: &gtk::Window) {
+    let dialog = gtk::FileChooserDialog::new(Some("Receive Files (ZMODEM)"),
+                                             Some(window),
+                                             gtk::FileChooserAction::SelectFolder);
+    dialog.add_buttons(&[("Receive", gtk::ResponseType::Ok),
+                         ("Cancel", gtk::ResponseType::Cancel)]);
+    dialog.connect_response(move |d, r| {
+        let dir = if r == gtk::ResponseType::Ok { d.get_filename() } else { None };
+        d.destroy();
+        let receiving = GLOBAL.with(|global| {
+            match global.borrow_mut().get_mut(&id) {
+                Some(&mut (ref ui, _, ref mut state)) => {
+                    state.zmodem_offered = false;
+                    let idle = !ui.send_button.get_active() && !ui.receive_button.get_active();
+                    if dir.is_some() && idle {
+                        signal_handler_block(&ui.receive_button,
+                                             &ui.receive_button_toggled_signal);
+                        ui.receive_button.set_active(true);
+                        signal_handler_unblock(&ui.receive_button,
+                                               &ui.receive_button_toggled_signal);
+                        true
+                    } else {
+                        false
+                    }
+                }
+                None => false,
+            }
+        });
+        if let (true, Some(dir)) = (receiving, dir) {
+            start_file_send(id, vec![dir], Protocol::Zmodem { resume: false }, true);
+        }
+    });
+    dialog.show_all();
+}
+
+/// Resets the file transfer buttons once a transfer has ended, leaving them usable if `connected`.
+fn show_transfer_finished(ui: &Ui, connected: bool) {
+    signal_handler_block(&ui.send_button, &ui.send_button_toggled_signal);
+    ui.send_button.set_active(false);
+    signal_handler_unblock(&ui.send_button, &ui.send_button_toggled_signal);
+    signal_handler_block(&ui.receive_button, &ui.receive_button_toggled_signal);
+    ui.receive_button.set_active(false);
+    signal_handler_unblock(&ui.receive_button, &ui.receive_button_toggled_signal);
+    ui.send_button.set_sensitive(connected);
+    ui.receive_button.set_sensitive(connected);
+    ui.queue_button.set_sensitive(connected);
+    ui.send_button.set_image(&ui.send_button_static_icon);
+    ui.text_view.set_editable(true);
+}
+
+/// What a text send waits for after each line, as listed in the send dialog.
+static WAIT_FOR_CHOICES: [&str; 3] = ["Nothing", "Echo", "Prompt"];
+
+/// The widgets in the send dialog that set the `SendOptions`. Only those used by the chosen
+/// protocol are shown.
+#[derive(Clone)]
+struct SendOptionsWidgets {
+    container: gtk::Box,
+    throughput_box: gtk::Box,
+    throughput: gtk::SpinButton,
+    fill_box: gtk::Box,
+    fill: gtk::Entry,
+    ack_box: gtk::Box,
+    ack: gtk::Entry,
+    pacing: LinePacingWidgets,
+    flash: FlashOptionsWidgets,
+}
+
+impl SendOptionsWidgets {
+    fn new(options: &SendOptions) -> Self {
+        let container = gtk::Box::new(gtk::Orientation::Vertical, 6);
+
+        let throughput_box = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+        let label = gtk::Label::new(Some("Limit rate (bytes/s, 0 for the port's rate):"));
+        throughput_box.pack_start(&label, false, false, 0);
+        let throughput = gtk::SpinButton::new_with_range(0.0, 10_000_000.0, 100.0);
+        throughput.set_value(options.throughput.unwrap_or(0) as f64);
+        throughput_box.pack_start(&throughput, false, false, 0);
+        container.pack_start(&throughput_box, false, false, 0);
+
+        let (fill_box, fill) = hex_byte_entry("Fill gaps with (hex):", options.image.fill);
+        container.pack_start(&fill_box, false, false, 0);
+        let (ack_box, ack) = hex_byte_entry("Acknowledgement (hex):", options.image.ack);
+        container.pack_start(&ack_box, false, false, 0);
+
+        let pacing = LinePacingWidgets::new(&options.pacing);
+        container.pack_start(&pacing.container, false, false, 0);
+
+        let flash = FlashOptionsWidgets::new(&options.flash);
+        container.pack_start(&flash.container, false, false, 0);
+
+        SendOptionsWidgets {
+            container,
+            throughput_box,
+            throughput,
+            fill_box,
+            fill,
+            ack_box,
+            ack,
+            pacing,
+            flash,
+        }
+    }
+
+    /// Shows the options that apply to sending with `protocol`.
+    fn show_for(&self, protocol: Protocol) {
+        self.throughput_box
+            .set_visible(protocol == Protocol::Raw || protocol == Protocol::HexImage);
+        self.fill_box.set_visible(protocol == Protocol::HexImage);
+        self.ack_box.set_visible(protocol == Protocol::HexRecords);
+        self.pacing.container.set_visible(protocol == Protocol::Text);
+        self.flash.show_for(protocol);
+    }
+
+    /// The options that have been set, keeping those from `previous` where nothing valid was
+    /// entered.
+    fn options(&self, previous: &SendOptions) -> SendOptions {
+        SendOptions {
+            pacing: self.pacing.pacing(),
+            throughput: match self.throughput.get_value_as_int() {
+                t if t > 0 => Some(t as u32),
+                _ => None,
+            },
+            image: ImageOptions {
+                fill: parse_hex_byte(&self.fill).unwrap_or(previous.image.fill),
+                ack: parse_hex_byte(&self.ack).unwrap_or(previous.image.ack),
+            },
+            flash: self.flash.options(&previous.flash),
+        }
+    }
+}
+
+/// The widgets in the send dialog that set how images are flashed through microcontroller
+/// bootloaders.
+#[derive(Clone)]
+struct FlashOptionsWidgets {
+    container: gtk::Box,
+    address_box: gtk::Box,
+    address: gtk::Entry,
+    erase: gtk::CheckButton,
+    verify: gtk::CheckButton,
+    go: gtk::CheckButton,
+    reset: gtk::CheckButton,
+}
+
+impl FlashOptionsWidgets {
+    fn new(options: &FlashOptions) -> Self {
+        let container = gtk::Box::new(gtk::Orientation::Vertical, 6);
+
+        let address_box = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+        let label = gtk::Label::new(Some("Address of binary files (hex):"));
+        address_box.pack_start(&label, false, false, 0);
+        let address = gtk::Entry::new();
+        address.set_max_length(8);
+        address.set_width_chars(9);
+        address.set_text(&format!("{:08X}", options.address));
+        address_box.pack_start(&address, false, false, 0);
+        container.pack_start(&address_box, false, false, 0);
+
+        let check_button = |label: &str, active: bool| {
+            let button = gtk::CheckButton::new_with_label(label);
+            button.set_active(active);
+            container.pack_start(&button, false, false, 0);
+            button
+        };
+        let erase = check_button("Erase the flash first", options.erase);
+        let verify = check_button("Verify after writing", options.verify);
+        let go = check_button("Start the application", options.go);
+        let reset = check_button("Reset with DTR and select the bootloader with RTS",
+                                 options.reset);
+
+        FlashOptionsWidgets {
+            container,
+            address_box,
+            address,
+            erase,
+            verify,
+            go,
+            reset,
+        }
+    }
+
+    /// Shows the options that apply to flashing with `protocol`. Arduino bootloaders are always
+    /// reset with DTR, erase pages as they're written, and start the application afterwards.
+    fn show_for(&self, protocol: Protocol) {
+        let stm32 = protocol == Protocol::Stm32;
+        self.container.set_visible(stm32 || protocol == Protocol::Stk500);
+        self.address_box.set_visible(stm32);
+        self.erase.set_visible(stm32);
+        self.go.set_visible(stm32);
+        self.reset.set_visible(stm32);
+    }
+
+    /// The options that have been set, keeping the address from `previous` if the one entered
+    /// isn't valid.
+    fn options(&self, previous: &FlashOptions) -> FlashOptions {
+        let address = self.address
+            .get_text()
+            .and_then(|t| u32::from_str_radix(t.trim(), 16).ok())
+            .unwrap_or(previous.address);
+        FlashOptions {
+            address,
+            erase: self.erase.get_active(),
+            verify: self.verify.get_active(),
+            go: self.go.get_active(),
+            reset: self.reset.get_active(),
+        }
+    }
+}
+
+/// Creates an entry for a byte written in hex, labeled with `label`.
+fn hex_byte_entry(label: &str, value: u8) -> (gtk::Box, gtk::Entry) {
+    let container = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+    container.pack_start(&gtk::Label::new(Some(label)), false, false, 0);
+    let entry = gtk::Entry::new();
+    entry.set_max_length(2);
+    entry.set_width_chars(3);
+    entry.set_text(&format!("{:02X}", value));
+    container.pack_start(&entry, false, false, 0);
+    (container, entry)
+}
+
+fn parse_hex_byte(entry: &gtk::Entry) -> Option<u8> {
+    entry.get_text().and_then(|t| u8::from_str_radix(t.trim(), 16).ok())
+}
+
+/// The widgets in the send dialog that set how text files are paced.
+#[derive(Clone)]
+struct LinePacingWidgets {
+    container: gtk::Grid,
+    line_delay: gtk::SpinButton,
+    char_delay: gtk::SpinButton,
+    wait_for: gtk::ComboBoxText,
+    prompt: gtk::Entry,
+}
+
+impl LinePacingWidgets {
+    fn new(pacing: &LinePacing) -> Self {
+        let container = gtk::Grid::new();
+        container.set_row_spacing(6);
+        container.set_column_spacing(6);
+
+        let line_delay = gtk::SpinButton::new_with_range(0.0, 60_000.0, 10.0);
+        line_delay.set_value(duration_ms(pacing.line_delay) as f64);
+        container.attach(&gtk::Label::new(Some("Delay after lines (ms):")), 0, 0, 1, 1);
+        container.attach(&line_delay, 1, 0, 1, 1);
+
+        let char_delay = gtk::SpinButton::new_with_range(0.0, 10_000.0, 1.0);
+        char_delay.set_value(duration_ms(pacing.char_delay) as f64);
+        container.attach(&gtk::Label::new(Some("Delay after characters (ms):")), 0, 1, 1, 1);
+        container.attach(&char_delay, 1, 1, 1, 1);
+
+        let wait_for = gtk::ComboBoxText::new();
+        for choice in &WAIT_FOR_CHOICES {
+            wait_for.append_text(choice);
+        }
+        let prompt = gtk::Entry::new();
+        let (active, text) = match pacing.wait_for {
+            WaitFor::Nothing => (0, ""),
+            WaitFor::Echo => (1, ""),
+            WaitFor::Prompt(ref p) => (2, p.as_str()),
+        };
+        wait_for.set_active(active);
+        prompt.set_text(text);
+        prompt.set_sensitive(active == 2);
+        {
+            let prompt = prompt.clone();
+            wait_for.connect_changed(move |w| prompt.set_sensitive(w.get_active() == Some(2)));
+        }
+        container.attach(&gtk::Label::new(Some("Wait for:")), 0, 2, 1, 1);
+        container.attach(&wait_for, 1, 2, 1, 1);
+        container.attach(&prompt, 2, 2, 1, 1);
+
+        LinePacingWidgets {
+            container,
+            line_delay,
+            char_delay,
+            wait_for,
+            prompt,
+        }
+    }
+
+    /// The pacing that has been set.
+    fn pacing(&self) -> LinePacing {
+        let wait_for = match self.wait_for.get_active() {
+            Some(1) => WaitFor::Echo,
+            Some(2) => {
+                let prompt = self.prompt.get_text().map_or(String::new(), |t| t.to_string());
+                WaitFor::Prompt(prompt)
+            }
+            _ => WaitFor::Nothing,
+        };
+        LinePacing {
+            line_delay: Duration::from_millis(self.line_delay.get_value_as_int() as u64),
+            char_delay: Duration::from_millis(self.char_delay.get_value_as_int() as u64),
+            wait_for,
+        }
+    }
+}
+
+/// Describes how far a transfer is, as in "1.5 MB of 4.0 MB (37%), 11.5 kB/s, 3:37 left".
+fn describe_progress(report: &ProgressReport) -> String {
+    let mut s = format_bytes(report.bytes as f64);
+    if let (Some(total), Some(percentage)) = (report.total, report.percentage()) {
+        s += &format!(" of {} ({}%)", format_bytes(total as f64), percentage);
+    }
+    s += &format!(", {}/s", format_bytes(report.bytes_per_sec));
+    if let Some(remaining) = report.remaining {
+        let secs = remaining.as_secs();
+        let time = if secs >= 3600 {
+            format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+        } else {
+            format!("{}:{:02}", secs / 60, secs % 60)
+        };
+        s += &format!(", {} left", time);
+    }
+    s
+}
+
+fn format_bytes(bytes: f64) -> String {
+    if bytes >= 1_000_000.0 {
+        format!("{:.1} MB", bytes / 1_000_000.0)
+    } else if bytes >= 1000.0 {
+        format!("{:.1} kB", bytes / 1000.0)
+    } else {
+        format!("{:.0} B", bytes)
+    }
+}
+
+fn duration_ms(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + duration.subsec_millis() as u64
+}
+
+/// Asks for the files to send or where to receive and the protocol to use, with `selected` being
+/// the protocol chosen by default. Several files can be sent at once, and batch protocols receive
+/// into a directory. The options for the sending protocols start out as `options`.
+fn choose_transfer_files(window: &gtk::Window,
+                         receive: bool,
+                         protocols: &'static [Protocol],
+                         selected: Protocol,
+                         options: &SendOptions)
+                         -> Option<(Vec<PathBuf>, Protocol, SendOptions)> {
+    let (title, accept) = if receive {
+        ("Receive File", "Receive")
+    } else {
+        ("Send File", "Send")
+    };
+    let action = file_chooser_action(receive, selected);
+    let dialog = gtk::FileChooserDialog::new(Some(title), Some(window), action);
+    dialog.add_buttons(&[(accept, gtk::ResponseType::Ok),
+                         ("Cancel", gtk::ResponseType::Cancel)]);
+    dialog.set_do_overwrite_confirmation(true);
+    dialog.set_select_multiple(!receive);
+
+    let protocol_box = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+    protocol_box.pack_start(&gtk::Label::new(Some("Protocol:")), false, false, 0);
+    let protocol_dropdown = gtk::ComboBoxText::new();
+    for protocol in protocols {
+        protocol_dropdown.append_text(&protocol.to_string());
+    }
+    let active = protocols.iter().position(|p| *p == selected).unwrap_or(0);
+    protocol_dropdown.set_active(active as u32);
+    protocol_box.pack_start(&protocol_dropdown, false, false, 0);
+
+    let options_widgets = SendOptionsWidgets::new(options);
+    let extra_box = gtk::Box::new(gtk::Orientation::Vertical, 6);
+    extra_box.pack_start(&protocol_box, false, false, 0);
+    extra_box.pack_start(&options_widgets.container, false, false, 0);
+    extra_box.show_all();
+    options_widgets.container.set_visible(!receive);
+    options_widgets.show_for(selected);
+    dialog.set_extra_widget(&extra_box);
+    {
+        let dialog = dialog.clone();
+        let options_widgets = options_widgets.clone();
+        protocol_dropdown.connect_changed(move |d| if let Some(i) = d.get_active() {
+            let protocol = protocols[i as usize];
+            dialog.set_action(file_chooser_action(receive, protocol));
+            options_widgets.show_for(protocol);
+        });
+    }
+
+    let result = if dialog.run() == gtk::ResponseType::Ok.into() {
+        let protocol = protocol_dropdown.get_active().map_or(selected, |i| protocols[i as usize]);
+        let paths = dialog.get_filenames();
+        if paths.is_empty() {
+            None
+        } else {
+            Some((paths, protocol, options_widgets.options(options)))
+        }
+    } else {
+        None
+    };
+    dialog.destroy();
+    result
+}
+
+/// The file chooser action used to pick the files to send, or where to receive using `protocol`.
+fn file_chooser_action(receive: bool, protocol: Protocol) -> gtk::FileChooserAction {
+    if !receive {
+        gtk::FileChooserAction::Open
+    } else if protocol.is_batch() {
+        gtk::FileChooserAction::SelectFolder
+    } else {
+        gtk::FileChooserAction::Save
+    }
+}
+
+/// Starts a file transfer in the chosen direction when `b` is activated, and cancels the running
+/// one when it's deactivated.
+fn transfer_button_toggled(id: SessionId, b: &gtk::ToggleButton, receive: bool) {
+    if !b.get_active() {
+        GLOBAL.with(|global| {
+            if let Some((_, serial_thread, _)) = global.borrow().get(&id) {
                 match serial_thread.send_cancel_file_cmd() {
                     Err(GeneralError::Send(_)) => {
                         error!("Error sending cancel_file command to child thread. Aborting.");
@@ -1333,56 +2397,556 @@ fn send_button_connect_toggled(b: &gtk::ToggleButton) {
                     Err(_) | Ok(_) => (),
                 }
             }
+        });
+        return;
+    }
+
+    // The dialog is run without holding on to the session so that responses from the port thread
+    // can still be handled meanwhile.
+    let session = GLOBAL.with(|global| {
+        global.borrow().get(&id).map(|(ui, _, state)| {
+            let protocol = if receive { state.receive_protocol } else { state.send_protocol };
+            (ui.window.clone(), protocol, state.send_options.clone())
+        })
+    });
+    let (window, protocol, options) = match session {
+        Some(s) => s,
+        None => return,
+    };
+    let protocols: &'static [Protocol] = if receive {
+        &RECEIVE_PROTOCOLS
+    } else {
+        &SEND_PROTOCOLS
+    };
+    match choose_transfer_files(&window, receive, protocols, protocol, &options) {
+        Some((paths, protocol, options)) => {
+            if !receive {
+                set_send_options(id, options);
+            }
+            start_file_send(id, paths, protocol, receive);
+        }
+        None => {
+            // Make the button look inactive if the user canceled the file dialog
+            GLOBAL.with(|global| {
+                if let Some((ui, _, _)) = global.borrow().get(&id) {
+                    show_transfer_finished(ui, true);
+                }
+            });
+        }
+    }
+}
+
+/// Remembers `options` and uses them for sending files from now on.
+fn set_send_options(id: SessionId, options: SendOptions) {
+    GLOBAL.with(|global| {
+        if let Some(&mut (_, ref serial_thread, ref mut state)) =
+            global.borrow_mut().get_mut(&id) {
+            if serial_thread.send_line_pacing_cmd(options.pacing.clone()).is_err() {
+                error!("Error sending line_pacing command to child thread. Aborting.");
+            }
+            if serial_thread.send_throughput_cmd(options.throughput).is_err() {
+                error!("Error sending throughput command to child thread. Aborting.");
+            }
+            if serial_thread.send_image_options_cmd(options.image).is_err() {
+                error!("Error sending image_options command to child thread. Aborting.");
+            }
+            if serial_thread.send_flash_options_cmd(options.flash).is_err() {
+                error!("Error sending flash_options command to child thread. Aborting.");
+            }
+            state.send_options = options;
         }
     });
 }
 
-fn save_button_connect_toggled(b: &gtk::ToggleButton) {
+fn send_button_connect_toggled(id: SessionId, b: &gtk::ToggleButton) {
+    transfer_button_toggled(id, b, false);
+}
+
+fn receive_button_connect_toggled(id: SessionId, b: &gtk::ToggleButton) {
+    transfer_button_toggled(id, b, true);
+}
+
+fn save_button_connect_toggled(id: SessionId, b: &gtk::ToggleButton) {
+    if !b.get_active() {
+        GLOBAL.with(|global| {
+            if let Some((_, serial_thread, _)) = global.borrow().get(&id) {
+                if let Err(GeneralError::Send(_)) = serial_thread.send_cancel_log_to_file_cmd() {
+                    error!("Error sending cancel_log_to_file command to child thread. \
+                            Aborting.");
+                }
+            }
+        });
+        return;
+    }
+
+    // The dialog is run without holding on to the session so that responses from the port thread
+    // can still be handled meanwhile.
+    let session = GLOBAL.with(|global| {
+        global.borrow()
+            .get(&id)
+            .map(|(ui, _, state)| {
+                     (ui.window.clone(), state.log_options, state.auto_log.clone())
+                 })
+    });
+    let (window, options, auto_log) = match session {
+        Some(s) => s,
+        None => return,
+    };
+    let choice = choose_log_file(&window, options, auto_log);
     GLOBAL.with(|global| {
-        if let Some((ref ui, ref serial_thread, _)) = *global.borrow() {
-            let window = &ui.window;
-            if b.get_active() {
-                let dialog = gtk::FileChooserDialog::new(Some("Log to File"),
-                                                         Some(window),
-                                                         gtk::FileChooserAction::Save);
-                dialog.add_buttons(&[("Log", gtk::ResponseType::Ok.into()),
-                                     ("Cancel", gtk::ResponseType::Cancel.into())]);
-                let result = dialog.run();
-                if result == gtk::ResponseType::Ok.into() {
-                    let filename = dialog.get_filename().unwrap();
-                    if serial_thread.send_log_to_file_cmd(filename.clone()).is_err() {
-                        error!("Error sending log_to_file command to child thread. Aborting.");
+        if let Some(&mut (ref ui, ref serial_thread, ref mut state)) =
+            global.borrow_mut().get_mut(&id) {
+            match choice {
+                Some((template, options, automatic)) => {
+                    state.log_options = options;
+                    state.auto_log = if automatic { Some(template.clone()) } else { None };
+                    let filename = expand_log_path(ui, state, &template);
+                    if !start_logging(ui, serial_thread, filename, options) {
                         b.set_sensitive(true);
                         b.set_active(false);
-                    } else {
-                        // TODO: Add a SerialResponse::LogToFileStarted and move this into receive()
-                        log_status(&ui,
-                                   StatusContext::FileOperation,
-                                   &format!("Started logging to file '{}'",
-                                            filename.to_str().unwrap()));
                     }
-                } else {
+                }
+                None => {
                     // Make the button look inactive if the user canceled the
                     // file save dialog
                     signal_handler_block(&ui.save_button, &ui.save_button_toggled_signal);
                     b.set_active(false);
                     signal_handler_unblock(&ui.save_button, &ui.save_button_toggled_signal);
                 }
+            }
+        }
+    });
+}
+
+/// Tells the port thread to log to `filename`, returning whether the command could be sent.
+fn start_logging(ui: &Ui,
+                 serial_thread: &SerialThread,
+                 filename: PathBuf,
+                 options: LogOptions)
+                 -> bool {
+    let status = format!("Started logging to file '{}'", filename.to_string_lossy());
+    if serial_thread.send_log_to_file_cmd(filename, options).is_err() {
+        error!("Error sending log_to_file command to child thread. Aborting.");
+        false
+    } else {
+        // TODO: Add a SerialResponse::LogToFileStarted and move this into receive()
+        log_status(ui, StatusContext::FileOperation, &status);
+        true
+    }
+}
+
+/// Fills in the log file path `template` for the session's port as it is now.
+fn expand_log_path(ui: &Ui, state: &State, template: &str) -> PathBuf {
+    let port = state.connected_port.as_ref().map_or("", |p| p.as_str());
+    let baud_rate = ui.baud_dropdown.get_active_text().and_then(|b| parse_baud_rate(&b));
+    capture::expand_path(template, port, baud_rate.unwrap_or(0), Local::now())
+}
 
-                dialog.destroy();
+/// Asks for the file to log to and how to write it, starting out with `options` and the template
+/// logged to automatically, if any. Returns the file as a template, the options, and whether to
+/// log to it automatically.
+fn choose_log_file(window: &gtk::Window,
+                   options: LogOptions,
+                   auto_log: Option<String>)
+                   -> Option<(String, LogOptions, bool)> {
+    let dialog = gtk::FileChooserDialog::new(Some("Log to File"),
+                                             Some(window),
+                                             gtk::FileChooserAction::Save);
+    dialog.add_buttons(&[("Log", gtk::ResponseType::Ok),
+                         ("Cancel", gtk::ResponseType::Cancel)]);
+    if let Some(ref template) = auto_log {
+        let template = Path::new(template);
+        if let Some(folder) = template.parent() {
+            dialog.set_current_folder(folder);
+        }
+        if let Some(name) = template.file_name() {
+            dialog.set_current_name(name);
+        }
+    }
+
+    let format_box = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+    format_box.pack_start(&gtk::Label::new(Some("Format:")), false, false, 0);
+    let format_dropdown = gtk::ComboBoxText::new();
+    for format in &LOG_FORMATS {
+        format_dropdown.append_text(&format.to_string());
+    }
+    let active = LOG_FORMATS.iter().position(|f| *f == options.format).unwrap_or(0);
+    format_dropdown.set_active(active as u32);
+    format_box.pack_start(&format_dropdown, false, false, 0);
+    let include_sent = gtk::CheckButton::new_with_label("Include sent data");
+    include_sent.set_active(options.include_sent);
+    format_box.pack_start(&include_sent, false, false, 0);
+    {
+        // pcapng captures always include sent data, and raw logs get markers with it
+        let include_sent = include_sent.clone();
+        let show_format = move |format: LogFormat| {
+            if format == LogFormat::Pcapng {
+                include_sent.set_active(true);
+                include_sent.set_sensitive(false);
             } else {
-                match serial_thread.send_cancel_log_to_file_cmd() {
-                    Err(GeneralError::Send(_)) => {
-                        error!("Error sending cancel_log_to_file command to child thread. \
-                                Aborting.");
-                    }
-                    Err(_) | Ok(_) => (),
+                include_sent.set_sensitive(true);
+            }
+            if format == LogFormat::Raw {
+                include_sent.set_tooltip_text("Marks the data with [TX] and [RX] where the \
+                                               direction changes");
+            } else {
+                include_sent.set_tooltip_text(None);
+            }
+        };
+        show_format(options.format);
+        format_dropdown.connect_changed(move |d| if let Some(i) = d.get_active() {
+            show_format(LOG_FORMATS[i as usize]);
+        });
+    }
+    let append = gtk::CheckButton::new_with_label("Append");
+    append.set_active(options.append);
+    format_box.pack_start(&append, false, false, 0);
+
+    let rollover = gtk::Grid::new();
+    rollover.set_row_spacing(6);
+    rollover.set_column_spacing(6);
+    let max_size = gtk::SpinButton::new_with_range(0.0, 1_000_000.0, 1.0);
+    max_size.set_value(options.max_size.map_or(0, |size| size / 1_000_000) as f64);
+    rollover.attach(&gtk::Label::new(Some("New file after (MB, 0 for never):")), 0, 0, 1, 1);
+    rollover.attach(&max_size, 1, 0, 1, 1);
+    let interval = gtk::SpinButton::new_with_range(0.0, 100_000.0, 1.0);
+    interval.set_value(options.interval.map_or(0, |interval| interval.as_secs() / 60) as f64);
+    rollover.attach(&gtk::Label::new(Some("or after (minutes, 0 for never):")), 2, 0, 1, 1);
+    rollover.attach(&interval, 3, 0, 1, 1);
+    let naming_dropdown = gtk::ComboBoxText::new();
+    for naming in &LOG_FILE_NAMINGS {
+        naming_dropdown.append_text(&naming.to_string());
+    }
+    let active = LOG_FILE_NAMINGS.iter().position(|n| *n == options.naming).unwrap_or(0);
+    naming_dropdown.set_active(active as u32);
+    rollover.attach(&gtk::Label::new(Some("File names:")), 0, 1, 1, 1);
+    rollover.attach(&naming_dropdown, 1, 1, 1, 1);
+    let keep = gtk::SpinButton::new_with_range(0.0, 10_000.0, 1.0);
+    keep.set_value(options.keep.unwrap_or(0) as f64);
+    rollover.attach(&gtk::Label::new(Some("Files to keep (0 for all):")), 2, 1, 1, 1);
+    rollover.attach(&keep, 3, 1, 1, 1);
+    let automatic = gtk::CheckButton::new_with_label("Log whenever the port is opened");
+    automatic.set_active(auto_log.is_some());
+    rollover.attach(&automatic, 0, 2, 2, 1);
+    let placeholders = gtk::Label::new(Some("{port}, {baud}, {date} and {time} in the name are \
+                                             filled in"));
+    rollover.attach(&placeholders, 2, 2, 2, 1);
+
+    let extra = gtk::Box::new(gtk::Orientation::Vertical, 6);
+    extra.pack_start(&format_box, false, false, 0);
+    extra.pack_start(&rollover, false, false, 0);
+    extra.show_all();
+    dialog.set_extra_widget(&extra);
+
+    let result = if dialog.run() == gtk::ResponseType::Ok.into() {
+        let format = format_dropdown.get_active()
+            .map_or(options.format, |i| LOG_FORMATS[i as usize]);
+        let naming = naming_dropdown.get_active()
+            .map_or(options.naming, |i| LOG_FILE_NAMINGS[i as usize]);
+        let options = LogOptions {
+            format,
+            include_sent: include_sent.get_active(),
+            append: append.get_active(),
+            max_size: positive(max_size.get_value_as_int()).map(|mb| mb * 1_000_000),
+            interval: positive(interval.get_value_as_int()).map(|m| Duration::from_secs(m * 60)),
+            naming,
+            keep: positive(keep.get_value_as_int()).map(|n| n as usize),
+        };
+        dialog.get_filename()
+            .map(|f| (f.to_string_lossy().into_owned(), options, automatic.get_active()))
+    } else {
+        None
+    };
+    dialog.destroy();
+    result
+}
+
+/// The value of a spin button where 0 turns a setting off.
+fn positive(value: i32) -> Option<u64> {
+    if value > 0 { Some(value as u64) } else { None }
+}
+
+/// Parses a baud rate, which can be any positive integer.
+fn parse_baud_rate(baud_rate: &str) -> Option<u32> {
+    match baud_rate.trim().parse::<u32>() {
+        Ok(b) if b > 0 => Some(b),
+        _ => None,
+    }
+}
+
+fn validate_baud_rate(baud_rate: String) -> Result<(), String> {
+    match parse_baud_rate(&baud_rate) {
+        Some(_) => Ok(()),
+        None => Err(format!("'{}' isn't a positive integer", baud_rate)),
+    }
+}
+
+fn validate_positive(value: String) -> Result<(), String> {
+    match value.parse::<u64>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err(format!("'{}' isn't a positive integer", value)),
+    }
+}
+
+/// Reads how to log from the command-line arguments, which have already been validated.
+fn parse_log_options(matches: &clap::ArgMatches) -> LogOptions {
+    let number = |name| matches.value_of(name).map(|n| n.parse::<u64>().unwrap());
+    let format = matches.value_of("log_format")
+        .and_then(|f| LOG_FORMAT_NAMES.iter().position(|n| *n == f))
+        .map_or(LogFormat::default(), |i| LOG_FORMATS[i]);
+    let naming = matches.value_of("log_naming")
+        .and_then(|f| LOG_FILE_NAMING_NAMES.iter().position(|n| *n == f))
+        .map_or(FileNaming::default(), |i| LOG_FILE_NAMINGS[i]);
+    LogOptions {
+        format,
+        include_sent: matches.is_present("log_sent"),
+        append: matches.is_present("log_append"),
+        max_size: number("log_max_size").map(|mb| mb * 1_000_000),
+        interval: number("log_interval").map(|minutes| Duration::from_secs(minutes * 60)),
+        naming,
+        keep: number("log_keep").map(|n| n as usize),
+    }
+}
+
+/// Sends a new baud rate to the port thread, reporting invalid ones in the status bar.
+fn change_baud_rate(id: SessionId, baud_rate: &str) {
+    GLOBAL.with(|global| {
+        if let Some((ui, serial_thread, _)) = global.borrow().get(&id) {
+            let baud_rate = match parse_baud_rate(baud_rate) {
+                Some(b) => b,
+                None => {
+                    let msg = format!("Invalid baud rate '{}'", baud_rate);
+                    log_status(ui, StatusContext::PortOperation, &msg);
+                    return;
+                }
+            };
+            match serial_thread.send_port_change_baud_cmd(baud_rate) {
+                Err(GeneralError::Parse(_)) => unreachable!(),
+                Err(GeneralError::Send(_)) => {
+                    error!("Error sending baud rate change command to child thread. Aborting.")
                 }
+                Ok(_) => (),
             }
         }
     });
 }
 
+/// Shows `baud_rate` in the baud rate dropdown, selecting it if it's listed.
+fn set_baud_rate_text(ui: &Ui, baud_rate: &str) {
+    if !ui.baud_dropdown.set_active_id(baud_rate) {
+        ui.baud_entry.set_text(baud_rate);
+    }
+    set_entry_error(&ui.baud_entry, parse_baud_rate(baud_rate).is_none());
+}
+
+fn set_entry_error(entry: &gtk::Entry, error: bool) {
+    let style_context = entry.get_style_context();
+    if error {
+        style_context.add_class("error");
+    } else {
+        style_context.remove_class("error");
+    }
+}
+
+/// Replaces the listed baud rates with the common ones and `custom_baud_rates`, fastest first.
+fn fill_baud_dropdown(dropdown: &gtk::ComboBoxText, custom_baud_rates: &[u32]) {
+    let mut baud_rates: Vec<u32> = BAUD_RATES.iter()
+        .map(|b| b.parse().unwrap())
+        .chain(custom_baud_rates.iter().cloned())
+        .collect();
+    baud_rates.sort_by(|a, b| b.cmp(a));
+    baud_rates.dedup();
+    dropdown.remove_all();
+    for b in baud_rates {
+        let b = b.to_string();
+        dropdown.append(Some(b.as_str()), &b);
+    }
+}
+
+/// Adds a baud rate that a port has accepted to the list of custom baud rates if it's not listed
+/// already, dropping the oldest one if there are too many. The list is shared by all sessions.
+fn remember_baud_rate(sessions: &HashMap<SessionId, Session>, baud_rate: u32) {
+    SHARED.with(|s| {
+        if let Some(ref mut shared) = *s.borrow_mut() {
+            let listed = BAUD_RATES.iter().any(|b| *b == baud_rate.to_string()) ||
+                         shared.custom_baud_rates.contains(&baud_rate);
+            if listed {
+                return;
+            }
+            shared.custom_baud_rates.push(baud_rate);
+            if shared.custom_baud_rates.len() > MAX_CUSTOM_BAUD_RATES {
+                shared.custom_baud_rates.remove(0);
+            }
+            save_custom_baud_rates(&shared.custom_baud_rates);
+
+            for (ui, ..) in sessions.values() {
+                let text =
+                    ui.baud_dropdown.get_active_text().map(|t| t.to_string()).unwrap_or_default();
+                signal_handler_block(&ui.baud_dropdown, &ui.baud_dropdown_changed_signal);
+                fill_baud_dropdown(&ui.baud_dropdown, &shared.custom_baud_rates);
+                set_baud_rate_text(ui, &text);
+                signal_handler_unblock(&ui.baud_dropdown, &ui.baud_dropdown_changed_signal);
+            }
+        }
+    });
+}
+
+/// The file custom baud rates are stored in, one per line.
+fn custom_baud_rates_path() -> Option<PathBuf> {
+    glib::get_user_config_dir().map(|d| d.join("gattii").join("baud_rates"))
+}
+
+fn load_custom_baud_rates() -> Vec<u32> {
+    match custom_baud_rates_path().map(fs::read_to_string) {
+        Some(Ok(s)) => s.lines().filter_map(parse_baud_rate).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn save_custom_baud_rates(baud_rates: &[u32]) {
+    if let Some(path) = custom_baud_rates_path() {
+        let contents: String = baud_rates.iter().map(|b| format!("{}\n", b)).collect();
+        let result = path.parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(&path, contents));
+        if let Err(e) = result {
+            warn!("Failed to save custom baud rates to {:?}: {}", path, e);
+        }
+    }
+}
+
+/// Updates the port settings widgets to show `settings` without sending them to the port again.
+fn show_settings(ui: &Ui, settings: &SerialPortSettings) {
+    signal_handler_block(&ui.baud_dropdown, &ui.baud_dropdown_changed_signal);
+    set_baud_rate_text(ui, &settings.baud_rate.to_string());
+    signal_handler_unblock(&ui.baud_dropdown, &ui.baud_dropdown_changed_signal);
+
+    let data_bits = match settings.data_bits {
+        DataBits::Five => 5.0,
+        DataBits::Six => 6.0,
+        DataBits::Seven => 7.0,
+        DataBits::Eight => 8.0,
+    };
+    signal_handler_block(&ui.data_bits_scale, &ui.data_bits_scale_changed_signal);
+    ui.data_bits_scale.set_value(data_bits);
+    signal_handler_unblock(&ui.data_bits_scale, &ui.data_bits_scale_changed_signal);
+
+    let stop_bits = match settings.stop_bits {
+        StopBits::One => 1.0,
+        StopBits::Two => 2.0,
+    };
+    signal_handler_block(&ui.stop_bits_scale, &ui.stop_bits_scale_changed_signal);
+    ui.stop_bits_scale.set_value(stop_bits);
+    signal_handler_unblock(&ui.stop_bits_scale, &ui.stop_bits_scale_changed_signal);
+
+    let parity = match settings.parity {
+        Parity::None => "none",
+        Parity::Odd => "odd",
+        Parity::Even => "even",
+    };
+    signal_handler_block(&ui.parity_dropdown, &ui.parity_dropdown_changed_signal);
+    ui.parity_dropdown.set_active(ui.parity_map[parity]);
+    signal_handler_unblock(&ui.parity_dropdown, &ui.parity_dropdown_changed_signal);
+
+    let flow_control = match settings.flow_control {
+        FlowControl::None => "none",
+        FlowControl::Software => "software",
+        FlowControl::Hardware => "hardware",
+    };
+    signal_handler_block(&ui.flow_control_dropdown, &ui.flow_control_dropdown_changed_signal);
+    ui.flow_control_dropdown.set_active(ui.flow_control_map[flow_control]);
+    signal_handler_unblock(&ui.flow_control_dropdown, &ui.flow_control_dropdown_changed_signal);
+}
+
+fn send_break(serial_thread: &SerialThread) {
+    info!("Sending break");
+    let duration = Duration::from_millis(BREAK_DURATION_MS);
+    if serial_thread.send_break_cmd(duration).is_err() {
+        error!("Error sending break command to child thread. Aborting.")
+    }
+}
+
+/// Updates the control lines panel to show `lines` without sending the output levels to the
+/// port again.
+fn show_control_lines(ui: &Ui, lines: &ControlLines) {
+    signal_handler_block(&ui.dtr_button, &ui.dtr_button_toggled_signal);
+    ui.dtr_button.set_active(lines.dtr);
+    signal_handler_unblock(&ui.dtr_button, &ui.dtr_button_toggled_signal);
+    signal_handler_block(&ui.rts_button, &ui.rts_button_toggled_signal);
+    ui.rts_button.set_active(lines.rts);
+    signal_handler_unblock(&ui.rts_button, &ui.rts_button_toggled_signal);
+
+    set_line_indicator(&ui.cts_indicator, lines.cts);
+    set_line_indicator(&ui.dsr_indicator, lines.dsr);
+    set_line_indicator(&ui.ri_indicator, lines.ri);
+    set_line_indicator(&ui.cd_indicator, lines.cd);
+}
+
+/// Lights up a session's tab indicator while its port is open.
+fn show_session_connected(ui: &Ui, connected: bool) {
+    let style_context = ui.tab_indicator.get_style_context();
+    if connected {
+        style_context.add_class("connected");
+        ui.tab_indicator.set_tooltip_text("Port open");
+    } else {
+        style_context.remove_class("connected");
+        ui.tab_indicator.set_tooltip_text("Port closed");
+    }
+}
+
+/// Turns off all input line indicators, as there's no port to read them from anymore.
+fn clear_input_lines(ui: &Ui) {
+    for indicator in &[&ui.cts_indicator, &ui.dsr_indicator, &ui.ri_indicator, &ui.cd_indicator] {
+        set_line_indicator(indicator, false);
+    }
+}
+
+fn set_line_indicator(indicator: &gtk::Label, asserted: bool) {
+    let style_context = indicator.get_style_context();
+    if asserted {
+        style_context.add_class("asserted");
+    } else {
+        style_context.remove_class("asserted");
+    }
+}
+
+/// Lists `ports` in the ports dropdown by their labels, keeping the selected port if it's still
+/// available. The dropdown is disabled if there are no ports.
+fn fill_ports_dropdown(dropdown: &gtk::ComboBoxText, ports: &[PortInfo]) {
+    let selected = dropdown.get_active_id();
+    dropdown.remove_all();
+    if ports.is_empty() {
+        dropdown.append(None, "No ports found");
+        dropdown.set_active(0);
+    } else {
+        for p in ports {
+            dropdown.append(Some(p.name.as_str()), &p.label());
+        }
+        if !selected.map_or(false, |s| dropdown.set_active_id(s.as_str())) {
+            dropdown.set_active(0);
+        }
+    }
+    dropdown.set_sensitive(!ports.is_empty());
+    set_port_tooltip(dropdown, ports);
+}
+
+/// Shows the details of the selected port as the tooltip of the ports dropdown.
+fn set_port_tooltip(dropdown: &gtk::ComboBoxText, ports: &[PortInfo]) {
+    let selected = dropdown.get_active_id();
+    match ports.iter().find(|p| selected.as_ref().map_or(false, |s| p.name == s.as_str())) {
+        Some(p) => dropdown.set_tooltip_text(p.description().as_str()),
+        None => dropdown.set_tooltip_text(None),
+    }
+}
+
+/// Updates the ports listed for a session without changing its port.
+fn show_ports(ui: &Ui, ports: &[PortInfo]) {
+    signal_handler_block(&ui.ports_dropdown, &ui.ports_dropdown_changed_signal);
+    fill_ports_dropdown(&ui.ports_dropdown, ports);
+    signal_handler_unblock(&ui.ports_dropdown, &ui.ports_dropdown_changed_signal);
+    ui.open_button.set_sensitive(!ports.is_empty());
+}
+
 /// Log messages to the status bar using the specific status context.
 fn log_status(ui: &Ui, context: StatusContext, message: &str) {
     let context_id = ui.status_bar_contexts.get(&context).unwrap();
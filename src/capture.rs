@@ -0,0 +1,741 @@
+//! Writing the data that goes through a port to a log file, in one of several formats.
+//!
+//! Sent data can be logged along with what's received. Everything is then logged in the order it
+//! went through the port, marked with its direction.
+//!
+//! Long captures can be split into several files, rolling over to a new file once the current one
+//! reaches a size or has been written for some time, and only keeping the latest files.
+//!
+//! Captures can also be written as pcapng, to be analysed with Wireshark. Every chunk of data is
+//! a packet on an interface named after the port, with link type `LINKTYPE_USER0` so that a
+//! dissector can be assigned to it.
+//!
+//! Where logs are written can be given as a template filled in with the port and the time logging
+//! starts at, so that every connection gets a log file of its own.
+
+use std::collections::VecDeque;
+use std::env;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{self, DateTime, Local};
+
+/// How many bytes each line of a hexdump shows.
+const HEXDUMP_LINE_LEN: usize = 16;
+
+/// How the time a file was started at is added to its name with `FileNaming::Timestamped`, and
+/// how long that is
+const TIMESTAMP_FORMAT: &str = "%Y%m%d-%H%M%S";
+const TIMESTAMP_LEN: usize = 15;
+
+/// pcapng block types
+const SECTION_HEADER_BLOCK: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 1;
+const ENHANCED_PACKET_BLOCK: u32 = 6;
+
+/// Starts a pcapng section to give its byte order, which is always little-endian here
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+/// pcapng options
+const OPT_ENDOFOPT: u16 = 0;
+const SHB_USERAPPL: u16 = 4;
+const IF_NAME: u16 = 2;
+const IF_DESCRIPTION: u16 = 3;
+const EPB_FLAGS: u16 = 2;
+
+/// The `epb_flags` of inbound and outbound packets
+const EPB_INBOUND: u32 = 1;
+const EPB_OUTBOUND: u32 = 2;
+
+/// The link type reserved for private use that captures are written with
+const LINKTYPE_USER0: u16 = 147;
+
+/// How data is written to a log file.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum LogFormat {
+    /// The data exactly as it went through the port. If sent data is included, "[TX] " and
+    /// "[RX] " are inserted wherever the direction changes, so the log is no longer just the data.
+    Raw,
+    /// The data as text, with every line prefixed by the time it started at
+    Text,
+    /// A canonical hexdump like `hexdump -C` produces, with offsets, the bytes in hex and their
+    /// printable characters. Repeated lines are shown rather than replaced by '*'.
+    Hexdump,
+    /// A CSV file with a row for every chunk of data, giving the time, the direction, the bytes in
+    /// hex and their printable characters
+    Csv,
+    /// A pcapng capture for Wireshark with a packet for every chunk of data, its direction given
+    /// by the packet's flags. Sent data is always captured. Appending to a capture starts a new
+    /// section in it.
+    Pcapng,
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Raw
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            LogFormat::Raw => "Raw",
+            LogFormat::Text => "Text with timestamps",
+            LogFormat::Hexdump => "Hexdump",
+            LogFormat::Csv => "CSV",
+            LogFormat::Pcapng => "pcapng (Wireshark)",
+        };
+        f.write_str(name)
+    }
+}
+
+/// How data is logged to a file.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct LogOptions {
+    pub format: LogFormat,
+    /// Whether the data sent is logged as well as what's received, which marks the direction of
+    /// all data. Raw logs then get "[TX] " or "[RX] " whenever the direction changes, text and
+    /// hexdump lines start with "TX" or "RX" and start over when the direction changes, and CSV
+    /// logs have sent rows in between received ones. pcapng captures always include sent data.
+    pub include_sent: bool,
+    /// Whether an existing file is appended to instead of being replaced
+    pub append: bool,
+    /// The size in bytes after which the log rolls over to a new file
+    pub max_size: Option<u64>,
+    /// How long each file is written before the log rolls over to a new one. It's checked
+    /// whenever data is logged, so a file isn't started while nothing goes through the port.
+    pub interval: Option<Duration>,
+    /// How the files are named when the log rolls over
+    pub naming: FileNaming,
+    /// How many files are kept, including the one being written. The oldest are deleted when the
+    /// log rolls over. Only files named as the log's would be are deleted.
+    pub keep: Option<usize>,
+}
+
+/// How the files of a log that rolls over are named.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum FileNaming {
+    /// The log is always written to the file it was started in. When it rolls over, that file
+    /// gets ".1" appended to its name and the older ones are numbered up, like logrotate does.
+    Numbered,
+    /// Every file has the time it was started at added to its name, before the extension, like
+    /// "capture_20180301-123456.log".
+    Timestamped,
+}
+
+impl Default for FileNaming {
+    fn default() -> Self {
+        FileNaming::Numbered
+    }
+}
+
+impl fmt::Display for FileNaming {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            FileNaming::Numbered => "Numbered",
+            FileNaming::Timestamped => "Timestamped",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Which way data went through the port.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+    /// Received from the port
+    Rx,
+    /// Sent to the port
+    Tx,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+                        Direction::Rx => "RX",
+                        Direction::Tx => "TX",
+                    })
+    }
+}
+
+/// The port a log is written for. pcapng logs describe it as the interface their packets were
+/// captured on.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct LoggedPort {
+    /// The name of the port
+    pub name: String,
+    /// The settings of the port when logging started, like "115200 8N1"
+    pub settings: String,
+}
+
+/// Writes data to `writer` as set in a `LogOptions`. Each chunk is written at once, so a log file
+/// can be followed while it's written, except for the last line of a hexdump, which is only
+/// written once it's complete or the logger is finished.
+pub struct Logger<W: Write> {
+    writer: W,
+    options: LogOptions,
+    /// The direction of the data last logged
+    direction: Option<Direction>,
+    /// Whether the next byte logged as text starts a line
+    at_line_start: bool,
+    /// The offset of the hexdump line in `pending`
+    offset: u64,
+    /// The bytes of the hexdump line that isn't complete yet
+    pending: Vec<u8>,
+    /// How many bytes have been written
+    written: u64,
+    finished: bool,
+}
+
+impl<W: Write> Logger<W> {
+    /// Creates a logger writing to `writer`, starting with a header if the format has one.
+    pub fn new(writer: W, options: LogOptions) -> io::Result<Self> {
+        Logger::for_port(writer, options, &Default::default())
+    }
+
+    /// Creates a logger writing data that goes through `port` to `writer`, starting with a header
+    /// if the format has one.
+    pub fn for_port(writer: W, options: LogOptions, port: &LoggedPort) -> io::Result<Self> {
+        let mut logger = Logger::continuing(writer, options);
+        match options.format {
+            LogFormat::Csv => logger.write(b"timestamp,direction,hex,ascii\n")?,
+            LogFormat::Pcapng => {
+                let mut header = section_header_block();
+                header.extend(interface_description_block(port));
+                logger.write(&header)?;
+            }
+            _ => (),
+        }
+        Ok(logger)
+    }
+
+    /// Creates a logger adding to a log that's already been started in `writer`, so without a
+    /// header.
+    pub fn continuing(writer: W, options: LogOptions) -> Self {
+        Logger {
+            writer,
+            options,
+            direction: None,
+            at_line_start: true,
+            offset: 0,
+            pending: Vec::new(),
+            written: 0,
+            finished: false,
+        }
+    }
+
+    /// How many bytes the logger has written.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// How the logger writes data.
+    pub fn options(&self) -> LogOptions {
+        self.options
+    }
+
+    /// Gets a reference to the writer the log is written to.
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    /// Logs `data` that went through the port in `direction` at `time`. Sent data is ignored
+    /// unless it's included in the options or the log is a pcapng capture.
+    pub fn log(&mut self,
+               direction: Direction,
+               data: &[u8],
+               time: DateTime<Local>)
+               -> io::Result<()> {
+        let logs_sent = self.options.include_sent || self.options.format == LogFormat::Pcapng;
+        if data.is_empty() || (direction == Direction::Tx && !logs_sent) {
+            return Ok(());
+        }
+        let turned = self.direction.map_or(false, |d| d != direction);
+        let marker = if self.options.include_sent {
+            format!("{} ", direction)
+        } else {
+            String::new()
+        };
+
+        let mut out = Vec::new();
+        match self.options.format {
+            LogFormat::Raw => {
+                if self.options.include_sent && self.direction != Some(direction) {
+                    out.extend_from_slice(format!("[{}] ", direction).as_bytes());
+                }
+                out.extend_from_slice(data);
+            }
+            LogFormat::Text => {
+                if turned && !self.at_line_start {
+                    out.push(b'\n');
+                    self.at_line_start = true;
+                }
+                for &b in data {
+                    if self.at_line_start {
+                        let prefix = format!("[{}] {}",
+                                             time.format("%Y-%m-%d %H:%M:%S%.3f"),
+                                             marker);
+                        out.extend_from_slice(prefix.as_bytes());
+                    }
+                    out.push(b);
+                    self.at_line_start = b == b'\n';
+                }
+            }
+            LogFormat::Hexdump => {
+                if turned {
+                    out.extend_from_slice(self.take_hexdump_line().as_bytes());
+                }
+                self.pending.extend_from_slice(data);
+                while self.pending.len() >= HEXDUMP_LINE_LEN {
+                    let line: Vec<u8> = self.pending.drain(..HEXDUMP_LINE_LEN).collect();
+                    let line = hexdump_line(&marker, self.offset, &line);
+                    out.extend_from_slice(line.as_bytes());
+                    self.offset += HEXDUMP_LINE_LEN as u64;
+                }
+            }
+            LogFormat::Csv => {
+                let hex = data.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ");
+                let ascii = printable(data).replace('"', "\"\"");
+                let row = format!("{},{},{},\"{}\"\n",
+                                  time.format("%Y-%m-%dT%H:%M:%S%.3f%:z"),
+                                  direction,
+                                  hex,
+                                  ascii);
+                out.extend_from_slice(row.as_bytes());
+            }
+            LogFormat::Pcapng => out = enhanced_packet_block(direction, data, time),
+        }
+        self.direction = Some(direction);
+        self.write(&out)
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.writer.write_all(data)?;
+        self.written += data.len() as u64;
+        Ok(())
+    }
+
+    /// The hexdump line of the bytes that haven't been written yet, if there are any.
+    fn take_hexdump_line(&mut self) -> String {
+        if self.pending.is_empty() {
+            return String::new();
+        }
+        let marker = match self.direction {
+            Some(direction) if self.options.include_sent => format!("{} ", direction),
+            _ => String::new(),
+        };
+        let line = hexdump_line(&marker, self.offset, &self.pending);
+        self.offset += self.pending.len() as u64;
+        self.pending.clear();
+        line
+    }
+
+    /// Writes whatever is left over, which is the last line of a hexdump and its length, and
+    /// flushes the writer. This happens when the logger is dropped as well, only logging errors.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        if self.options.format == LogFormat::Hexdump {
+            let mut out = self.take_hexdump_line();
+            if self.offset > 0 {
+                out.push_str(&format!("{:08x}\n", self.offset));
+            }
+            self.write(out.as_bytes())?;
+        }
+        self.writer.flush()
+    }
+}
+
+impl<W: Write> Drop for Logger<W> {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            error!("Failed to finish the log file: {}", e);
+        }
+    }
+}
+
+/// A log written to files as set in a `LogOptions`, rolling over to new files as it grows.
+pub struct LogFile {
+    /// The path the log was started with, which the names of its files are based on
+    path: PathBuf,
+    options: LogOptions,
+    port: LoggedPort,
+    logger: Logger<File>,
+    /// The file being written
+    current: PathBuf,
+    /// How long the file being written was when the logger started on it
+    initial_len: u64,
+    /// When the file being written was started
+    started: DateTime<Local>,
+    /// The files with timestamped names, including those left by earlier logs, oldest first
+    files: VecDeque<PathBuf>,
+}
+
+impl LogFile {
+    /// Starts a log of `port` at `path` at `time`. With `FileNaming::Timestamped`, the file
+    /// written is named after `path` rather than being `path` itself.
+    pub fn create(path: &Path,
+                  options: LogOptions,
+                  port: LoggedPort,
+                  time: DateTime<Local>)
+                  -> io::Result<Self> {
+        let current = match options.naming {
+            FileNaming::Numbered => path.to_path_buf(),
+            FileNaming::Timestamped => timestamped_path(path, time, options.append),
+        };
+        let (logger, initial_len) = open_logger(&current, options, &port)?;
+        let mut files = VecDeque::new();
+        if options.naming == FileNaming::Timestamped {
+            // Files from earlier logs count towards the files to keep as well
+            files.extend(timestamped_files(path)?.into_iter().filter(|f| *f != current));
+            files.push_back(current.clone());
+        }
+        let mut log = LogFile {
+            path: path.to_path_buf(),
+            options,
+            port,
+            logger,
+            current,
+            initial_len,
+            started: time,
+            files,
+        };
+        log.delete_old_files()?;
+        Ok(log)
+    }
+
+    /// The file being written.
+    pub fn current_path(&self) -> &Path {
+        &self.current
+    }
+
+    /// Logs `data` that went through the port in `direction` at `time`, first rolling over to a
+    /// new file if the current one is full or has been written long enough.
+    pub fn log(&mut self,
+               direction: Direction,
+               data: &[u8],
+               time: DateTime<Local>)
+               -> io::Result<()> {
+        let len = self.initial_len + self.logger.written();
+        let full = self.options.max_size.map_or(false, |max| len >= max);
+        let expired = self.options.interval.map_or(false, |interval| {
+            chrono::Duration::from_std(interval)
+                .map(|interval| time.signed_duration_since(self.started) >= interval)
+                .unwrap_or(false)
+        });
+        if (full || expired) && len > 0 {
+            self.roll_over(time)?;
+        }
+        self.logger.log(direction, data, time)
+    }
+
+    /// Finishes the file being written.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.logger.finish()
+    }
+
+    /// Deletes the oldest timestamped files beyond those to keep.
+    fn delete_old_files(&mut self) -> io::Result<()> {
+        let keep = self.options.keep.unwrap_or(usize::max_value()).max(1);
+        while self.files.len() > keep {
+            if let Some(old) = self.files.pop_front() {
+                info!("Deleting the old log file {:?}", old);
+                remove_if_exists(&old)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Finishes the file being written and starts a new one at `time`, deleting the oldest files
+    /// beyond those to keep.
+    fn roll_over(&mut self, time: DateTime<Local>) -> io::Result<()> {
+        self.logger.finish()?;
+        match self.options.naming {
+            FileNaming::Numbered => self.renumber()?,
+            FileNaming::Timestamped => {
+                // A new file is always started, even when appending
+                self.current = timestamped_path(&self.path, time, false);
+                self.files.push_back(self.current.clone());
+                self.delete_old_files()?;
+            }
+        }
+        info!("Logging to the new file {:?}", self.current);
+        let options = LogOptions {
+            append: false,
+            ..self.options
+        };
+        let (logger, initial_len) = open_logger(&self.current, options, &self.port)?;
+        self.logger = logger;
+        self.initial_len = initial_len;
+        self.started = time;
+        Ok(())
+    }
+
+    /// Moves the log's file out of the way by numbering it and the older ones up, and deletes
+    /// the oldest beyond those to keep.
+    fn renumber(&self) -> io::Result<()> {
+        let mut count = 0;
+        while numbered_path(&self.path, count + 1).exists() {
+            count += 1;
+        }
+        // The new file takes up a place as well
+        let old_files = self.options.keep.map(|keep| keep.max(1) - 1);
+        if let Some(old_files) = old_files {
+            while count > 0 && count >= old_files {
+                let old = numbered_path(&self.path, count);
+                info!("Deleting the old log file {:?}", old);
+                remove_if_exists(&old)?;
+                count -= 1;
+            }
+            if old_files == 0 {
+                return remove_if_exists(&self.path);
+            }
+        }
+        for i in (1..count + 1).rev() {
+            fs::rename(numbered_path(&self.path, i), numbered_path(&self.path, i + 1))?;
+        }
+        fs::rename(&self.path, numbered_path(&self.path, 1))
+    }
+}
+
+/// Opens the file at `path` for a logger of `port`, creating the directories it's in if needed.
+/// Returns the logger and how long the file already is.
+fn open_logger(path: &Path,
+               options: LogOptions,
+               port: &LoggedPort)
+               -> io::Result<(Logger<File>, u64)> {
+    path.parent().map_or(Ok(()), fs::create_dir_all)?;
+    let file = OpenOptions::new().write(true)
+        .create(true)
+        .append(options.append)
+        .truncate(!options.append)
+        .open(path)?;
+    let len = file.metadata()?.len();
+    // A pcapng capture that's appended to gets a section of its own
+    if len > 0 && options.format != LogFormat::Pcapng {
+        Ok((Logger::continuing(file, options), len))
+    } else {
+        Ok((Logger::for_port(file, options, port)?, len))
+    }
+}
+
+/// The file numbered `n` among the older files of the log at `path`.
+pub fn numbered_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// The file of the log at `path` started at `time`. Unless it's appended to, a file that already
+/// exists isn't used, counting up instead.
+pub fn timestamped_path(path: &Path, time: DateTime<Local>, append: bool) -> PathBuf {
+    let stem = path.file_stem().map_or(String::new(), |s| s.to_string_lossy().into_owned());
+    let extension = path.extension().map(|e| e.to_string_lossy().into_owned());
+    let name = |suffix: &str| {
+        let mut name = format!("{}_{}{}", stem, time.format(TIMESTAMP_FORMAT), suffix);
+        if let Some(ref extension) = extension {
+            name.push('.');
+            name.push_str(extension);
+        }
+        path.with_file_name(name)
+    };
+    let mut timestamped = name("");
+    let mut n = 1;
+    while !append && timestamped.exists() {
+        n += 1;
+        timestamped = name(&format!("-{}", n));
+    }
+    timestamped
+}
+
+/// The files named by `timestamped_path()` for the log at `path` that exist, oldest first.
+fn timestamped_files(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let stem = path.file_stem().map_or(String::new(), |s| s.to_string_lossy().into_owned());
+    let prefix = format!("{}_", stem);
+    let suffix = path.extension().map_or(String::new(), |e| format!(".{}", e.to_string_lossy()));
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !name.starts_with(&prefix) || !name.ends_with(&suffix) ||
+           name.len() < prefix.len() + TIMESTAMP_LEN + suffix.len() {
+            continue;
+        }
+        // What's left is the time the file was started at, maybe followed by a counter
+        let rest = &name[prefix.len()..name.len() - suffix.len()];
+        if !rest.is_char_boundary(TIMESTAMP_LEN) {
+            continue;
+        }
+        let (time, counter) = rest.split_at(TIMESTAMP_LEN);
+        let started = chrono::NaiveDateTime::parse_from_str(time, TIMESTAMP_FORMAT);
+        let n = if counter.is_empty() {
+            Some(1)
+        } else if counter.starts_with('-') {
+            counter[1..].parse::<u32>().ok()
+        } else {
+            None
+        };
+        if let (Ok(started), Some(n)) = (started, n) {
+            files.push(((started, n), entry.path()));
+        }
+    }
+    files.sort_by_key(|f| f.0);
+    Ok(files.into_iter().map(|f| f.1).collect())
+}
+
+/// Fills in the placeholders in a log file path `template` for a log of `port` at `baud_rate`
+/// starting at `time`:
+///
+/// * `{port}`: The name of the port, without the directory of Unix device files
+/// * `{baud}`: The baud rate
+/// * `{date}`: The date, like "2018-03-01"
+/// * `{time}`: The time, like "12-34-56"
+///
+/// A leading `~` stands for the home directory.
+pub fn expand_path(template: &str, port: &str, baud_rate: u32, time: DateTime<Local>) -> PathBuf {
+    let port = port.rsplit(&['/', '\\'][..]).next().unwrap_or(port);
+    let port: String = port.chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' || c == '.' { c } else { '_' })
+        .collect();
+    let expanded = template.replace("{port}", &port)
+        .replace("{baud}", &baud_rate.to_string())
+        .replace("{date}", &time.format("%Y-%m-%d").to_string())
+        .replace("{time}", &time.format("%H-%M-%S").to_string());
+    let home = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE"));
+    match home {
+        Some(ref home) if expanded == "~" => PathBuf::from(home),
+        Some(ref home) if expanded.starts_with("~/") || expanded.starts_with("~\\") => {
+            Path::new(home).join(&expanded[2..])
+        }
+        _ => PathBuf::from(expanded),
+    }
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        r => r,
+    }
+}
+
+/// The pcapng block starting a section, which names the application that wrote it.
+fn section_header_block() -> Vec<u8> {
+    let mut body = Vec::new();
+    push_u32(&mut body, BYTE_ORDER_MAGIC);
+    // Version 1.0
+    push_u16(&mut body, 1);
+    push_u16(&mut body, 0);
+    // The length of the section isn't known in advance
+    body.extend_from_slice(&[0xff; 8]);
+    push_option(&mut body, SHB_USERAPPL, b"Gattii");
+    push_option(&mut body, OPT_ENDOFOPT, b"");
+    pcapng_block(SECTION_HEADER_BLOCK, &body)
+}
+
+/// The pcapng block describing `port` as the interface all packets are captured on.
+fn interface_description_block(port: &LoggedPort) -> Vec<u8> {
+    let mut body = Vec::new();
+    push_u16(&mut body, LINKTYPE_USER0);
+    push_u16(&mut body, 0);
+    // Packets aren't truncated
+    push_u32(&mut body, 0);
+    if !port.name.is_empty() {
+        push_option(&mut body, IF_NAME, port.name.as_bytes());
+    }
+    if !port.settings.is_empty() {
+        push_option(&mut body, IF_DESCRIPTION, port.settings.as_bytes());
+    }
+    push_option(&mut body, OPT_ENDOFOPT, b"");
+    pcapng_block(INTERFACE_DESCRIPTION_BLOCK, &body)
+}
+
+/// The pcapng block of a packet of `data` that went through the port in `direction` at `time`,
+/// with the timestamp in microseconds.
+fn enhanced_packet_block(direction: Direction, data: &[u8], time: DateTime<Local>) -> Vec<u8> {
+    let timestamp = time.timestamp() as u64 * 1_000_000 + time.timestamp_subsec_micros() as u64;
+    let mut body = Vec::new();
+    // The interface
+    push_u32(&mut body, 0);
+    push_u32(&mut body, (timestamp >> 32) as u32);
+    push_u32(&mut body, timestamp as u32);
+    // The captured and the original length
+    push_u32(&mut body, data.len() as u32);
+    push_u32(&mut body, data.len() as u32);
+    body.extend_from_slice(data);
+    pad(&mut body);
+    let flags = match direction {
+        Direction::Rx => EPB_INBOUND,
+        Direction::Tx => EPB_OUTBOUND,
+    };
+    let mut value = Vec::new();
+    push_u32(&mut value, flags);
+    push_option(&mut body, EPB_FLAGS, &value);
+    push_option(&mut body, OPT_ENDOFOPT, b"");
+    pcapng_block(ENHANCED_PACKET_BLOCK, &body)
+}
+
+/// A pcapng block of `block_type` around `body`, whose length is a multiple of 4.
+fn pcapng_block(block_type: u32, body: &[u8]) -> Vec<u8> {
+    let len = body.len() as u32 + 12;
+    let mut block = Vec::with_capacity(len as usize);
+    push_u32(&mut block, block_type);
+    push_u32(&mut block, len);
+    block.extend_from_slice(body);
+    push_u32(&mut block, len);
+    block
+}
+
+fn push_option(out: &mut Vec<u8>, code: u16, value: &[u8]) {
+    push_u16(out, code);
+    push_u16(out, value.len() as u16);
+    out.extend_from_slice(value);
+    pad(out);
+}
+
+/// Pads `out` with zeroes to a multiple of 4 bytes.
+fn pad(out: &mut Vec<u8>) {
+    while out.len() % 4 != 0 {
+        out.push(0);
+    }
+}
+
+fn push_u16(out: &mut Vec<u8>, n: u16) {
+    out.extend_from_slice(&[n as u8, (n >> 8) as u8]);
+}
+
+fn push_u32(out: &mut Vec<u8>, n: u32) {
+    out.extend_from_slice(&[n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8]);
+}
+
+/// A line of a canonical hexdump of `bytes`, which start at `offset`, starting with `marker`.
+fn hexdump_line(marker: &str, offset: u64, bytes: &[u8]) -> String {
+    let mut line = format!("{}{:08x}  ", marker, offset);
+    for i in 0..HEXDUMP_LINE_LEN {
+        match bytes.get(i) {
+            Some(b) => line.push_str(&format!("{:02x} ", b)),
+            None => line.push_str("   "),
+        }
+        if i == HEXDUMP_LINE_LEN / 2 - 1 {
+            line.push(' ');
+        }
+    }
+    line.push_str(&format!(" |{}|\n", printable(bytes)));
+    line
+}
+
+/// `bytes` as printable ASCII characters, with '.' standing in for everything else.
+fn printable(bytes: &[u8]) -> String {
+    bytes.iter()
+        .map(|&b| if b >= 0x20 && b < 0x7f { b as char } else { '.' })
+        .collect()
+}
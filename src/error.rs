@@ -0,0 +1,172 @@
+//! The error type reported by the port thread.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serialport;
+
+#[cfg(unix)]
+use nix::libc::{EBUSY, ENODEV, ENXIO};
+
+/// A failure in the port thread, reported back through a `SerialResponse`.
+#[derive(Debug)]
+pub enum SerialError {
+    /// The port is already in use by another program.
+    PortBusy(String),
+    /// The port doesn't exist, or its device has been disconnected.
+    NoDevice(String),
+    /// The current user isn't allowed to access the port or file.
+    PermissionDenied(String),
+    /// The port or its driver rejected a setting.
+    UnsupportedSetting(String),
+    /// The file to be read doesn't exist.
+    FileNotFound(PathBuf),
+    /// The command requires an open port, but none is open.
+    NoPortOpen,
+    /// A file transfer protocol gave up, e.g. because the other end canceled or stopped
+    /// responding.
+    TransferFailed(String),
+    /// A file to be sent as an Intel HEX or S-record image isn't a valid one.
+    InvalidImage(String),
+    /// Any other I/O error, along with the OS error number if there is one.
+    Io {
+        errno: Option<i32>,
+        description: String,
+    },
+}
+
+impl SerialError {
+    /// Classifies an error returned by `Backend::open()` while opening the port called `name` at
+    /// `baud_rate`. Invalid arguments are blamed on the baud rate, as that's the only setting that
+    /// can be out of range.
+    pub fn from_open_error(name: &str, baud_rate: u32, e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound => SerialError::NoDevice(name.to_string()),
+            io::ErrorKind::PermissionDenied => SerialError::PermissionDenied(name.to_string()),
+            io::ErrorKind::InvalidInput => {
+                SerialError::UnsupportedSetting(format!("baud rate {}: {}", baud_rate, e))
+            }
+            _ => SerialError::from_open_os_error(name, e),
+        }
+    }
+
+    /// Classifies an open error whose kind doesn't say what went wrong by its OS error number.
+    #[cfg(unix)]
+    fn from_open_os_error(name: &str, e: io::Error) -> Self {
+        match e.raw_os_error() {
+            Some(EBUSY) => SerialError::PortBusy(name.to_string()),
+            Some(ENODEV) | Some(ENXIO) => SerialError::NoDevice(name.to_string()),
+            _ => SerialError::from(e),
+        }
+    }
+
+    /// Windows error codes aren't classified, as serialport-rs doesn't keep them when opening a
+    /// port fails. It reports a port that's in use as one that doesn't exist instead.
+    #[cfg(not(unix))]
+    fn from_open_os_error(_: &str, e: io::Error) -> Self {
+        SerialError::from(e)
+    }
+
+    /// Classifies an error returned when applying `setting` (e.g. "baud rate 300") to a port.
+    pub fn from_setting_error(setting: &str, e: serialport::Error) -> Self {
+        match e.kind() {
+            serialport::ErrorKind::InvalidInput |
+            serialport::ErrorKind::Io(io::ErrorKind::InvalidInput) => {
+                SerialError::UnsupportedSetting(format!("{}: {}", setting, e.description))
+            }
+            _ => {
+                SerialError::Io {
+                    errno: os_error(&e),
+                    description: format!("Failed to set {}: {}", setting, e.description),
+                }
+            }
+        }
+    }
+
+    /// Classifies an error returned while opening or accessing the file at `path`.
+    pub fn from_file_error(path: &Path, e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound => SerialError::FileNotFound(path.to_path_buf()),
+            io::ErrorKind::PermissionDenied => {
+                SerialError::PermissionDenied(path.display().to_string())
+            }
+            _ => SerialError::from(e),
+        }
+    }
+}
+
+impl From<io::Error> for SerialError {
+    fn from(e: io::Error) -> Self {
+        SerialError::Io {
+            errno: e.raw_os_error(),
+            description: e.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for SerialError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SerialError::PortBusy(ref name) => write!(f, "Port '{}' is already in use", name),
+            SerialError::NoDevice(ref name) => write!(f, "Port '{}' doesn't exist", name),
+            SerialError::PermissionDenied(ref name) => {
+                write!(f, "Permission denied for '{}'", name)
+            }
+            SerialError::UnsupportedSetting(ref s) => write!(f, "Unsupported setting ({})", s),
+            SerialError::FileNotFound(ref path) => {
+                write!(f, "File '{}' doesn't exist", path.display())
+            }
+            SerialError::NoPortOpen => write!(f, "No port is open"),
+            SerialError::TransferFailed(ref s) => write!(f, "File transfer failed ({})", s),
+            SerialError::InvalidImage(ref s) => write!(f, "Invalid image file ({})", s),
+            SerialError::Io { errno: Some(errno), ref description } => {
+                write!(f, "{} (os error {})", description, errno)
+            }
+            SerialError::Io { errno: None, ref description } => write!(f, "{}", description),
+        }
+    }
+}
+
+impl Error for SerialError {
+    fn description(&self) -> &str {
+        match *self {
+            SerialError::PortBusy(_) => "port busy",
+            SerialError::NoDevice(_) => "no such port",
+            SerialError::PermissionDenied(_) => "permission denied",
+            SerialError::UnsupportedSetting(_) => "unsupported setting",
+            SerialError::FileNotFound(_) => "file not found",
+            SerialError::NoPortOpen => "no port open",
+            SerialError::TransferFailed(_) => "file transfer failed",
+            SerialError::InvalidImage(_) => "invalid image file",
+            SerialError::Io { ref description, .. } => description,
+        }
+    }
+}
+
+/// Recovers the OS error number of `e`, as serialport-rs only keeps its description. That's an
+/// `io::Error`'s ending in "(os error N)", or on Unix nix's description of the error number.
+pub(crate) fn os_error(e: &serialport::Error) -> Option<i32> {
+    const PREFIX: &str = "(os error ";
+
+    let code = e.description
+        .rfind(PREFIX)
+        .and_then(|i| e.description[i + PREFIX.len()..].trim_end_matches(')').parse().ok());
+    code.or_else(|| errno_described_as(&e.description))
+}
+
+#[cfg(unix)]
+fn errno_described_as(description: &str) -> Option<i32> {
+    use nix::errno::Errno;
+
+    (1..256)
+        .map(Errno::from_i32)
+        .find(|errno| *errno != Errno::UnknownErrno && errno.desc() == description)
+        .map(|errno| errno as i32)
+}
+
+#[cfg(not(unix))]
+fn errno_described_as(_: &str) -> Option<i32> {
+    None
+}
@@ -1,18 +1,36 @@
+extern crate chrono;
 extern crate core;
 #[macro_use]
 extern crate log;
+#[cfg(unix)]
+#[macro_use]
+extern crate nix;
 extern crate serialport;
+#[cfg(windows)]
+extern crate winapi;
 
 use core::num;
-use std::fs;
-use std::fs::File;
-use std::io::prelude::*;
 use std::path::PathBuf;
-use std::sync::mpsc::{channel, Sender, Receiver, TryRecvError};
+use std::sync::mpsc::{channel, Receiver, SendError};
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::Duration;
 
 pub use self::serialport::prelude::*;
+pub use backend::{Backend, LoopbackBackend, LoopbackPort, Port, Readiness, SerialPortBackend};
+pub use capture::{Direction, FileNaming, LogFormat, LogOptions};
+pub use error::SerialError;
+pub use transfer::{FileProgress, FlashOptions, ImageOptions, LinePacing, LineRequest, Progress,
+                   ProgressReport, Protocol, WaitFor};
+pub use wakeup::{CommandSender, Wakeup};
+
+use worker::Worker;
+
+mod backend;
+pub mod capture;
+mod error;
+pub mod transfer;
+mod wakeup;
+mod worker;
 
 #[derive(Debug)]
 pub enum SerialCommand {
@@ -26,8 +44,48 @@ pub enum SerialCommand {
     ConnectToPort { name: String, baud: u32 },
     Disconnect,
     SendData(Vec<u8>),
-    SendFile(PathBuf),
-    LogToFile(PathBuf),
+    /// Sends the file at `path` using `protocol`. Progress is reported with the `SendingFile*`
+    /// responses.
+    SendFile { path: PathBuf, protocol: Protocol },
+    /// Sends the files at `paths` in one batch, which only batch protocols like `Protocol::Ymodem`
+    /// can do unless it's a single file.
+    SendFiles {
+        paths: Vec<PathBuf>,
+        protocol: Protocol,
+    },
+    /// Sends `files` one after another, waiting for `gap` after each one. The whole queue is sent
+    /// as often as `repeat` says, which makes for a simple soak test. It's reported like sending a
+    /// single file, with `SerialResponse::SendingQueueProgress` as each file starts and
+    /// `SerialResponse::SendingFileComplete` only once the queue is done. Any file failing stops
+    /// the queue.
+    SendQueue {
+        files: Vec<QueuedFile>,
+        gap: Duration,
+        repeat: Repeat,
+    },
+    /// Receives a file into `path` using `protocol`, which can't be `Protocol::Raw`. For batch
+    /// protocols `path` is a directory that the files are created in, named as the sender calls
+    /// them. Progress is reported with the same responses as sending a file.
+    ReceiveFile { path: PathBuf, protocol: Protocol },
+    /// Sets how files sent with `Protocol::Text` are paced from now on.
+    SetLinePacing(LinePacing),
+    /// Limits files sent with `Protocol::Raw` to a number of bytes per second from now on. If
+    /// it's `None`, they're sent as fast as the port's settings allow.
+    SetThroughput(Option<u32>),
+    /// Sets how files sent with `Protocol::HexImage` and `Protocol::HexRecords` are sent from now
+    /// on.
+    SetImageOptions(ImageOptions),
+    /// Sets how images are flashed with `Protocol::Stm32` and `Protocol::Stk500` from now on.
+    SetFlashOptions(FlashOptions),
+    /// Sets the level of the DTR line. If no port is open, it's set once one is.
+    SetDataTerminalReady(bool),
+    /// Sets the level of the RTS line. If no port is open, it's set once one is.
+    SetRequestToSend(bool),
+    /// Transmits a break condition for `duration`.
+    SendBreak { duration: Duration },
+    /// Starts logging everything received, and everything sent if set in `options`, to the file
+    /// at `path`.
+    LogToFile { path: PathBuf, options: LogOptions },
     CancelLogToFile,
 }
 
@@ -36,23 +94,188 @@ pub enum SerialResponse {
     Data(Vec<u8>),
     SendingFileCanceled,
     SendingFileComplete,
-    /// Response to `SerialCommand::SendFile`. Confirms that the file has been opened successfully
-    /// and data is going to be sent.
+    /// Response to `SerialCommand::SendFile` and `SerialCommand::ReceiveFile`. Confirms that the
+    /// file has been opened successfully and the transfer is starting.
     SendingFileStarted,
-    /// Status response indicating what percentage of transmission a file is at
-    SendingFileProgress(u8),
-    SendingFileError(String),
+    /// Status response with how much of the transfer is done, how fast it's going and how long
+    /// it'll take. Sent a few times a second and once all data has been transferred.
+    SendingFileProgress(ProgressReport),
+    /// Status response for batch transfers, sent when the transfer moves on to another file and
+    /// whenever the percentage of that file advances.
+    SendingFileBatchProgress(FileProgress),
+    /// Status response for queues sent with `SerialCommand::SendQueue`, sent as each file starts.
+    /// `index` is the file's position in the queue starting at 0, and `pass` counts how often the
+    /// queue has been started, out of `passes` unless it repeats forever.
+    SendingQueueProgress {
+        index: usize,
+        count: usize,
+        pass: u32,
+        passes: Option<u32>,
+    },
+    /// Status response for transfers that go line by line, sent after each line.
+    SendingLineProgress { sent: usize, total: usize },
+    /// Status response for transfers that go through stages, like flashing a microcontroller,
+    /// sent with a description of each stage as it starts.
+    TransferStage(String),
+    SendingFileError(SerialError),
+    /// The other end started sending files using ZMODEM while no transfer was running. They can
+    /// be received with `SerialCommand::ReceiveFile` using `Protocol::Zmodem`.
+    ZmodemDetected,
     OpenPortSuccess(String),
-    OpenPortError(String),
+    OpenPortError(SerialError),
     DisconnectSuccess,
-    LogToFileError(String),
+    /// Logging to a file couldn't be started or has stopped because writing to it failed.
+    LogToFileError(SerialError),
     LoggingFileCanceled,
+    /// An error occurred on the open port that doesn't stop it from being used, such as failing to
+    /// send data.
+    PortError(SerialError),
+    /// Response to the `SerialCommand::Change*` commands confirming that the setting has been
+    /// applied. Contains all settings now in effect. If no port is open, they will be used once
+    /// one is.
+    SettingsApplied(SerialPortSettings),
+    /// Response to the `SerialCommand::Change*` commands when the port refused the new setting.
+    /// `settings` are the unchanged settings that are still in effect.
+    SettingsRejected {
+        settings: SerialPortSettings,
+        error: SerialError,
+    },
+    /// Response to `SerialCommand::SendBreak` once the break has ended.
+    BreakSent,
+    /// The state of the modem control lines. Sent after a port is opened, in response to
+    /// `SerialCommand::SetDataTerminalReady` and `SerialCommand::SetRequestToSend`, and whenever
+    /// one of the input lines changes. Not sent for ports that can't report their input lines.
+    ControlLinesChanged(ControlLines),
     /// A port error has occurred that is likely the result of a serial device disconnected. This
     /// also returns a list of all still-attached serial devices.
-    UnexpectedDisconnection(Vec<String>),
-    /// A sorted list of ports found during a port scan. Guaranteed to contain the currently-active
-    /// port if there is one.
-    PortsFound(Vec<String>),
+    UnexpectedDisconnection(Vec<PortInfo>),
+    /// A list of ports found during a port scan, sorted by name. Guaranteed to contain the
+    /// currently-active port if there is one.
+    PortsFound(Vec<PortInfo>),
+}
+
+/// A file sent as part of a queue with `SerialCommand::SendQueue`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct QueuedFile {
+    pub path: PathBuf,
+    pub protocol: Protocol,
+}
+
+/// How often a queue of files is sent.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Repeat {
+    Times(u32),
+    Forever,
+}
+
+impl Default for Repeat {
+    fn default() -> Self {
+        Repeat::Times(1)
+    }
+}
+
+/// The levels of the modem control lines of a port, `true` meaning asserted.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ControlLines {
+    /// Data Terminal Ready (output)
+    pub dtr: bool,
+    /// Request To Send (output)
+    pub rts: bool,
+    /// Clear To Send (input)
+    pub cts: bool,
+    /// Data Set Ready (input)
+    pub dsr: bool,
+    /// Ring Indicator (input)
+    pub ri: bool,
+    /// Carrier Detect (input)
+    pub cd: bool,
+}
+
+/// An available port along with what's known about the device providing it. Only USB ports
+/// describe their device.
+#[derive(Clone, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct PortInfo {
+    /// The name the port is opened with, e.g. "/dev/ttyUSB0" or "COM3"
+    pub name: String,
+    /// USB vendor ID
+    pub vid: Option<u16>,
+    /// USB product ID
+    pub pid: Option<u16>,
+    pub serial_number: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+}
+
+impl PortInfo {
+    /// Creates the info for a port that nothing else is known about.
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        PortInfo { name: name.into(), ..Default::default() }
+    }
+
+    /// A short label telling ports apart, e.g. "/dev/ttyUSB0 (FT232R USB UART, A50285BI)". It's
+    /// just the name for ports without device information.
+    pub fn label(&self) -> String {
+        let details: Vec<&str> = self.product
+            .as_ref()
+            .or(self.manufacturer.as_ref())
+            .into_iter()
+            .chain(self.serial_number.as_ref())
+            .map(|s| s.as_str())
+            .collect();
+        if details.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{} ({})", self.name, details.join(", "))
+        }
+    }
+
+    /// A description of the port and its device with one detail per line, starting with the name.
+    pub fn description(&self) -> String {
+        let mut lines = vec![self.name.clone()];
+        if let (Some(vid), Some(pid)) = (self.vid, self.pid) {
+            lines.push(format!("USB ID: {:04x}:{:04x}", vid, pid));
+        }
+        if let Some(ref manufacturer) = self.manufacturer {
+            lines.push(format!("Manufacturer: {}", manufacturer));
+        }
+        if let Some(ref product) = self.product {
+            lines.push(format!("Product: {}", product));
+        }
+        if let Some(ref serial_number) = self.serial_number {
+            lines.push(format!("Serial number: {}", serial_number));
+        }
+        lines.join("\n")
+    }
+}
+
+impl From<SerialPortInfo> for PortInfo {
+    fn from(info: SerialPortInfo) -> Self {
+        match info.port_type {
+            serialport::SerialPortType::UsbPort(usb) => {
+                PortInfo {
+                    name: info.port_name,
+                    vid: Some(usb.vid),
+                    pid: Some(usb.pid),
+                    serial_number: usb.serial_number,
+                    manufacturer: usb.manufacturer,
+                    product: usb.product,
+                }
+            }
+            _ => PortInfo::new(info.port_name),
+        }
+    }
+}
+
+impl From<String> for PortInfo {
+    fn from(name: String) -> Self {
+        PortInfo::new(name)
+    }
+}
+
+impl<'a> From<&'a str> for PortInfo {
+    fn from(name: &'a str) -> Self {
+        PortInfo::new(name)
+    }
 }
 
 #[derive(Debug)]
@@ -61,338 +284,88 @@ pub enum GeneralError {
     Send(SerialCommand),
 }
 
-pub enum ReadBytes {
-    /// Number of bytes read from the file, 0 if none but not an error
-    Bytes(usize),
-    /// The end of the file has been reached so no data was read
-    EndOfFile,
-    /// An error was encountered reading from the file
-    FileError,
-    /// No read was actually attempted
-    NoAttempt,
+impl From<num::ParseIntError> for GeneralError {
+    fn from(e: num::ParseIntError) -> Self {
+        GeneralError::Parse(e)
+    }
+}
+
+impl From<SendError<SerialCommand>> for GeneralError {
+    fn from(e: SendError<SerialCommand>) -> Self {
+        GeneralError::Send(e.0)
+    }
 }
 
 pub struct SerialThread {
     pub from_port_chan_rx: Receiver<SerialResponse>,
-    pub to_port_chan_tx: Sender<SerialCommand>,
+    pub to_port_chan_tx: CommandSender,
 }
 
-pub fn list_ports() -> serialport::Result<Vec<String>> {
+pub fn list_ports() -> serialport::Result<Vec<PortInfo>> {
     match serialport::available_ports() {
-        Ok(ports) => Ok(ports.into_iter().map(|x| x.port_name).collect()),
+        Ok(ports) => Ok(ports.into_iter().map(PortInfo::from).collect()),
         Err(e) => Err(e),
     }
 }
 
+/// Formats port settings in the common short form, e.g. "115200 8N1".
+pub fn format_settings(settings: &SerialPortSettings) -> String {
+    let data_bits = match settings.data_bits {
+        DataBits::Five => 5,
+        DataBits::Six => 6,
+        DataBits::Seven => 7,
+        DataBits::Eight => 8,
+    };
+    let parity = match settings.parity {
+        Parity::None => 'N',
+        Parity::Odd => 'O',
+        Parity::Even => 'E',
+    };
+    let stop_bits = match settings.stop_bits {
+        StopBits::One => 1,
+        StopBits::Two => 2,
+    };
+    let flow_control = match settings.flow_control {
+        FlowControl::None => "",
+        FlowControl::Software => " (software flow control)",
+        FlowControl::Hardware => " (hardware flow control)",
+    };
+    format!("{} {}{}{}{}",
+            settings.baud_rate,
+            data_bits,
+            parity,
+            stop_bits,
+            flow_control)
+}
+
 impl SerialThread {
+    /// Starts a new port thread that uses the system's serial ports. `callback` is called every
+    /// time a `SerialResponse` is sent back from the thread.
     pub fn new<F: Fn() + Send + 'static>(callback: F) -> Self {
+        SerialThread::with_backend(SerialPortBackend, callback)
+    }
 
+    /// Starts a new port thread that opens and enumerates ports through `backend` instead of
+    /// using the system's serial ports directly.
+    pub fn with_backend<B, F>(backend: B, callback: F) -> Self
+        where B: Backend,
+              F: Fn() + Send + 'static
+    {
         let (from_port_chan_tx, from_port_chan_rx) = channel();
         let (to_port_chan_tx, to_port_chan_rx) = channel();
 
+        let (waker, wakeup) = wakeup::pair().expect("Failed to set up port thread wakeup");
+
         // Open a thread to monitor the active serial channel. This thread is always-running and
         // listening for various port-related commands, but is not necessarily always connected to
-        // the port.
+        // the port. It exits once this `SerialThread` is dropped.
         thread::spawn(move || {
-            let mut port: Option<Box<dyn SerialPort>> = None;
-            let mut read_file: Option<Box<File>> = None;
-            let mut bytes_read = 0u64;
-            let mut bytes_total = 0u64;
-            let mut last_percentage = 0u8;
-            let mut write_file: Option<Box<File>> = None;
-
-            let mut serial_buf: Vec<u8> = vec![0; 1000];
-            let mut serial_buf_rx = [0; 1000];
-            let mut last_send_time = Instant::now();
-            let mut settings: SerialPortSettings = Default::default();
-
-            let loop_time = 10usize; // ms
-            let port_scan_time = Duration::from_secs(5);
-            let mut last_port_scan_time = Instant::now();
-
-            loop {
-                // First check if we have any incoming commands
-                match to_port_chan_rx.try_recv() {
-                    Ok(SerialCommand::ConnectToPort { name, baud }) => {
-                        settings.baud_rate = baud;
-                        info!("Connecting to {} at {} with settings {:?}",
-                              &name,
-                              &baud,
-                              &settings);
-                        match serialport::open_with_settings(&name, &settings) {
-                            Ok(p) => {
-                                port = Some(p);
-                                from_port_chan_tx
-                                    .send(SerialResponse::OpenPortSuccess(name))
-                                    .unwrap();
-                            }
-                            Err(serialport::Error {kind: serialport::ErrorKind::NoDevice, ..}) => {
-                                let err_str = format!("Port '{}' is already in use or doesn't \
-                                                       exist", &name);
-                                let error = SerialResponse::OpenPortError(err_str);
-                                from_port_chan_tx.send(error).unwrap();
-                            }
-                            Err(e) => {
-                                from_port_chan_tx.send(SerialResponse::OpenPortError(e.description))
-                                    .unwrap();
-                            }
-                        }
-                        callback();
-                    }
-                    Ok(SerialCommand::ChangeBaud(baud)) => {
-                        info!("Changing baud to {}", baud);
-                        settings.baud_rate = baud;
-                        if let Some(ref mut p) = port {
-                            p.set_baud_rate(baud).unwrap();
-                        }
-                    }
-                    Ok(SerialCommand::ChangeDataBits(data_bits)) => {
-                        info!("Changing data bits to {:?}", data_bits);
-                        settings.data_bits = data_bits;
-                        if let Some(ref mut p) = port {
-                            p.set_data_bits(data_bits).unwrap();
-                        }
-                    }
-                    Ok(SerialCommand::ChangeFlowControl(flow_control)) => {
-                        info!("Changing flow control to {:?}", flow_control);
-                        settings.flow_control = flow_control;
-                        if let Some(ref mut p) = port {
-                            p.set_flow_control(flow_control).unwrap();
-                        }
-                    }
-                    Ok(SerialCommand::ChangeStopBits(stop_bits)) => {
-                        info!("Changing stop bits to {:?}", stop_bits);
-                        settings.stop_bits = stop_bits;
-                        if let Some(ref mut p) = port {
-                            p.set_stop_bits(stop_bits).unwrap();
-                        }
-                    }
-                    Ok(SerialCommand::ChangeParity(parity)) => {
-                        info!("Changing parity to {:?}", parity);
-                        settings.parity = parity;
-                        if let Some(ref mut p) = port {
-                            p.set_parity(parity).unwrap();
-                        }
-                    }
-                    Ok(SerialCommand::ChangePort(name)) => {
-                        if port.is_some() {
-                            info!("Changing port to '{}' using settings {:?}",
-                                  &name,
-                                  &settings);
-
-                            match serialport::open_with_settings(&name, &settings) {
-                                Ok(p) => {
-                                    port = Some(p);
-                                    from_port_chan_tx
-                                        .send(SerialResponse::OpenPortSuccess(name))
-                                        .unwrap();
-                                }
-                                Err(_) => {
-                                    port = None;
-                                    let err_str = format!("Failed to open port '{}'", &name);
-                                    let error = SerialResponse::OpenPortError(err_str);
-                                    from_port_chan_tx.send(error).unwrap();
-                                    callback();
-                                }
-                            }
-                        }
-                    }
-                    Ok(SerialCommand::Disconnect) => {
-                        info!("Disconnecting");
-                        port = None;
-                        read_file = None;
-                        write_file = None;
-                        from_port_chan_tx.send(SerialResponse::DisconnectSuccess).unwrap();
-                        callback();
-                    }
-                    Ok(SerialCommand::SendData(d)) => {
-                        if let Some(ref mut p) = port {
-                            match p.write(d.as_ref()) {
-                                Ok(_) => (),
-                                Err(e) => error!("Error in SendData: {:?}", e),
-                            }
-                        }
-                    }
-                    Ok(SerialCommand::SendFile(f)) => {
-                        if port.is_some() {
-                            bytes_total = fs::metadata(&f).unwrap().len();
-                            last_percentage = 0;
-                            bytes_read = 0;
-                            info!("Sending file {:?} ({} bytes)", f, bytes_total);
-                            match File::open(f) {
-                                Ok(file) => read_file = Some(Box::new(file)),
-                                Err(e) => error!("{:?}", e),
-                            }
-                            from_port_chan_tx.send(SerialResponse::SendingFileStarted).unwrap();
-                            callback();
-                        } else {
-                            let err_str = String::from("No open port to send file");
-                            let error = SerialResponse::SendingFileError(err_str);
-                            from_port_chan_tx.send(error).unwrap();
-                            callback();
-                        }
-                    }
-                    Ok(SerialCommand::CancelSendFile) => {
-                        read_file = None;
-                        from_port_chan_tx.send(SerialResponse::SendingFileCanceled).unwrap();
-                        callback();
-                    }
-                    Ok(SerialCommand::LogToFile(f)) => {
-                        if port.is_some() {
-                            info!("Logging to file {:?}", f);
-                            match File::create(f) {
-                                Ok(file) => write_file = Some(Box::new(file)),
-                                Err(e) => error!("{:?}", e),
-                            }
-                        } else {
-                            let err_str = String::from("No open port to log file from");
-                            let error = SerialResponse::LogToFileError(err_str);
-                            from_port_chan_tx.send(error).unwrap();
-                            callback();
-                        }
-                    }
-                    Ok(SerialCommand::CancelLogToFile) => {
-                        write_file = None;
-                        from_port_chan_tx.send(SerialResponse::LoggingFileCanceled).unwrap();
-                        callback();
-                    }
-                    Err(TryRecvError::Empty) |
-                    Err(TryRecvError::Disconnected) => (),
-                }
-
-                // If a port is active, handle reading and writing to it.
-                if let Some(ref mut p) = port {
-                    // Read data from the port
-                    let rx_data_len = match p.read(serial_buf.as_mut_slice()) {
-                        Ok(t) => t,
-                        Err(_) => 0,
-                    };
-
-                    // And send this data over the channel
-                    if rx_data_len > 0 {
-                        let send_data = SerialResponse::Data(serial_buf[..rx_data_len].to_vec());
-                        from_port_chan_tx.send(send_data).unwrap();
-                        callback();
-
-                        // Write the data to a log file if one's set up
-                        if let Some(ref mut file) = write_file {
-                            match file.write(&serial_buf[..rx_data_len]) {
-                                Err(e) => error!("{:?}", e),
-                                Ok(l) => {
-                                    if l < rx_data_len {
-                                        warn!("Only {}/{} bytes logged", l, rx_data_len);
-                                    }
-                                }
-                            }
-                        }
-                    }
-
-                    // If a file has been opened, read the next 1ms of data from
-                    // it as determined by the current baud rate.
-                    let mut read_len: ReadBytes = ReadBytes::NoAttempt;
-                    let mut byte_as_serial_bits = 1 + 8;
-                    if p.parity().unwrap() != Parity::None {
-                        byte_as_serial_bits += 1;
-                    }
-                    if p.stop_bits().unwrap() == StopBits::One {
-                        byte_as_serial_bits += 1;
-                    } else if p.stop_bits().unwrap() == StopBits::Two {
-                        byte_as_serial_bits += 2;
-                    }
-                    // Write 10ms of data at a time to account for loop time
-                    // variation
-                    if last_send_time.elapsed().subsec_nanos() > loop_time as u32 * 1_000_000 {
-                        let baud: u32 = p.baud_rate().unwrap().into();
-                        let tx_data_len = baud as usize / byte_as_serial_bits / (1000 / loop_time);
-                        if let Some(ref mut file) = read_file {
-                            debug!("Reading {} bytes", tx_data_len);
-                            match file.read(&mut serial_buf_rx[..tx_data_len]) {
-                                Ok(0) => {
-                                    debug!("END OF FILE!");
-                                    read_len = ReadBytes::EndOfFile;
-                                }
-                                Ok(len) => {
-                                    bytes_read += len as u64;
-                                    debug!("Actually read {} bytes ({} total)", len, bytes_read);
-                                    read_len = ReadBytes::Bytes(len);
-                                    let percentage =
-                                        (bytes_read as f32 / bytes_total as f32 * 100.0) as u8;
-                                    if percentage >= last_percentage + 5 {
-                                        from_port_chan_tx
-                                            .send(SerialResponse::SendingFileProgress(percentage))
-                                            .unwrap();
-                                        callback();
-                                        last_percentage = percentage;
-                                    }
-                                }
-                                Err(e) => {
-                                    error!("File error trying to read {} bytes", tx_data_len);
-                                    error!("{:?}", e);
-                                    read_len = ReadBytes::FileError;
-                                }
-                            }
-                        }
-                    }
-
-                    match read_len {
-                        ReadBytes::Bytes(x) => {
-                            if p.write(&serial_buf_rx[..x]).is_err() {
-                                // TODO: Check if port still exists and send different error if so
-                                let err_str = format!("Failed to send {} bytes", x);
-                                error!("Failed to send {} bytes", x);
-                                read_file = None;
-                                from_port_chan_tx.send(SerialResponse::SendingFileError(err_str))
-                                    .unwrap();
-                                callback();
-                            }
-                            last_send_time = Instant::now();
-                        }
-                        ReadBytes::EndOfFile | ReadBytes::FileError => {
-                            read_file = None;
-                            from_port_chan_tx.send(SerialResponse::SendingFileComplete).unwrap();
-                            callback();
-                        }
-                        ReadBytes::NoAttempt => (),
-                    }
-                }
-
-                // Scan for ports every so often
-                if last_port_scan_time.elapsed() > port_scan_time {
-                    last_port_scan_time = Instant::now();
-                    let mut ports = list_ports().expect("Scanning for ports should never fail");
-                    ports.sort();
-                    debug!("Found ports: {:?}", &ports);
-
-                    // Check if our port was disconnected
-                    let message = {
-                        if let Some(ref mut p) = port {
-                            if let Some(name) = p.name() {
-                                if ports.binary_search(&name).is_err() {
-                                    SerialResponse::UnexpectedDisconnection(ports)
-                                } else {
-                                    SerialResponse::PortsFound(ports)
-                                }
-                            } else {
-                                SerialResponse::PortsFound(ports)
-                            }
-                        } else {
-                            SerialResponse::PortsFound(ports)
-                        }
-                    };
-                    if let SerialResponse::UnexpectedDisconnection(_) = message {
-                        port = None;
-                    }
-                    from_port_chan_tx.send(message).unwrap();
-                    callback();
-                }
-
-                thread::sleep(Duration::from_millis(loop_time as u64));
-            }
+            Worker::new(backend, callback, to_port_chan_rx, from_port_chan_tx, wakeup).run();
         });
 
         SerialThread {
-            from_port_chan_rx: from_port_chan_rx,
-            to_port_chan_tx: to_port_chan_tx,
+            from_port_chan_rx,
+            to_port_chan_tx: CommandSender::new(to_port_chan_tx, waker),
         }
     }
 
@@ -400,102 +373,165 @@ impl SerialThread {
                               port_name: String,
                               baud_rate: String)
                               -> Result<(), GeneralError> {
-        let baud_rate: u32 = baud_rate.parse().map_err(GeneralError::Parse)?;
+        let baud_rate: u32 = baud_rate.parse()?;
         let tx = &self.to_port_chan_tx;
-        // TODO: Remove in favor of impl From for GeneralError
         tx.send(SerialCommand::ConnectToPort {
                       name: port_name,
                       baud: baud_rate,
-                  })
-            .map_err(|e| GeneralError::Send(e.0))?;
+                  })?;
         Ok(())
     }
 
     pub fn send_port_close_cmd(&self) -> Result<(), GeneralError> {
-        let tx = &self.to_port_chan_tx;
-        // TODO: Remove in favor of impl From for GeneralError
-        tx.send(SerialCommand::Disconnect).map_err(|e| GeneralError::Send(e.0))?;
+        self.to_port_chan_tx.send(SerialCommand::Disconnect)?;
         Ok(())
     }
 
-    pub fn send_port_change_baud_cmd(&self, baud_rate: String) -> Result<(), GeneralError> {
-        let baud_rate: u32 = baud_rate.parse().map_err(GeneralError::Parse)?;
-        let tx = &self.to_port_chan_tx;
-        // TODO: Remove in favor of impl From for GeneralError
-        tx.send(SerialCommand::ChangeBaud(baud_rate)).map_err(|e| GeneralError::Send(e.0))?;
+    pub fn send_port_change_baud_cmd(&self, baud_rate: u32) -> Result<(), GeneralError> {
+        self.to_port_chan_tx.send(SerialCommand::ChangeBaud(baud_rate))?;
         Ok(())
     }
 
     pub fn send_port_change_data_bits_cmd(&self, data_bits: DataBits) -> Result<(), GeneralError> {
-        let tx = &self.to_port_chan_tx;
-        // TODO: Remove in favor of impl From for GeneralError
-        tx.send(SerialCommand::ChangeDataBits(data_bits)).map_err(|e| GeneralError::Send(e.0))?;
+        self.to_port_chan_tx.send(SerialCommand::ChangeDataBits(data_bits))?;
         Ok(())
     }
 
     pub fn send_port_change_flow_control_cmd(&self,
                                              flow_control: FlowControl)
                                              -> Result<(), GeneralError> {
-        let tx = &self.to_port_chan_tx;
-        // TODO: Remove in favor of impl From for GeneralError
-        tx.send(SerialCommand::ChangeFlowControl(flow_control))
-            .map_err(|e| GeneralError::Send(e.0))?;
+        self.to_port_chan_tx.send(SerialCommand::ChangeFlowControl(flow_control))?;
         Ok(())
     }
 
     pub fn send_port_change_stop_bits_cmd(&self, stop_bits: StopBits) -> Result<(), GeneralError> {
-        let tx = &self.to_port_chan_tx;
-        // TODO: Remove in favor of impl From for GeneralError
-        tx.send(SerialCommand::ChangeStopBits(stop_bits)).map_err(|e| GeneralError::Send(e.0))?;
+        self.to_port_chan_tx.send(SerialCommand::ChangeStopBits(stop_bits))?;
         Ok(())
     }
 
     pub fn send_port_change_parity_cmd(&self, parity: Parity) -> Result<(), GeneralError> {
-        let tx = &self.to_port_chan_tx;
-        // TODO: Remove in favor of impl From for GeneralError
-        tx.send(SerialCommand::ChangeParity(parity)).map_err(|e| GeneralError::Send(e.0))?;
+        self.to_port_chan_tx.send(SerialCommand::ChangeParity(parity))?;
         Ok(())
     }
 
     pub fn send_port_change_port_cmd(&self, port_name: String) -> Result<(), GeneralError> {
-        let tx = &self.to_port_chan_tx;
-        // TODO: Remove in favor of impl From for GeneralError
-        tx.send(SerialCommand::ChangePort(port_name)).map_err(|e| GeneralError::Send(e.0))?;
+        self.to_port_chan_tx.send(SerialCommand::ChangePort(port_name))?;
+        Ok(())
+    }
+
+    pub fn send_port_dtr_cmd(&self, level: bool) -> Result<(), GeneralError> {
+        self.to_port_chan_tx.send(SerialCommand::SetDataTerminalReady(level))?;
+        Ok(())
+    }
+
+    pub fn send_port_rts_cmd(&self, level: bool) -> Result<(), GeneralError> {
+        self.to_port_chan_tx.send(SerialCommand::SetRequestToSend(level))?;
+        Ok(())
+    }
+
+    pub fn send_break_cmd(&self, duration: Duration) -> Result<(), GeneralError> {
+        self.to_port_chan_tx.send(SerialCommand::SendBreak { duration })?;
         Ok(())
     }
 
     pub fn send_port_data_cmd(&self, data: &[u8]) -> Result<(), GeneralError> {
-        let tx = &self.to_port_chan_tx;
-        // TODO: Remove in favor of impl From for GeneralError
-        tx.send(SerialCommand::SendData(data.into())).map_err(|e| GeneralError::Send(e.0))?;
+        self.to_port_chan_tx.send(SerialCommand::SendData(data.into()))?;
         Ok(())
     }
 
     pub fn send_port_file_cmd(&self, path: PathBuf) -> Result<(), GeneralError> {
+        self.send_port_file_with_protocol_cmd(path, Protocol::Raw)
+    }
+
+    pub fn send_port_file_with_protocol_cmd(&self,
+                                            path: PathBuf,
+                                            protocol: Protocol)
+                                            -> Result<(), GeneralError> {
         let tx = &self.to_port_chan_tx;
-        // TODO: Remove in favor of impl From for GeneralError
-        tx.send(SerialCommand::SendFile(path)).map_err(|e| GeneralError::Send(e.0))?;
+        tx.send(SerialCommand::SendFile {
+                      path,
+                      protocol,
+                  })?;
         Ok(())
     }
 
-    pub fn send_cancel_file_cmd(&self) -> Result<(), GeneralError> {
+    pub fn send_port_files_cmd(&self,
+                               paths: Vec<PathBuf>,
+                               protocol: Protocol)
+                               -> Result<(), GeneralError> {
         let tx = &self.to_port_chan_tx;
-        // TODO: Remove in favor of impl From for GeneralError
-        tx.send(SerialCommand::CancelSendFile).map_err(|e| GeneralError::Send(e.0))?;
+        tx.send(SerialCommand::SendFiles {
+                      paths,
+                      protocol,
+                  })?;
         Ok(())
     }
 
-    pub fn send_log_to_file_cmd(&self, path: PathBuf) -> Result<(), GeneralError> {
+    pub fn send_queue_cmd(&self,
+                          files: Vec<QueuedFile>,
+                          gap: Duration,
+                          repeat: Repeat)
+                          -> Result<(), GeneralError> {
         let tx = &self.to_port_chan_tx;
-        // TODO: Remove in favor of impl From for GeneralError
-        tx.send(SerialCommand::LogToFile(path)).map_err(|e| GeneralError::Send(e.0))?;
+        tx.send(SerialCommand::SendQueue {
+                      files,
+                      gap,
+                      repeat,
+                  })?;
         Ok(())
     }
 
-    pub fn send_cancel_log_to_file_cmd(&self) -> Result<(), GeneralError> {
+    pub fn receive_port_file_cmd(&self,
+                                 path: PathBuf,
+                                 protocol: Protocol)
+                                 -> Result<(), GeneralError> {
         let tx = &self.to_port_chan_tx;
-        // TODO: Remove in favor of impl From for GeneralError
-        tx.send(SerialCommand::CancelLogToFile).map_err(|e| GeneralError::Send(e.0))?;
+        tx.send(SerialCommand::ReceiveFile {
+                      path,
+                      protocol,
+                  })?;
+        Ok(())
+    }
+
+    pub fn send_line_pacing_cmd(&self, pacing: LinePacing) -> Result<(), GeneralError> {
+        self.to_port_chan_tx.send(SerialCommand::SetLinePacing(pacing))?;
+        Ok(())
+    }
+
+    pub fn send_throughput_cmd(&self, throughput: Option<u32>) -> Result<(), GeneralError> {
+        self.to_port_chan_tx.send(SerialCommand::SetThroughput(throughput))?;
+        Ok(())
+    }
+
+    pub fn send_image_options_cmd(&self, options: ImageOptions) -> Result<(), GeneralError> {
+        self.to_port_chan_tx.send(SerialCommand::SetImageOptions(options))?;
+        Ok(())
+    }
+
+    pub fn send_flash_options_cmd(&self, options: FlashOptions) -> Result<(), GeneralError> {
+        self.to_port_chan_tx.send(SerialCommand::SetFlashOptions(options))?;
+        Ok(())
+    }
+
+    pub fn send_cancel_file_cmd(&self) -> Result<(), GeneralError> {
+        self.to_port_chan_tx.send(SerialCommand::CancelSendFile)?;
+        Ok(())
+    }
+
+    pub fn send_log_to_file_cmd(&self,
+                                path: PathBuf,
+                                options: LogOptions)
+                                -> Result<(), GeneralError> {
+        let tx = &self.to_port_chan_tx;
+        tx.send(SerialCommand::LogToFile {
+                      path,
+                      options,
+                  })?;
+        Ok(())
+    }
+
+    pub fn send_cancel_log_to_file_cmd(&self) -> Result<(), GeneralError> {
+        self.to_port_chan_tx.send(SerialCommand::CancelLogToFile)?;
         Ok(())
     }
 }
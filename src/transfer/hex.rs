@@ -0,0 +1,391 @@
+//! Intel HEX and Motorola S-record images, which are sent either as the binary they describe or
+//! record by record to loaders that acknowledge each one.
+
+use std::io::prelude::*;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use super::{Progress, Status, Transfer};
+use SerialError;
+
+/// How long to wait for a record to be acknowledged before giving up, in seconds.
+const ACK_TIMEOUT_SECS: u64 = 10;
+
+/// The largest binary an image is decoded into, so that data at addresses far apart, like flash and
+/// option bytes, doesn't fill gigabytes of gaps.
+const MAX_BINARY_LEN: u64 = 16 * 1024 * 1024;
+
+/// The extensions of Intel HEX and S-record files.
+const IMAGE_EXTENSIONS: [&str; 8] = ["hex", "ihex", "ihx", "srec", "s19", "s28", "s37", "mot"];
+
+/// How images are sent with `Protocol::HexImage` and `Protocol::HexRecords`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ImageOptions {
+    /// What the gaps between the data of a decoded image are filled with, 0xFF by default like
+    /// erased flash
+    pub fill: u8,
+    /// What the receiver acknowledges each record with, ASCII ACK by default
+    pub ack: u8,
+}
+
+impl Default for ImageOptions {
+    fn default() -> Self {
+        ImageOptions {
+            fill: 0xff,
+            ack: 0x06,
+        }
+    }
+}
+
+/// The format of an image file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Format {
+    IntelHex,
+    Srecord,
+}
+
+/// Data at consecutive addresses.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Segment {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    /// The address after the segment's last byte.
+    pub fn end(&self) -> u64 {
+        self.address as u64 + self.data.len() as u64
+    }
+}
+
+/// An Intel HEX or S-record image, with the checksums of all its records verified.
+#[derive(Clone, Debug)]
+pub struct Image {
+    pub format: Format,
+    /// The records as they are in the file, without line endings
+    pub records: Vec<Vec<u8>>,
+    /// The data, sorted by address and merged where it's consecutive
+    pub segments: Vec<Segment>,
+    /// Where execution starts, if the image says
+    pub start: Option<u32>,
+}
+
+impl Image {
+    /// Reads and parses an image from `file`.
+    pub fn read<R: Read>(mut file: R) -> Result<Self, SerialError> {
+        let mut text = Vec::new();
+        file.read_to_end(&mut text)?;
+        Image::parse(&text)
+    }
+
+    /// Parses the records in `text`, whose format is told by the first one. Blank lines are
+    /// skipped, and anything else that isn't a valid record is an error.
+    pub fn parse(text: &[u8]) -> Result<Self, SerialError> {
+        let mut format = None;
+        let mut records = Vec::new();
+        let mut segments = Vec::new();
+        let mut start = None;
+        // The base address set by Intel HEX extended address records
+        let mut base = 0u32;
+        let mut ended = false;
+
+        for (i, line) in text.split(|b| *b == b'\n').enumerate() {
+            let number = i + 1;
+            let line = trim(line);
+            if line.is_empty() {
+                continue;
+            }
+            if ended {
+                return Err(invalid(number, "data after the last record"));
+            }
+            let line_format = match line[0] {
+                b':' => Format::IntelHex,
+                b'S' => Format::Srecord,
+                _ => return Err(invalid(number, "not a record")),
+            };
+            if *format.get_or_insert(line_format) != line_format {
+                return Err(invalid(number, "Intel HEX and S-records are mixed"));
+            }
+
+            match line_format {
+                Format::IntelHex => {
+                    let bytes = decode_hex(&line[1..]).map_err(|e| invalid(number, e))?;
+                    if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+                        return Err(invalid(number, "the record's length is wrong"));
+                    }
+                    let checksum = bytes[..bytes.len() - 1]
+                        .iter()
+                        .fold(0u8, |sum, b| sum.wrapping_add(*b))
+                        .wrapping_neg();
+                    check_sum(number, checksum, bytes[bytes.len() - 1])?;
+
+                    let address = (bytes[1] as u32) << 8 | bytes[2] as u32;
+                    let data = &bytes[4..bytes.len() - 1];
+                    match (bytes[3], data.len()) {
+                        (0x00, _) => {
+                            segments.push(Segment {
+                                              address: base.wrapping_add(address),
+                                              data: data.to_vec(),
+                                          })
+                        }
+                        (0x01, _) => ended = true,
+                        (0x02, 2) => base = be_u32(data) << 4,
+                        (0x03, 4) => start = Some((be_u32(&data[..2]) << 4) + be_u32(&data[2..])),
+                        (0x04, 2) => base = be_u32(data) << 16,
+                        (0x05, 4) => start = Some(be_u32(data)),
+                        (0x02..=0x05, _) => {
+                            return Err(invalid(number, "the record's length is wrong"));
+                        }
+                        _ => return Err(invalid(number, "unknown record type")),
+                    }
+                }
+                Format::Srecord => {
+                    if line.len() < 2 {
+                        return Err(invalid(number, "the record is too short"));
+                    }
+                    let record_type = line[1];
+                    let bytes = decode_hex(&line[2..]).map_err(|e| invalid(number, e))?;
+                    if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+                        return Err(invalid(number, "the record's length is wrong"));
+                    }
+                    let checksum = !bytes[..bytes.len() - 1]
+                        .iter()
+                        .fold(0u8, |sum, b| sum.wrapping_add(*b));
+                    check_sum(number, checksum, bytes[bytes.len() - 1])?;
+
+                    let address_len = match record_type {
+                        b'0' | b'1' | b'5' | b'9' => 2,
+                        b'2' | b'6' | b'8' => 3,
+                        b'3' | b'7' => 4,
+                        _ => return Err(invalid(number, "unknown record type")),
+                    };
+                    if bytes.len() < address_len + 2 {
+                        return Err(invalid(number, "the record is too short"));
+                    }
+                    let address = be_u32(&bytes[1..address_len + 1]);
+                    let data = &bytes[address_len + 1..bytes.len() - 1];
+                    match record_type {
+                        b'1' | b'2' | b'3' => {
+                            segments.push(Segment {
+                                              address,
+                                              data: data.to_vec(),
+                                          })
+                        }
+                        b'7' | b'8' | b'9' => {
+                            start = Some(address);
+                            ended = true;
+                        }
+                        // Headers and record counts
+                        _ => (),
+                    }
+                }
+            }
+            records.push(line.to_vec());
+        }
+
+        let format = match format {
+            Some(format) => format,
+            None => return Err(SerialError::InvalidImage("there are no records".to_string())),
+        };
+        if format == Format::IntelHex && !ended {
+            let msg = "the end of file record is missing".to_string();
+            return Err(SerialError::InvalidImage(msg));
+        }
+        Ok(Image {
+               format,
+               records,
+               segments: merge(segments)?,
+               start,
+           })
+    }
+
+    /// The binary the image describes and the address it starts at, with `fill` in the gaps
+    /// between segments. It's empty for an image without data.
+    pub fn to_binary(&self, fill: u8) -> Result<(u32, Vec<u8>), SerialError> {
+        let (first, last) = match (self.segments.first(), self.segments.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return Ok((0, Vec::new())),
+        };
+        let len = last.end() - first.address as u64;
+        if len > MAX_BINARY_LEN {
+            let msg = format!("its data spans {} bytes from 0x{:08X}, which is too much to decode",
+                              len,
+                              first.address);
+            return Err(SerialError::InvalidImage(msg));
+        }
+
+        let mut binary = vec![fill; len as usize];
+        for segment in &self.segments {
+            let offset = (segment.address - first.address) as usize;
+            binary[offset..offset + segment.data.len()].copy_from_slice(&segment.data);
+        }
+        Ok((first.address, binary))
+    }
+}
+
+/// Reads the data to flash from `file`, which is called `name`. Intel HEX and S-record files are
+/// told by their extension, and anything else is a binary that's written at `address`.
+pub fn load<R: Read>(mut file: R, name: &str, address: u32) -> Result<Vec<Segment>, SerialError> {
+    let extension = Path::new(name)
+        .extension()
+        .map_or(String::new(), |e| e.to_string_lossy().to_lowercase());
+    let segments = if IMAGE_EXTENSIONS.contains(&extension.as_str()) {
+        Image::read(file)?.segments
+    } else {
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        vec![Segment {
+                 address,
+                 data,
+             }]
+    };
+    if segments.iter().all(|s| s.data.is_empty()) {
+        return Err(SerialError::InvalidImage("there's no data to write".to_string()));
+    }
+    Ok(segments)
+}
+
+fn invalid(line: usize, msg: &str) -> SerialError {
+    SerialError::InvalidImage(format!("line {}: {}", line, msg))
+}
+
+fn check_sum(line: usize, expected: u8, actual: u8) -> Result<(), SerialError> {
+    if expected == actual {
+        Ok(())
+    } else {
+        let msg = format!("line {}: the checksum is {:02X} instead of {:02X}",
+                          line,
+                          actual,
+                          expected);
+        Err(SerialError::InvalidImage(msg))
+    }
+}
+
+/// Strips the whitespace around a line, including the CR of CR LF line endings.
+fn trim(line: &[u8]) -> &[u8] {
+    let start = line.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(line.len());
+    let end = line.iter().rposition(|b| !b.is_ascii_whitespace()).map_or(start, |i| i + 1);
+    &line[start..end]
+}
+
+fn decode_hex(text: &[u8]) -> Result<Vec<u8>, &'static str> {
+    if text.len() % 2 != 0 {
+        return Err("the record has an odd number of digits");
+    }
+    text.chunks(2)
+        .map(|pair| {
+                 let high = (pair[0] as char).to_digit(16);
+                 let low = (pair[1] as char).to_digit(16);
+                 match (high, low) {
+                     (Some(high), Some(low)) => Ok((high << 4 | low) as u8),
+                     _ => Err("the record has characters that aren't hex digits"),
+                 }
+             })
+        .collect()
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |n, b| n << 8 | *b as u32)
+}
+
+/// Sorts `segments` by address and merges consecutive ones. Overlapping data is an error, as it's
+/// not clear which should win.
+fn merge(mut segments: Vec<Segment>) -> Result<Vec<Segment>, SerialError> {
+    segments.sort_by_key(|s| s.address);
+    let mut merged: Vec<Segment> = Vec::with_capacity(segments.len());
+    for segment in segments {
+        if segment.data.is_empty() {
+            continue;
+        }
+        if let Some(last) = merged.last_mut() {
+            if (segment.address as u64) < last.end() {
+                let msg = format!("the data at 0x{:08X} overlaps other data", segment.address);
+                return Err(SerialError::InvalidImage(msg));
+            } else if segment.address as u64 == last.end() {
+                last.data.extend_from_slice(&segment.data);
+                continue;
+            }
+        }
+        merged.push(segment);
+    }
+    Ok(merged)
+}
+
+/// Sends an image's records one per line, each ending with CR LF, and waits for the receiver to
+/// acknowledge each one before sending the next.
+pub struct RecordSender {
+    records: Vec<Vec<u8>>,
+    ack: u8,
+    /// The record being sent
+    record: usize,
+    /// Whether the record was sent and is waiting to be acknowledged
+    waiting: bool,
+    deadline: Option<Instant>,
+    progress: Progress,
+}
+
+impl RecordSender {
+    /// Creates a sender for the records of `image`, which the receiver acknowledges with `ack`.
+    pub fn new(image: Image, ack: u8) -> Self {
+        let total = image.records.iter().map(|r| r.len() as u64 + 2).sum();
+        let count = image.records.len();
+        RecordSender {
+            records: image.records,
+            ack,
+            record: 0,
+            waiting: false,
+            deadline: None,
+            progress: Progress {
+                bytes: 0,
+                total: Some(total),
+                file: None,
+                lines: Some((0, count)),
+                stage: None,
+            },
+        }
+    }
+}
+
+impl Transfer for RecordSender {
+    fn process(&mut self,
+               input: &[u8],
+               now: Instant,
+               output: &mut Vec<u8>)
+               -> Result<Status, SerialError> {
+        if self.waiting {
+            if input.contains(&self.ack) {
+                self.waiting = false;
+                self.record += 1;
+                self.progress.lines = Some((self.record, self.records.len()));
+                if self.record == self.records.len() {
+                    // Report the last record before completing
+                    self.deadline = Some(now);
+                    return Ok(Status::InProgress);
+                }
+            } else if self.deadline.map_or(false, |d| now >= d) {
+                let msg = format!("record {} wasn't acknowledged", self.record + 1);
+                return Err(SerialError::TransferFailed(msg));
+            } else {
+                return Ok(Status::InProgress);
+            }
+        }
+        if self.record >= self.records.len() {
+            return Ok(Status::Complete);
+        }
+
+        output.extend_from_slice(&self.records[self.record]);
+        output.extend_from_slice(b"\r\n");
+        self.progress.bytes += self.records[self.record].len() as u64 + 2;
+        self.waiting = true;
+        self.deadline = Some(now + Duration::from_secs(ACK_TIMEOUT_SECS));
+        Ok(Status::InProgress)
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    fn progress(&self) -> Progress {
+        self.progress.clone()
+    }
+}
@@ -0,0 +1,863 @@
+//! Kermit batch transfers.
+//!
+//! Kermit sends everything in short packets of printable characters, so that it works over 7-bit
+//! and otherwise unfriendly lines:
+//!
+//! ```text
+//! MARK LEN SEQ TYPE DATA... CHECK EOL
+//! ```
+//!
+//! Numbers are sent as `tochar(n) = n + 32`. Control characters in the data are prefixed with '#'
+//! and made printable, and bytes with the 8th bit set can be prefixed as well if both ends agree
+//! on it. Every packet is acknowledged before the next one is sent. The ends exchange their
+//! parameters in the Send-Init packet and its acknowledgement, which also settles which block
+//! check is used: a 6-bit or 12-bit checksum, or a CRC-16.
+//!
+//! Sliding windows, long packets and repeat counts aren't supported, and are turned down when the
+//! other end offers them.
+
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use super::xmodem::{MAX_RETRIES, START_INTERVAL_SECS, TIMEOUT_SECS};
+use super::{BatchFile, FileProgress, Progress, Status, Transfer};
+use SerialError;
+
+/// Starts every packet.
+pub const MARK: u8 = 0x01;
+/// Ends every packet.
+const EOL: u8 = b'\r';
+
+// Packet types
+pub const SEND_INIT: u8 = b'S';
+pub const FILE_HEADER: u8 = b'F';
+pub const ATTRIBUTES: u8 = b'A';
+pub const DATA: u8 = b'D';
+pub const END_OF_FILE: u8 = b'Z';
+pub const BREAK: u8 = b'B';
+pub const ACK: u8 = b'Y';
+pub const NAK: u8 = b'N';
+pub const ERROR: u8 = b'E';
+
+/// The longest packet that's sent or accepted, counting from SEQ to CHECK.
+const MAX_LEN: u8 = 94;
+
+/// The prefix for control characters.
+const CONTROL_PREFIX: u8 = b'#';
+
+/// The capability bit for handling Attribute packets.
+const CAPAS_ATTRIBUTES: u8 = 0x08;
+
+/// How much of a file is read at once.
+const READ_LEN: usize = 1024;
+
+/// Encodes a number from 0 to 94 as a printable character.
+pub fn tochar(n: u8) -> u8 {
+    n + 32
+}
+
+/// Decodes a number encoded with `tochar()`.
+pub fn unchar(c: u8) -> u8 {
+    c.wrapping_sub(32)
+}
+
+/// Calculates the CRC-16 used by Kermit, which is the CCITT polynomial processed least
+/// significant bit first.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &b in data {
+        crc ^= b as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0x8408 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+/// Calculates the block check of `packet` from LEN to the end of DATA. `check_type` is 1, 2 or 3,
+/// which is also the number of characters of the check.
+pub fn block_check(packet: &[u8], check_type: u8) -> Vec<u8> {
+    let sum = packet.iter().fold(0u32, |s, b| s + *b as u32);
+    match check_type {
+        2 => vec![tochar(((sum >> 6) & 0x3F) as u8), tochar((sum & 0x3F) as u8)],
+        3 => {
+            let crc = crc16(packet);
+            vec![tochar(((crc >> 12) & 0x0F) as u8),
+                 tochar(((crc >> 6) & 0x3F) as u8),
+                 tochar((crc & 0x3F) as u8)]
+        }
+        _ => vec![tochar(((sum + ((sum & 0xC0) >> 6)) & 0x3F) as u8)],
+    }
+}
+
+/// Encodes a packet with already prefixed `data`, which has to fit into `MAX_LEN`.
+pub fn encode_packet(seq: u8, kind: u8, data: &[u8], check_type: u8) -> Vec<u8> {
+    let len = 2 + data.len() + check_type as usize;
+    assert!(len <= MAX_LEN as usize, "Kermit packet too long");
+    let mut packet = vec![MARK, tochar(len as u8), tochar(seq % 64), kind];
+    packet.extend_from_slice(data);
+    let check = block_check(&packet[1..], check_type);
+    packet.extend(check);
+    packet.push(EOL);
+    packet
+}
+
+/// A received packet.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Packet {
+    pub seq: u8,
+    pub kind: u8,
+    /// The data as it was sent, still prefixed
+    pub data: Vec<u8>,
+}
+
+/// Decodes a packet read by a `Reader`, returning `None` if it's damaged.
+pub fn decode_packet(raw: &[u8], check_type: u8) -> Option<Packet> {
+    let check_len = check_type as usize;
+    if raw.len() < 3 + check_len || raw.len() != unchar(raw[0]) as usize + 1 {
+        return None;
+    }
+    let (body, check) = raw.split_at(raw.len() - check_len);
+    if block_check(body, check_type) != check || unchar(body[1]) >= 64 {
+        return None;
+    }
+    Some(Packet {
+             seq: unchar(body[1]),
+             kind: body[2],
+             data: body[3..].to_vec(),
+         })
+}
+
+/// Collects the bytes of packets from received data.
+#[derive(Debug, Default)]
+pub struct Reader {
+    buf: Vec<u8>,
+    reading: bool,
+}
+
+impl Reader {
+    /// Reads `byte`, returning the packet from LEN to CHECK if it completes one. Packets cut short
+    /// by a control character are returned as they are, so that they're found to be damaged.
+    pub fn read(&mut self, byte: u8) -> Option<Vec<u8>> {
+        if byte == MARK {
+            self.buf.clear();
+            self.reading = true;
+            return None;
+        }
+        if !self.reading {
+            return None;
+        }
+        if byte < 32 {
+            self.reading = false;
+            return Some(::std::mem::replace(&mut self.buf, Vec::new()));
+        }
+        self.buf.push(byte);
+        if self.buf.len() == unchar(self.buf[0]) as usize + 1 {
+            self.reading = false;
+            return Some(::std::mem::replace(&mut self.buf, Vec::new()));
+        }
+        None
+    }
+}
+
+/// How bytes in the data field are prefixed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Quoting {
+    /// The prefix for control characters
+    pub control: u8,
+    /// The prefix for bytes with the 8th bit set, if they're prefixed
+    pub eight_bit: Option<u8>,
+}
+
+impl Default for Quoting {
+    fn default() -> Self {
+        Quoting {
+            control: CONTROL_PREFIX,
+            eight_bit: None,
+        }
+    }
+}
+
+impl Quoting {
+    /// Appends `byte` with the prefixes it needs to `encoded`.
+    fn encode_byte(&self, byte: u8, encoded: &mut Vec<u8>) {
+        let mut b = byte;
+        if let Some(prefix) = self.eight_bit {
+            if b & 0x80 != 0 {
+                encoded.push(prefix);
+                b &= 0x7F;
+            }
+        }
+        let low = b & 0x7F;
+        if low < 32 || low == 127 {
+            encoded.push(self.control);
+            encoded.push(b ^ 64);
+        } else if low == self.control || Some(low) == self.eight_bit {
+            encoded.push(self.control);
+            encoded.push(b);
+        } else {
+            encoded.push(b);
+        }
+    }
+
+    /// Prefixes `data`.
+    pub fn encode(&self, data: &[u8]) -> Vec<u8> {
+        let mut encoded = Vec::with_capacity(data.len());
+        for &b in data {
+            self.encode_byte(b, &mut encoded);
+        }
+        encoded
+    }
+
+    /// Prefixes as much of `data` as fits into `capacity` characters, returning the prefixed data
+    /// and how many bytes of `data` it holds.
+    fn encode_into(&self, data: &[u8], capacity: usize) -> (Vec<u8>, usize) {
+        let mut encoded = Vec::with_capacity(capacity);
+        let mut byte = Vec::with_capacity(3);
+        let mut count = 0;
+        for &b in data {
+            byte.clear();
+            self.encode_byte(b, &mut byte);
+            if encoded.len() + byte.len() > capacity {
+                break;
+            }
+            encoded.extend_from_slice(&byte);
+            count += 1;
+        }
+        (encoded, count)
+    }
+
+    /// Removes the prefixes from `data`.
+    pub fn decode(&self, data: &[u8]) -> Vec<u8> {
+        let mut decoded = Vec::with_capacity(data.len());
+        let mut bytes = data.iter().cloned();
+        while let Some(mut b) = bytes.next() {
+            let mut high = 0;
+            if Some(b) == self.eight_bit {
+                high = 0x80;
+                b = match bytes.next() {
+                    Some(b) => b,
+                    None => break,
+                };
+            }
+            if b == self.control {
+                b = match bytes.next() {
+                    Some(b) => b,
+                    None => break,
+                };
+                let low = b & 0x7F;
+                if low >= 63 && low <= 95 {
+                    b ^= 64;
+                }
+            }
+            decoded.push(b | high);
+        }
+        decoded
+    }
+}
+
+/// The parameters exchanged in the Send-Init packet and its acknowledgement.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Params {
+    /// The longest packet this end accepts
+    max_len: u8,
+    /// The prefix this end uses for control characters
+    control: u8,
+    /// The prefix for 8th bit quoting, 'Y' to agree to one or 'N' to refuse
+    eight_bit: u8,
+    check_type: u8,
+    capabilities: u8,
+}
+
+impl Params {
+    /// The parameters used by this end.
+    fn ours() -> Self {
+        Params {
+            max_len: MAX_LEN,
+            control: CONTROL_PREFIX,
+            eight_bit: b'Y',
+            check_type: 3,
+            capabilities: CAPAS_ATTRIBUTES,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        vec![tochar(self.max_len),
+             tochar(TIMEOUT_SECS as u8),
+             // No padding, with NUL as the padding character
+             tochar(0),
+             b'@',
+             tochar(EOL),
+             self.control,
+             self.eight_bit,
+             b'0' + self.check_type,
+             // No repeat counts
+             b' ',
+             tochar(self.capabilities)]
+    }
+
+    /// Decodes the parameters of the other end, using the defaults for those that are left out.
+    fn decode(data: &[u8]) -> Self {
+        let field = |i: usize| data.get(i).cloned().filter(|c| *c != b' ');
+        Params {
+            max_len: field(0).map_or(80, |c| unchar(c).min(MAX_LEN).max(10)),
+            control: field(5).unwrap_or(CONTROL_PREFIX),
+            eight_bit: field(6).unwrap_or(b'N'),
+            check_type: field(7).map_or(1, |c| c.wrapping_sub(b'0')),
+            capabilities: field(9).map_or(0, unchar),
+        }
+    }
+
+    /// Settles the block check and 8th bit prefix to use with the other end's parameters.
+    fn negotiate(&self, theirs: &Params) -> (u8, Option<u8>) {
+        let check_type = if theirs.check_type == self.check_type { self.check_type } else { 1 };
+        let valid = |c: u8| (c >= 33 && c <= 62) || (c >= 96 && c <= 126);
+        let eight_bit = match (self.eight_bit, theirs.eight_bit) {
+            (b'Y', c) | (c, b'Y') if valid(c) => Some(c),
+            (a, b) if a == b && valid(a) => Some(a),
+            _ => None,
+        };
+        (check_type, eight_bit)
+    }
+}
+
+/// Encodes an Error packet telling the other end why the transfer stopped.
+fn error_packet(seq: u8, message: &str, check_type: u8) -> Vec<u8> {
+    let capacity = MAX_LEN as usize - 2 - check_type as usize;
+    let (data, _) = Quoting::default().encode_into(message.as_bytes(), capacity);
+    encode_packet(seq, ERROR, &data, check_type)
+}
+
+/// The error for an Error packet received from the other end.
+fn peer_error(packet: &Packet) -> SerialError {
+    let message = Quoting::default().decode(&packet.data);
+    let msg = format!("the other end reported '{}'", String::from_utf8_lossy(&message));
+    SerialError::TransferFailed(msg)
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum SendState {
+    Init,
+    FileHeader,
+    Attributes,
+    Data,
+    EndOfFile,
+    Break,
+}
+
+/// Sends a batch of files using Kermit.
+pub struct Sender<R> {
+    files: Vec<BatchFile<R>>,
+    /// The position of the file being sent in `files`
+    index: usize,
+    state: SendState,
+    reader: Reader,
+    /// The sequence number of the last packet sent
+    seq: u8,
+    check_type: u8,
+    quoting: Quoting,
+    /// The longest packet the receiver accepts
+    max_len: u8,
+    /// Whether the receiver takes Attribute packets
+    attributes: bool,
+    /// Data read from the current file that hasn't been sent yet
+    buf: Vec<u8>,
+    buf_pos: usize,
+    /// How much of the current file has been sent
+    file_bytes: u64,
+    /// Bytes of the files before the current one
+    bytes_done: u64,
+    /// The last packet sent, which is sent again when it's not acknowledged
+    last_packet: Vec<u8>,
+    retries: u32,
+    deadline: Instant,
+    progress: Progress,
+}
+
+impl<R: Read> Sender<R> {
+    pub fn new(files: Vec<BatchFile<R>>) -> Self {
+        let total = files.iter().map(|f| f.len).sum();
+        Sender {
+            files,
+            index: 0,
+            state: SendState::Init,
+            reader: Reader::default(),
+            seq: 0,
+            check_type: 1,
+            quoting: Quoting::default(),
+            max_len: 80,
+            attributes: false,
+            buf: Vec::new(),
+            buf_pos: 0,
+            file_bytes: 0,
+            bytes_done: 0,
+            last_packet: Vec::new(),
+            retries: 0,
+            deadline: Instant::now(),
+            progress: Progress {
+                bytes: 0,
+                total: Some(total),
+                file: None,
+                lines: None,
+                stage: None,
+            },
+        }
+    }
+
+    /// Sends the next packet, which will be sent again until it's acknowledged.
+    fn send(&mut self, kind: u8, data: &[u8], output: &mut Vec<u8>) {
+        self.seq = (self.seq + 1) % 64;
+        self.last_packet = encode_packet(self.seq, kind, data, self.check_type);
+        output.extend_from_slice(&self.last_packet);
+        self.retries = 0;
+    }
+
+    /// Announces the next file, or ends the batch if all have been sent.
+    fn send_file(&mut self, output: &mut Vec<u8>) {
+        if self.index >= self.files.len() {
+            self.state = SendState::Break;
+            self.send(BREAK, &[], output);
+            return;
+        }
+
+        let (name, len) = {
+            let file = &self.files[self.index];
+            (file.name.clone(), file.len)
+        };
+        let capacity = self.max_len as usize - 2 - self.check_type as usize;
+        let (data, _) = self.quoting.encode_into(name.as_bytes(), capacity);
+        self.progress.file = Some(FileProgress {
+            name,
+            index: self.index,
+            count: Some(self.files.len()),
+            bytes: 0,
+            total: Some(len),
+        });
+        self.buf.clear();
+        self.buf_pos = 0;
+        self.file_bytes = 0;
+        self.state = SendState::FileHeader;
+        self.send(FILE_HEADER, &data, output);
+    }
+
+    /// Sends the size of the current file.
+    fn send_attributes(&mut self, output: &mut Vec<u8>) {
+        let len = self.files[self.index].len.to_string();
+        let mut data = vec![b'1', tochar(len.len() as u8)];
+        data.extend_from_slice(len.as_bytes());
+        self.state = SendState::Attributes;
+        self.send(ATTRIBUTES, &data, output);
+    }
+
+    /// Sends the next packet of the current file's data, or ends the file if it's all been sent.
+    fn send_data(&mut self, output: &mut Vec<u8>) -> Result<(), SerialError> {
+        if self.buf_pos >= self.buf.len() {
+            self.buf.resize(READ_LEN, 0);
+            let len = self.files[self.index].file.read(&mut self.buf)?;
+            self.buf.truncate(len);
+            self.buf_pos = 0;
+        }
+        if self.buf.is_empty() {
+            self.state = SendState::EndOfFile;
+            self.send(END_OF_FILE, &[], output);
+            return Ok(());
+        }
+
+        let capacity = self.max_len as usize - 2 - self.check_type as usize;
+        let (data, count) = self.quoting.encode_into(&self.buf[self.buf_pos..], capacity);
+        self.buf_pos += count;
+        self.file_bytes += count as u64;
+        self.progress.bytes = self.bytes_done + self.file_bytes;
+        if let Some(ref mut file) = self.progress.file {
+            file.bytes = self.file_bytes;
+        }
+        self.state = SendState::Data;
+        self.send(DATA, &data, output);
+        Ok(())
+    }
+
+    /// Ends the current file early, telling the receiver to discard it.
+    fn skip_file(&mut self, output: &mut Vec<u8>) {
+        info!("The receiver refused '{}'", self.files[self.index].name);
+        self.state = SendState::EndOfFile;
+        self.send(END_OF_FILE, b"D", output);
+    }
+
+    /// Moves on once the last packet sent has been acknowledged with `data`. Returns whether the
+    /// transfer is complete.
+    fn acknowledged(&mut self, data: &[u8], output: &mut Vec<u8>) -> Result<bool, SerialError> {
+        match self.state {
+            SendState::Init => {
+                let ours = Params::ours();
+                let theirs = Params::decode(data);
+                let (check_type, eight_bit) = ours.negotiate(&theirs);
+                debug!("Receiver parameters {:?}, using block check {}", theirs, check_type);
+                self.check_type = check_type;
+                self.quoting.eight_bit = eight_bit;
+                self.max_len = theirs.max_len;
+                self.attributes = theirs.capabilities & CAPAS_ATTRIBUTES != 0;
+                self.send_file(output);
+            }
+            SendState::FileHeader if self.attributes => self.send_attributes(output),
+            SendState::FileHeader => self.send_data(output)?,
+            SendState::Attributes if data.first() == Some(&b'N') => self.skip_file(output),
+            SendState::Attributes => self.send_data(output)?,
+            // The receiver can ask to skip the rest of this file (X) or of the batch (Z)
+            SendState::Data if data.first() == Some(&b'X') => self.skip_file(output),
+            SendState::Data if data.first() == Some(&b'Z') => {
+                self.index = self.files.len() - 1;
+                self.skip_file(output);
+            }
+            SendState::Data => self.send_data(output)?,
+            SendState::EndOfFile => {
+                self.bytes_done += self.files[self.index].len;
+                self.progress.bytes = self.bytes_done;
+                self.index += 1;
+                self.send_file(output);
+            }
+            SendState::Break => return Ok(true),
+        }
+        Ok(false)
+    }
+
+    fn resend(&mut self, output: &mut Vec<u8>) -> Result<(), SerialError> {
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            let msg = if self.state == SendState::Init {
+                "the receiver didn't start the transfer"
+            } else {
+                "the receiver didn't acknowledge a packet"
+            };
+            output.extend(error_packet(self.seq, msg, self.check_type));
+            return Err(SerialError::TransferFailed(msg.to_string()));
+        }
+        output.extend_from_slice(&self.last_packet);
+        Ok(())
+    }
+}
+
+impl<R: Read> Transfer for Sender<R> {
+    fn process(&mut self,
+               input: &[u8],
+               now: Instant,
+               output: &mut Vec<u8>)
+               -> Result<Status, SerialError> {
+        if self.last_packet.is_empty() {
+            self.last_packet = encode_packet(0, SEND_INIT, &Params::ours().encode(), 1);
+            output.extend_from_slice(&self.last_packet);
+            self.deadline = now + Duration::from_secs(START_INTERVAL_SECS);
+        }
+
+        for &b in input {
+            let raw = match self.reader.read(b) {
+                Some(raw) => raw,
+                None => continue,
+            };
+            // The Send-Init exchange always uses the 6-bit checksum
+            let check_type = if self.state == SendState::Init { 1 } else { self.check_type };
+            let packet = match decode_packet(&raw, check_type) {
+                Some(packet) => packet,
+                None => {
+                    debug!("Received a damaged packet");
+                    self.resend(output)?;
+                    continue;
+                }
+            };
+
+            // A NAK for the next packet means that this one arrived
+            let next = (self.seq + 1) % 64;
+            let acked = match packet.kind {
+                ACK => packet.seq == self.seq,
+                NAK => packet.seq == next && self.state != SendState::Init,
+                ERROR => return Err(peer_error(&packet)),
+                _ => false,
+            };
+            if acked {
+                if self.acknowledged(&packet.data, output)? {
+                    return Ok(Status::Complete);
+                }
+            } else if packet.kind == NAK {
+                self.resend(output)?;
+            }
+            self.deadline = now + Duration::from_secs(TIMEOUT_SECS);
+        }
+
+        if now >= self.deadline {
+            debug!("Timed out waiting for the receiver, sending again");
+            self.resend(output)?;
+            let interval = if self.state == SendState::Init {
+                START_INTERVAL_SECS
+            } else {
+                TIMEOUT_SECS
+            };
+            self.deadline = now + Duration::from_secs(interval);
+        }
+        Ok(Status::InProgress)
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        Some(self.deadline)
+    }
+
+    fn progress(&self) -> Progress {
+        self.progress.clone()
+    }
+
+    fn cancel(&mut self, output: &mut Vec<u8>) {
+        output.extend(error_packet(self.seq, "Canceled by the sender", self.check_type));
+    }
+}
+
+/// Receives a batch of files using Kermit into a directory, naming them as the sender does.
+/// Existing files are overwritten.
+pub struct Receiver {
+    dir: PathBuf,
+    reader: Reader,
+    /// The sequence number of the next packet expected
+    seq: u8,
+    /// Whether the Send-Init exchange is done
+    initialized: bool,
+    check_type: u8,
+    quoting: Quoting,
+    /// The file being received and where it is
+    file: Option<(File, PathBuf)>,
+    /// How many files have been received
+    files: usize,
+    /// The last acknowledgement sent, which is sent again when the sender repeats a packet
+    last_ack: Vec<u8>,
+    retries: u32,
+    deadline: Option<Instant>,
+    progress: Progress,
+}
+
+impl Receiver {
+    /// Creates a receiver writing files into `dir`, which must exist.
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Receiver {
+            dir: dir.as_ref().to_path_buf(),
+            reader: Reader::default(),
+            seq: 0,
+            initialized: false,
+            check_type: 1,
+            quoting: Quoting::default(),
+            file: None,
+            files: 0,
+            last_ack: Vec::new(),
+            retries: 0,
+            deadline: None,
+            progress: Progress::default(),
+        }
+    }
+
+    fn ack(&mut self, data: &[u8], check_type: u8, output: &mut Vec<u8>) {
+        self.last_ack = encode_packet(self.seq, ACK, data, check_type);
+        output.extend_from_slice(&self.last_ack);
+        self.seq = (self.seq + 1) % 64;
+        self.retries = 0;
+    }
+
+    fn nak(&mut self, output: &mut Vec<u8>) -> Result<(), SerialError> {
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            let msg = if self.initialized {
+                "the sender stopped responding"
+            } else {
+                "the sender didn't start the transfer"
+            };
+            output.extend(error_packet(self.seq, msg, self.check_type));
+            return Err(SerialError::TransferFailed(msg.to_string()));
+        }
+        output.extend(encode_packet(self.seq, NAK, &[], self.check_type));
+        Ok(())
+    }
+
+    /// Fails the transfer with `error`, telling the sender about it.
+    fn fail(&self, error: SerialError, output: &mut Vec<u8>) -> SerialError {
+        output.extend(error_packet(self.seq, &error.to_string(), self.check_type));
+        error
+    }
+
+    /// Opens the file announced in a File-Header packet.
+    fn start_file(&mut self, data: &[u8], output: &mut Vec<u8>) -> Result<(), SerialError> {
+        let name = String::from_utf8_lossy(&self.quoting.decode(data)).into_owned();
+        let file_name = match Path::new(&name).file_name() {
+            Some(file_name) => file_name.to_os_string(),
+            None => {
+                let msg = format!("the sender sent an invalid file name '{}'", name);
+                return Err(self.fail(SerialError::TransferFailed(msg), output));
+            }
+        };
+        let path = self.dir.join(file_name);
+        info!("Receiving {:?}", path);
+        match File::create(&path) {
+            Ok(file) => self.file = Some((file, path)),
+            Err(e) => {
+                let error = SerialError::from_file_error(&path, e);
+                return Err(self.fail(error, output));
+            }
+        }
+        self.progress.file = Some(FileProgress {
+            name,
+            index: self.files,
+            count: None,
+            bytes: 0,
+            total: None,
+        });
+        // The name the file is stored under may be sent back, but it would have to be prefixed
+        // and might not fit, so it's left out
+        let check_type = self.check_type;
+        self.ack(&[], check_type, output);
+        Ok(())
+    }
+
+    /// Reads the file size from an Attribute packet. The other attributes are ignored.
+    fn read_attributes(&mut self, data: &[u8]) {
+        let mut rest = data;
+        while rest.len() >= 2 {
+            let len = (unchar(rest[1]) as usize).min(rest.len() - 2);
+            let value = &rest[2..2 + len];
+            let parsed = String::from_utf8_lossy(value).trim().parse::<u64>().ok();
+            let total = match rest[0] {
+                b'1' => parsed,
+                // The size in kilobytes, which is only used if the exact one isn't known
+                b'!' => parsed.map(|k| k * 1024),
+                _ => None,
+            };
+            if let (Some(total), Some(file)) = (total, self.progress.file.as_mut()) {
+                if rest[0] == b'1' || file.total.is_none() {
+                    file.total = Some(total);
+                }
+            }
+            rest = &rest[2 + len..];
+        }
+    }
+
+    /// Handles a packet with the expected sequence number. Returns whether the transfer is
+    /// complete.
+    fn handle(&mut self, packet: Packet, output: &mut Vec<u8>) -> Result<bool, SerialError> {
+        match packet.kind {
+            SEND_INIT => {
+                let ours = Params::ours();
+                let theirs = Params::decode(&packet.data);
+                let (check_type, eight_bit) = ours.negotiate(&theirs);
+                debug!("Sender parameters {:?}, using block check {}", theirs, check_type);
+                self.ack(&ours.encode(), 1, output);
+                self.initialized = true;
+                self.check_type = check_type;
+                self.quoting = Quoting {
+                    control: theirs.control,
+                    eight_bit,
+                };
+            }
+            FILE_HEADER => self.start_file(&packet.data, output)?,
+            ATTRIBUTES => {
+                self.read_attributes(&packet.data);
+                let check_type = self.check_type;
+                self.ack(b"Y", check_type, output);
+            }
+            DATA => {
+                let data = self.quoting.decode(&packet.data);
+                if let Some((ref mut file, _)) = self.file {
+                    file.write_all(&data)?;
+                }
+                self.progress.bytes += data.len() as u64;
+                if let Some(ref mut file) = self.progress.file {
+                    file.bytes += data.len() as u64;
+                }
+                let check_type = self.check_type;
+                self.ack(&[], check_type, output);
+            }
+            END_OF_FILE => {
+                if let Some((mut file, path)) = self.file.take() {
+                    file.flush()?;
+                    if packet.data == b"D" {
+                        info!("The sender discarded {:?}", path);
+                        drop(file);
+                        let _ = fs::remove_file(&path);
+                    } else {
+                        self.files += 1;
+                    }
+                }
+                let check_type = self.check_type;
+                self.ack(&[], check_type, output);
+            }
+            BREAK => {
+                let check_type = self.check_type;
+                self.ack(&[], check_type, output);
+                return Ok(true);
+            }
+            _ => {
+                let msg = format!("unexpected packet type '{}'", packet.kind as char);
+                return Err(self.fail(SerialError::TransferFailed(msg), output));
+            }
+        }
+        Ok(false)
+    }
+}
+
+impl Transfer for Receiver {
+    fn process(&mut self,
+               input: &[u8],
+               now: Instant,
+               output: &mut Vec<u8>)
+               -> Result<Status, SerialError> {
+        let mut deadline = self.deadline.unwrap_or(now + Duration::from_secs(TIMEOUT_SECS));
+
+        for &b in input {
+            let raw = match self.reader.read(b) {
+                Some(raw) => raw,
+                None => continue,
+            };
+            deadline = now + Duration::from_secs(TIMEOUT_SECS);
+            // Send-Init packets always use the 6-bit checksum
+            let check_type = if !self.initialized || raw.get(2) == Some(&SEND_INIT) {
+                1
+            } else {
+                self.check_type
+            };
+            let packet = match decode_packet(&raw, check_type) {
+                Some(packet) => packet,
+                None => {
+                    debug!("Received a damaged packet");
+                    self.nak(output)?;
+                    continue;
+                }
+            };
+
+            if packet.kind == ERROR {
+                return Err(peer_error(&packet));
+            } else if packet.seq == self.seq {
+                if self.handle(packet, output)? {
+                    return Ok(Status::Complete);
+                }
+            } else if (packet.seq + 1) % 64 == self.seq && !self.last_ack.is_empty() {
+                // The acknowledgement got lost
+                output.extend_from_slice(&self.last_ack);
+            } else {
+                self.nak(output)?;
+            }
+        }
+
+        if now >= deadline {
+            debug!("Timed out waiting for the sender");
+            self.nak(output)?;
+            deadline = now + Duration::from_secs(TIMEOUT_SECS);
+        }
+        self.deadline = Some(deadline);
+        Ok(Status::InProgress)
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    fn progress(&self) -> Progress {
+        self.progress.clone()
+    }
+
+    fn cancel(&mut self, output: &mut Vec<u8>) {
+        output.extend(error_packet(self.seq, "Canceled by the receiver", self.check_type));
+    }
+}
@@ -0,0 +1,310 @@
+//! File transfers run by the port thread.
+//!
+//! Transfers don't do any I/O on the port themselves. The port thread passes them everything it
+//! receives, sends whatever they produce, and calls them again once their deadline passes. This
+//! keeps the protocols independent of ports and threads, so a sender can be tested by connecting
+//! it directly to a receiver.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use SerialError;
+
+pub mod hex;
+pub mod kermit;
+pub mod pacer;
+pub mod raw;
+pub mod stk500;
+pub mod stm32;
+pub mod text;
+pub mod xmodem;
+pub mod ymodem;
+pub mod zmodem;
+
+pub use self::hex::ImageOptions;
+pub use self::raw::RawSender;
+pub use self::text::{LinePacing, TextSender, WaitFor};
+
+/// The protocol used to send or receive a file.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Protocol {
+    /// The file's contents as they are, paced to the baud rate. Files can't be received this way,
+    /// that's what logging is for.
+    Raw,
+    /// XMODEM with 128 byte blocks and an arithmetic checksum. When sending, a CRC is used instead
+    /// if the receiver asks for one.
+    Xmodem,
+    /// XMODEM with 128 byte blocks and a CRC.
+    XmodemCrc,
+    /// XMODEM with 1024 byte blocks and a CRC. Receiving this is the same as `XmodemCrc`, as the
+    /// sender picks the block size.
+    Xmodem1k,
+    /// YMODEM batch transfers, which send the name and size of each file along with it.
+    Ymodem,
+    /// ZMODEM batch transfers, which stream files and recover from errors by restarting at the
+    /// last good position. With `resume`, a file that was partially transferred before continues
+    /// where it was interrupted instead of starting over.
+    Zmodem { resume: bool },
+    /// Kermit batch transfers, which send short packets of printable characters and work over
+    /// lines that aren't 8-bit clean.
+    Kermit,
+    /// A text file sent line by line as set in a `LinePacing`, which gives command interpreters
+    /// time to handle each line.
+    Text,
+    /// An Intel HEX or S-record file sent as the binary it describes, with the gaps between its
+    /// data filled as set in an `ImageOptions` and paced like `Raw`.
+    HexImage,
+    /// An Intel HEX or S-record file sent record by record, waiting for the receiver to
+    /// acknowledge each one with the byte set in an `ImageOptions`.
+    HexRecords,
+    /// An image flashed through the ROM bootloader of STM32 microcontrollers (ST's AN3155), as
+    /// set in a `FlashOptions`. The port has to use even parity, as the bootloader does.
+    Stm32,
+    /// An Intel HEX file uploaded to an AVR microcontroller through an STK500v1 bootloader, like
+    /// that of Arduino boards, which is started by pulsing DTR. Only `verify` of the
+    /// `FlashOptions` applies.
+    Stk500,
+}
+
+impl Protocol {
+    /// Whether the protocol can transfer several files at once. Received files are then named by
+    /// the sender, so they're received into a directory instead of a file.
+    pub fn is_batch(&self) -> bool {
+        match *self {
+            Protocol::Ymodem | Protocol::Zmodem { .. } | Protocol::Kermit => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Protocol::Raw => "Raw",
+            Protocol::Xmodem => "XMODEM",
+            Protocol::XmodemCrc => "XMODEM-CRC",
+            Protocol::Xmodem1k => "XMODEM-1K",
+            Protocol::Ymodem => "YMODEM",
+            Protocol::Zmodem { resume: false } => "ZMODEM",
+            Protocol::Zmodem { resume: true } => "ZMODEM (resume)",
+            Protocol::Kermit => "Kermit",
+            Protocol::Text => "Text (line by line)",
+            Protocol::HexImage => "HEX/S-record (decoded)",
+            Protocol::HexRecords => "HEX/S-record (record by record)",
+            Protocol::Stm32 => "STM32 bootloader",
+            Protocol::Stk500 => "Arduino (STK500v1)",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A file to be sent as part of a batch.
+pub struct BatchFile<R> {
+    /// The name the file is received as
+    pub name: String,
+    pub file: R,
+    pub len: u64,
+}
+
+/// Whether a transfer is still going.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Status {
+    InProgress,
+    Complete,
+}
+
+/// How much of a transfer is done.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Progress {
+    /// Bytes transferred so far, over all files of a batch
+    pub bytes: u64,
+    /// The size of the file or all files of a batch, if it's known
+    pub total: Option<u64>,
+    /// The file that's being transferred, for batch transfers
+    pub file: Option<FileProgress>,
+    /// How many lines have been sent and how many there are, for transfers that go line by line
+    pub lines: Option<(usize, usize)>,
+    /// What the transfer is doing, for transfers that go through several stages
+    pub stage: Option<String>,
+}
+
+impl Progress {
+    /// The percentage [0, 100] of the transfer that's done, if its size is known.
+    pub fn percentage(&self) -> Option<u8> {
+        percentage(self.bytes, self.total)
+    }
+}
+
+/// How much of the current file of a batch transfer has been transferred.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FileProgress {
+    /// The name of the file as it's known to the other end
+    pub name: String,
+    /// The position of the file in the batch, starting at 0
+    pub index: usize,
+    /// How many files there are, if it's known
+    pub count: Option<usize>,
+    /// Bytes of the file transferred so far
+    pub bytes: u64,
+    /// The size of the file, if it's known
+    pub total: Option<u64>,
+}
+
+impl FileProgress {
+    /// The percentage [0, 100] of the file that has been transferred, if its size is known.
+    pub fn percentage(&self) -> Option<u8> {
+        percentage(self.bytes, self.total)
+    }
+}
+
+/// Progress as it's reported with `SerialResponse::SendingFileProgress`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProgressReport {
+    /// Bytes transferred so far
+    pub bytes: u64,
+    /// The size of the transfer, if it's known
+    pub total: Option<u64>,
+    /// How fast data has been transferred recently, in bytes per second
+    pub bytes_per_sec: f64,
+    /// How long the rest of the transfer takes at that rate, if its size is known
+    pub remaining: Option<Duration>,
+}
+
+impl ProgressReport {
+    /// The percentage [0, 100] of the transfer that's done, if its size is known.
+    pub fn percentage(&self) -> Option<u8> {
+        percentage(self.bytes, self.total)
+    }
+}
+
+/// How long `RateMeter` measures the transfer rate over, in milliseconds.
+const RATE_WINDOW_MS: u64 = 2000;
+
+/// Measures the rate of a transfer over the last `RATE_WINDOW_MS`.
+#[derive(Debug, Default)]
+pub(crate) struct RateMeter {
+    /// The bytes transferred by when, oldest first
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl RateMeter {
+    /// Records that `bytes` have been transferred by `now`.
+    pub(crate) fn add(&mut self, now: Instant, bytes: u64) {
+        self.samples.push_back((now, bytes));
+        let window = Duration::from_millis(RATE_WINDOW_MS);
+        while self.samples.len() > 2 && now.duration_since(self.samples[1].0) >= window {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Creates a report for the transfer as it was last recorded.
+    pub(crate) fn report(&self, total: Option<u64>) -> ProgressReport {
+        let (first, last) = match (self.samples.front(), self.samples.back()) {
+            (Some(first), Some(last)) => (*first, *last),
+            _ => return ProgressReport { total, ..Default::default() },
+        };
+        let elapsed = last.0.duration_since(first.0);
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+        let bytes_per_sec = if secs > 0.0 {
+            last.1.saturating_sub(first.1) as f64 / secs
+        } else {
+            0.0
+        };
+        let remaining = match total {
+            Some(total) if bytes_per_sec > 0.0 => {
+                let secs = total.saturating_sub(last.1) as f64 / bytes_per_sec;
+                Some(Duration::from_millis((secs * 1000.0) as u64))
+            }
+            _ => None,
+        };
+        ProgressReport {
+            bytes: last.1,
+            total,
+            bytes_per_sec,
+            remaining,
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.samples.clear();
+    }
+}
+
+fn percentage(bytes: u64, total: Option<u64>) -> Option<u8> {
+    total.map(|total| if total == 0 || bytes >= total {
+                  100
+              } else {
+                  (bytes * 100 / total) as u8
+              })
+}
+
+/// How images are flashed to microcontrollers through their bootloaders.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FlashOptions {
+    /// Where binary files are written, as HEX and S-record files carry their own addresses
+    pub address: u32,
+    /// Whether the flash is erased before it's written
+    pub erase: bool,
+    /// Whether everything written is read back and compared
+    pub verify: bool,
+    /// Whether the flashed application is started afterwards
+    pub go: bool,
+    /// Whether the target is reset into the bootloader first, and back into the application
+    /// afterwards, by driving its reset pin with DTR and its boot mode pin with RTS
+    pub reset: bool,
+}
+
+impl Default for FlashOptions {
+    fn default() -> Self {
+        FlashOptions {
+            address: 0x0800_0000,
+            erase: true,
+            verify: true,
+            go: true,
+            reset: false,
+        }
+    }
+}
+
+/// Levels a transfer wants the DTR and RTS lines set to, `None` leaving a line as it is.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct LineRequest {
+    pub dtr: Option<bool>,
+    pub rts: Option<bool>,
+}
+
+/// A file transfer in one direction using a particular protocol.
+pub trait Transfer {
+    /// Advances the transfer. `input` holds everything received from the port since the last
+    /// call, and is empty if the transfer is only being called because its deadline has passed.
+    /// Data to be sent to the port is appended to `output`.
+    ///
+    /// This is called once with no input right after the transfer is created so that it can get
+    /// things going.
+    fn process(&mut self,
+               input: &[u8],
+               now: Instant,
+               output: &mut Vec<u8>)
+               -> Result<Status, SerialError>;
+
+    /// When `process()` needs to be called again even if nothing is received.
+    fn deadline(&self) -> Option<Instant>;
+
+    fn progress(&self) -> Progress;
+
+    /// Takes the levels the DTR and RTS lines should be set to before the output of the last
+    /// `process()` call is sent, for transfers that reset the other end. Most don't touch them.
+    fn take_line_request(&mut self) -> LineRequest {
+        LineRequest::default()
+    }
+
+    /// Aborts the transfer, appending anything that tells the other end about it to `output`.
+    fn cancel(&mut self, _output: &mut Vec<u8>) {}
+
+    /// Whether data received during the transfer should still be shown. Protocols consume the
+    /// data they receive, so by default it isn't.
+    fn shows_input(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,96 @@
+//! Limiting how fast data is written to the rate a port actually sends it at.
+
+use std::time::{Duration, Instant};
+
+use serialport::prelude::*;
+
+/// How much data a `Pacer` lets through at once, as the time it takes to send it.
+const BURST_MS: u64 = 10;
+
+/// The number of bits a port sends for every byte with `settings`: the start bit, the data bits,
+/// the parity bit if there is one and the stop bits. That's 10 for 8N1 and 11 for 7E2.
+pub fn frame_bits(settings: &SerialPortSettings) -> u32 {
+    let data_bits = match settings.data_bits {
+        DataBits::Five => 5,
+        DataBits::Six => 6,
+        DataBits::Seven => 7,
+        DataBits::Eight => 8,
+    };
+    let parity_bits = if settings.parity == Parity::None { 0 } else { 1 };
+    let stop_bits = match settings.stop_bits {
+        StopBits::One => 1,
+        StopBits::Two => 2,
+    };
+    1 + data_bits + parity_bits + stop_bits
+}
+
+/// A token bucket limiting data to a number of bytes per second. The bucket fills up with one
+/// token per byte that may be sent, and holds enough to send for `BURST_MS` at once.
+#[derive(Clone, Debug)]
+pub struct Pacer {
+    bytes_per_sec: f64,
+    tokens: f64,
+    capacity: f64,
+    last_refill: Instant,
+}
+
+impl Pacer {
+    /// Creates a pacer for `bytes_per_sec`, which starts out full.
+    pub fn new(bytes_per_sec: f64, now: Instant) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1.0);
+        let capacity = (bytes_per_sec * BURST_MS as f64 / 1000.0).max(1.0).floor();
+        Pacer {
+            bytes_per_sec,
+            tokens: capacity,
+            capacity,
+            last_refill: now,
+        }
+    }
+
+    /// Creates a pacer for `throughput` bytes per second if it's given, and otherwise for as fast
+    /// as a port with `settings` sends.
+    pub fn for_port(settings: &SerialPortSettings, throughput: Option<u32>, now: Instant) -> Self {
+        let bytes_per_sec = match throughput {
+            Some(throughput) if throughput > 0 => throughput as f64,
+            _ => settings.baud_rate as f64 / frame_bits(settings) as f64,
+        };
+        Pacer::new(bytes_per_sec, now)
+    }
+
+    /// The rate data is limited to, in bytes per second.
+    pub fn bytes_per_sec(&self) -> f64 {
+        self.bytes_per_sec
+    }
+
+    /// The most bytes that are let through at once.
+    pub fn capacity(&self) -> usize {
+        self.capacity as usize
+    }
+
+    /// How many bytes may be sent at `now`.
+    pub fn available(&mut self, now: Instant) -> usize {
+        if now > self.last_refill {
+            let elapsed = now - self.last_refill;
+            let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+            self.tokens = (self.tokens + secs * self.bytes_per_sec).min(self.capacity);
+            self.last_refill = now;
+        }
+        self.tokens.max(0.0) as usize
+    }
+
+    /// Takes `bytes` that have been sent out of the bucket. Sending more than is available leaves
+    /// the bucket in debt, which has to be paid off before anything else may be sent.
+    pub fn consume(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+
+    /// When enough tokens to send `bytes`, or as many as fit into the bucket, will be available.
+    pub fn ready_at(&self, bytes: usize) -> Instant {
+        let missing = (bytes as f64).min(self.capacity) - self.tokens;
+        if missing <= 0.0 {
+            return self.last_refill;
+        }
+        let nanos = (missing / self.bytes_per_sec * 1e9).ceil() as u64;
+        self.last_refill + Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32)
+    }
+}
@@ -0,0 +1,92 @@
+//! Sending a file's contents as they are.
+
+use std::io::prelude::*;
+use std::time::Instant;
+
+use serialport::prelude::*;
+
+use super::pacer::Pacer;
+use super::{Progress, Status, Transfer};
+use SerialError;
+
+/// Sends a file without any protocol, paced by a `Pacer` so that data is written only as fast as
+/// the port sends it.
+pub struct RawSender<R> {
+    file: R,
+    pacer: Pacer,
+    buf: Vec<u8>,
+    progress: Progress,
+    next_send_time: Instant,
+}
+
+impl<R: Read> RawSender<R> {
+    /// Creates a sender for `len` bytes read from `file`, paced for a port using `settings`, or to
+    /// `throughput` bytes per second if it's given.
+    pub fn new(file: R, len: u64, settings: &SerialPortSettings, throughput: Option<u32>) -> Self {
+        let now = Instant::now();
+        RawSender {
+            file,
+            pacer: Pacer::for_port(settings, throughput, now),
+            buf: Vec::new(),
+            progress: Progress {
+                bytes: 0,
+                total: Some(len),
+                file: None,
+                lines: None,
+                stage: None,
+            },
+            next_send_time: now,
+        }
+    }
+}
+
+impl<R: Read> Transfer for RawSender<R> {
+    fn process(&mut self,
+               _input: &[u8],
+               now: Instant,
+               output: &mut Vec<u8>)
+               -> Result<Status, SerialError> {
+        if now < self.next_send_time {
+            return Ok(Status::InProgress);
+        }
+
+        let tx_data_len = self.pacer.available(now).max(1);
+        if self.buf.len() < tx_data_len {
+            self.buf.resize(tx_data_len, 0);
+        }
+        debug!("Reading {} bytes", tx_data_len);
+        match self.file.read(&mut self.buf[..tx_data_len]) {
+            Ok(0) => {
+                debug!("END OF FILE!");
+                Ok(Status::Complete)
+            }
+            Ok(len) => {
+                self.progress.bytes += len as u64;
+                debug!("Actually read {} bytes ({} total)", len, self.progress.bytes);
+                output.extend_from_slice(&self.buf[..len]);
+                self.pacer.consume(len);
+                // Wait for the bucket to fill up so that data isn't written a few bytes at a time
+                let capacity = self.pacer.capacity();
+                self.next_send_time = self.pacer.ready_at(capacity);
+                Ok(Status::InProgress)
+            }
+            Err(e) => {
+                error!("File error trying to read {} bytes: {}", tx_data_len, e);
+                Err(SerialError::from(e))
+            }
+        }
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        Some(self.next_send_time)
+    }
+
+    fn progress(&self) -> Progress {
+        self.progress.clone()
+    }
+
+    /// The other end isn't part of the transfer, so whatever it sends is shown as usual.
+    fn shows_input(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,495 @@
+//! Uploading to AVR microcontrollers through bootloaders speaking version 1 of Atmel's STK500
+//! protocol, like the ones on Arduino boards.
+//!
+//! Every command ends with `CRC_EOP` and is answered with `INSYNC`, any data the command returns,
+//! and `OK`. Flash is addressed in 16-bit words and written a page at a time.
+
+use std::collections::VecDeque;
+use std::mem;
+use std::time::{Duration, Instant};
+
+use super::hex::Image;
+use super::{FlashOptions, LineRequest, Progress, Status, Transfer};
+use SerialError;
+
+pub const CRC_EOP: u8 = 0x20;
+pub const INSYNC: u8 = 0x14;
+pub const NOSYNC: u8 = 0x15;
+pub const OK: u8 = 0x10;
+pub const FAILED: u8 = 0x11;
+
+pub const GET_SYNC: u8 = 0x30;
+pub const ENTER_PROGMODE: u8 = 0x50;
+pub const LEAVE_PROGMODE: u8 = 0x51;
+pub const LOAD_ADDRESS: u8 = 0x55;
+pub const PROG_PAGE: u8 = 0x64;
+pub const READ_PAGE: u8 = 0x74;
+pub const READ_SIGN: u8 = 0x75;
+
+/// The memory type of `PROG_PAGE` and `READ_PAGE` for flash.
+const FLASH_MEMORY: u8 = b'F';
+
+/// The size of the pages flash is written in, which is that of the ATmega328P. Bootloaders
+/// buffer whole pages, so smaller ones are written correctly with it too.
+pub const PAGE_LEN: usize = 128;
+
+/// The most flash that can be addressed with the 16-bit word addresses of `LOAD_ADDRESS`.
+const MAX_ADDRESS: u32 = 0x2_0000;
+
+/// How long to wait for a reply, in milliseconds.
+const REPLY_TIMEOUT_MS: u64 = 1000;
+
+/// How long to wait for the bootloader to answer `GET_SYNC`, in milliseconds.
+const SYNC_TIMEOUT_MS: u64 = 200;
+
+/// How often `GET_SYNC` is sent before giving up. The bootloader waits about a second after a
+/// reset before starting the application.
+const SYNC_ATTEMPTS: u32 = 10;
+
+/// How long DTR is released before it's asserted to reset the board, in milliseconds.
+const RESET_IDLE_MS: u64 = 250;
+
+/// How long the bootloader takes to start after the reset, in milliseconds.
+const BOOT_DELAY_MS: u64 = 50;
+
+/// Something the uploader does with a single command.
+#[derive(Debug)]
+enum Step {
+    /// Sets DTR and waits for `delay_ms` before going on
+    SetDtr { level: bool, delay_ms: u64 },
+    Sync,
+    /// Waits until the bootloader stops answering sync commands that were sent before it started,
+    /// discarding the late replies
+    Settle,
+    ReadSignature,
+    EnterProgmode,
+    LoadAddress(u32),
+    ProgPage(Vec<u8>),
+    ReadPage(Vec<u8>),
+    LeaveProgmode,
+}
+
+impl Step {
+    /// The command packet for the step, and how many bytes it returns between `INSYNC` and `OK`.
+    fn command(&self) -> (Vec<u8>, usize) {
+        let (mut packet, len) = match *self {
+            Step::SetDtr { .. } | Step::Settle => (vec![], 0),
+            Step::Sync => (vec![GET_SYNC], 0),
+            Step::ReadSignature => (vec![READ_SIGN], 3),
+            Step::EnterProgmode => (vec![ENTER_PROGMODE], 0),
+            Step::LoadAddress(address) => {
+                let word = address / 2;
+                (vec![LOAD_ADDRESS, word as u8, (word >> 8) as u8], 0)
+            }
+            Step::ProgPage(ref data) => {
+                let mut packet = vec![PROG_PAGE,
+                                      (data.len() >> 8) as u8,
+                                      data.len() as u8,
+                                      FLASH_MEMORY];
+                packet.extend_from_slice(data);
+                (packet, 0)
+            }
+            Step::ReadPage(ref data) => {
+                (vec![READ_PAGE, (data.len() >> 8) as u8, data.len() as u8, FLASH_MEMORY],
+                 data.len())
+            }
+            Step::LeaveProgmode => (vec![LEAVE_PROGMODE], 0),
+        };
+        packet.push(CRC_EOP);
+        (packet, len)
+    }
+
+    /// What the step is, for error messages.
+    fn describe(&self) -> &'static str {
+        match *self {
+            Step::SetDtr { .. } => "setting DTR",
+            Step::Sync => "the synchronization command",
+            Step::Settle => "waiting for late synchronization replies",
+            Step::ReadSignature => "reading the signature",
+            Step::EnterProgmode => "entering programming mode",
+            Step::LoadAddress(_) => "loading an address",
+            Step::ProgPage(_) => "writing a page",
+            Step::ReadPage(_) => "reading a page",
+            Step::LeaveProgmode => "leaving programming mode",
+        }
+    }
+}
+
+/// Uploads an image through an STK500v1 bootloader: it resets the board by pulsing DTR,
+/// synchronizes with the bootloader, reads the device signature, writes the image page by page,
+/// verifies it if set in the `FlashOptions`, and leaves programming mode, which starts the
+/// application.
+pub struct Uploader {
+    steps: VecDeque<Step>,
+    step: Option<Step>,
+    /// Whether the current step's command has been sent
+    sent: bool,
+    /// Whether the uploader is waiting for a delay rather than a reply
+    pausing: bool,
+    deadline: Option<Instant>,
+    received: Vec<u8>,
+    /// The address of the page being written or verified
+    address: u32,
+    sync_attempts: u32,
+    line_request: LineRequest,
+    progress: Progress,
+}
+
+impl Uploader {
+    /// Creates an uploader writing the data of `image` as set in `options`. Only `verify` applies,
+    /// as the bootloader erases each page as it's written and starts the application when it's
+    /// done. Gaps in the image are written as 0xFF, like erased flash.
+    pub fn new(image: &Image, options: FlashOptions) -> Result<Self, SerialError> {
+        let (start, binary) = image.to_binary(0xff)?;
+        if binary.is_empty() {
+            return Err(SerialError::InvalidImage("there's no data to write".to_string()));
+        }
+        if start as u64 + binary.len() as u64 > MAX_ADDRESS as u64 {
+            let msg = "the data goes beyond the 128 KiB the bootloader can address";
+            return Err(SerialError::InvalidImage(msg.to_string()));
+        }
+
+        // Pages are written whole, so pad the image to page boundaries
+        let offset = start as usize % PAGE_LEN;
+        let mut data = vec![0xff; offset];
+        data.extend_from_slice(&binary);
+        let start = start - offset as u32;
+
+        let mut steps = VecDeque::new();
+        steps.push_back(Step::SetDtr {
+                            level: false,
+                            delay_ms: RESET_IDLE_MS,
+                        });
+        steps.push_back(Step::SetDtr {
+                            level: true,
+                            delay_ms: BOOT_DELAY_MS,
+                        });
+        steps.push_back(Step::Sync);
+        steps.push_back(Step::ReadSignature);
+        steps.push_back(Step::EnterProgmode);
+        for (i, page) in data.chunks(PAGE_LEN).enumerate() {
+            steps.push_back(Step::LoadAddress(start + (i * PAGE_LEN) as u32));
+            steps.push_back(Step::ProgPage(page.to_vec()));
+        }
+        if options.verify {
+            for (i, page) in data.chunks(PAGE_LEN).enumerate() {
+                steps.push_back(Step::LoadAddress(start + (i * PAGE_LEN) as u32));
+                steps.push_back(Step::ReadPage(page.to_vec()));
+            }
+        }
+        steps.push_back(Step::LeaveProgmode);
+
+        let total = data.len() as u64 * if options.verify { 2 } else { 1 };
+        Ok(Uploader {
+               steps,
+               step: None,
+               sent: false,
+               pausing: false,
+               deadline: None,
+               received: Vec::new(),
+               address: start,
+               sync_attempts: 0,
+               line_request: LineRequest::default(),
+               progress: Progress {
+                   bytes: 0,
+                   total: Some(total),
+                   file: None,
+                   lines: None,
+                   stage: None,
+               },
+           })
+    }
+
+    /// Starts `step`, sending its command if it has one.
+    fn start_step(&mut self, step: Step, now: Instant) {
+        let stage = match step {
+            Step::SetDtr { level, delay_ms } => {
+                self.line_request.dtr = Some(level);
+                self.pausing = true;
+                self.deadline = Some(now + Duration::from_millis(delay_ms));
+                Some("Resetting the board")
+            }
+            Step::Sync => Some("Connecting to the bootloader"),
+            Step::Settle => {
+                self.pausing = true;
+                self.deadline = Some(now + Duration::from_millis(SYNC_TIMEOUT_MS));
+                None
+            }
+            Step::LoadAddress(address) => {
+                self.address = address;
+                None
+            }
+            Step::ProgPage(_) => Some("Writing"),
+            Step::ReadPage(_) => Some("Verifying"),
+            Step::LeaveProgmode => Some("Starting the application"),
+            _ => None,
+        };
+        if let Some(stage) = stage {
+            self.progress.stage = Some(stage.to_string());
+        }
+        self.sent = false;
+        self.step = Some(step);
+    }
+
+    /// Handles the data returned by the finished `step`.
+    fn finish_step(&mut self, step: Step, data: &[u8]) -> Result<(), SerialError> {
+        match step {
+            // A bootloader that started while the sync command was being retried answers every
+            // one it received, so the replies to the retries are still to come
+            Step::Sync if self.sync_attempts > 0 => self.steps.push_front(Step::Settle),
+            Step::ReadSignature => {
+                let stage = format!("Connected to a device with signature {:02X} {:02X} {:02X}",
+                                    data[0],
+                                    data[1],
+                                    data[2]);
+                info!("{}", stage);
+                self.progress.stage = Some(stage);
+            }
+            Step::ProgPage(page) => self.progress.bytes += page.len() as u64,
+            Step::ReadPage(page) => {
+                if let Some(i) = page.iter().zip(data).position(|(a, b)| a != b) {
+                    let msg = format!("verification failed at 0x{:05X}", self.address + i as u32);
+                    return Err(SerialError::TransferFailed(msg));
+                }
+                self.progress.bytes += page.len() as u64;
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn syncing(&self) -> bool {
+        match self.step {
+            Some(Step::Sync) => true,
+            _ => false,
+        }
+    }
+
+    /// Takes the reply to the current step from what's been received if it's complete, returning
+    /// the data in it.
+    fn take_reply(&mut self, len: usize) -> Result<Option<Vec<u8>>, SerialError> {
+        if self.syncing() {
+            // There may be noise from the reset before the bootloader answers
+            let end = self.received.len();
+            let noise = self.received.iter().position(|b| *b == INSYNC).unwrap_or(end);
+            self.received.drain(..noise);
+        }
+        let what = self.step.as_ref().map_or("", |s| s.describe());
+        match self.received.first() {
+            None => return Ok(None),
+            Some(&INSYNC) => (),
+            Some(&NOSYNC) => {
+                let msg = format!("the bootloader lost synchronization while {}", what);
+                return Err(SerialError::TransferFailed(msg));
+            }
+            Some(b) => {
+                let msg = format!("unexpected reply 0x{:02X} to {}", b, what);
+                return Err(SerialError::TransferFailed(msg));
+            }
+        }
+        if self.received.len() < len + 2 {
+            return Ok(None);
+        }
+        match self.received[len + 1] {
+            OK => (),
+            FAILED => {
+                let msg = format!("the bootloader failed {}", what);
+                return Err(SerialError::TransferFailed(msg));
+            }
+            b => {
+                let msg = format!("unexpected reply 0x{:02X} to {}", b, what);
+                return Err(SerialError::TransferFailed(msg));
+            }
+        }
+        let reply: Vec<u8> = self.received.drain(..len + 2).collect();
+        Ok(Some(reply[1..len + 1].to_vec()))
+    }
+}
+
+impl Transfer for Uploader {
+    fn process(&mut self,
+               input: &[u8],
+               now: Instant,
+               output: &mut Vec<u8>)
+               -> Result<Status, SerialError> {
+        self.received.extend_from_slice(input);
+        loop {
+            if self.pausing {
+                if let Some(Step::Settle) = self.step {
+                    // Each late reply starts the wait again
+                    if !self.received.is_empty() {
+                        self.received.clear();
+                        self.deadline = Some(now + Duration::from_millis(SYNC_TIMEOUT_MS));
+                    }
+                }
+                if self.deadline.map_or(false, |d| now < d) {
+                    return Ok(Status::InProgress);
+                }
+                self.pausing = false;
+                self.step = None;
+            }
+
+            if self.step.is_none() {
+                match self.steps.pop_front() {
+                    Some(step) => self.start_step(step, now),
+                    None => return Ok(Status::Complete),
+                }
+                continue;
+            }
+            let (command, len) = self.step.as_ref().map_or((Vec::new(), 0), |s| s.command());
+
+            if !self.sent {
+                if self.syncing() {
+                    self.received.clear();
+                }
+                output.extend_from_slice(&command);
+                self.sent = true;
+                let timeout = if self.syncing() { SYNC_TIMEOUT_MS } else { REPLY_TIMEOUT_MS };
+                self.deadline = Some(now + Duration::from_millis(timeout));
+            }
+
+            match self.take_reply(len)? {
+                Some(data) => {
+                    let stage = self.progress.stage.clone();
+                    if let Some(step) = self.step.take() {
+                        self.finish_step(step, &data)?;
+                    }
+                    if self.progress.stage != stage {
+                        // Give the new stage a chance to be reported before the next one
+                        self.deadline = Some(now);
+                        return Ok(Status::InProgress);
+                    }
+                }
+                None if self.deadline.map_or(false, |d| now >= d) => {
+                    if self.syncing() && self.sync_attempts + 1 < SYNC_ATTEMPTS {
+                        self.sync_attempts += 1;
+                        self.sent = false;
+                        continue;
+                    }
+                    let what = self.step.as_ref().map_or("", |s| s.describe());
+                    let msg = format!("the bootloader didn't answer {}", what);
+                    return Err(SerialError::TransferFailed(msg));
+                }
+                None => return Ok(Status::InProgress),
+            }
+        }
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    fn progress(&self) -> Progress {
+        self.progress.clone()
+    }
+
+    fn take_line_request(&mut self) -> LineRequest {
+        mem::replace(&mut self.line_request, LineRequest::default())
+    }
+}
+
+/// A simulated STK500v1 bootloader with some flash memory, for testing uploads without hardware.
+/// It runs on the device end of a port, answering the commands it receives, and is complete once
+/// it leaves programming mode.
+pub struct Simulator {
+    /// The contents of the flash, starting at address 0
+    pub flash: Vec<u8>,
+    /// The device signature returned by `READ_SIGN`
+    pub signature: [u8; 3],
+    /// Whether programming mode has been left, which starts the application
+    pub started: bool,
+    /// The byte address loaded with `LOAD_ADDRESS`
+    address: usize,
+    received: Vec<u8>,
+}
+
+impl Simulator {
+    /// Creates a bootloader for an ATmega328P with `len` bytes of erased flash.
+    pub fn new(len: usize) -> Self {
+        Simulator {
+            flash: vec![0xff; len],
+            signature: [0x1e, 0x95, 0x0f],
+            started: false,
+            address: 0,
+            received: Vec::new(),
+        }
+    }
+
+    /// The length of the command at the start of `received` including `CRC_EOP`, if it's
+    /// complete.
+    fn command_len(&self) -> Option<usize> {
+        let received = &self.received;
+        let len = match received[0] {
+            LOAD_ADDRESS => 4,
+            PROG_PAGE => {
+                if received.len() < 3 {
+                    return None;
+                }
+                5 + ((received[1] as usize) << 8 | received[2] as usize)
+            }
+            READ_PAGE => 5,
+            _ => 2,
+        };
+        if received.len() >= len { Some(len) } else { None }
+    }
+
+    /// Answers the complete command `command`.
+    fn handle(&mut self, command: &[u8], output: &mut Vec<u8>) {
+        if command.last() != Some(&CRC_EOP) {
+            output.push(NOSYNC);
+            return;
+        }
+        output.push(INSYNC);
+        match command[0] {
+            READ_SIGN => output.extend_from_slice(&self.signature),
+            LOAD_ADDRESS => self.address = (command[1] as usize | (command[2] as usize) << 8) * 2,
+            PROG_PAGE | READ_PAGE => {
+                let len = (command[1] as usize) << 8 | command[2] as usize;
+                let address = self.address;
+                if command[3] != FLASH_MEMORY || address + len > self.flash.len() {
+                    output.push(FAILED);
+                    return;
+                }
+                if command[0] == PROG_PAGE {
+                    self.flash[address..address + len].copy_from_slice(&command[4..4 + len]);
+                } else {
+                    output.extend_from_slice(&self.flash[address..address + len]);
+                }
+            }
+            LEAVE_PROGMODE => self.started = true,
+            _ => (),
+        }
+        output.push(OK);
+    }
+}
+
+impl Transfer for Simulator {
+    fn process(&mut self,
+               input: &[u8],
+               _now: Instant,
+               output: &mut Vec<u8>)
+               -> Result<Status, SerialError> {
+        self.received.extend_from_slice(input);
+        while !self.received.is_empty() && !self.started {
+            let len = match self.command_len() {
+                Some(len) => len,
+                None => break,
+            };
+            let command: Vec<u8> = self.received.drain(..len).collect();
+            self.handle(&command, output);
+        }
+        if self.started {
+            Ok(Status::Complete)
+        } else {
+            Ok(Status::InProgress)
+        }
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    fn progress(&self) -> Progress {
+        Progress::default()
+    }
+}
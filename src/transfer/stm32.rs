@@ -0,0 +1,730 @@
+//! Flashing STM32 microcontrollers through the bootloader in their system memory, using the UART
+//! protocol described in ST's application note AN3155.
+//!
+//! Every command is sent along with its complement and answered with ACK or NACK. Addresses and
+//! data are followed by an XOR checksum.
+
+use std::collections::VecDeque;
+use std::mem;
+use std::time::{Duration, Instant};
+
+use super::hex::Segment;
+use super::{FlashOptions, LineRequest, Progress, Status, Transfer};
+use SerialError;
+
+/// Sent first so that the bootloader can measure the baud rate.
+pub const SYNC: u8 = 0x7f;
+pub const ACK: u8 = 0x79;
+pub const NACK: u8 = 0x1f;
+
+pub const GET: u8 = 0x00;
+pub const GET_ID: u8 = 0x02;
+pub const READ_MEMORY: u8 = 0x11;
+pub const GO: u8 = 0x21;
+pub const WRITE_MEMORY: u8 = 0x31;
+pub const ERASE: u8 = 0x43;
+pub const EXTENDED_ERASE: u8 = 0x44;
+
+/// The most data that's written or read with one command.
+pub const BLOCK_LEN: usize = 256;
+
+/// How long to wait for a reply, in milliseconds.
+const REPLY_TIMEOUT_MS: u64 = 1000;
+
+/// How long to wait for the bootloader to answer the synchronization byte, in milliseconds.
+const SYNC_TIMEOUT_MS: u64 = 500;
+
+/// How often the synchronization byte is sent before giving up.
+const SYNC_ATTEMPTS: u32 = 5;
+
+/// How long a mass erase may take, in seconds. It's tens of seconds for the largest parts.
+const ERASE_TIMEOUT_SECS: u64 = 40;
+
+/// How long the reset pin is held low when resetting the target, in milliseconds.
+const RESET_PULSE_MS: u64 = 50;
+
+/// How long the bootloader takes to start after a reset, in milliseconds.
+const BOOT_DELAY_MS: u64 = 100;
+
+/// Something the flasher does, made up of one or more exchanges with the bootloader.
+#[derive(Debug)]
+enum Step {
+    /// Sets the control lines and waits for `delay_ms` before going on
+    SetLines {
+        lines: LineRequest,
+        delay_ms: u64,
+    },
+    Sync,
+    Get,
+    GetId,
+    Erase,
+    Write { address: u32, data: Vec<u8> },
+    Verify { address: u32, data: Vec<u8> },
+    Go { address: u32 },
+}
+
+impl Step {
+    /// What the step is, for error messages.
+    fn describe(&self) -> String {
+        match *self {
+            Step::SetLines { .. } => "setting the control lines".to_string(),
+            Step::Sync => "the synchronization byte".to_string(),
+            Step::Get => "the GET command".to_string(),
+            Step::GetId => "the GET ID command".to_string(),
+            Step::Erase => "erasing the flash".to_string(),
+            Step::Write { address, .. } => format!("writing at 0x{:08X}", address),
+            Step::Verify { address, .. } => format!("reading at 0x{:08X}", address),
+            Step::Go { address } => format!("starting the application at 0x{:08X}", address),
+        }
+    }
+}
+
+/// What the bootloader answers a part of a command with.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Reply {
+    Ack,
+    /// ACK, a byte N, N + 1 bytes and another ACK, like the answers to GET and GET ID
+    List,
+    /// ACK followed by this many bytes, like the answer to READ MEMORY
+    Data(usize),
+}
+
+/// Something sent to the bootloader and what it's expected to reply.
+#[derive(Debug)]
+struct Exchange {
+    send: Vec<u8>,
+    reply: Reply,
+    timeout: Duration,
+}
+
+impl Exchange {
+    fn new(send: Vec<u8>, reply: Reply) -> Self {
+        Exchange {
+            send,
+            reply,
+            timeout: Duration::from_millis(REPLY_TIMEOUT_MS),
+        }
+    }
+
+    /// A command byte followed by its complement.
+    fn command(command: u8) -> Self {
+        Exchange::new(vec![command, !command], Reply::Ack)
+    }
+
+    /// An address followed by its checksum.
+    fn address(address: u32) -> Self {
+        Exchange::new(with_checksum(&be_bytes(address)), Reply::Ack)
+    }
+}
+
+/// Flashes an image through the STM32 bootloader as set in a `FlashOptions`: it connects, reads
+/// the bootloader's version and the chip ID, erases the flash, writes and verifies the image, and
+/// starts the application.
+pub struct Flasher {
+    steps: VecDeque<Step>,
+    /// The step whose exchanges are running
+    step: Option<Step>,
+    exchanges: VecDeque<Exchange>,
+    /// Whether the first of `exchanges` has been sent
+    sent: bool,
+    /// Whether the flasher is waiting for a delay rather than a reply
+    pausing: bool,
+    deadline: Option<Instant>,
+    /// What's been received and not used yet
+    received: Vec<u8>,
+    /// The data the bootloader replied with during the current step, without ACKs
+    replies: Vec<u8>,
+    sync_attempts: u32,
+    /// The erase command the bootloader supports
+    erase_command: Option<u8>,
+    bootloader_version: u8,
+    line_request: LineRequest,
+    progress: Progress,
+}
+
+impl Flasher {
+    /// Creates a flasher writing `segments` as set in `options`.
+    pub fn new(segments: Vec<Segment>, options: FlashOptions) -> Self {
+        let mut steps = VecDeque::new();
+        if options.reset {
+            // Select the bootloader with BOOT0 while NRST is held low
+            steps.push_back(Step::SetLines {
+                                lines: LineRequest {
+                                    dtr: Some(true),
+                                    rts: Some(true),
+                                },
+                                delay_ms: RESET_PULSE_MS,
+                            });
+            steps.push_back(Step::SetLines {
+                                lines: LineRequest {
+                                    dtr: Some(false),
+                                    rts: None,
+                                },
+                                delay_ms: BOOT_DELAY_MS,
+                            });
+        }
+        steps.push_back(Step::Sync);
+        steps.push_back(Step::Get);
+        steps.push_back(Step::GetId);
+        if options.erase {
+            steps.push_back(Step::Erase);
+        }
+
+        let mut blocks = Vec::new();
+        for segment in &segments {
+            for (i, chunk) in segment.data.chunks(BLOCK_LEN).enumerate() {
+                // Flash is written in words
+                let mut data = chunk.to_vec();
+                while data.len() % 4 != 0 {
+                    data.push(0xff);
+                }
+                blocks.push((segment.address + (i * BLOCK_LEN) as u32, data));
+            }
+        }
+        let mut total = blocks.iter().map(|b| b.1.len() as u64).sum::<u64>();
+        if options.verify {
+            total *= 2;
+            for &(address, ref data) in &blocks {
+                steps.push_back(Step::Write {
+                                    address,
+                                    data: data.clone(),
+                                });
+            }
+            for (address, data) in blocks {
+                steps.push_back(Step::Verify {
+                                    address,
+                                    data,
+                                });
+            }
+        } else {
+            for (address, data) in blocks {
+                steps.push_back(Step::Write {
+                                    address,
+                                    data,
+                                });
+            }
+        }
+
+        let start = segments.iter().map(|s| s.address).min().unwrap_or(options.address);
+        if options.go {
+            steps.push_back(Step::Go { address: start });
+            if options.reset {
+                // Boot into the application the next time it's reset
+                steps.push_back(Step::SetLines {
+                                    lines: LineRequest {
+                                        dtr: None,
+                                        rts: Some(false),
+                                    },
+                                    delay_ms: 0,
+                                });
+            }
+        } else if options.reset {
+            steps.push_back(Step::SetLines {
+                                lines: LineRequest {
+                                    dtr: Some(true),
+                                    rts: Some(false),
+                                },
+                                delay_ms: RESET_PULSE_MS,
+                            });
+            steps.push_back(Step::SetLines {
+                                lines: LineRequest {
+                                    dtr: Some(false),
+                                    rts: None,
+                                },
+                                delay_ms: 0,
+                            });
+        }
+
+        Flasher {
+            steps,
+            step: None,
+            exchanges: VecDeque::new(),
+            sent: false,
+            pausing: false,
+            deadline: None,
+            received: Vec::new(),
+            replies: Vec::new(),
+            sync_attempts: 0,
+            erase_command: None,
+            bootloader_version: 0,
+            line_request: LineRequest::default(),
+            progress: Progress {
+                bytes: 0,
+                total: Some(total),
+                file: None,
+                lines: None,
+                stage: None,
+            },
+        }
+    }
+
+    /// Starts `step`, queuing up its exchanges.
+    fn start_step(&mut self, step: Step, now: Instant) -> Result<(), SerialError> {
+        let stage = match step {
+            Step::SetLines { lines, delay_ms } => {
+                self.line_request = lines;
+                self.pausing = true;
+                self.deadline = Some(now + Duration::from_millis(delay_ms));
+                None
+            }
+            Step::Sync => {
+                self.exchanges.push_back(Exchange {
+                                             send: vec![SYNC],
+                                             reply: Reply::Ack,
+                                             timeout: Duration::from_millis(SYNC_TIMEOUT_MS),
+                                         });
+                Some("Connecting to the bootloader")
+            }
+            Step::Get => {
+                self.exchanges.push_back(Exchange::new(vec![GET, !GET], Reply::List));
+                None
+            }
+            Step::GetId => {
+                self.exchanges.push_back(Exchange::new(vec![GET_ID, !GET_ID], Reply::List));
+                None
+            }
+            Step::Erase => {
+                let (command, global) = match self.erase_command {
+                    Some(EXTENDED_ERASE) => (EXTENDED_ERASE, vec![0xff, 0xff, 0x00]),
+                    Some(_) => (ERASE, vec![0xff, 0x00]),
+                    None => {
+                        let msg = "the bootloader doesn't support erasing".to_string();
+                        return Err(SerialError::TransferFailed(msg));
+                    }
+                };
+                self.exchanges.push_back(Exchange::command(command));
+                self.exchanges.push_back(Exchange {
+                                             send: global,
+                                             reply: Reply::Ack,
+                                             timeout: Duration::from_secs(ERASE_TIMEOUT_SECS),
+                                         });
+                Some("Erasing")
+            }
+            Step::Write { address, ref data } => {
+                let mut block = vec![(data.len() - 1) as u8];
+                block.extend_from_slice(data);
+                self.exchanges.push_back(Exchange::command(WRITE_MEMORY));
+                self.exchanges.push_back(Exchange::address(address));
+                self.exchanges.push_back(Exchange::new(with_checksum(&block), Reply::Ack));
+                Some("Writing")
+            }
+            Step::Verify { address, ref data } => {
+                let len = (data.len() - 1) as u8;
+                self.exchanges.push_back(Exchange::command(READ_MEMORY));
+                self.exchanges.push_back(Exchange::address(address));
+                self.exchanges.push_back(Exchange::new(vec![len, !len], Reply::Data(data.len())));
+                Some("Verifying")
+            }
+            Step::Go { address } => {
+                self.exchanges.push_back(Exchange::command(GO));
+                self.exchanges.push_back(Exchange::address(address));
+                Some("Starting the application")
+            }
+        };
+        if let Some(stage) = stage {
+            self.progress.stage = Some(stage.to_string());
+        }
+        self.step = Some(step);
+        Ok(())
+    }
+
+    /// Handles the replies once all exchanges of `step` are done.
+    fn finish_step(&mut self, step: Step) -> Result<(), SerialError> {
+        let replies = mem::replace(&mut self.replies, Vec::new());
+        match step {
+            Step::Get => {
+                self.bootloader_version = replies.first().cloned().unwrap_or(0);
+                let commands = replies.get(1..).unwrap_or(&[]);
+                self.erase_command = if commands.contains(&EXTENDED_ERASE) {
+                    Some(EXTENDED_ERASE)
+                } else if commands.contains(&ERASE) {
+                    Some(ERASE)
+                } else {
+                    None
+                };
+                debug!("Bootloader commands {:?}", commands);
+            }
+            Step::GetId => {
+                let id = replies.iter().fold(0u32, |id, b| id << 8 | *b as u32);
+                let version = self.bootloader_version;
+                let stage = format!("Connected to bootloader {}.{} on chip ID 0x{:03X}",
+                                    version >> 4,
+                                    version & 0xf,
+                                    id);
+                info!("{}", stage);
+                self.progress.stage = Some(stage);
+            }
+            Step::Write { data, .. } => self.progress.bytes += data.len() as u64,
+            Step::Verify { address, data } => {
+                if let Some(i) = data.iter().zip(&replies).position(|(a, b)| a != b) {
+                    let msg = format!("verification failed at 0x{:08X}", address + i as u32);
+                    return Err(SerialError::TransferFailed(msg));
+                }
+                self.progress.bytes += data.len() as u64;
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Takes the reply to `exchange` from what's been received, if it's complete. The data in it
+    /// is added to `replies`.
+    fn take_reply(&mut self, reply: Reply) -> Result<bool, SerialError> {
+        let first = match self.received.first() {
+            Some(b) => *b,
+            None => return Ok(false),
+        };
+        if first == NACK {
+            // The bootloader answers the synchronization byte with NACK if it's already running
+            if let Some(Step::Sync) = self.step {
+                self.received.remove(0);
+                return Ok(true);
+            }
+            let what = self.step.as_ref().map_or(String::new(), |s| s.describe());
+            return Err(SerialError::TransferFailed(format!("the bootloader rejected {}", what)));
+        }
+        if first != ACK {
+            let what = self.step.as_ref().map_or(String::new(), |s| s.describe());
+            let msg = format!("unexpected reply 0x{:02X} to {}", first, what);
+            return Err(SerialError::TransferFailed(msg));
+        }
+
+        let len = match reply {
+            Reply::Ack => 1,
+            Reply::List => {
+                match self.received.get(1) {
+                    Some(n) => *n as usize + 4,
+                    None => return Ok(false),
+                }
+            }
+            Reply::Data(n) => n + 1,
+        };
+        if self.received.len() < len {
+            return Ok(false);
+        }
+        let reply_bytes: Vec<u8> = self.received.drain(..len).collect();
+        match reply {
+            Reply::Ack => (),
+            Reply::List => {
+                if reply_bytes[len - 1] != ACK {
+                    let what = self.step.as_ref().map_or(String::new(), |s| s.describe());
+                    let msg = format!("the reply to {} didn't end with ACK", what);
+                    return Err(SerialError::TransferFailed(msg));
+                }
+                self.replies.extend_from_slice(&reply_bytes[2..len - 1]);
+            }
+            Reply::Data(_) => self.replies.extend_from_slice(&reply_bytes[1..]),
+        }
+        Ok(true)
+    }
+}
+
+impl Transfer for Flasher {
+    fn process(&mut self,
+               input: &[u8],
+               now: Instant,
+               output: &mut Vec<u8>)
+               -> Result<Status, SerialError> {
+        self.received.extend_from_slice(input);
+        loop {
+            if self.pausing {
+                if self.deadline.map_or(false, |d| now < d) {
+                    return Ok(Status::InProgress);
+                }
+                self.pausing = false;
+            }
+
+            let reply = match self.exchanges.front() {
+                Some(exchange) if !self.sent => {
+                    output.extend_from_slice(&exchange.send);
+                    self.sent = true;
+                    self.deadline = Some(now + exchange.timeout);
+                    continue;
+                }
+                Some(exchange) => Some(exchange.reply),
+                None => None,
+            };
+
+            match reply {
+                Some(reply) => {
+                    if self.take_reply(reply)? {
+                        self.exchanges.pop_front();
+                        self.sent = false;
+                    } else if self.deadline.map_or(false, |d| now >= d) {
+                        let syncing = match self.step {
+                            Some(Step::Sync) => true,
+                            _ => false,
+                        };
+                        if syncing && self.sync_attempts + 1 < SYNC_ATTEMPTS {
+                            self.sync_attempts += 1;
+                            self.received.clear();
+                            self.sent = false;
+                            continue;
+                        }
+                        let what = self.step.as_ref().map_or(String::new(), |s| s.describe());
+                        let msg = format!("the bootloader didn't answer {}", what);
+                        return Err(SerialError::TransferFailed(msg));
+                    } else {
+                        return Ok(Status::InProgress);
+                    }
+                }
+                None => {
+                    if let Some(step) = self.step.take() {
+                        let stage = self.progress.stage.clone();
+                        self.finish_step(step)?;
+                        if self.progress.stage != stage {
+                            // Give the new stage a chance to be reported before the next one
+                            self.deadline = Some(now);
+                            return Ok(Status::InProgress);
+                        }
+                    }
+                    match self.steps.pop_front() {
+                        Some(step) => self.start_step(step, now)?,
+                        None => return Ok(Status::Complete),
+                    }
+                }
+            }
+        }
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    fn progress(&self) -> Progress {
+        self.progress.clone()
+    }
+
+    fn take_line_request(&mut self) -> LineRequest {
+        mem::replace(&mut self.line_request, LineRequest::default())
+    }
+}
+
+/// What a `Simulator` is expecting next.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum SimState {
+    /// The synchronization byte
+    Sync,
+    Command,
+    /// The address for a READ MEMORY, WRITE MEMORY or GO command
+    Address(u8),
+    /// The length of the data to read
+    ReadLength(u32),
+    /// The data to write
+    WriteData(u32),
+    /// The arguments of an erase command
+    Erase(u8),
+}
+
+/// A simulated STM32 bootloader with some flash memory, for testing flashing without hardware.
+/// It runs on the device end of a port, answering the commands it receives, and is complete once
+/// it's told to start the application.
+///
+/// Like real flash, writing can only clear bits, so data that's written without erasing first
+/// doesn't read back the same.
+pub struct Simulator {
+    /// The contents of the flash
+    pub flash: Vec<u8>,
+    /// The address the flash starts at
+    pub base: u32,
+    /// The address the application was started at, once it has been
+    pub started: Option<u32>,
+    /// The product ID reported with GET ID
+    pub product_id: u16,
+    /// Whether EXTENDED ERASE is supported rather than ERASE
+    pub extended_erase: bool,
+    state: SimState,
+    received: Vec<u8>,
+}
+
+impl Simulator {
+    /// Creates a bootloader with `len` bytes of erased flash at 0x08000000.
+    pub fn new(len: usize) -> Self {
+        Simulator {
+            flash: vec![0xff; len],
+            base: 0x0800_0000,
+            started: None,
+            product_id: 0x413,
+            extended_erase: true,
+            state: SimState::Sync,
+            received: Vec::new(),
+        }
+    }
+
+    /// The offset of the `len` bytes at `address` into the flash, if they're all in it.
+    fn offset(&self, address: u32, len: usize) -> Option<usize> {
+        let offset = address.checked_sub(self.base)? as usize;
+        if offset + len <= self.flash.len() {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+
+    /// Handles the start of `received` if it's complete, returning how many bytes were used.
+    fn handle(&mut self, output: &mut Vec<u8>) -> usize {
+        let received = &self.received;
+        match self.state {
+            SimState::Sync => {
+                if received[0] == SYNC {
+                    output.push(ACK);
+                    self.state = SimState::Command;
+                }
+                1
+            }
+            SimState::Command => {
+                if received.len() < 2 {
+                    return 0;
+                }
+                let command = received[0];
+                if received[1] != !command {
+                    output.push(NACK);
+                    return 2;
+                }
+                let erase_command = if self.extended_erase { EXTENDED_ERASE } else { ERASE };
+                match command {
+                    GET => {
+                        let commands = [GET, GET_ID, READ_MEMORY, GO, WRITE_MEMORY, erase_command];
+                        output.extend_from_slice(&[ACK, commands.len() as u8, 0x31]);
+                        output.extend_from_slice(&commands);
+                        output.push(ACK);
+                    }
+                    GET_ID => {
+                        output.extend_from_slice(&[ACK, 1]);
+                        output.extend_from_slice(&[(self.product_id >> 8) as u8,
+                                                   self.product_id as u8,
+                                                   ACK]);
+                    }
+                    READ_MEMORY | WRITE_MEMORY | GO => {
+                        output.push(ACK);
+                        self.state = SimState::Address(command);
+                    }
+                    c if c == erase_command => {
+                        output.push(ACK);
+                        self.state = SimState::Erase(command);
+                    }
+                    _ => output.push(NACK),
+                }
+                2
+            }
+            SimState::Address(command) => {
+                if received.len() < 5 {
+                    return 0;
+                }
+                let address = received[..4].iter().fold(0, |a, b| a << 8 | *b as u32);
+                self.state = SimState::Command;
+                if checksum(&received[..4]) != received[4] ||
+                   self.offset(address, 1).is_none() {
+                    output.push(NACK);
+                    return 5;
+                }
+                output.push(ACK);
+                match command {
+                    READ_MEMORY => self.state = SimState::ReadLength(address),
+                    WRITE_MEMORY => self.state = SimState::WriteData(address),
+                    _ => self.started = Some(address),
+                }
+                5
+            }
+            SimState::ReadLength(address) => {
+                if received.len() < 2 {
+                    return 0;
+                }
+                let len = received[0] as usize + 1;
+                self.state = SimState::Command;
+                match self.offset(address, len) {
+                    Some(offset) if received[1] == !received[0] => {
+                        output.push(ACK);
+                        output.extend_from_slice(&self.flash[offset..offset + len]);
+                    }
+                    _ => output.push(NACK),
+                }
+                2
+            }
+            SimState::WriteData(address) => {
+                let len = received[0] as usize + 1;
+                if received.len() < len + 2 {
+                    return 0;
+                }
+                self.state = SimState::Command;
+                let data = &received[1..len + 1];
+                match self.offset(address, len) {
+                    Some(offset) if checksum(&received[..len + 1]) == received[len + 1] => {
+                        for (cell, b) in self.flash[offset..offset + len].iter_mut().zip(data) {
+                            *cell &= *b;
+                        }
+                        output.push(ACK);
+                    }
+                    _ => output.push(NACK),
+                }
+                len + 2
+            }
+            SimState::Erase(command) => {
+                // Only global erases are supported
+                let global: &[u8] = if command == EXTENDED_ERASE {
+                    &[0xff, 0xff, 0x00]
+                } else {
+                    &[0xff, 0x00]
+                };
+                if received.len() < global.len() {
+                    return 0;
+                }
+                self.state = SimState::Command;
+                if &received[..global.len()] == global {
+                    for cell in &mut self.flash {
+                        *cell = 0xff;
+                    }
+                    output.push(ACK);
+                } else {
+                    output.push(NACK);
+                }
+                global.len()
+            }
+        }
+    }
+}
+
+impl Transfer for Simulator {
+    fn process(&mut self,
+               input: &[u8],
+               _now: Instant,
+               output: &mut Vec<u8>)
+               -> Result<Status, SerialError> {
+        self.received.extend_from_slice(input);
+        while !self.received.is_empty() && self.started.is_none() {
+            let used = self.handle(output);
+            if used == 0 {
+                break;
+            }
+            self.received.drain(..used);
+        }
+        if self.started.is_some() {
+            Ok(Status::Complete)
+        } else {
+            Ok(Status::InProgress)
+        }
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    fn progress(&self) -> Progress {
+        Progress::default()
+    }
+}
+
+/// The XOR of `bytes`, which is how the bootloader checks addresses and data.
+pub fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |sum, b| sum ^ b)
+}
+
+fn with_checksum(bytes: &[u8]) -> Vec<u8> {
+    let mut v = bytes.to_vec();
+    v.push(checksum(bytes));
+    v
+}
+
+fn be_bytes(n: u32) -> [u8; 4] {
+    [(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]
+}
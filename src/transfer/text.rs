@@ -0,0 +1,216 @@
+//! Sending text files line by line, for command interpreters without flow control that lose
+//! characters when a whole file arrives at once.
+
+use std::io::prelude::*;
+use std::time::{Duration, Instant};
+
+use super::{Progress, Status, Transfer};
+use SerialError;
+
+/// How long to wait for an echo or prompt before giving up, in seconds.
+const RESPONSE_TIMEOUT_SECS: u64 = 10;
+
+/// How much received data is kept to look for an echo or prompt in.
+const MAX_RECEIVED_LEN: usize = 64 * 1024;
+
+/// How text files are paced when sent with `Protocol::Text`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct LinePacing {
+    /// How long to wait after each line before sending the next one
+    pub line_delay: Duration,
+    /// How long to wait after each character. Lines are sent all at once if this is zero.
+    pub char_delay: Duration,
+    /// What has to be received after a line before the next one is sent
+    pub wait_for: WaitFor,
+}
+
+/// What a `TextSender` waits for after sending a line.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum WaitFor {
+    Nothing,
+    /// The line being echoed back, without its line ending
+    Echo,
+    /// The given text, such as ">>> " for the MicroPython REPL
+    Prompt(String),
+}
+
+impl Default for WaitFor {
+    fn default() -> Self {
+        WaitFor::Nothing
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum TextState {
+    Sending,
+    /// Waiting for the echo or prompt after a line
+    Waiting,
+}
+
+/// Sends a text file line by line, paced as set in a `LinePacing`.
+pub struct TextSender {
+    lines: Vec<Vec<u8>>,
+    pacing: LinePacing,
+    state: TextState,
+    /// The line being sent
+    line: usize,
+    /// How much of the line has been sent
+    pos: usize,
+    /// What's been received since the line was started
+    received: Vec<u8>,
+    deadline: Instant,
+    progress: Progress,
+}
+
+impl TextSender {
+    /// Creates a sender for the text read from `file`. Lines end after each '\n', and are sent
+    /// with the line endings they have.
+    pub fn new<R: Read>(mut file: R, pacing: LinePacing) -> Result<Self, SerialError> {
+        let mut text = Vec::new();
+        file.read_to_end(&mut text)?;
+        let mut lines: Vec<Vec<u8>> = text.split(|b| *b == b'\n')
+            .map(|l| {
+                     let mut line = l.to_vec();
+                     line.push(b'\n');
+                     line
+                 })
+            .collect();
+        // The text after the last line ending, which has none
+        if let Some(last) = lines.last_mut() {
+            last.pop();
+        }
+        if lines.last().map_or(false, |l| l.is_empty()) {
+            lines.pop();
+        }
+
+        let count = lines.len();
+        Ok(TextSender {
+               lines,
+               pacing,
+               state: TextState::Sending,
+               line: 0,
+               pos: 0,
+               received: Vec::new(),
+               deadline: Instant::now(),
+               progress: Progress {
+                   bytes: 0,
+                   total: Some(text.len() as u64),
+                   file: None,
+                   lines: Some((0, count)),
+                   stage: None,
+               },
+           })
+    }
+
+    /// Whether what's been received so far is what the sender is waiting for.
+    fn response_received(&self) -> bool {
+        let line = &self.lines[self.line];
+        let expected = match self.pacing.wait_for {
+            WaitFor::Nothing => return true,
+            WaitFor::Echo => {
+                let end = line.iter()
+                    .rposition(|b| *b != b'\r' && *b != b'\n')
+                    .map_or(0, |i| i + 1);
+                &line[..end]
+            }
+            WaitFor::Prompt(ref prompt) => prompt.as_bytes(),
+        };
+        expected.is_empty() || self.received.windows(expected.len()).any(|w| w == expected)
+    }
+
+    /// Moves on to the next line once the current one is done. Returns whether that was the last
+    /// line, which still gets its delay and is reported before the transfer completes.
+    fn finish_line(&mut self, now: Instant) -> bool {
+        self.line += 1;
+        self.pos = 0;
+        self.state = TextState::Sending;
+        self.deadline = now + self.pacing.line_delay;
+        self.progress.lines = Some((self.line, self.lines.len()));
+        self.line >= self.lines.len()
+    }
+}
+
+impl Transfer for TextSender {
+    fn process(&mut self,
+               input: &[u8],
+               now: Instant,
+               output: &mut Vec<u8>)
+               -> Result<Status, SerialError> {
+        self.received.extend_from_slice(input);
+        if self.received.len() > MAX_RECEIVED_LEN {
+            let excess = self.received.len() - MAX_RECEIVED_LEN;
+            self.received.drain(..excess);
+        }
+
+        loop {
+            match self.state {
+                TextState::Sending => {
+                    if now < self.deadline {
+                        return Ok(Status::InProgress);
+                    }
+                    if self.line >= self.lines.len() {
+                        return Ok(Status::Complete);
+                    }
+                    if self.pos == 0 {
+                        // Only what's received in response to this line counts
+                        self.received.clear();
+                    }
+
+                    let len = self.lines[self.line].len();
+                    let end = if self.pacing.char_delay == Duration::from_secs(0) {
+                        len
+                    } else {
+                        (self.pos + 1).min(len)
+                    };
+                    output.extend_from_slice(&self.lines[self.line][self.pos..end]);
+                    self.progress.bytes += (end - self.pos) as u64;
+                    self.pos = end;
+                    self.deadline = now + self.pacing.char_delay;
+                    if self.pos < len {
+                        return Ok(Status::InProgress);
+                    }
+
+                    if self.pacing.wait_for == WaitFor::Nothing {
+                        if self.finish_line(now) {
+                            return Ok(Status::InProgress);
+                        }
+                    } else {
+                        self.state = TextState::Waiting;
+                        self.deadline = now + Duration::from_secs(RESPONSE_TIMEOUT_SECS);
+                    }
+                }
+                TextState::Waiting => {
+                    if self.response_received() {
+                        if self.finish_line(now) {
+                            return Ok(Status::InProgress);
+                        }
+                    } else if now >= self.deadline {
+                        let what = match self.pacing.wait_for {
+                            WaitFor::Prompt(ref prompt) => format!("the prompt '{}'", prompt),
+                            _ => "the echo".to_string(),
+                        };
+                        let msg = format!("{} wasn't received after line {}",
+                                          what,
+                                          self.line + 1);
+                        return Err(SerialError::TransferFailed(msg));
+                    } else {
+                        return Ok(Status::InProgress);
+                    }
+                }
+            }
+        }
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        Some(self.deadline)
+    }
+
+    fn progress(&self) -> Progress {
+        self.progress.clone()
+    }
+
+    /// The point of waiting for prompts is to see them, so everything received is shown.
+    fn shows_input(&self) -> bool {
+        true
+    }
+}
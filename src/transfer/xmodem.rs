@@ -0,0 +1,465 @@
+//! XMODEM in its original form with 128 byte blocks and an arithmetic checksum, with a CRC
+//! (XMODEM-CRC), and with 1024 byte blocks (XMODEM-1K).
+//!
+//! The receiver starts a transfer by repeatedly sending NAK, or 'C' to ask for CRCs. Each block
+//! is then sent as a header byte (SOH for 128 bytes, STX for 1024), the block number and its
+//! complement, the data padded with SUB, and the checksum or big-endian CRC. The receiver answers
+//! every block with ACK, or NAK to have it sent again. EOT ends the transfer and CAN aborts it.
+
+use std::io::prelude::*;
+use std::time::{Duration, Instant};
+
+use super::{Progress, Protocol, Status, Transfer};
+use SerialError;
+
+pub const SOH: u8 = 0x01;
+pub const STX: u8 = 0x02;
+pub const EOT: u8 = 0x04;
+pub const ACK: u8 = 0x06;
+pub const NAK: u8 = 0x15;
+pub const CAN: u8 = 0x18;
+pub const SUB: u8 = 0x1A;
+/// Sent by the receiver instead of NAK to start a transfer using CRCs
+pub const CRC_REQUEST: u8 = b'C';
+
+/// What's sent to cancel a transfer. Two CANs in a row are needed so that line noise doesn't
+/// cancel anything.
+pub const CANCEL: [u8; 2] = [CAN, CAN];
+
+/// How long a sender waits for the receiver to start the transfer or answer a block. This is
+/// longer than `TIMEOUT_SECS` as it's the receiver that asks for blocks to be sent again, and
+/// both ends doing so at the same time would send a block twice.
+pub(crate) const RESPONSE_TIMEOUT_SECS: u64 = 60;
+
+/// How often a receiver asks for the transfer to start.
+pub(crate) const START_INTERVAL_SECS: u64 = 3;
+
+/// How long a receiver waits for a block, or for the rest of one to arrive.
+pub(crate) const TIMEOUT_SECS: u64 = 10;
+
+/// How many times in a row a block is sent or requested again before giving up.
+pub(crate) const MAX_RETRIES: u32 = 10;
+
+/// How many times a receiver asks for CRCs before falling back to checksums for senders that
+/// don't support them.
+const CRC_REQUESTS: u32 = 3;
+
+/// Calculates the CRC-16/XMODEM of `data`: polynomial 0x1021, initial value 0, not reflected.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &b in data {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Calculates the arithmetic checksum of `data`, the sum of its bytes modulo 256.
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+/// Encodes a block. `data` must be exactly 128 or 1024 bytes long, so it has to have been padded
+/// already.
+pub fn encode_block(number: u8, data: &[u8], crc: bool) -> Vec<u8> {
+    debug_assert!(data.len() == 128 || data.len() == 1024);
+    let mut block = Vec::with_capacity(data.len() + 5);
+    block.push(if data.len() == 1024 { STX } else { SOH });
+    block.push(number);
+    block.push(!number);
+    block.extend_from_slice(data);
+    if crc {
+        let crc = crc16(data);
+        block.push((crc >> 8) as u8);
+        block.push(crc as u8);
+    } else {
+        block.push(checksum(data));
+    }
+    block
+}
+
+/// Reads from `file` until `buf` is full or the end of the file is reached, returning how much was
+/// read.
+pub fn read_full<R: Read>(file: &mut R, buf: &mut [u8]) -> ::std::io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match file.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+/// Returns the full length of a block starting with `header`, which must be SOH or STX.
+pub fn block_len(header: u8, crc: bool) -> usize {
+    let size = if header == STX { 1024 } else { 128 };
+    3 + size + if crc { 2 } else { 1 }
+}
+
+/// Checks the complete, encoded `block`, returning its number and data if it's intact.
+pub fn decode_block(block: &[u8], crc: bool) -> Option<(u8, &[u8])> {
+    let number = block[1];
+    let data_end = block.len() - if crc { 2 } else { 1 };
+    let data = &block[3..data_end];
+    let valid = number == !block[2] &&
+                if crc {
+                    let crc = ((block[data_end] as u16) << 8) | block[data_end + 1] as u16;
+                    crc16(data) == crc
+                } else {
+                    checksum(data) == block[data_end]
+                };
+    if valid { Some((number, data)) } else { None }
+}
+
+/// Tracks CANs received in a row, as two of them cancel a transfer.
+#[derive(Debug, Default)]
+pub(crate) struct CancelDetector {
+    count: usize,
+}
+
+impl CancelDetector {
+    /// Returns whether `byte` completes a cancellation.
+    pub(crate) fn check(&mut self, byte: u8) -> bool {
+        if byte == CAN {
+            self.count += 1;
+        } else {
+            self.count = 0;
+        }
+        self.count >= CANCEL.len()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum SendState {
+    /// Waiting for the receiver to ask for the first block
+    Starting,
+    /// Waiting for the current block to be acknowledged
+    Block,
+    /// Waiting for EOT to be acknowledged
+    End,
+}
+
+/// Sends a file using XMODEM.
+pub struct Sender<R> {
+    file: R,
+    block_size: usize,
+    crc: bool,
+    state: SendState,
+    block_number: u8,
+    /// The encoded block that's being sent
+    block: Vec<u8>,
+    /// How much of the file is in `block`
+    block_len: usize,
+    retries: u32,
+    /// Whether a request to start that arrived while the first block was waiting to be
+    /// acknowledged has been ignored
+    ignored_start_request: bool,
+    deadline: Instant,
+    cancel: CancelDetector,
+    progress: Progress,
+}
+
+impl<R: Read> Sender<R> {
+    /// Creates a sender for `len` bytes read from `file`. `protocol` must be one of the XMODEM
+    /// variants.
+    pub fn new(file: R, len: u64, protocol: Protocol) -> Self {
+        Sender {
+            file,
+            block_size: if protocol == Protocol::Xmodem1k { 1024 } else { 128 },
+            crc: protocol != Protocol::Xmodem,
+            state: SendState::Starting,
+            block_number: 1,
+            block: Vec::new(),
+            block_len: 0,
+            retries: 0,
+            ignored_start_request: false,
+            deadline: Instant::now() + Duration::from_secs(RESPONSE_TIMEOUT_SECS),
+            cancel: CancelDetector::default(),
+            progress: Progress {
+                bytes: 0,
+                total: Some(len),
+                file: None,
+                lines: None,
+                stage: None,
+            },
+        }
+    }
+
+    /// Reads the next block from the file and sends it, or sends EOT if the whole file has been
+    /// sent.
+    fn send_next_block(&mut self, output: &mut Vec<u8>) -> Result<(), SerialError> {
+        let mut data = vec![0; self.block_size];
+        let len = read_full(&mut self.file, &mut data)?;
+        self.retries = 0;
+        if len == 0 {
+            self.state = SendState::End;
+            output.push(EOT);
+            return Ok(());
+        }
+
+        // Don't pad out a mostly-empty 1K block when a short one will do
+        let size = if len <= 128 { 128 } else { self.block_size };
+        data.truncate(size);
+        for b in &mut data[len..] {
+            *b = SUB;
+        }
+        self.block = encode_block(self.block_number, &data, self.crc);
+        self.block_len = len;
+        self.state = SendState::Block;
+        output.extend_from_slice(&self.block);
+        Ok(())
+    }
+
+    /// Sends the current block or EOT again.
+    fn retry(&mut self, output: &mut Vec<u8>) -> Result<(), SerialError> {
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            return Err(SerialError::TransferFailed("the receiver kept rejecting data".to_string()));
+        }
+        match self.state {
+            SendState::Starting => (),
+            SendState::Block => output.extend_from_slice(&self.block),
+            SendState::End => output.push(EOT),
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Transfer for Sender<R> {
+    fn process(&mut self,
+               input: &[u8],
+               now: Instant,
+               output: &mut Vec<u8>)
+               -> Result<Status, SerialError> {
+        for &b in input {
+            if self.cancel.check(b) {
+                return Err(SerialError::TransferFailed("canceled by the receiver".to_string()));
+            }
+            match (self.state, b) {
+                (SendState::Starting, CRC_REQUEST) |
+                (SendState::Starting, NAK) => {
+                    // The receiver decides whether to use CRCs. Blocks of 1024 bytes are only
+                    // allowed with CRCs.
+                    self.crc = b == CRC_REQUEST;
+                    if !self.crc {
+                        self.block_size = 128;
+                    }
+                    self.send_next_block(output)?;
+                }
+                (SendState::Block, ACK) => {
+                    self.progress.bytes += self.block_len as u64;
+                    self.block_number = self.block_number.wrapping_add(1);
+                    self.send_next_block(output)?;
+                }
+                (SendState::End, ACK) => return Ok(Status::Complete),
+                // A receiver that missed the first block asks for the transfer to start again.
+                // The first request after it was sent is ignored though, as the receiver keeps
+                // asking until the block arrives, and sending it twice would get it acknowledged
+                // twice.
+                (SendState::Block, CRC_REQUEST) if self.block_number == 1 &&
+                                                   self.progress.bytes == 0 => {
+                    if !self.ignored_start_request {
+                        self.ignored_start_request = true;
+                        continue;
+                    }
+                    self.retry(output)?;
+                }
+                (SendState::Block, NAK) |
+                (SendState::End, NAK) => self.retry(output)?,
+                // Anything else is line noise
+                _ => continue,
+            }
+            self.deadline = now + Duration::from_secs(RESPONSE_TIMEOUT_SECS);
+        }
+
+        if now >= self.deadline {
+            if self.state == SendState::Starting {
+                let msg = "the receiver didn't start the transfer";
+                return Err(SerialError::TransferFailed(msg.to_string()));
+            }
+            debug!("Timed out waiting for the receiver, sending again");
+            self.retry(output)?;
+            self.deadline = now + Duration::from_secs(RESPONSE_TIMEOUT_SECS);
+        }
+        Ok(Status::InProgress)
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        Some(self.deadline)
+    }
+
+    fn progress(&self) -> Progress {
+        self.progress.clone()
+    }
+
+    fn cancel(&mut self, output: &mut Vec<u8>) {
+        output.extend_from_slice(&CANCEL);
+    }
+}
+
+/// Receives a file using XMODEM.
+///
+/// XMODEM doesn't send the size of the file, so the received file is padded with SUB to a
+/// multiple of the block size.
+pub struct Receiver<W> {
+    file: W,
+    crc: bool,
+    /// Whether the first block has been received
+    started: bool,
+    /// How many times the transfer has been asked to start
+    requests: u32,
+    expected_block: u8,
+    /// The block being received
+    block: Vec<u8>,
+    retries: u32,
+    /// `None` until the transfer has been asked to start for the first time
+    deadline: Option<Instant>,
+    cancel: CancelDetector,
+    progress: Progress,
+}
+
+impl<W: Write> Receiver<W> {
+    /// Creates a receiver writing to `file`. `protocol` must be one of the XMODEM variants.
+    pub fn new(file: W, protocol: Protocol) -> Self {
+        Receiver {
+            file,
+            crc: protocol != Protocol::Xmodem,
+            started: false,
+            requests: 0,
+            expected_block: 1,
+            block: Vec::new(),
+            retries: 0,
+            deadline: None,
+            cancel: CancelDetector::default(),
+            progress: Progress::default(),
+        }
+    }
+
+    /// Asks the sender to start the transfer, falling back to checksums if it doesn't seem to
+    /// support CRCs.
+    fn request_start(&mut self, output: &mut Vec<u8>) -> Result<(), SerialError> {
+        if self.requests >= MAX_RETRIES {
+            return Err(SerialError::TransferFailed("the sender didn't start the transfer"
+                                                       .to_string()));
+        }
+        if self.crc && self.requests >= CRC_REQUESTS {
+            debug!("No response to CRC requests, falling back to checksums");
+            self.crc = false;
+        }
+        self.requests += 1;
+        output.push(if self.crc { CRC_REQUEST } else { NAK });
+        Ok(())
+    }
+
+    /// Asks for the current block to be sent again.
+    fn reject_block(&mut self, output: &mut Vec<u8>) -> Result<(), SerialError> {
+        self.block.clear();
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            output.extend_from_slice(&CANCEL);
+            return Err(SerialError::TransferFailed("too many corrupted blocks".to_string()));
+        }
+        output.push(NAK);
+        Ok(())
+    }
+
+    /// Checks the complete block in `self.block`, writing it out if it's the next one. Returns
+    /// whether the block was valid.
+    fn handle_block(&mut self, output: &mut Vec<u8>) -> Result<bool, SerialError> {
+        let block = ::std::mem::replace(&mut self.block, Vec::new());
+        let (number, data) = match decode_block(&block, self.crc) {
+            Some(decoded) => decoded,
+            None => {
+                debug!("Received a corrupted block");
+                self.reject_block(output)?;
+                return Ok(false);
+            }
+        };
+
+        if number == self.expected_block {
+            self.file.write_all(data)?;
+            self.progress.bytes += data.len() as u64;
+            self.expected_block = self.expected_block.wrapping_add(1);
+            self.started = true;
+        } else if number != self.expected_block.wrapping_sub(1) || !self.started {
+            output.extend_from_slice(&CANCEL);
+            return Err(SerialError::TransferFailed(format!("expected block {} but received {}",
+                                                           self.expected_block,
+                                                           number)));
+        }
+        // The previous block is sent again if our ACK got lost, so that's acknowledged as well
+        self.retries = 0;
+        output.push(ACK);
+        Ok(true)
+    }
+}
+
+impl<W: Write> Transfer for Receiver<W> {
+    fn process(&mut self,
+               input: &[u8],
+               now: Instant,
+               output: &mut Vec<u8>)
+               -> Result<Status, SerialError> {
+        if self.deadline.is_none() {
+            self.request_start(output)?;
+            self.deadline = Some(now + Duration::from_secs(START_INTERVAL_SECS));
+        }
+
+        for &b in input {
+            if self.block.is_empty() {
+                if self.cancel.check(b) {
+                    return Err(SerialError::TransferFailed("canceled by the sender".to_string()));
+                }
+                match b {
+                    SOH | STX => self.block.push(b),
+                    EOT => {
+                        output.push(ACK);
+                        self.file.flush()?;
+                        return Ok(Status::Complete);
+                    }
+                    // Anything else is line noise
+                    _ => continue,
+                }
+            } else {
+                self.block.push(b);
+                let len = block_len(self.block[0], self.crc);
+                if self.block.len() == len && !self.handle_block(output)? {
+                    // Drop the rest of whatever was corrupted instead of looking for a block in
+                    // the middle of it.
+                    self.deadline = Some(now + Duration::from_secs(TIMEOUT_SECS));
+                    return Ok(Status::InProgress);
+                }
+            }
+            self.deadline = Some(now + Duration::from_secs(TIMEOUT_SECS));
+        }
+
+        if self.deadline.map_or(false, |d| now >= d) {
+            if self.started || !self.block.is_empty() {
+                debug!("Timed out waiting for a block");
+                self.reject_block(output)?;
+                self.deadline = Some(now + Duration::from_secs(TIMEOUT_SECS));
+            } else {
+                self.request_start(output)?;
+                self.deadline = Some(now + Duration::from_secs(START_INTERVAL_SECS));
+            }
+        }
+        Ok(Status::InProgress)
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    fn progress(&self) -> Progress {
+        self.progress.clone()
+    }
+
+    fn cancel(&mut self, output: &mut Vec<u8>) {
+        output.extend_from_slice(&CANCEL);
+    }
+}
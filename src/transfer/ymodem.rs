@@ -0,0 +1,474 @@
+//! YMODEM batch transfers.
+//!
+//! YMODEM is XMODEM-1K with CRCs, where each file is preceded by block 0 holding its name and size
+//! as "name\0size". The receiver asks for every file with 'C', and an empty block 0 ends the batch.
+//! The received files are cut to their size, so unlike with XMODEM they aren't padded.
+
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use super::xmodem::{self, CancelDetector, ACK, CANCEL, CRC_REQUEST, EOT, MAX_RETRIES, NAK,
+                    RESPONSE_TIMEOUT_SECS, SOH, START_INTERVAL_SECS, STX, SUB, TIMEOUT_SECS};
+use super::{BatchFile, FileProgress, Progress, Status, Transfer};
+use SerialError;
+
+/// Encodes block 0 announcing a file, or ending the batch if `file` is `None`.
+pub fn encode_header(file: Option<(&str, u64)>) -> Vec<u8> {
+    let mut data = Vec::new();
+    if let Some((name, len)) = file {
+        data.extend_from_slice(name.as_bytes());
+        data.push(0);
+        data.extend_from_slice(len.to_string().as_bytes());
+        data.push(0);
+    }
+    let size = if data.len() <= 128 { 128 } else { 1024 };
+    data.resize(size, 0);
+    xmodem::encode_block(0, &data, true)
+}
+
+/// Reads the name and size of a file from the data of block 0. The name is empty at the end of
+/// the batch, and the size is `None` if the sender didn't include it.
+pub fn decode_header(data: &[u8]) -> (String, Option<u64>) {
+    let mut fields = data.split(|b| *b == 0);
+    let name = String::from_utf8_lossy(fields.next().unwrap_or(&[])).into_owned();
+    let len = fields.next()
+        .and_then(|f| String::from_utf8_lossy(f).split_whitespace().next().map(str::to_string))
+        .and_then(|f| f.parse().ok());
+    (name, len)
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum SendState {
+    /// Waiting for the receiver to ask for the next file
+    Starting,
+    /// Waiting for a file's header to be acknowledged
+    Header,
+    /// Waiting for the receiver to ask for a file's data after acknowledging its header
+    DataStart,
+    /// Waiting for the current block to be acknowledged
+    Block,
+    /// Waiting for EOT to be acknowledged
+    End,
+    /// Waiting for the header ending the batch to be acknowledged
+    Finishing,
+}
+
+/// Sends a batch of files using YMODEM.
+pub struct Sender<R> {
+    files: Vec<BatchFile<R>>,
+    /// The position of the file being sent in `files`
+    index: usize,
+    state: SendState,
+    block_number: u8,
+    /// The encoded block that's being sent, including headers
+    block: Vec<u8>,
+    /// How much of the file is in `block`
+    block_len: usize,
+    retries: u32,
+    deadline: Instant,
+    cancel: CancelDetector,
+    progress: Progress,
+}
+
+impl<R: Read> Sender<R> {
+    pub fn new(files: Vec<BatchFile<R>>) -> Self {
+        let total = files.iter().map(|f| f.len).sum();
+        Sender {
+            files,
+            index: 0,
+            state: SendState::Starting,
+            block_number: 0,
+            block: Vec::new(),
+            block_len: 0,
+            retries: 0,
+            deadline: Instant::now() + Duration::from_secs(RESPONSE_TIMEOUT_SECS),
+            cancel: CancelDetector::default(),
+            progress: Progress {
+                bytes: 0,
+                total: Some(total),
+                file: None,
+                lines: None,
+                stage: None,
+            },
+        }
+    }
+
+    /// Sends the header of the next file, or the one ending the batch if all have been sent.
+    fn send_header(&mut self, output: &mut Vec<u8>) {
+        self.retries = 0;
+        self.block_len = 0;
+        if self.index < self.files.len() {
+            let file = &self.files[self.index];
+            self.block = encode_header(Some((&file.name, file.len)));
+            self.state = SendState::Header;
+            self.progress.file = Some(FileProgress {
+                name: file.name.clone(),
+                index: self.index,
+                count: Some(self.files.len()),
+                bytes: 0,
+                total: Some(file.len),
+            });
+        } else {
+            self.block = encode_header(None);
+            self.state = SendState::Finishing;
+        }
+        output.extend_from_slice(&self.block);
+    }
+
+    /// Reads the next block of the file and sends it, or sends EOT if the whole file has been
+    /// sent.
+    fn send_next_block(&mut self, output: &mut Vec<u8>) -> Result<(), SerialError> {
+        let mut data = vec![0; 1024];
+        let len = xmodem::read_full(&mut self.files[self.index].file, &mut data)?;
+        self.retries = 0;
+        if len == 0 {
+            self.state = SendState::End;
+            output.push(EOT);
+            return Ok(());
+        }
+
+        let size = if len <= 128 { 128 } else { 1024 };
+        data.truncate(size);
+        for b in &mut data[len..] {
+            *b = SUB;
+        }
+        self.block = xmodem::encode_block(self.block_number, &data, true);
+        self.block_len = len;
+        self.state = SendState::Block;
+        output.extend_from_slice(&self.block);
+        Ok(())
+    }
+
+    /// Sends the current block or EOT again.
+    fn retry(&mut self, output: &mut Vec<u8>) -> Result<(), SerialError> {
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            return Err(SerialError::TransferFailed("the receiver kept rejecting data".to_string()));
+        }
+        match self.state {
+            SendState::Starting | SendState::DataStart => (),
+            SendState::Header | SendState::Block | SendState::Finishing => {
+                output.extend_from_slice(&self.block)
+            }
+            SendState::End => output.push(EOT),
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Transfer for Sender<R> {
+    fn process(&mut self,
+               input: &[u8],
+               now: Instant,
+               output: &mut Vec<u8>)
+               -> Result<Status, SerialError> {
+        for &b in input {
+            if self.cancel.check(b) {
+                return Err(SerialError::TransferFailed("canceled by the receiver".to_string()));
+            }
+            match (self.state, b) {
+                (SendState::Starting, CRC_REQUEST) => self.send_header(output),
+                (SendState::Header, ACK) => {
+                    self.block_number = 1;
+                    self.state = SendState::DataStart;
+                }
+                (SendState::DataStart, CRC_REQUEST) => self.send_next_block(output)?,
+                (SendState::Block, ACK) => {
+                    self.progress.bytes += self.block_len as u64;
+                    if let Some(ref mut file) = self.progress.file {
+                        file.bytes += self.block_len as u64;
+                    }
+                    self.block_number = self.block_number.wrapping_add(1);
+                    self.send_next_block(output)?;
+                }
+                (SendState::End, ACK) => {
+                    self.index += 1;
+                    self.state = SendState::Starting;
+                }
+                (SendState::Finishing, ACK) => return Ok(Status::Complete),
+                // Receivers NAK the first EOT to make sure it wasn't line noise, and ask for a
+                // header again if they missed it
+                (SendState::Header, NAK) |
+                (SendState::Header, CRC_REQUEST) |
+                (SendState::Block, NAK) |
+                (SendState::End, NAK) |
+                (SendState::Finishing, NAK) |
+                (SendState::Finishing, CRC_REQUEST) => self.retry(output)?,
+                // Anything else is line noise
+                _ => continue,
+            }
+            self.deadline = now + Duration::from_secs(RESPONSE_TIMEOUT_SECS);
+        }
+
+        if now >= self.deadline {
+            if self.state == SendState::Starting {
+                let msg = "the receiver didn't ask for the next file";
+                return Err(SerialError::TransferFailed(msg.to_string()));
+            }
+            debug!("Timed out waiting for the receiver, sending again");
+            self.retry(output)?;
+            self.deadline = now + Duration::from_secs(RESPONSE_TIMEOUT_SECS);
+        }
+        Ok(Status::InProgress)
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        Some(self.deadline)
+    }
+
+    fn progress(&self) -> Progress {
+        self.progress.clone()
+    }
+
+    fn cancel(&mut self, output: &mut Vec<u8>) {
+        output.extend_from_slice(&CANCEL);
+    }
+}
+
+/// Receives a batch of files using YMODEM into a directory, naming them as the sender does.
+/// Existing files are overwritten.
+pub struct Receiver {
+    dir: PathBuf,
+    /// The file being received, `None` while waiting for a header
+    file: Option<File>,
+    /// How much of the file is still to be received, if its size is known
+    remaining: Option<u64>,
+    /// How many files have been received
+    files: usize,
+    expected_block: u8,
+    /// The block being received
+    block: Vec<u8>,
+    /// How many times the next file has been asked for
+    requests: u32,
+    retries: u32,
+    /// `None` until the first file has been asked for
+    deadline: Option<Instant>,
+    cancel: CancelDetector,
+    progress: Progress,
+}
+
+impl Receiver {
+    /// Creates a receiver writing files into `dir`, which must exist.
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Receiver {
+            dir: dir.as_ref().to_path_buf(),
+            file: None,
+            remaining: None,
+            files: 0,
+            expected_block: 0,
+            block: Vec::new(),
+            requests: 0,
+            retries: 0,
+            deadline: None,
+            cancel: CancelDetector::default(),
+            progress: Progress::default(),
+        }
+    }
+
+    /// Asks the sender for the next file.
+    fn request_file(&mut self, output: &mut Vec<u8>) -> Result<(), SerialError> {
+        if self.requests >= MAX_RETRIES {
+            return Err(SerialError::TransferFailed("the sender didn't send the next file"
+                                                       .to_string()));
+        }
+        self.requests += 1;
+        output.push(CRC_REQUEST);
+        Ok(())
+    }
+
+    /// Asks for the current block to be sent again.
+    fn reject_block(&mut self, output: &mut Vec<u8>) -> Result<(), SerialError> {
+        self.block.clear();
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            output.extend_from_slice(&CANCEL);
+            return Err(SerialError::TransferFailed("too many corrupted blocks".to_string()));
+        }
+        output.push(NAK);
+        Ok(())
+    }
+
+    /// Starts receiving the file announced by a header. Returns whether the batch has ended.
+    fn start_file(&mut self, data: &[u8], output: &mut Vec<u8>) -> Result<bool, SerialError> {
+        let (name, len) = decode_header(data);
+        if name.is_empty() {
+            output.push(ACK);
+            return Ok(true);
+        }
+
+        // Only ever create files in the chosen directory, whatever the sender's path is
+        let file_name = match Path::new(&name).file_name() {
+            Some(file_name) => file_name.to_os_string(),
+            None => {
+                output.extend_from_slice(&CANCEL);
+                let msg = format!("the sender sent an invalid file name '{}'", name);
+                return Err(SerialError::TransferFailed(msg));
+            }
+        };
+        let path = self.dir.join(file_name);
+        info!("Receiving {:?} ({:?} bytes)", path, len);
+        match File::create(&path) {
+            Ok(file) => self.file = Some(file),
+            Err(e) => {
+                output.extend_from_slice(&CANCEL);
+                return Err(SerialError::from_file_error(&path, e));
+            }
+        }
+        self.remaining = len;
+        self.expected_block = 1;
+        self.requests = 0;
+        self.progress.file = Some(FileProgress {
+            name,
+            index: self.files,
+            count: None,
+            bytes: 0,
+            total: len,
+        });
+        output.push(ACK);
+        output.push(CRC_REQUEST);
+        Ok(false)
+    }
+
+    /// Writes the data of a block to the current file, leaving out the padding after its end.
+    fn write_data(&mut self, data: &[u8]) -> Result<(), SerialError> {
+        let len = match self.remaining {
+            Some(remaining) => (remaining.min(data.len() as u64)) as usize,
+            None => data.len(),
+        };
+        if let Some(ref mut file) = self.file {
+            file.write_all(&data[..len])?;
+        }
+        self.remaining = self.remaining.map(|r| r - len as u64);
+        self.progress.bytes += len as u64;
+        if let Some(ref mut file) = self.progress.file {
+            file.bytes += len as u64;
+        }
+        Ok(())
+    }
+
+    /// Checks the complete block in `self.block` and handles it. Returns the transfer's status if
+    /// the block was valid.
+    fn handle_block(&mut self, output: &mut Vec<u8>) -> Result<Option<Status>, SerialError> {
+        let block = ::std::mem::replace(&mut self.block, Vec::new());
+        let (number, data) = match xmodem::decode_block(&block, true) {
+            Some(decoded) => decoded,
+            None => {
+                debug!("Received a corrupted block");
+                self.reject_block(output)?;
+                return Ok(None);
+            }
+        };
+        self.retries = 0;
+
+        if self.file.is_none() && number == 0 {
+            if self.start_file(data, output)? {
+                return Ok(Some(Status::Complete));
+            }
+        } else if self.file.is_some() && number == self.expected_block {
+            self.write_data(data)?;
+            self.expected_block = self.expected_block.wrapping_add(1);
+            output.push(ACK);
+        } else if number == self.expected_block.wrapping_sub(1) {
+            // The previous block is sent again if our ACK got lost. For a header, the sender is
+            // then also still waiting to be asked for the data.
+            output.push(ACK);
+            if number == 0 {
+                output.push(CRC_REQUEST);
+            }
+        } else {
+            output.extend_from_slice(&CANCEL);
+            return Err(SerialError::TransferFailed(format!("expected block {} but received {}",
+                                                           self.expected_block,
+                                                           number)));
+        }
+        Ok(Some(Status::InProgress))
+    }
+}
+
+impl Transfer for Receiver {
+    fn process(&mut self,
+               input: &[u8],
+               now: Instant,
+               output: &mut Vec<u8>)
+               -> Result<Status, SerialError> {
+        if self.deadline.is_none() {
+            self.request_file(output)?;
+            self.deadline = Some(now + Duration::from_secs(START_INTERVAL_SECS));
+        }
+
+        for &b in input {
+            if self.block.is_empty() {
+                if self.cancel.check(b) {
+                    return Err(SerialError::TransferFailed("canceled by the sender".to_string()));
+                }
+                match b {
+                    SOH | STX => self.block.push(b),
+                    EOT => {
+                        // A repeated EOT means our ACK got lost
+                        output.push(ACK);
+                        if let Some(mut file) = self.file.take() {
+                            file.flush()?;
+                            self.files += 1;
+                        }
+                        output.push(CRC_REQUEST);
+                        self.deadline = Some(now + Duration::from_secs(START_INTERVAL_SECS));
+                        continue;
+                    }
+                    // Anything else is line noise
+                    _ => continue,
+                }
+            } else {
+                self.block.push(b);
+                if self.block.len() == xmodem::block_len(self.block[0], true) {
+                    match self.handle_block(output)? {
+                        Some(Status::Complete) => return Ok(Status::Complete),
+                        Some(Status::InProgress) => (),
+                        None => {
+                            // Drop the rest of whatever was corrupted instead of looking for a
+                            // block in the middle of it.
+                            self.deadline = Some(now + Duration::from_secs(TIMEOUT_SECS));
+                            return Ok(Status::InProgress);
+                        }
+                    }
+                }
+            }
+            self.deadline = Some(now + Duration::from_secs(TIMEOUT_SECS));
+        }
+
+        if self.deadline.map_or(false, |d| now >= d) {
+            if self.file.is_some() && self.expected_block == 1 && self.block.is_empty() {
+                // The sender didn't get the request for the file's data
+                self.retries += 1;
+                if self.retries > MAX_RETRIES {
+                    output.extend_from_slice(&CANCEL);
+                    let msg = "the sender didn't send the file's data";
+                    return Err(SerialError::TransferFailed(msg.to_string()));
+                }
+                output.push(CRC_REQUEST);
+                self.deadline = Some(now + Duration::from_secs(TIMEOUT_SECS));
+            } else if self.file.is_some() || !self.block.is_empty() {
+                debug!("Timed out waiting for a block");
+                self.reject_block(output)?;
+                self.deadline = Some(now + Duration::from_secs(TIMEOUT_SECS));
+            } else {
+                self.request_file(output)?;
+                self.deadline = Some(now + Duration::from_secs(START_INTERVAL_SECS));
+            }
+        }
+        Ok(Status::InProgress)
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    fn progress(&self) -> Progress {
+        self.progress.clone()
+    }
+
+    fn cancel(&mut self, output: &mut Vec<u8>) {
+        output.extend_from_slice(&CANCEL);
+    }
+}
@@ -0,0 +1,1057 @@
+//! ZMODEM batch transfers, compatible with `sz` and `rz` from lrzsz.
+//!
+//! Everything is sent as frames starting with a header of a frame type and four bytes that hold
+//! either a file position (little-endian) or flags. Headers are sent as hex digits ("**\x18B...")
+//! or in binary with a CRC-16 ("*\x18A...") or CRC-32 ("*\x18C..."). Some headers are followed by
+//! data subpackets, each ending with ZDLE, a frame end character and a CRC. ZDLE also escapes
+//! bytes that could be mangled on the way, and five of them in a row cancel the transfer.
+//!
+//! The sender streams a file's data without waiting for acknowledgements. When data is lost the
+//! receiver asks for it again with ZRPOS, which is also how a receiver resumes a partially
+//! received file.
+
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use serialport::prelude::*;
+
+use super::pacer::Pacer;
+use super::xmodem::{crc16, MAX_RETRIES, RESPONSE_TIMEOUT_SECS, START_INTERVAL_SECS,
+                    TIMEOUT_SECS};
+use super::ymodem::decode_header;
+use super::{BatchFile, FileProgress, Progress, Status, Transfer};
+use SerialError;
+
+pub const ZPAD: u8 = b'*';
+pub const ZDLE: u8 = 0x18;
+/// Binary header with a CRC-16
+pub const ZBIN: u8 = b'A';
+/// Hex header
+pub const ZHEX: u8 = b'B';
+/// Binary header with a CRC-32
+pub const ZBIN32: u8 = b'C';
+
+// Frame types
+pub const ZRQINIT: u8 = 0;
+pub const ZRINIT: u8 = 1;
+pub const ZSINIT: u8 = 2;
+pub const ZACK: u8 = 3;
+pub const ZFILE: u8 = 4;
+pub const ZSKIP: u8 = 5;
+pub const ZNAK: u8 = 6;
+pub const ZABORT: u8 = 7;
+pub const ZFIN: u8 = 8;
+pub const ZRPOS: u8 = 9;
+pub const ZDATA: u8 = 10;
+pub const ZEOF: u8 = 11;
+pub const ZFERR: u8 = 12;
+pub const ZCOMMAND: u8 = 18;
+
+// Frame ends of data subpackets
+/// End of frame, the header follows
+pub const ZCRCE: u8 = b'h';
+/// More data follows without a response
+pub const ZCRCG: u8 = b'i';
+/// More data follows after a ZACK
+pub const ZCRCQ: u8 = b'j';
+/// End of frame, ZACK expected
+pub const ZCRCW: u8 = b'k';
+
+/// Escaped 0x7F
+const ZRUB0: u8 = b'l';
+/// Escaped 0xFF
+const ZRUB1: u8 = b'm';
+
+// ZRINIT flags
+/// Can send and receive at the same time
+pub const CANFDX: u8 = 0x01;
+/// Can receive data while writing to disk
+pub const CANOVIO: u8 = 0x02;
+/// Can use CRC-32
+pub const CANFC32: u8 = 0x20;
+/// Wants all control characters escaped
+pub const ESCCTL: u8 = 0x40;
+
+// ZFILE conversion options
+/// Binary transfer
+pub const ZCBIN: u8 = 1;
+/// Resume an interrupted transfer
+pub const ZCRESUM: u8 = 3;
+
+/// Starts the receiving program on the other end if it's a shell.
+const RZ_COMMAND: &[u8] = b"rz\r";
+
+/// Sent to cancel a transfer.
+pub const CANCEL: [u8; 18] = [ZDLE, ZDLE, ZDLE, ZDLE, ZDLE, ZDLE, ZDLE, ZDLE, ZDLE, ZDLE, 8, 8, 8,
+                              8, 8, 8, 8, 8];
+
+/// How much data is sent in a subpacket.
+const SUBPACKET_LEN: usize = 1024;
+
+/// The longest data subpacket that's accepted.
+const MAX_SUBPACKET_LEN: usize = 8 * 1024;
+
+/// Calculates the CRC-32 used by ZMODEM, which is the same as for Ethernet and zip.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// How a header is encoded, which for headers followed by data also determines the CRC used for
+/// the data.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Encoding {
+    Hex,
+    Bin16,
+    Bin32,
+}
+
+/// A frame header.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Header {
+    pub kind: u8,
+    /// ZP0 to ZP3, which are ZF3 to ZF0 for headers with flags
+    pub data: [u8; 4],
+}
+
+impl Header {
+    pub fn new(kind: u8) -> Self {
+        Header {
+            kind,
+            data: [0; 4],
+        }
+    }
+
+    /// Creates a header holding a file position.
+    pub fn with_position(kind: u8, position: u32) -> Self {
+        Header {
+            kind,
+            data: [position as u8, (position >> 8) as u8, (position >> 16) as u8,
+                   (position >> 24) as u8],
+        }
+    }
+
+    /// Creates a header holding the flags ZF0 and ZF1.
+    pub fn with_flags(kind: u8, zf0: u8, zf1: u8) -> Self {
+        Header {
+            kind,
+            data: [0, 0, zf1, zf0],
+        }
+    }
+
+    pub fn position(&self) -> u32 {
+        self.data.iter().rev().fold(0, |p, b| (p << 8) | *b as u32)
+    }
+
+    /// The flags byte ZF0.
+    pub fn zf0(&self) -> u8 {
+        self.data[3]
+    }
+
+    /// Whether a data subpacket follows the header.
+    fn has_data(&self) -> bool {
+        match self.kind {
+            ZSINIT | ZFILE | ZDATA | ZCOMMAND => true,
+            _ => false,
+        }
+    }
+
+    /// Encodes the header in hex, which is used for headers that don't carry data.
+    pub fn encode_hex(&self) -> Vec<u8> {
+        let mut bytes = vec![self.kind];
+        bytes.extend_from_slice(&self.data);
+        let crc = crc16(&bytes);
+        bytes.push((crc >> 8) as u8);
+        bytes.push(crc as u8);
+
+        let mut encoded = vec![ZPAD, ZPAD, ZDLE, ZHEX];
+        for b in bytes {
+            encoded.extend_from_slice(format!("{:02x}", b).as_bytes());
+        }
+        encoded.extend_from_slice(b"\r\x8a");
+        // XON to restart a sender that stopped because of line noise
+        if self.kind != ZFIN && self.kind != ZACK {
+            encoded.push(0x11);
+        }
+        encoded
+    }
+
+    /// Encodes the header in binary, using a CRC-32 if `crc32` is set.
+    pub fn encode_binary(&self, crc32: bool, escape_control: bool) -> Vec<u8> {
+        let mut bytes = vec![self.kind];
+        bytes.extend_from_slice(&self.data);
+        let mut encoded = vec![ZPAD, ZDLE, if crc32 { ZBIN32 } else { ZBIN }];
+        let mut escaper = Escaper::new(escape_control);
+        escaper.escape(&bytes, &mut encoded);
+        escaper.escape(&crc(&bytes, crc32), &mut encoded);
+        encoded
+    }
+}
+
+/// Calculates the CRC of `data`, in the byte order it's sent in.
+fn crc(data: &[u8], crc32: bool) -> Vec<u8> {
+    if crc32 {
+        let crc = self::crc32(data);
+        vec![crc as u8, (crc >> 8) as u8, (crc >> 16) as u8, (crc >> 24) as u8]
+    } else {
+        let crc = crc16(data);
+        vec![(crc >> 8) as u8, crc as u8]
+    }
+}
+
+/// Encodes a data subpacket ending with `end`.
+pub fn encode_data(data: &[u8], end: u8, crc32: bool, escape_control: bool) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(data.len() + data.len() / 8 + 12);
+    let mut escaper = Escaper::new(escape_control);
+    escaper.escape(data, &mut encoded);
+    encoded.push(ZDLE);
+    encoded.push(end);
+    let mut checked = data.to_vec();
+    checked.push(end);
+    escaper.escape(&crc(&checked, crc32), &mut encoded);
+    encoded
+}
+
+/// Escapes bytes with ZDLE. Besides ZDLE itself this covers the flow control characters, and
+/// carriage returns after '@' so that they can't form the Telenet escape sequence.
+struct Escaper {
+    escape_control: bool,
+    last: u8,
+}
+
+impl Escaper {
+    fn new(escape_control: bool) -> Self {
+        Escaper {
+            escape_control,
+            last: 0,
+        }
+    }
+
+    fn escape(&mut self, data: &[u8], encoded: &mut Vec<u8>) {
+        for &b in data {
+            let escape = match b {
+                ZDLE | 0x10 | 0x11 | 0x13 | 0x90 | 0x91 | 0x93 => true,
+                0x0D | 0x8D => self.escape_control || self.last & 0x7F == b'@',
+                _ => self.escape_control && b & 0x60 == 0,
+            };
+            if escape {
+                encoded.push(ZDLE);
+                encoded.push(b ^ 0x40);
+            } else {
+                encoded.push(b);
+            }
+            self.last = b;
+        }
+    }
+}
+
+/// Something read by a `Decoder`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Event {
+    Header(Header),
+    /// A data subpacket following a header, with the character it ended with
+    Data { data: Vec<u8>, end: u8 },
+    /// A header or data subpacket was damaged. Everything up to the next header is dropped.
+    Corrupted,
+    /// The other end canceled the transfer
+    Canceled,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum DecodeState {
+    /// Looking for ZPAD
+    Idle,
+    /// Seen ZPAD, waiting for ZDLE
+    Pad,
+    /// Seen ZPAD ZDLE, waiting for the header encoding
+    Start,
+    HexHeader,
+    BinHeader(bool),
+    /// Reading a data subpacket, using a CRC-32 if set
+    Data(bool),
+    /// Reading the CRC of a data subpacket that ended with the given character
+    DataCrc(bool, u8),
+}
+
+/// Reads headers and data subpackets from received bytes.
+pub struct Decoder {
+    state: DecodeState,
+    buf: Vec<u8>,
+    /// How much of `buf` is data when reading the CRC of a data subpacket
+    data_len: usize,
+    /// Whether the last byte was a ZDLE escaping the next one
+    escaped: bool,
+    /// How many ZDLEs (which are CANs) were received in a row
+    cancels: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Decoder {
+            state: DecodeState::Idle,
+            buf: Vec::new(),
+            data_len: 0,
+            escaped: false,
+            cancels: 0,
+        }
+    }
+}
+
+impl Decoder {
+    /// Decodes `byte`, returning an event if it completes something.
+    pub fn decode(&mut self, byte: u8) -> Option<Event> {
+        if byte == ZDLE {
+            self.cancels += 1;
+            if self.cancels == 5 {
+                self.reset();
+                return Some(Event::Canceled);
+            }
+        } else {
+            self.cancels = 0;
+        }
+
+        match self.state {
+            DecodeState::Idle => {
+                if byte == ZPAD {
+                    self.state = DecodeState::Pad;
+                }
+                None
+            }
+            DecodeState::Pad => {
+                self.state = match byte {
+                    ZPAD => DecodeState::Pad,
+                    ZDLE => DecodeState::Start,
+                    _ => DecodeState::Idle,
+                };
+                None
+            }
+            DecodeState::Start => {
+                self.buf.clear();
+                self.escaped = false;
+                self.state = match byte {
+                    ZHEX => DecodeState::HexHeader,
+                    ZBIN => DecodeState::BinHeader(false),
+                    ZBIN32 => DecodeState::BinHeader(true),
+                    _ => DecodeState::Idle,
+                };
+                None
+            }
+            DecodeState::HexHeader => {
+                let digit = match (byte as char).to_digit(16) {
+                    Some(digit) => digit as u8,
+                    None => return Some(self.corrupted()),
+                };
+                self.buf.push(digit);
+                if self.buf.len() < 14 {
+                    return None;
+                }
+                let bytes: Vec<u8> = self.buf.chunks(2).map(|c| (c[0] << 4) | c[1]).collect();
+                let crc = ((bytes[5] as u16) << 8) | bytes[6] as u16;
+                if crc16(&bytes[..5]) != crc {
+                    return Some(self.corrupted());
+                }
+                Some(self.header(&bytes, Encoding::Hex))
+            }
+            DecodeState::BinHeader(crc32) => {
+                let b = match self.unescape(byte) {
+                    Some(Ok(b)) => b,
+                    Some(Err(_)) => return Some(self.corrupted()),
+                    None => return None,
+                };
+                self.buf.push(b);
+                if self.buf.len() < if crc32 { 9 } else { 7 } {
+                    return None;
+                }
+                if crc(&self.buf[..5], crc32)[..] != self.buf[5..] {
+                    return Some(self.corrupted());
+                }
+                let bytes = self.buf.clone();
+                Some(self.header(&bytes, if crc32 { Encoding::Bin32 } else { Encoding::Bin16 }))
+            }
+            DecodeState::Data(crc32) => {
+                match self.unescape(byte) {
+                    Some(Ok(b)) => {
+                        self.buf.push(b);
+                        if self.buf.len() > MAX_SUBPACKET_LEN {
+                            return Some(self.corrupted());
+                        }
+                    }
+                    Some(Err(end)) => {
+                        self.data_len = self.buf.len();
+                        self.buf.push(end);
+                        self.state = DecodeState::DataCrc(crc32, end);
+                    }
+                    None => (),
+                }
+                None
+            }
+            DecodeState::DataCrc(crc32, end) => {
+                let b = match self.unescape(byte) {
+                    Some(Ok(b)) => b,
+                    Some(Err(_)) => return Some(self.corrupted()),
+                    None => return None,
+                };
+                self.buf.push(b);
+                let crc_len = if crc32 { 4 } else { 2 };
+                if self.buf.len() < self.data_len + 1 + crc_len {
+                    return None;
+                }
+                if crc(&self.buf[..self.data_len + 1], crc32)[..] != self.buf[self.data_len + 1..] {
+                    return Some(self.corrupted());
+                }
+                let mut data = ::std::mem::replace(&mut self.buf, Vec::new());
+                data.truncate(self.data_len);
+                self.state = match end {
+                    ZCRCG | ZCRCQ => DecodeState::Data(crc32),
+                    _ => DecodeState::Idle,
+                };
+                Some(Event::Data {
+                         data,
+                         end,
+                     })
+            }
+        }
+    }
+
+    /// Stops reading the current frame, so that everything up to the next header is ignored.
+    pub fn reset(&mut self) {
+        self.state = DecodeState::Idle;
+        self.buf.clear();
+        self.escaped = false;
+    }
+
+    fn corrupted(&mut self) -> Event {
+        self.reset();
+        Event::Corrupted
+    }
+
+    /// Finishes reading a header from its bytes, getting ready for a data subpacket if one
+    /// follows.
+    fn header(&mut self, bytes: &[u8], encoding: Encoding) -> Event {
+        let header = Header {
+            kind: bytes[0],
+            data: [bytes[1], bytes[2], bytes[3], bytes[4]],
+        };
+        self.buf.clear();
+        self.escaped = false;
+        self.state = if header.has_data() {
+            DecodeState::Data(encoding == Encoding::Bin32)
+        } else {
+            DecodeState::Idle
+        };
+        Event::Header(header)
+    }
+
+    /// Undoes ZDLE escaping. Returns `None` if `byte` doesn't stand for anything by itself,
+    /// `Ok` with a data byte or `Err` with the end of a data subpacket.
+    fn unescape(&mut self, byte: u8) -> Option<Result<u8, u8>> {
+        if self.escaped {
+            self.escaped = false;
+            match byte {
+                ZCRCE | ZCRCG | ZCRCQ | ZCRCW => Some(Err(byte)),
+                ZRUB0 => Some(Ok(0x7F)),
+                ZRUB1 => Some(Ok(0xFF)),
+                b if b & 0x60 == 0x40 => Some(Ok(b ^ 0x40)),
+                // Some other character after ZDLE is line noise, which the CRC will catch
+                b => Some(Ok(b)),
+            }
+        } else {
+            match byte {
+                ZDLE => {
+                    self.escaped = true;
+                    None
+                }
+                // Flow control characters are never part of the data
+                0x11 | 0x13 | 0x91 | 0x93 => None,
+                b => Some(Ok(b)),
+            }
+        }
+    }
+}
+
+/// Finds the start of a transfer from `sz` in received data: a ZRQINIT header.
+#[derive(Debug, Default)]
+pub struct Detector {
+    matched: usize,
+}
+
+impl Detector {
+    const PATTERN: &'static [u8] = b"**\x18B00";
+
+    /// Looks at received `data`, returning whether it completes a ZRQINIT header. Headers split
+    /// over several calls are found as well.
+    pub fn detect(&mut self, data: &[u8]) -> bool {
+        let mut found = false;
+        for &b in data {
+            if b == Self::PATTERN[self.matched] {
+                self.matched += 1;
+                if self.matched == Self::PATTERN.len() {
+                    self.matched = 0;
+                    found = true;
+                }
+            } else if b == ZPAD {
+                // Any number of pads can start a header
+                self.matched = if self.matched == 2 { 2 } else { 1 };
+            } else {
+                self.matched = 0;
+            }
+        }
+        found
+    }
+}
+
+/// Encodes the data subpacket following ZFILE, which holds the name and size of the file.
+fn file_info(name: &str, len: u64) -> Vec<u8> {
+    let mut info = Vec::new();
+    info.extend_from_slice(name.as_bytes());
+    info.push(0);
+    info.extend_from_slice(len.to_string().as_bytes());
+    info.push(0);
+    info
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum SendState {
+    /// Waiting for ZRINIT before sending the first file
+    Starting,
+    /// Waiting for the receiver to answer ZFILE with the position to start at
+    File,
+    /// Streaming a file's data
+    Data,
+    /// Waiting for the receiver to confirm ZEOF with ZRINIT
+    End,
+    /// Waiting for the receiver to confirm ZFIN
+    Finishing,
+}
+
+/// Sends a batch of files using ZMODEM.
+pub struct Sender<R> {
+    files: Vec<BatchFile<R>>,
+    /// The position of the file being sent in `files`
+    index: usize,
+    resume: bool,
+    state: SendState,
+    decoder: Decoder,
+    /// Whether the receiver can use CRC-32
+    crc32: bool,
+    /// Whether the receiver wants all control characters escaped
+    escape_control: bool,
+    /// The position in the file of the next data to send
+    position: u64,
+    /// The last header sent, which is sent again on timeouts
+    last_header: Vec<u8>,
+    /// The position the receiver last asked for data from
+    requested: u64,
+    retries: u32,
+    deadline: Instant,
+    /// Limits file data to the rate the port sends it
+    pacer: Pacer,
+    /// Bytes of the files before the current one
+    bytes_done: u64,
+    progress: Progress,
+}
+
+impl<R: Read + Seek> Sender<R> {
+    /// Creates a sender for `files`, paced for a port using `settings`. With `resume`, the
+    /// receiver is asked to continue files it already has part of.
+    pub fn new(files: Vec<BatchFile<R>>, resume: bool, settings: &SerialPortSettings) -> Self {
+        let total = files.iter().map(|f| f.len).sum();
+        let now = Instant::now();
+        Sender {
+            files,
+            index: 0,
+            resume,
+            state: SendState::Starting,
+            decoder: Decoder::default(),
+            crc32: false,
+            escape_control: false,
+            position: 0,
+            last_header: Vec::new(),
+            requested: 0,
+            retries: 0,
+            deadline: now,
+            pacer: Pacer::for_port(settings, None, now),
+            bytes_done: 0,
+            progress: Progress {
+                bytes: 0,
+                total: Some(total),
+                file: None,
+                lines: None,
+                stage: None,
+            },
+        }
+    }
+
+    fn send_header(&mut self, header: Vec<u8>, output: &mut Vec<u8>) {
+        output.extend_from_slice(&header);
+        self.last_header = header;
+    }
+
+    /// Announces the next file, or ends the session if all have been sent.
+    fn send_file(&mut self, output: &mut Vec<u8>) {
+        self.retries = 0;
+        if self.index >= self.files.len() {
+            self.state = SendState::Finishing;
+            let header = Header::new(ZFIN).encode_hex();
+            self.send_header(header, output);
+            return;
+        }
+
+        let conversion = if self.resume { ZCRESUM } else { ZCBIN };
+        let mut frame = Header::with_flags(ZFILE, conversion, 0)
+            .encode_binary(self.crc32, self.escape_control);
+        {
+            let file = &self.files[self.index];
+            let info = file_info(&file.name, file.len);
+            frame.extend(encode_data(&info, ZCRCW, self.crc32, self.escape_control));
+            self.progress.file = Some(FileProgress {
+                name: file.name.clone(),
+                index: self.index,
+                count: Some(self.files.len()),
+                bytes: 0,
+                total: Some(file.len),
+            });
+        }
+        self.state = SendState::File;
+        self.send_header(frame, output);
+    }
+
+    /// Starts sending the current file's data from `position`.
+    fn start_data(&mut self, position: u32, output: &mut Vec<u8>) -> Result<(), SerialError> {
+        let position = (position as u64).min(self.files[self.index].len);
+        self.files[self.index].file.seek(SeekFrom::Start(position))?;
+        self.position = position;
+        self.state = SendState::Data;
+        output.extend(Header::with_position(ZDATA, position as u32)
+            .encode_binary(self.crc32, self.escape_control));
+        Ok(())
+    }
+
+    /// Sends subpackets of the current file for as long as the pacer lets them through, ending the
+    /// file if it's all been sent.
+    fn send_data(&mut self, now: Instant, output: &mut Vec<u8>) -> Result<(), SerialError> {
+        while self.pacer.available(now) > 0 {
+            let start = output.len();
+            let mut data = vec![0; SUBPACKET_LEN];
+            let len = super::xmodem::read_full(&mut self.files[self.index].file, &mut data)?;
+            data.truncate(len);
+            self.position += len as u64;
+            let done = self.position >= self.files[self.index].len || len < SUBPACKET_LEN;
+            let end = if done { ZCRCE } else { ZCRCG };
+            output.extend(encode_data(&data, end, self.crc32, self.escape_control));
+            if let Some(ref mut file) = self.progress.file {
+                file.bytes = self.position;
+            }
+            self.progress.bytes = self.bytes_done + self.position;
+            self.pacer.consume(output.len() - start);
+            if done {
+                self.state = SendState::End;
+                self.retries = 0;
+                let header = Header::with_position(ZEOF, self.position as u32)
+                    .encode_binary(self.crc32, self.escape_control);
+                self.send_header(header, output);
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles a header from the receiver. Returns whether the transfer is complete.
+    fn handle_header(&mut self,
+                     header: Header,
+                     output: &mut Vec<u8>)
+                     -> Result<bool, SerialError> {
+        match (self.state, header.kind) {
+            (SendState::Starting, ZRINIT) => {
+                self.crc32 = header.zf0() & CANFC32 != 0;
+                self.escape_control = header.zf0() & ESCCTL != 0;
+                self.send_file(output);
+            }
+            (SendState::File, ZRPOS) |
+            (SendState::Data, ZRPOS) |
+            (SendState::End, ZRPOS) => {
+                debug!("Receiver asked for data from {}", header.position());
+                // Only give up if the receiver doesn't get any further
+                if self.state == SendState::File || header.position() as u64 > self.requested {
+                    self.retries = 0;
+                } else {
+                    self.retries += 1;
+                    if self.retries > MAX_RETRIES {
+                        let msg = "the receiver kept rejecting data";
+                        return Err(SerialError::TransferFailed(msg.to_string()));
+                    }
+                }
+                self.requested = header.position() as u64;
+                self.start_data(header.position(), output)?;
+            }
+            (SendState::File, ZSKIP) |
+            (SendState::End, ZRINIT) => {
+                self.bytes_done += self.files[self.index].len;
+                self.progress.bytes = self.bytes_done;
+                self.index += 1;
+                self.send_file(output);
+            }
+            // The receiver didn't get the file header
+            (SendState::File, ZRINIT) |
+            (SendState::File, ZNAK) |
+            (SendState::End, ZNAK) |
+            (SendState::Finishing, ZNAK) => {
+                let header = self.last_header.clone();
+                output.extend(header);
+            }
+            (SendState::Finishing, ZFIN) => {
+                output.extend_from_slice(b"OO");
+                return Ok(true);
+            }
+            (_, ZFERR) | (_, ZABORT) => {
+                let msg = "the receiver couldn't write the file";
+                return Err(SerialError::TransferFailed(msg.to_string()));
+            }
+            // Anything else is ignored, including the ZACKs receivers send now and then
+            _ => (),
+        }
+        Ok(false)
+    }
+}
+
+impl<R: Read + Seek> Transfer for Sender<R> {
+    fn process(&mut self,
+               input: &[u8],
+               now: Instant,
+               output: &mut Vec<u8>)
+               -> Result<Status, SerialError> {
+        if self.last_header.is_empty() {
+            output.extend_from_slice(RZ_COMMAND);
+            let header = Header::new(ZRQINIT).encode_hex();
+            self.send_header(header, output);
+            self.deadline = now + Duration::from_secs(START_INTERVAL_SECS);
+        }
+
+        for &b in input {
+            let header = match self.decoder.decode(b) {
+                Some(Event::Header(header)) => header,
+                Some(Event::Canceled) => {
+                    let msg = "canceled by the receiver";
+                    return Err(SerialError::TransferFailed(msg.to_string()));
+                }
+                _ => continue,
+            };
+            if self.handle_header(header, output)? {
+                return Ok(Status::Complete);
+            }
+            self.deadline = now + Duration::from_secs(RESPONSE_TIMEOUT_SECS);
+        }
+
+        if self.state == SendState::Data {
+            self.send_data(now, output)?;
+            // Carry on once the port has sent what's been written
+            let capacity = self.pacer.capacity();
+            self.deadline = self.pacer.ready_at(capacity);
+            if self.state == SendState::End {
+                self.deadline = now + Duration::from_secs(RESPONSE_TIMEOUT_SECS);
+            }
+        } else if now >= self.deadline {
+            self.retries += 1;
+            if self.retries > MAX_RETRIES {
+                let msg = if self.state == SendState::Starting {
+                    "the receiver didn't start the transfer"
+                } else {
+                    "the receiver stopped responding"
+                };
+                return Err(SerialError::TransferFailed(msg.to_string()));
+            }
+            debug!("Timed out waiting for the receiver, sending again");
+            output.extend_from_slice(&self.last_header);
+            let interval = if self.state == SendState::Starting {
+                START_INTERVAL_SECS
+            } else {
+                TIMEOUT_SECS
+            };
+            self.deadline = now + Duration::from_secs(interval);
+        }
+        Ok(Status::InProgress)
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        Some(self.deadline)
+    }
+
+    fn progress(&self) -> Progress {
+        self.progress.clone()
+    }
+
+    fn cancel(&mut self, output: &mut Vec<u8>) {
+        output.extend_from_slice(&CANCEL);
+    }
+}
+
+/// Receives a batch of files using ZMODEM into a directory, naming them as the sender does.
+///
+/// Existing files are overwritten, unless a partially received file is resumed. That happens
+/// when the receiver is created with `resume` or the sender asks for it, and the existing file is
+/// shorter than the one being sent.
+pub struct Receiver {
+    dir: PathBuf,
+    resume: bool,
+    decoder: Decoder,
+    /// The last header received, which data subpackets belong to
+    last_header: Option<Header>,
+    /// The file being received
+    file: Option<File>,
+    /// The position in the file of the next data expected
+    position: u64,
+    /// Whether data is dropped until the sender restarts at `position`
+    skipping: bool,
+    /// How many files have been received
+    files: usize,
+    /// Whether the session has been started with ZRINIT
+    started: bool,
+    retries: u32,
+    deadline: Instant,
+    progress: Progress,
+}
+
+impl Receiver {
+    /// Creates a receiver writing files into `dir`, which must exist.
+    pub fn new<P: AsRef<Path>>(dir: P, resume: bool) -> Self {
+        Receiver {
+            dir: dir.as_ref().to_path_buf(),
+            resume,
+            decoder: Decoder::default(),
+            last_header: None,
+            file: None,
+            position: 0,
+            skipping: false,
+            files: 0,
+            started: false,
+            retries: 0,
+            deadline: Instant::now(),
+            progress: Progress::default(),
+        }
+    }
+
+    fn send_init(&self, output: &mut Vec<u8>) {
+        output.extend(Header::with_flags(ZRINIT, CANFDX | CANOVIO | CANFC32, 0).encode_hex());
+    }
+
+    /// Asks the sender to continue from `self.position`, dropping everything until it does.
+    fn request_position(&mut self, output: &mut Vec<u8>) -> Result<(), SerialError> {
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            output.extend_from_slice(&CANCEL);
+            return Err(SerialError::TransferFailed("too much data was damaged".to_string()));
+        }
+        self.skipping = true;
+        self.decoder.reset();
+        output.extend(Header::with_position(ZRPOS, self.position as u32).encode_hex());
+        Ok(())
+    }
+
+    /// Opens the file announced by ZFILE and tells the sender where to start.
+    fn start_file(&mut self,
+                  conversion: u8,
+                  info: &[u8],
+                  output: &mut Vec<u8>)
+                  -> Result<(), SerialError> {
+        let (name, len) = decode_header(info);
+        let file_name = match Path::new(&name).file_name() {
+            Some(file_name) => file_name.to_os_string(),
+            None => {
+                output.extend_from_slice(&CANCEL);
+                let msg = format!("the sender sent an invalid file name '{}'", name);
+                return Err(SerialError::TransferFailed(msg));
+            }
+        };
+        let path = self.dir.join(file_name);
+
+        // Resume files that are shorter than what's being sent
+        let existing = path.metadata().ok().filter(|m| m.is_file()).map(|m| m.len());
+        let resume = (self.resume || conversion == ZCRESUM) && existing.is_some();
+        let received = match (existing, len) {
+            (Some(existing), Some(len)) => existing >= len,
+            // Without a size there's no telling, so the sender is asked for the rest
+            _ => false,
+        };
+        if resume && received {
+            info!("Skipping {:?}, which has been received already", path);
+            self.files += 1;
+            output.extend(Header::new(ZSKIP).encode_hex());
+            return Ok(());
+        }
+        let opened = if resume {
+            OpenOptions::new().append(true).open(&path)
+        } else {
+            File::create(&path)
+        };
+        let file = match opened {
+            Ok(file) => file,
+            Err(e) => {
+                output.extend(Header::new(ZFERR).encode_hex());
+                return Err(SerialError::from_file_error(&path, e));
+            }
+        };
+        self.position = if resume { existing.unwrap_or(0) } else { 0 };
+        info!("Receiving {:?} ({:?} bytes) from {}", path, len, self.position);
+
+        self.file = Some(file);
+        self.retries = 0;
+        self.skipping = false;
+        self.progress.file = Some(FileProgress {
+            name,
+            index: self.files,
+            count: None,
+            bytes: self.position,
+            total: len,
+        });
+        output.extend(Header::with_position(ZRPOS, self.position as u32).encode_hex());
+        Ok(())
+    }
+
+    /// Handles a header from the sender. Returns whether the transfer is complete.
+    fn handle_header(&mut self,
+                     header: Header,
+                     output: &mut Vec<u8>)
+                     -> Result<bool, SerialError> {
+        match header.kind {
+            ZRQINIT => self.send_init(output),
+            ZDATA => {
+                if self.file.is_none() {
+                    // Data for a file that was skipped or is already done
+                    self.skipping = true;
+                    self.decoder.reset();
+                } else if header.position() as u64 == self.position {
+                    self.skipping = false;
+                } else if !self.skipping {
+                    self.request_position(output)?;
+                } else {
+                    self.decoder.reset();
+                }
+            }
+            // A ZEOF that doesn't match is from before the sender went back, so it's ignored
+            ZEOF if header.position() as u64 == self.position => {
+                if let Some(mut file) = self.file.take() {
+                    file.flush()?;
+                    self.files += 1;
+                }
+                self.send_init(output);
+            }
+            ZFIN => {
+                output.extend(Header::new(ZFIN).encode_hex());
+                return Ok(true);
+            }
+            // ZFILE and ZSINIT are handled with their data
+            _ => (),
+        }
+        Ok(false)
+    }
+
+    /// Handles a data subpacket following `header`.
+    fn handle_data(&mut self,
+                   header: Header,
+                   data: &[u8],
+                   end: u8,
+                   output: &mut Vec<u8>)
+                   -> Result<(), SerialError> {
+        match header.kind {
+            ZFILE => self.start_file(header.zf0(), data, output)?,
+            ZSINIT => output.extend(Header::new(ZACK).encode_hex()),
+            ZDATA if !self.skipping && self.file.is_some() => {
+                if let Some(ref mut file) = self.file {
+                    file.write_all(data)?;
+                }
+                self.position += data.len() as u64;
+                self.progress.bytes += data.len() as u64;
+                if let Some(ref mut file) = self.progress.file {
+                    file.bytes = self.position;
+                }
+                self.retries = 0;
+                if end == ZCRCW || end == ZCRCQ {
+                    output.extend(Header::with_position(ZACK, self.position as u32).encode_hex());
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+impl Transfer for Receiver {
+    fn process(&mut self,
+               input: &[u8],
+               now: Instant,
+               output: &mut Vec<u8>)
+               -> Result<Status, SerialError> {
+        if !self.started {
+            self.started = true;
+            self.send_init(output);
+            self.deadline = now + Duration::from_secs(START_INTERVAL_SECS);
+        }
+
+        for &b in input {
+            match self.decoder.decode(b) {
+                Some(Event::Header(header)) => {
+                    self.last_header = Some(header);
+                    if self.handle_header(header, output)? {
+                        return Ok(Status::Complete);
+                    }
+                }
+                Some(Event::Data { data, end }) => {
+                    if let Some(header) = self.last_header {
+                        self.handle_data(header, &data, end, output)?;
+                    }
+                }
+                Some(Event::Corrupted) => {
+                    debug!("Received a damaged frame");
+                    if self.file.is_some() {
+                        self.request_position(output)?;
+                    } else {
+                        output.extend(Header::new(ZNAK).encode_hex());
+                    }
+                }
+                Some(Event::Canceled) => {
+                    let msg = "canceled by the sender";
+                    return Err(SerialError::TransferFailed(msg.to_string()));
+                }
+                None => continue,
+            }
+            self.deadline = now + Duration::from_secs(TIMEOUT_SECS);
+        }
+
+        if now >= self.deadline {
+            debug!("Timed out waiting for the sender");
+            if self.file.is_some() {
+                self.request_position(output)?;
+            } else {
+                self.retries += 1;
+                if self.retries > MAX_RETRIES {
+                    let msg = "the sender stopped responding";
+                    return Err(SerialError::TransferFailed(msg.to_string()));
+                }
+                self.send_init(output);
+            }
+            self.deadline = now + Duration::from_secs(TIMEOUT_SECS);
+        }
+        Ok(Status::InProgress)
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        Some(self.deadline)
+    }
+
+    fn progress(&self) -> Progress {
+        self.progress.clone()
+    }
+
+    fn cancel(&mut self, output: &mut Vec<u8>) {
+        output.extend_from_slice(&CANCEL);
+    }
+}
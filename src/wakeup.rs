@@ -0,0 +1,211 @@
+//! Lets the port thread sleep until either its port has data or a new command arrives.
+//!
+//! Every `SerialCommand` sent through a `CommandSender` also signals a `Wakeup`, which the port
+//! thread blocks on (together with its port) while it has nothing else to do. On Unix this is a
+//! self-pipe so it can be polled alongside the port's file descriptor.
+
+use std::io;
+use std::sync::Arc;
+use std::sync::mpsc::{SendError, Sender};
+use std::time::Duration;
+
+use SerialCommand;
+
+/// Creates a linked `Waker` and `Wakeup` pair.
+pub(crate) fn pair() -> io::Result<(Waker, Wakeup)> {
+    let signal = Arc::new(imp::Signal::new()?);
+    Ok((Waker { signal: signal.clone() }, Wakeup { signal }))
+}
+
+/// Wakes up the port thread from another thread.
+#[derive(Clone)]
+pub(crate) struct Waker {
+    signal: Arc<imp::Signal>,
+}
+
+impl Waker {
+    pub fn wake(&self) {
+        self.signal.set();
+    }
+}
+
+/// The receiving end of wakeups sent to the port thread. Stays signalled until it's cleared.
+pub struct Wakeup {
+    signal: Arc<imp::Signal>,
+}
+
+impl Wakeup {
+    /// Blocks until this has been signalled or `timeout` has elapsed, returning whether it was
+    /// signalled. Waits forever if `timeout` is `None`.
+    pub fn wait(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        self.signal.wait(timeout)
+    }
+
+    /// Resets the signalled state.
+    pub(crate) fn clear(&self) {
+        self.signal.clear();
+    }
+}
+
+#[cfg(unix)]
+impl ::std::os::unix::io::AsRawFd for Wakeup {
+    /// Returns a descriptor that polls as readable while this is signalled.
+    fn as_raw_fd(&self) -> ::std::os::unix::io::RawFd {
+        self.signal.read_fd
+    }
+}
+
+/// Sends commands to the port thread, waking it up if it's waiting.
+#[derive(Clone)]
+pub struct CommandSender {
+    tx: Sender<SerialCommand>,
+    waker: Waker,
+}
+
+impl CommandSender {
+    pub(crate) fn new(tx: Sender<SerialCommand>, waker: Waker) -> Self {
+        CommandSender { tx, waker }
+    }
+
+    /// Queues a command for the port thread. This only fails if the thread has exited.
+    pub fn send(&self, command: SerialCommand) -> Result<(), SendError<SerialCommand>> {
+        self.tx.send(command)?;
+        self.waker.wake();
+        Ok(())
+    }
+}
+
+/// Converts the timeout into the number of milliseconds expected by `poll()`, rounding up so that
+/// short waits don't turn into busy loops.
+#[cfg(unix)]
+pub(crate) fn poll_timeout(timeout: Option<Duration>) -> i32 {
+    match timeout {
+        Some(t) => {
+            let ms = t.as_secs()
+                .saturating_mul(1000)
+                .saturating_add((u64::from(t.subsec_nanos()) + 999_999) / 1_000_000);
+            ms.min(i32::max_value() as u64) as i32
+        }
+        None => -1,
+    }
+}
+
+/// Converts errors from `nix` into standard I/O errors.
+#[cfg(unix)]
+pub(crate) fn nix_to_io(e: ::nix::Error) -> io::Error {
+    match e {
+        ::nix::Error::Sys(errno) => io::Error::from(errno),
+        e => io::Error::new(io::ErrorKind::Other, e),
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::io;
+    use std::os::unix::io::RawFd;
+    use std::time::Duration;
+
+    use nix::errno::Errno;
+    use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
+    use nix::poll::{poll, EventFlags, PollFd};
+    use nix::unistd::{close, pipe, read, write};
+
+    use super::{nix_to_io, poll_timeout};
+
+    /// A non-blocking self-pipe that is readable while signalled.
+    pub struct Signal {
+        pub read_fd: RawFd,
+        write_fd: RawFd,
+    }
+
+    impl Signal {
+        pub fn new() -> io::Result<Self> {
+            let (read_fd, write_fd) = pipe().map_err(nix_to_io)?;
+            let signal = Signal { read_fd, write_fd };
+            for &fd in &[read_fd, write_fd] {
+                fcntl(fd, FcntlArg::F_SETFL(OFlag::O_NONBLOCK)).map_err(nix_to_io)?;
+                fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).map_err(nix_to_io)?;
+            }
+            Ok(signal)
+        }
+
+        pub fn set(&self) {
+            // A full pipe means that a wakeup is already pending, so that's not an error.
+            match write(self.write_fd, &[0]) {
+                Ok(_) | Err(::nix::Error::Sys(Errno::EAGAIN)) => (),
+                Err(e) => error!("Failed to signal port thread: {}", e),
+            }
+        }
+
+        pub fn clear(&self) {
+            let mut buf = [0u8; 64];
+            while let Ok(n) = read(self.read_fd, &mut buf) {
+                if n == 0 {
+                    break;
+                }
+            }
+        }
+
+        pub fn wait(&self, timeout: Option<Duration>) -> io::Result<bool> {
+            let mut fds = [PollFd::new(self.read_fd, EventFlags::POLLIN)];
+            match poll(&mut fds, poll_timeout(timeout)) {
+                Ok(n) => Ok(n > 0),
+                Err(::nix::Error::Sys(Errno::EINTR)) => Ok(false),
+                Err(e) => Err(nix_to_io(e)),
+            }
+        }
+    }
+
+    impl Drop for Signal {
+        fn drop(&mut self) {
+            let _ = close(self.read_fd);
+            let _ = close(self.write_fd);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use std::io;
+    use std::sync::{Condvar, Mutex};
+    use std::time::{Duration, Instant};
+
+    /// A flag that can be waited on until it's set.
+    pub struct Signal {
+        flag: Mutex<bool>,
+        condvar: Condvar,
+    }
+
+    impl Signal {
+        pub fn new() -> io::Result<Self> {
+            Ok(Signal { flag: Mutex::new(false), condvar: Condvar::new() })
+        }
+
+        pub fn set(&self) {
+            *self.flag.lock().unwrap() = true;
+            self.condvar.notify_one();
+        }
+
+        pub fn clear(&self) {
+            *self.flag.lock().unwrap() = false;
+        }
+
+        pub fn wait(&self, timeout: Option<Duration>) -> io::Result<bool> {
+            let deadline = timeout.map(|t| Instant::now() + t);
+            let mut flag = self.flag.lock().unwrap();
+            while !*flag {
+                match deadline {
+                    Some(d) => {
+                        let now = Instant::now();
+                        if now >= d {
+                            break;
+                        }
+                        flag = self.condvar.wait_timeout(flag, d - now).unwrap().0;
+                    }
+                    None => flag = self.condvar.wait(flag).unwrap(),
+                }
+            }
+            Ok(*flag)
+        }
+    }
+}
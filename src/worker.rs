@@ -0,0 +1,1040 @@
+//! The event loop run by the port thread behind every `SerialThread`.
+//!
+//! The thread sleeps until its port has data, a command arrives, or a timer (a file transfer's
+//! deadline, ending a break, checking the control lines, rescanning ports) expires, so it's idle
+//! while nothing is happening.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::Cursor;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use std::time::{Duration, Instant};
+
+use chrono::Local;
+use serialport;
+use serialport::prelude::*;
+
+use backend::{Backend, Port, Readiness};
+use capture::{Direction, LogFile, LoggedPort};
+use error::SerialError;
+use transfer::hex::{self, Image, RecordSender};
+use transfer::{kermit, stk500, stm32, xmodem, ymodem, zmodem, BatchFile, FlashOptions, ImageOptions,
+               LinePacing, Protocol, RateMeter, RawSender, Status, TextSender, Transfer};
+use wakeup::Wakeup;
+use {format_settings, ControlLines, QueuedFile, Repeat, SerialCommand, SerialResponse};
+
+/// How much data is read from the port at once. This is large enough to hold more than the
+/// kernel's receive buffer so that high baud rates don't overrun.
+const READ_BUFFER_SIZE: usize = 16 * 1024;
+
+/// How long to back off when waiting for port activity fails.
+const WAIT_RETRY_MS: u64 = 10;
+
+/// How often the progress of a transfer is reported, in milliseconds.
+const PROGRESS_INTERVAL_MS: u64 = 250;
+
+/// How often the system is scanned for new and removed ports, in seconds.
+const PORT_SCAN_INTERVAL_SECS: u64 = 5;
+
+/// How often the input control lines of the open port are checked for changes. Ports don't
+/// signal these changes through `Port::wait()`, so they have to be polled.
+const CONTROL_LINES_INTERVAL_MS: u64 = 100;
+
+/// Files being sent one after another with `SerialCommand::SendQueue`.
+struct Queue {
+    files: Vec<QueuedFile>,
+    gap: Duration,
+    repeat: Repeat,
+    /// The file being sent, or the next one while waiting between files
+    index: usize,
+    /// How many times all files have been sent
+    passes_done: u32,
+    /// When the next file is started, while waiting between files
+    next_start: Option<Instant>,
+}
+
+pub struct Worker<B: Backend, F> {
+    backend: B,
+    callback: F,
+    commands: Receiver<SerialCommand>,
+    responses: Sender<SerialResponse>,
+    wakeup: Wakeup,
+
+    port: Option<B::Port>,
+    settings: SerialPortSettings,
+    serial_buf: Vec<u8>,
+    /// Data waiting to be sent until the port can take it
+    output: VecDeque<u8>,
+    control_lines: ControlLines,
+    poll_control_lines: bool,
+    last_control_lines_time: Instant,
+    break_end: Option<Instant>,
+
+    transfer: Option<Box<dyn Transfer>>,
+    /// Data received for the transfer that it hasn't been given yet, as its earlier output is
+    /// still waiting to be sent
+    transfer_input: Vec<u8>,
+    /// The queue of files being sent, if the transfer is part of one
+    queue: Option<Queue>,
+    /// Measures how fast the transfer is going
+    rate_meter: RateMeter,
+    /// When progress was last reported and how many bytes were done then
+    last_progress: Option<(Instant, u64)>,
+    /// The index and percentage of the file last reported for a batch transfer
+    last_file_progress: Option<(usize, Option<u8>)>,
+    /// The lines of a transfer going line by line that have been reported as sent
+    last_line: usize,
+    /// The stage of the transfer that was last reported
+    last_stage: Option<String>,
+    /// How `Protocol::Text` sends are paced
+    line_pacing: LinePacing,
+    /// The rate `Protocol::Raw` sends are limited to instead of the port's, in bytes per second
+    throughput: Option<u32>,
+    /// How `Protocol::HexImage` and `Protocol::HexRecords` sends are made
+    image_options: ImageOptions,
+    /// How `Protocol::Stm32` and `Protocol::Stk500` sends flash the image
+    flash_options: FlashOptions,
+    /// Looks for a ZMODEM sender starting a transfer while no transfer is running
+    zmodem_detector: zmodem::Detector,
+
+    write_file: Option<LogFile>,
+
+    last_port_scan_time: Instant,
+}
+
+impl<B: Backend, F: Fn()> Worker<B, F> {
+    pub fn new(backend: B,
+               callback: F,
+               commands: Receiver<SerialCommand>,
+               responses: Sender<SerialResponse>,
+               wakeup: Wakeup)
+               -> Self {
+        Worker {
+            backend,
+            callback,
+            commands,
+            responses,
+            wakeup,
+            port: None,
+            settings: Default::default(),
+            serial_buf: vec![0; READ_BUFFER_SIZE],
+            output: VecDeque::new(),
+            // Opening a port asserts DTR and RTS on most systems
+            control_lines: ControlLines { dtr: true, rts: true, ..Default::default() },
+            poll_control_lines: false,
+            last_control_lines_time: Instant::now(),
+            break_end: None,
+            transfer: None,
+            transfer_input: Vec::new(),
+            queue: None,
+            rate_meter: Default::default(),
+            last_progress: None,
+            last_file_progress: None,
+            last_line: 0,
+            last_stage: None,
+            line_pacing: Default::default(),
+            throughput: None,
+            image_options: Default::default(),
+            flash_options: Default::default(),
+            zmodem_detector: Default::default(),
+            write_file: None,
+            last_port_scan_time: Instant::now(),
+        }
+    }
+
+    /// Runs until the `SerialThread` that owns this worker is dropped.
+    pub fn run(&mut self) {
+        loop {
+            let timeout = self.next_deadline().map(|d| {
+                let now = Instant::now();
+                if d > now { d - now } else { Duration::from_secs(0) }
+            });
+
+            // Sleep until there's something to do
+            let write = !self.output.is_empty();
+            let wait_result = match self.port {
+                Some(ref mut p) => p.wait(&self.wakeup, timeout, write),
+                None => self.wakeup.wait(timeout).map(|_| Readiness::default()),
+            };
+            let ready = match wait_result {
+                Ok(r) => r,
+                Err(e) => {
+                    // Don't spin if waiting keeps failing
+                    error!("Failed to wait for port activity: {}", e);
+                    ::std::thread::sleep(Duration::from_millis(WAIT_RETRY_MS));
+                    Readiness::default()
+                }
+            };
+
+            // Clear the wakeup before handling commands so that any command sent from here on
+            // wakes up the next wait.
+            self.wakeup.clear();
+            loop {
+                match self.commands.try_recv() {
+                    Ok(command) => self.handle_command(command),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        info!("Port thread exiting");
+                        return;
+                    }
+                }
+            }
+
+            if ready.readable {
+                self.read_port();
+            }
+            if ready.writable {
+                self.write_port();
+            }
+
+            // A transfer waits for its earlier output to go out before it's given what was
+            // received meanwhile or a chance to produce more
+            if self.port.is_some() && self.output.is_empty() {
+                let transfer_deadline = self.transfer.as_ref().and_then(|t| t.deadline());
+                if !self.transfer_input.is_empty() ||
+                   transfer_deadline.map_or(false, |d| Instant::now() >= d) {
+                    let input = mem::replace(&mut self.transfer_input, Vec::new());
+                    self.run_transfer(&input);
+                }
+            }
+
+            let queue_start = self.queue.as_ref().and_then(|q| q.next_start);
+            if self.port.is_some() && queue_start.map_or(false, |t| Instant::now() >= t) {
+                self.start_queued_file();
+            }
+
+            if self.break_end.map_or(false, |end| Instant::now() >= end) {
+                self.end_break();
+            }
+
+            if self.port.is_some() && self.poll_control_lines &&
+               self.last_control_lines_time.elapsed() >=
+               Duration::from_millis(CONTROL_LINES_INTERVAL_MS) {
+                self.update_control_lines(false);
+            }
+
+            let port_scan_interval = Duration::from_secs(PORT_SCAN_INTERVAL_SECS);
+            if self.last_port_scan_time.elapsed() >= port_scan_interval {
+                self.scan_ports();
+            }
+        }
+    }
+
+    /// Returns when the next timer expires.
+    fn next_deadline(&self) -> Option<Instant> {
+        let port_scan_interval = Duration::from_secs(PORT_SCAN_INTERVAL_SECS);
+        let mut deadline = self.last_port_scan_time + port_scan_interval;
+        if self.port.is_some() {
+            // While output is waiting, the port becoming writable is what wakes things up
+            let transfer_deadline = self.transfer.as_ref().and_then(|t| t.deadline());
+            if let Some(transfer_deadline) = transfer_deadline.filter(|_| self.output.is_empty()) {
+                deadline = deadline.min(transfer_deadline);
+            }
+            if let Some(start) = self.queue.as_ref().and_then(|q| q.next_start) {
+                deadline = deadline.min(start);
+            }
+        }
+        if let Some(end) = self.break_end {
+            deadline = deadline.min(end);
+        }
+        if self.port.is_some() && self.poll_control_lines {
+            let check_time = self.last_control_lines_time +
+                             Duration::from_millis(CONTROL_LINES_INTERVAL_MS);
+            deadline = deadline.min(check_time);
+        }
+        Some(deadline)
+    }
+
+    /// Sends a response back to the `SerialThread` and notifies it.
+    fn respond(&self, response: SerialResponse) {
+        // This only fails if the receiving end has been dropped, in which case nobody's listening
+        // anymore and the thread will exit once the command channel closes as well.
+        if self.responses.send(response).is_ok() {
+            (self.callback)();
+        }
+    }
+
+    /// Acknowledges an attempt to apply `setting` to the open port. The new setting is only kept
+    /// if `result` is `Ok`, which it always is when no port is open as it's then only used once
+    /// one is.
+    fn setting_changed<U>(&mut self, setting: &str, result: serialport::Result<()>, update: U)
+        where U: FnOnce(&mut SerialPortSettings)
+    {
+        match result {
+            Ok(()) => {
+                update(&mut self.settings);
+                self.respond(SerialResponse::SettingsApplied(self.settings));
+            }
+            Err(e) => {
+                let error = SerialError::from_setting_error(setting, e);
+                error!("{}", error);
+                self.respond(SerialResponse::SettingsRejected {
+                                 settings: self.settings,
+                                 error,
+                             });
+            }
+        }
+    }
+
+    /// Starts using a newly-opened port, setting its output control lines to their last requested
+    /// levels.
+    fn port_opened(&mut self, mut port: B::Port, name: String) {
+        let lines = self.control_lines;
+        let result = port.write_data_terminal_ready(lines.dtr)
+            .and_then(|_| port.write_request_to_send(lines.rts));
+        if let Err(e) = result {
+            debug!("Failed to set control lines of '{}': {}", name, e);
+        }
+        self.port = Some(port);
+        self.respond(SerialResponse::OpenPortSuccess(name));
+
+        self.poll_control_lines = true;
+        self.update_control_lines(true);
+    }
+
+    /// Sets the level of the DTR line, or the level it's set to once a port is opened.
+    fn set_data_terminal_ready(&mut self, level: bool) {
+        info!("Setting DTR to {}", level);
+        let line = format!("DTR {}", if level { "high" } else { "low" });
+        let result = self.port.as_mut().map_or(Ok(()), |p| p.write_data_terminal_ready(level));
+        self.control_line_changed(&line, result, |l| l.dtr = level);
+    }
+
+    /// Sets the level of the RTS line, or the level it's set to once a port is opened.
+    fn set_request_to_send(&mut self, level: bool) {
+        info!("Setting RTS to {}", level);
+        let line = format!("RTS {}", if level { "high" } else { "low" });
+        let result = self.port.as_mut().map_or(Ok(()), |p| p.write_request_to_send(level));
+        self.control_line_changed(&line, result, |l| l.rts = level);
+    }
+
+    /// Acknowledges an attempt to set an output control line, with `update` applying the new
+    /// level if `result` is `Ok`.
+    fn control_line_changed<U>(&mut self, line: &str, result: serialport::Result<()>, update: U)
+        where U: FnOnce(&mut ControlLines)
+    {
+        match result {
+            Ok(()) => update(&mut self.control_lines),
+            Err(e) => {
+                let error = SerialError::from_setting_error(line, e);
+                error!("{}", error);
+                self.respond(SerialResponse::PortError(error));
+            }
+        }
+        if !(self.poll_control_lines && self.update_control_lines(true)) {
+            self.respond(SerialResponse::ControlLinesChanged(self.control_lines));
+        }
+    }
+
+    /// Reads the input control lines of the open port, reporting them if they've changed or
+    /// `always_report` is set. Returns whether they were reported.
+    ///
+    /// If the port can't report its control lines, they're not checked again until a port is
+    /// opened.
+    fn update_control_lines(&mut self, always_report: bool) -> bool {
+        self.last_control_lines_time = Instant::now();
+        let mut lines = self.control_lines;
+        let result = match self.port {
+            Some(ref mut p) => read_input_lines(p, &mut lines),
+            None => return false,
+        };
+        if let Err(e) = result {
+            info!("Port can't report its control lines: {}", e);
+            self.poll_control_lines = false;
+            return false;
+        }
+        if always_report || lines != self.control_lines {
+            debug!("Control lines: {:?}", lines);
+            self.control_lines = lines;
+            self.respond(SerialResponse::ControlLinesChanged(lines));
+            return true;
+        }
+        false
+    }
+
+    /// Stops the break that is being sent, reporting that it's been sent.
+    fn end_break(&mut self) {
+        self.break_end = None;
+        let result = match self.port {
+            Some(ref mut p) => p.clear_break(),
+            None => return,
+        };
+        match result {
+            Ok(()) => self.respond(SerialResponse::BreakSent),
+            Err(e) => {
+                let error = SerialError::from_setting_error("break", e);
+                error!("{}", error);
+                self.respond(SerialResponse::PortError(error));
+            }
+        }
+    }
+
+    /// Closes the open port, making sure it's not left sending a break.
+    fn close_port(&mut self) {
+        self.output.clear();
+        if let Some(mut p) = self.port.take() {
+            if self.break_end.take().is_some() {
+                let _ = p.clear_break();
+            }
+        }
+    }
+
+    fn handle_command(&mut self, command: SerialCommand) {
+        match command {
+            SerialCommand::ConnectToPort { name, baud } => {
+                self.settings.baud_rate = baud;
+                info!("Connecting to {} at {} with settings {:?}",
+                      &name,
+                      &baud,
+                      &self.settings);
+                match self.backend.open(&name, &self.settings) {
+                    Ok(p) => self.port_opened(p, name),
+                    Err(e) => {
+                        let error = SerialError::from_open_error(&name, baud, e);
+                        error!("Failed to open port: {}", error);
+                        self.respond(SerialResponse::OpenPortError(error));
+                    }
+                }
+            }
+            SerialCommand::ChangeBaud(baud) => {
+                info!("Changing baud to {}", baud);
+                let setting = format!("baud rate {}", baud);
+                let result = self.port.as_mut().map_or(Ok(()), |p| p.set_baud_rate(baud));
+                self.setting_changed(&setting, result, |s| s.baud_rate = baud);
+            }
+            SerialCommand::ChangeDataBits(data_bits) => {
+                info!("Changing data bits to {:?}", data_bits);
+                let setting = format!("data bits {:?}", data_bits);
+                let result = self.port.as_mut().map_or(Ok(()), |p| p.set_data_bits(data_bits));
+                self.setting_changed(&setting, result, |s| s.data_bits = data_bits);
+            }
+            SerialCommand::ChangeFlowControl(flow_control) => {
+                info!("Changing flow control to {:?}", flow_control);
+                let setting = format!("flow control {:?}", flow_control);
+                let result = self.port
+                    .as_mut()
+                    .map_or(Ok(()), |p| p.set_flow_control(flow_control));
+                self.setting_changed(&setting, result, |s| s.flow_control = flow_control);
+            }
+            SerialCommand::ChangeStopBits(stop_bits) => {
+                info!("Changing stop bits to {:?}", stop_bits);
+                let setting = format!("stop bits {:?}", stop_bits);
+                let result = self.port.as_mut().map_or(Ok(()), |p| p.set_stop_bits(stop_bits));
+                self.setting_changed(&setting, result, |s| s.stop_bits = stop_bits);
+            }
+            SerialCommand::ChangeParity(parity) => {
+                info!("Changing parity to {:?}", parity);
+                let setting = format!("parity {:?}", parity);
+                let result = self.port.as_mut().map_or(Ok(()), |p| p.set_parity(parity));
+                self.setting_changed(&setting, result, |s| s.parity = parity);
+            }
+            SerialCommand::ChangePort(name) => {
+                if self.port.is_some() {
+                    info!("Changing port to '{}' using settings {:?}",
+                          &name,
+                          &self.settings);
+
+                    self.close_port();
+                    // Whatever was being sent was meant for the old port
+                    let transferring = self.transfer.take().is_some();
+                    if self.queue.take().is_some() || transferring {
+                        self.respond(SerialResponse::SendingFileCanceled);
+                    }
+                    match self.backend.open(&name, &self.settings) {
+                        Ok(p) => self.port_opened(p, name),
+                        Err(e) => {
+                            let baud = self.settings.baud_rate;
+                            let error = SerialError::from_open_error(&name, baud, e);
+                            error!("Failed to change port: {}", error);
+                            self.respond(SerialResponse::OpenPortError(error));
+                        }
+                    }
+                }
+            }
+            SerialCommand::Disconnect => {
+                info!("Disconnecting");
+                self.close_port();
+                self.transfer = None;
+                self.queue = None;
+                self.write_file = None;
+                self.respond(SerialResponse::DisconnectSuccess);
+            }
+            SerialCommand::SetLinePacing(pacing) => {
+                info!("Pacing text sends with {:?}", pacing);
+                self.line_pacing = pacing;
+            }
+            SerialCommand::SetThroughput(throughput) => {
+                info!("Limiting raw sends to {:?} bytes per second", throughput);
+                self.throughput = throughput;
+            }
+            SerialCommand::SetImageOptions(options) => {
+                info!("Sending images with {:?}", options);
+                self.image_options = options;
+            }
+            SerialCommand::SetFlashOptions(options) => {
+                info!("Flashing with {:?}", options);
+                self.flash_options = options;
+            }
+            SerialCommand::SetDataTerminalReady(level) => self.set_data_terminal_ready(level),
+            SerialCommand::SetRequestToSend(level) => self.set_request_to_send(level),
+            SerialCommand::SendBreak { duration } => {
+                info!("Sending a break for {:?}", duration);
+                let result = match self.port {
+                    Some(ref mut p) => p.set_break(),
+                    None => {
+                        self.respond(SerialResponse::PortError(SerialError::NoPortOpen));
+                        return;
+                    }
+                };
+                match result {
+                    Ok(()) => self.break_end = Some(Instant::now() + duration),
+                    Err(e) => {
+                        let error = SerialError::from_setting_error("break", e);
+                        error!("{}", error);
+                        self.respond(SerialResponse::PortError(error));
+                    }
+                }
+            }
+            SerialCommand::SendData(d) => self.send(&d),
+            SerialCommand::SendFile { path, protocol } => self.send_files(vec![path], protocol),
+            SerialCommand::SendFiles { paths, protocol } => self.send_files(paths, protocol),
+            SerialCommand::SendQueue { files, gap, repeat } => self.send_queue(files, gap, repeat),
+            SerialCommand::ReceiveFile { path, protocol } => {
+                if self.port.is_none() {
+                    self.respond(SerialResponse::SendingFileError(SerialError::NoPortOpen));
+                    return;
+                }
+                match create_receiver(&path, protocol) {
+                    Ok(transfer) => {
+                        info!("Receiving into {:?} using {}", path, protocol);
+                        self.start_transfer(transfer);
+                    }
+                    Err(error) => {
+                        error!("Failed to receive {:?}: {}", path, error);
+                        self.respond(SerialResponse::SendingFileError(error));
+                    }
+                }
+            }
+            SerialCommand::CancelSendFile => {
+                self.queue = None;
+                if let Some(mut transfer) = self.transfer.take() {
+                    // What the transfer hasn't sent yet is dropped so that canceling it isn't
+                    // held up by a receiver that stopped reading
+                    self.output.clear();
+                    let mut output = Vec::new();
+                    transfer.cancel(&mut output);
+                    self.send(&output);
+                }
+                self.respond(SerialResponse::SendingFileCanceled);
+            }
+            SerialCommand::LogToFile { path: f, options } => {
+                if self.port.is_some() {
+                    info!("Logging to file {:?} with {:?}", f, options);
+                    let port = LoggedPort {
+                        name: self.port.as_ref().and_then(|p| p.name()).unwrap_or_default(),
+                        settings: format_settings(&self.settings),
+                    };
+                    match LogFile::create(&f, options, port, Local::now()) {
+                        Ok(log) => self.write_file = Some(log),
+                        Err(e) => {
+                            error!("Failed to create {:?}: {}", f, e);
+                            let error = SerialError::from_file_error(&f, e);
+                            self.respond(SerialResponse::LogToFileError(error));
+                        }
+                    }
+                } else {
+                    self.respond(SerialResponse::LogToFileError(SerialError::NoPortOpen));
+                }
+            }
+            SerialCommand::CancelLogToFile => {
+                if let Some(mut log) = self.write_file.take() {
+                    if let Err(e) = log.finish() {
+                        error!("Failed to finish the log file: {}", e);
+                        self.respond(SerialResponse::LogToFileError(SerialError::from(e)));
+                    }
+                }
+                self.respond(SerialResponse::LoggingFileCanceled);
+            }
+        }
+    }
+
+    /// Writes `data` that went through the port in `direction` to the log file if one's set up.
+    fn log(&mut self, direction: Direction, data: &[u8]) {
+        let result = match self.write_file {
+            Some(ref mut log) => log.log(direction, data, Local::now()),
+            None => return,
+        };
+        if let Err(e) = result {
+            error!("Failed to write to log file: {}", e);
+            self.write_file = None;
+            self.respond(SerialResponse::LogToFileError(SerialError::from(e)));
+        }
+    }
+
+    /// Queues `data` to be sent, sending as much of it right away as the port takes.
+    fn send(&mut self, data: &[u8]) {
+        if self.port.is_some() && !data.is_empty() {
+            self.output.extend(data);
+            self.write_port();
+        }
+    }
+
+    /// Sends as much of the queued output as the port takes without blocking. A failure ends the
+    /// running transfer, as the output is most likely part of it.
+    fn write_port(&mut self) {
+        while !self.output.is_empty() {
+            let result = match self.port {
+                Some(ref mut p) => p.write(self.output.as_slices().0),
+                None => return,
+            };
+            match result {
+                Ok(0) => break,
+                Ok(n) => {
+                    let sent: Vec<u8> = self.output.drain(..n).collect();
+                    self.log(Direction::Tx, &sent);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut ||
+                              e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => {
+                    error!("Failed to send {} bytes: {}", self.output.len(), e);
+                    self.output.clear();
+                    let error = SerialError::from(e);
+                    // A queue that's waiting between files gives up as well
+                    let transferring = self.transfer.take().is_some();
+                    if self.queue.take().is_some() || transferring {
+                        self.respond(SerialResponse::SendingFileError(error));
+                    } else {
+                        self.respond(SerialResponse::PortError(error));
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Reads all available data from the port and passes it on.
+    fn read_port(&mut self) {
+        let rx_data_len = match self.port {
+            Some(ref mut p) => {
+                match p.read(self.serial_buf.as_mut_slice()) {
+                    Ok(t) => t,
+                    Err(ref e) if e.kind() == io::ErrorKind::TimedOut ||
+                                  e.kind() == io::ErrorKind::WouldBlock ||
+                                  e.kind() == io::ErrorKind::Interrupted => 0,
+                    Err(e) => {
+                        // The port will keep reporting that it's readable, so drop it instead of
+                        // spinning until the next port scan notices it's gone.
+                        error!("Error reading from port: {}", e);
+                        self.port_lost();
+                        return;
+                    }
+                }
+            }
+            None => return,
+        };
+
+        if rx_data_len > 0 {
+            let data = self.serial_buf[..rx_data_len].to_vec();
+
+            self.log(Direction::Rx, &data);
+
+            // Data that's part of a file transfer is only passed on if the protocol doesn't use it
+            if self.transfer.as_ref().map_or(true, |t| t.shows_input()) {
+                self.respond(SerialResponse::Data(data.clone()));
+            }
+            if self.transfer.is_some() {
+                self.transfer_input.extend_from_slice(&data);
+            } else if self.zmodem_detector.detect(&data) {
+                info!("The other end started sending files using ZMODEM");
+                self.respond(SerialResponse::ZmodemDetected);
+            }
+        }
+    }
+
+    /// Starts sending the files at `paths` using `protocol`.
+    fn send_files(&mut self, paths: Vec<PathBuf>, protocol: Protocol) {
+        if self.port.is_none() {
+            self.respond(SerialResponse::SendingFileError(SerialError::NoPortOpen));
+            return;
+        }
+        match self.open_sender(paths, protocol) {
+            Ok(transfer) => self.start_transfer(transfer),
+            Err(e) => self.respond(SerialResponse::SendingFileError(e)),
+        }
+    }
+
+    /// Starts sending `files` one after another, waiting for `gap` after each one, and going
+    /// through them as often as `repeat` says.
+    fn send_queue(&mut self, files: Vec<QueuedFile>, gap: Duration, repeat: Repeat) {
+        if self.port.is_none() {
+            self.respond(SerialResponse::SendingFileError(SerialError::NoPortOpen));
+            return;
+        }
+        if files.is_empty() || repeat == Repeat::Times(0) {
+            let msg = "the queue is empty".to_string();
+            self.respond(SerialResponse::SendingFileError(SerialError::TransferFailed(msg)));
+            return;
+        }
+
+        info!("Sending a queue of {} files {:?} with {:?} between them",
+              files.len(),
+              repeat,
+              gap);
+        self.transfer = None;
+        self.respond(SerialResponse::SendingFileStarted);
+        self.queue = Some(Queue {
+                              files,
+                              gap,
+                              repeat,
+                              index: 0,
+                              passes_done: 0,
+                              next_start: None,
+                          });
+        self.start_queued_file();
+    }
+
+    /// Starts sending the current file of the queue, giving up on the whole queue if that fails.
+    fn start_queued_file(&mut self) {
+        let (file, progress) = match self.queue {
+            Some(ref mut queue) => {
+                queue.next_start = None;
+                let progress = SerialResponse::SendingQueueProgress {
+                    index: queue.index,
+                    count: queue.files.len(),
+                    pass: queue.passes_done + 1,
+                    passes: match queue.repeat {
+                        Repeat::Times(n) => Some(n),
+                        Repeat::Forever => None,
+                    },
+                };
+                (queue.files[queue.index].clone(), progress)
+            }
+            None => return,
+        };
+        match self.open_sender(vec![file.path], file.protocol) {
+            Ok(transfer) => {
+                self.respond(progress);
+                self.begin_transfer(transfer);
+            }
+            Err(e) => {
+                self.queue = None;
+                self.respond(SerialResponse::SendingFileError(e));
+            }
+        }
+    }
+
+    /// Moves the queue on to its next file once the current one is complete, which is started
+    /// once the gap between files has passed. Returns whether there's another file to send.
+    fn advance_queue(&mut self) -> bool {
+        let more = match self.queue {
+            Some(ref mut queue) => {
+                queue.index += 1;
+                if queue.index == queue.files.len() {
+                    queue.index = 0;
+                    queue.passes_done += 1;
+                }
+                queue.next_start = Some(Instant::now() + queue.gap);
+                match queue.repeat {
+                    Repeat::Times(n) => queue.passes_done < n,
+                    Repeat::Forever => true,
+                }
+            }
+            None => false,
+        };
+        if !more {
+            self.queue = None;
+        }
+        more
+    }
+
+    /// Creates a transfer sending the files at `paths` using `protocol`.
+    fn open_sender(&self,
+                   paths: Vec<PathBuf>,
+                   protocol: Protocol)
+                   -> Result<Box<dyn Transfer>, SerialError> {
+        if paths.len() != 1 && !protocol.is_batch() {
+            let msg = format!("{} can only send one file at a time", protocol);
+            return Err(SerialError::TransferFailed(msg));
+        }
+        // AN3155 has the bootloader use 8 data bits with even parity
+        if protocol == Protocol::Stm32 &&
+           (self.settings.data_bits != DataBits::Eight || self.settings.parity != Parity::Even) {
+            let msg = "the STM32 bootloader needs 8 data bits with even parity";
+            return Err(SerialError::UnsupportedSetting(msg.to_string()));
+        }
+
+        let mut files = Vec::with_capacity(paths.len());
+        for path in paths {
+            let opened = File::open(&path).and_then(|file| {
+                let len = file.metadata()?.len();
+                Ok((file, len))
+            });
+            match opened {
+                Ok((file, len)) => {
+                    info!("Sending file {:?} ({} bytes) using {}", path, len, protocol);
+                    let name = path.file_name().map_or(String::new(), |n| {
+                        n.to_string_lossy().into_owned()
+                    });
+                    files.push(BatchFile {
+                                   name,
+                                   file,
+                                   len,
+                               });
+                }
+                Err(e) => {
+                    error!("Failed to open {:?}: {}", path, e);
+                    return Err(SerialError::from_file_error(&path, e));
+                }
+            }
+        }
+
+        match protocol {
+            Protocol::Ymodem => Ok(Box::new(ymodem::Sender::new(files))),
+            Protocol::Zmodem { resume } => {
+                Ok(Box::new(zmodem::Sender::new(files, resume, &self.settings)))
+            }
+            Protocol::Kermit => Ok(Box::new(kermit::Sender::new(files))),
+            _ => {
+                let f = files.pop().expect("Exactly one file should be sent");
+                let name = f.name.clone();
+                self.create_sender(f, protocol).map_err(|e| {
+                    error!("Failed to read '{}': {}", name, e);
+                    e
+                })
+            }
+        }
+    }
+
+    /// Creates a transfer sending the single file `f` using `protocol`. Files that are read up
+    /// front are checked before anything is sent.
+    fn create_sender(&self,
+                     f: BatchFile<File>,
+                     protocol: Protocol)
+                     -> Result<Box<dyn Transfer>, SerialError> {
+        Ok(match protocol {
+               Protocol::Raw => {
+                   Box::new(RawSender::new(f.file, f.len, &self.settings, self.throughput))
+               }
+               Protocol::Text => Box::new(TextSender::new(f.file, self.line_pacing.clone())?),
+               Protocol::HexImage => {
+                   let (address, binary) = Image::read(f.file)?
+                       .to_binary(self.image_options.fill)?;
+                   info!("Sending the {} bytes decoded from '{}', starting at 0x{:08X}",
+                         binary.len(),
+                         f.name,
+                         address);
+                   let len = binary.len() as u64;
+                   let binary = Cursor::new(binary);
+                   Box::new(RawSender::new(binary, len, &self.settings, self.throughput))
+               }
+               Protocol::HexRecords => {
+                   let image = Image::read(f.file)?;
+                   Box::new(RecordSender::new(image, self.image_options.ack))
+               }
+               Protocol::Stm32 => {
+                   let segments = hex::load(f.file, &f.name, self.flash_options.address)?;
+                   Box::new(stm32::Flasher::new(segments, self.flash_options))
+               }
+               Protocol::Stk500 => {
+                   let image = Image::read(f.file)?;
+                   Box::new(stk500::Uploader::new(&image, self.flash_options)?)
+               }
+               _ => Box::new(xmodem::Sender::new(f.file, f.len, protocol)),
+           })
+    }
+
+    /// Starts running `transfer`, replacing any transfer or queue that's already running.
+    fn start_transfer(&mut self, transfer: Box<dyn Transfer>) {
+        self.queue = None;
+        self.respond(SerialResponse::SendingFileStarted);
+        self.begin_transfer(transfer);
+    }
+
+    /// Runs `transfer` from the start, without reporting that it started.
+    fn begin_transfer(&mut self, transfer: Box<dyn Transfer>) {
+        self.transfer = Some(transfer);
+        self.transfer_input.clear();
+        self.rate_meter.clear();
+        self.last_progress = None;
+        self.last_file_progress = None;
+        self.last_line = 0;
+        self.last_stage = None;
+        self.run_transfer(&[]);
+    }
+
+    /// Passes `input` to the running transfer and sends whatever it produces, reporting its
+    /// progress and when it's finished.
+    fn run_transfer(&mut self, input: &[u8]) {
+        let mut output = Vec::new();
+        let (result, lines) = match self.transfer {
+            Some(ref mut t) => {
+                let result = t.process(input, Instant::now(), &mut output);
+                (result, t.take_line_request())
+            }
+            None => return,
+        };
+
+        // The lines are set before the output that follows setting them is sent
+        if let Some(level) = lines.dtr {
+            self.set_data_terminal_ready(level);
+        }
+        if let Some(level) = lines.rts {
+            self.set_request_to_send(level);
+        }
+
+        self.send(&output);
+        if self.transfer.is_none() {
+            // Sending failed, which ended the transfer
+            return;
+        }
+
+        match result {
+            Ok(Status::InProgress) => {
+                let progress = match self.transfer {
+                    Some(ref t) => t.progress(),
+                    None => return,
+                };
+                if let Some(file) = progress.file {
+                    // Report when the transfer moves on to another file as well
+                    let percentage = file.percentage();
+                    let report = match self.last_file_progress {
+                        Some((index, last)) if index == file.index => {
+                            percentage.map_or(false, |p| p >= last.unwrap_or(0) + 5)
+                        }
+                        _ => true,
+                    };
+                    if report {
+                        self.last_file_progress = Some((file.index, percentage));
+                        self.respond(SerialResponse::SendingFileBatchProgress(file));
+                    }
+                }
+                if let Some((sent, total)) = progress.lines {
+                    if sent != self.last_line {
+                        self.last_line = sent;
+                        self.respond(SerialResponse::SendingLineProgress {
+                                         sent,
+                                         total,
+                                     });
+                    }
+                }
+                if progress.stage.is_some() && progress.stage != self.last_stage {
+                    self.last_stage = progress.stage.clone();
+                    self.respond(SerialResponse::TransferStage(progress.stage.unwrap_or_default()));
+                }
+                self.report_progress(progress.bytes, progress.total);
+            }
+            Ok(Status::Complete) => {
+                info!("File transfer complete");
+                self.transfer = None;
+                if !self.advance_queue() {
+                    self.respond(SerialResponse::SendingFileComplete);
+                }
+            }
+            Err(e) => {
+                error!("File transfer failed: {}", e);
+                self.transfer = None;
+                self.queue = None;
+                self.respond(SerialResponse::SendingFileError(e));
+            }
+        }
+    }
+
+    /// Reports the progress of the transfer every `PROGRESS_INTERVAL_MS`, and once it's done.
+    fn report_progress(&mut self, bytes: u64, total: Option<u64>) {
+        let now = Instant::now();
+        self.rate_meter.add(now, bytes);
+        let report = match self.last_progress {
+            _ if bytes == 0 => false,
+            None => true,
+            Some((time, last_bytes)) => {
+                let interval = Duration::from_millis(PROGRESS_INTERVAL_MS);
+                let finished = Some(bytes) == total && last_bytes != bytes;
+                (now.duration_since(time) >= interval && bytes != last_bytes) || finished
+            }
+        };
+        if report {
+            self.last_progress = Some((now, bytes));
+            let report = self.rate_meter.report(total);
+            self.respond(SerialResponse::SendingFileProgress(report));
+        }
+    }
+
+    /// Scans for ports, checking whether the open port has been disconnected.
+    fn scan_ports(&mut self) {
+        self.last_port_scan_time = Instant::now();
+        let mut ports = match self.backend.list_ports() {
+            Ok(ports) => ports,
+            Err(e) => {
+                warn!("Failed to scan for ports: {}", e);
+                return;
+            }
+        };
+        ports.sort();
+        debug!("Found ports: {:?}", &ports);
+
+        // Check if our port was disconnected
+        let disconnected = match self.port.as_ref().and_then(|p| p.name()) {
+            Some(name) => ports.binary_search_by(|p| p.name.cmp(&name)).is_err(),
+            None => false,
+        };
+        if disconnected {
+            self.close_port();
+            self.transfer = None;
+            self.queue = None;
+            self.respond(SerialResponse::UnexpectedDisconnection(ports));
+        } else {
+            self.respond(SerialResponse::PortsFound(ports));
+        }
+    }
+
+    /// Handles the open port failing, dropping it and reporting it as disconnected.
+    fn port_lost(&mut self) {
+        self.close_port();
+        self.transfer = None;
+        self.queue = None;
+        self.last_port_scan_time = Instant::now();
+        let mut ports = self.backend.list_ports().unwrap_or_default();
+        ports.sort();
+        self.respond(SerialResponse::UnexpectedDisconnection(ports));
+    }
+}
+
+/// Creates a transfer receiving into `path` using `protocol`. That's the file to create, or the
+/// directory to create files in for batch protocols.
+fn create_receiver(path: &Path, protocol: Protocol) -> Result<Box<dyn Transfer>, SerialError> {
+    match protocol {
+        Protocol::Raw | Protocol::Text | Protocol::HexImage | Protocol::HexRecords => {
+            let msg = "files can't be received without a protocol, log to a file instead";
+            Err(SerialError::TransferFailed(msg.to_string()))
+        }
+        Protocol::Stm32 | Protocol::Stk500 => {
+            let msg = "bootloaders can only be written to";
+            Err(SerialError::TransferFailed(msg.to_string()))
+        }
+        Protocol::Ymodem | Protocol::Zmodem { .. } | Protocol::Kermit if !path.is_dir() => {
+            let msg = format!("'{}' isn't a directory to receive files into", path.display());
+            Err(SerialError::TransferFailed(msg))
+        }
+        Protocol::Ymodem => Ok(Box::new(ymodem::Receiver::new(path))),
+        Protocol::Zmodem { resume } => Ok(Box::new(zmodem::Receiver::new(path, resume))),
+        Protocol::Kermit => Ok(Box::new(kermit::Receiver::new(path))),
+        Protocol::Xmodem | Protocol::XmodemCrc | Protocol::Xmodem1k => {
+            let file = File::create(path).map_err(|e| SerialError::from_file_error(path, e))?;
+            Ok(Box::new(xmodem::Receiver::new(file, protocol)))
+        }
+    }
+}
+
+/// Reads the levels of the input control lines of `port` into `lines`.
+fn read_input_lines<P: Port>(port: &mut P, lines: &mut ControlLines) -> serialport::Result<()> {
+    lines.cts = port.read_clear_to_send()?;
+    lines.dsr = port.read_data_set_ready()?;
+    lines.ri = port.read_ring_indicator()?;
+    lines.cd = port.read_carrier_detect()?;
+    Ok(())
+}
@@ -0,0 +1,412 @@
+//! Tests of the formats data is logged to files in, including pcapng captures, and of how log
+//! files roll over.
+
+extern crate chrono;
+extern crate gattii;
+extern crate serialport;
+
+mod common;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::prelude::*;
+
+use gattii::capture::{self, Direction, FileNaming, LogFile, LogFormat, LogOptions, LoggedPort,
+                      Logger};
+
+use common::temp_dir;
+
+fn time(milli: u32) -> DateTime<Local> {
+    Local.ymd(2018, 3, 1).and_hms_milli(12, 34, 56, milli)
+}
+
+/// Logs `chunks` received at consecutive milliseconds in `format`, returning the log file.
+fn log(format: LogFormat, chunks: &[&[u8]]) -> String {
+    let chunks: Vec<_> = chunks.iter().map(|c| (Direction::Rx, *c)).collect();
+    log_both(format, false, &chunks)
+}
+
+/// Logs `chunks` that went through the port at consecutive milliseconds in `format`, including
+/// sent data if `include_sent` is set.
+fn log_both(format: LogFormat, include_sent: bool, chunks: &[(Direction, &[u8])]) -> String {
+    let options = LogOptions {
+        format,
+        include_sent,
+        ..Default::default()
+    };
+    let mut logger = Logger::new(Vec::new(), options).unwrap();
+    for (i, &(direction, chunk)) in chunks.iter().enumerate() {
+        logger.log(direction, chunk, time(i as u32)).unwrap();
+    }
+    logger.finish().unwrap();
+    String::from_utf8(logger.get_ref().clone()).unwrap()
+}
+
+/// A command sent in two parts and its response.
+static EXCHANGE: [(Direction, &[u8]); 4] = [(Direction::Tx, b"AT"),
+                                             (Direction::Tx, b"I\r"),
+                                             (Direction::Rx, b"Modem\r\n"),
+                                             (Direction::Rx, b"OK\r\n")];
+
+#[test]
+fn raw() {
+    assert_eq!(log(LogFormat::Raw, &[b"logged", b" data\r\n"]), "logged data\r\n");
+}
+
+#[test]
+fn text_lines_are_timestamped() {
+    let text = log(LogFormat::Text, &[b"first li", b"ne\nsecond\n", b"third"]);
+    assert_eq!(text,
+               "[2018-03-01 12:34:56.000] first line\n\
+                [2018-03-01 12:34:56.001] second\n\
+                [2018-03-01 12:34:56.002] third");
+}
+
+#[test]
+fn hexdump() {
+    let text = log(LogFormat::Hexdump, &[b"0123456789", b"abcdef\"\x00\x7f\xff"]);
+    assert_eq!(text,
+               "00000000  30 31 32 33 34 35 36 37  38 39 61 62 63 64 65 66  |0123456789abcdef|\n\
+                00000010  22 00 7f ff                                       |\"...|\n\
+                00000014\n");
+    assert_eq!(log(LogFormat::Hexdump, &[]), "");
+}
+
+#[test]
+fn hexdump_lines_are_written_once_complete() {
+    let options = LogOptions {
+        format: LogFormat::Hexdump,
+        ..Default::default()
+    };
+    let mut logger = Logger::new(Vec::new(), options).unwrap();
+    logger.log(Direction::Rx, b"0123456789", time(0)).unwrap();
+    assert!(logger.get_ref().is_empty());
+    logger.log(Direction::Rx, b"abcdefghij", time(1)).unwrap();
+    assert_eq!(logger.get_ref().len(), 79);
+}
+
+#[test]
+fn csv() {
+    let text = log(LogFormat::Csv, &[b"ok\r\n", b"say \"hi\""]);
+    let offset = time(0).format("%:z").to_string();
+    let expected = format!("timestamp,direction,hex,ascii\n\
+                            2018-03-01T12:34:56.000{0},RX,6f 6b 0d 0a,\"ok..\"\n\
+                            2018-03-01T12:34:56.001{0},RX,73 61 79 20 22 68 69 22,\
+                            \"say \"\"hi\"\"\"\n",
+                           offset);
+    assert_eq!(text, expected);
+}
+
+#[test]
+fn sent_data_is_only_logged_if_included() {
+    assert_eq!(log_both(LogFormat::Raw, false, &EXCHANGE), "Modem\r\nOK\r\n");
+    assert_eq!(log_both(LogFormat::Raw, true, &EXCHANGE), "[TX] ATI\r[RX] Modem\r\nOK\r\n");
+}
+
+#[test]
+fn text_lines_are_marked_with_direction() {
+    assert_eq!(log_both(LogFormat::Text, true, &EXCHANGE),
+               "[2018-03-01 12:34:56.000] TX ATI\r\n\
+                [2018-03-01 12:34:56.002] RX Modem\r\n\
+                [2018-03-01 12:34:56.003] RX OK\r\n");
+}
+
+#[test]
+fn hexdump_lines_are_marked_with_direction() {
+    assert_eq!(log_both(LogFormat::Hexdump, true, &EXCHANGE),
+               "TX 00000000  41 54 49 0d                                       |ATI.|\n\
+                RX 00000004  4d 6f 64 65 6d 0d 0a 4f  4b 0d 0a                 |Modem..OK..|\n\
+                0000000f\n");
+}
+
+#[test]
+fn csv_rows_have_direction() {
+    let text = log_both(LogFormat::Csv, true, &EXCHANGE[1..3]);
+    let directions: Vec<_> = text.lines().map(|l| l.split(',').nth(1).unwrap()).collect();
+    assert_eq!(directions, vec!["direction", "TX", "RX"]);
+}
+
+/// The names of the files in `dir` and what's in them, sorted by name.
+fn files(dir: &Path) -> Vec<(String, String)> {
+    let mut files: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|e| {
+                 let path = e.unwrap().path();
+                 (path.file_name().unwrap().to_string_lossy().into_owned(),
+                  fs::read_to_string(&path).unwrap())
+             })
+        .collect();
+    files.sort();
+    files
+}
+
+/// Logs `chunks` received a second apart to a log started at `path`.
+fn log_to_file(path: &Path, options: LogOptions, chunks: &[&str]) {
+    let start = time(0);
+    let mut log = LogFile::create(path, options, Default::default(), start).unwrap();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let time = start + chrono::Duration::seconds(i as i64);
+        log.log(Direction::Rx, chunk.as_bytes(), time).unwrap();
+    }
+    log.finish().unwrap();
+}
+
+fn entries(files: &[(&str, &str)]) -> Vec<(String, String)> {
+    files.iter().map(|&(name, data)| (name.to_string(), data.to_string())).collect()
+}
+
+#[test]
+fn log_files_are_replaced() {
+    let dir = temp_dir("log_replaced");
+    let path = dir.join("capture.log");
+    fs::write(&path, "old\n").unwrap();
+    log_to_file(&path, Default::default(), &["new\n"]);
+    assert_eq!(files(&dir), entries(&[("capture.log", "new\n")]));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn log_directories_are_created() {
+    let dir = temp_dir("log_directories");
+    let path = dir.join("captures/ttyUSB0/capture.log");
+    log_to_file(&path, Default::default(), &["data\n"]);
+    assert_eq!(fs::read_to_string(&path).unwrap(), "data\n");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn log_files_are_appended_to() {
+    let dir = temp_dir("log_appended");
+    let path = dir.join("capture.csv");
+    let options = LogOptions {
+        format: LogFormat::Csv,
+        append: true,
+        ..Default::default()
+    };
+    log_to_file(&path, options, &["one"]);
+    log_to_file(&path, options, &["two"]);
+    let log = fs::read_to_string(&path).unwrap();
+    let rows: Vec<_> = log.lines().map(|l| l.rsplit(',').next().unwrap()).collect();
+    // The header is only written once
+    assert_eq!(rows, vec!["ascii", "\"one\"", "\"two\""]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn numbered_log_files_roll_over_by_size() {
+    let dir = temp_dir("log_numbered");
+    let path = dir.join("capture.log");
+    let options = LogOptions {
+        max_size: Some(4),
+        ..Default::default()
+    };
+    log_to_file(&path, options, &["one\n", "two\n", "3", "4", "5\n", "six\n"]);
+    assert_eq!(files(&dir),
+               entries(&[("capture.log", "six\n"),
+                         ("capture.log.1", "345\n"),
+                         ("capture.log.2", "two\n"),
+                         ("capture.log.3", "one\n")]));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn only_the_latest_numbered_log_files_are_kept() {
+    let dir = temp_dir("log_numbered_kept");
+    let path = dir.join("capture.log");
+    let options = LogOptions {
+        max_size: Some(1),
+        keep: Some(2),
+        ..Default::default()
+    };
+    log_to_file(&path, options, &["1", "2", "3", "4"]);
+    assert_eq!(files(&dir), entries(&[("capture.log", "4"), ("capture.log.1", "3")]));
+
+    // Only the file being written is kept with a single file
+    let options = LogOptions {
+        keep: Some(1),
+        ..options
+    };
+    log_to_file(&path, options, &["5", "6"]);
+    assert_eq!(files(&dir), entries(&[("capture.log", "6")]));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn timestamped_log_files_roll_over_by_time() {
+    let dir = temp_dir("log_timestamped");
+    let path = dir.join("capture.log");
+    let options = LogOptions {
+        interval: Some(Duration::from_secs(2)),
+        naming: FileNaming::Timestamped,
+        keep: Some(2),
+        ..Default::default()
+    };
+    log_to_file(&path, options, &["1", "2", "3", "4", "5"]);
+    assert_eq!(files(&dir),
+               entries(&[("capture_20180301-123458.log", "34"),
+                         ("capture_20180301-123500.log", "5")]));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn timestamped_log_files_are_not_replaced() {
+    let dir = temp_dir("log_timestamped_existing");
+    let path = dir.join("capture.log");
+    let options = LogOptions {
+        naming: FileNaming::Timestamped,
+        ..Default::default()
+    };
+    log_to_file(&path, options, &["first"]);
+    log_to_file(&path, options, &["second"]);
+    assert_eq!(capture::timestamped_path(&path, time(0), true),
+               dir.join("capture_20180301-123456.log"));
+    assert_eq!(files(&dir),
+               entries(&[("capture_20180301-123456-2.log", "second"),
+                         ("capture_20180301-123456.log", "first")]));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn earlier_timestamped_log_files_are_not_kept() {
+    let dir = temp_dir("log_timestamped_earlier");
+    let path = dir.join("capture.log");
+    for name in &["capture_20180228-235959.log",
+                  "capture_20180301-120000.log",
+                  "capture_20180301-120000-2.log",
+                  "other_20180301-120000.log"] {
+        fs::write(dir.join(name), "old").unwrap();
+    }
+    let options = LogOptions {
+        naming: FileNaming::Timestamped,
+        keep: Some(2),
+        ..Default::default()
+    };
+    log_to_file(&path, options, &["1"]);
+    assert_eq!(files(&dir),
+               entries(&[("capture_20180301-120000-2.log", "old"),
+                         ("capture_20180301-123456.log", "1"),
+                         ("other_20180301-120000.log", "old")]));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn log_paths_are_filled_in() {
+    let path = capture::expand_path("/var/log/{port}_{baud}_{date}_{time}.log",
+                                    "/dev/ttyUSB0",
+                                    115_200,
+                                    time(0));
+    assert_eq!(path, Path::new("/var/log/ttyUSB0_115200_2018-03-01_12-34-56.log"));
+    let path = capture::expand_path("{port}.log", r"\\.\COM10", 9600, time(0));
+    assert_eq!(path, Path::new("COM10.log"));
+    let path = capture::expand_path("{port}.log", "usb:1a86 7523", 9600, time(0));
+    assert_eq!(path, Path::new("usb_1a86_7523.log"));
+}
+
+#[test]
+fn log_paths_start_in_the_home_directory() {
+    let home = match env::var_os("HOME").or_else(|| env::var_os("USERPROFILE")) {
+        Some(home) => PathBuf::from(home),
+        None => return,
+    };
+    let path = capture::expand_path("~/captures/{port}.log", "COM3", 9600, time(0));
+    assert_eq!(path, home.join("captures/COM3.log"));
+    assert_eq!(capture::expand_path("~", "COM3", 9600, time(0)), home);
+    // Only a leading ~ is the home directory
+    assert_eq!(capture::expand_path("a~/b", "COM3", 9600, time(0)), Path::new("a~/b"));
+}
+
+/// Reads a little-endian number from the start of `bytes`.
+fn le(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |n, b| n << 8 | *b as u64)
+}
+
+/// Splits a pcapng capture into the types and bodies of its blocks.
+fn pcapng_blocks(mut capture: &[u8]) -> Vec<(u32, Vec<u8>)> {
+    let mut blocks = Vec::new();
+    while !capture.is_empty() {
+        let len = le(&capture[4..8]) as usize;
+        assert_eq!(len % 4, 0);
+        assert_eq!(le(&capture[len - 4..len]), len as u64);
+        blocks.push((le(&capture[..4]) as u32, capture[8..len - 4].to_vec()));
+        capture = &capture[len..];
+    }
+    blocks
+}
+
+/// Splits the options of a pcapng block into their codes and values.
+fn pcapng_options(mut options: &[u8]) -> Vec<(u16, Vec<u8>)> {
+    let mut parsed = Vec::new();
+    loop {
+        let code = le(&options[..2]) as u16;
+        let len = le(&options[2..4]) as usize;
+        if code == 0 {
+            return parsed;
+        }
+        parsed.push((code, options[4..4 + len].to_vec()));
+        options = &options[4 + (len + 3) / 4 * 4..];
+    }
+}
+
+#[test]
+fn pcapng() {
+    let options = LogOptions {
+        format: LogFormat::Pcapng,
+        include_sent: true,
+        ..Default::default()
+    };
+    let port = LoggedPort {
+        name: "/dev/ttyUSB0".to_string(),
+        settings: "115200 8N1".to_string(),
+    };
+    let mut logger = Logger::for_port(Vec::new(), options, &port).unwrap();
+    for (i, &(direction, chunk)) in EXCHANGE[1..3].iter().enumerate() {
+        logger.log(direction, chunk, time(i as u32 * 250)).unwrap();
+    }
+    logger.finish().unwrap();
+
+    let blocks = pcapng_blocks(logger.get_ref());
+    let types: Vec<_> = blocks.iter().map(|b| b.0).collect();
+    assert_eq!(types, vec![0x0A0D_0D0A, 1, 6, 6]);
+    assert_eq!(&blocks[0].1[..4], &[0x4D, 0x3C, 0x2B, 0x1A]);
+
+    // The interface is the port with a link type for private use
+    let interface = &blocks[1].1;
+    assert_eq!(le(&interface[..2]), 147);
+    assert_eq!(pcapng_options(&interface[8..]),
+               vec![(2, b"/dev/ttyUSB0".to_vec()), (3, b"115200 8N1".to_vec())]);
+
+    let start = time(0).timestamp() as u64 * 1_000_000;
+    let packets: Vec<_> = blocks[2..]
+        .iter()
+        .map(|block| {
+                 let packet = &block.1;
+                 let len = le(&packet[12..16]) as usize;
+                 let timestamp = le(&packet[4..8]) << 32 | le(&packet[8..12]);
+                 let flags = pcapng_options(&packet[20 + (len + 3) / 4 * 4..]);
+                 (timestamp - start, packet[20..20 + len].to_vec(), flags)
+             })
+        .collect();
+    assert_eq!(packets,
+               vec![(0, b"I\r".to_vec(), vec![(2, vec![2, 0, 0, 0])]),
+                    (250_000, b"Modem\r\n".to_vec(), vec![(2, vec![1, 0, 0, 0])])]);
+}
+
+#[test]
+fn appended_pcapng_captures_start_new_sections() {
+    let dir = temp_dir("log_pcapng_appended");
+    let path = dir.join("capture.pcapng");
+    let options = LogOptions {
+        format: LogFormat::Pcapng,
+        append: true,
+        ..Default::default()
+    };
+    log_to_file(&path, options, &["one"]);
+    log_to_file(&path, options, &["two"]);
+    let capture = fs::read(&path).unwrap();
+    let types: Vec<_> = pcapng_blocks(&capture).iter().map(|b| b.0).collect();
+    assert_eq!(types, vec![0x0A0D_0D0A, 1, 6, 0x0A0D_0D0A, 1, 6]);
+    fs::remove_dir_all(&dir).unwrap();
+}
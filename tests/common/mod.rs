@@ -0,0 +1,79 @@
+//! Helpers shared by the integration tests, including ones that run a `SerialThread` against
+//! Linux pseudo-terminals.
+
+#![allow(dead_code)]
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::time::{Duration, Instant};
+
+use gattii::SerialError;
+use gattii::transfer::{Status, Transfer};
+
+#[cfg(target_os = "linux")]
+mod pty;
+
+#[cfg(target_os = "linux")]
+#[allow(unused_imports)]
+pub use self::pty::*;
+
+/// How long to wait for any single response or piece of data before failing a test.
+pub fn timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+/// Runs `sender` and `receiver` against each other until both are done, with `corrupt` getting a
+/// chance to mangle everything the sender sends. Time is simulated, jumping ahead to the next
+/// deadline whenever nothing is in flight.
+pub fn run<S, R, C>(sender: &mut S, receiver: &mut R, mut corrupt: C) -> Result<(), SerialError>
+    where S: Transfer,
+          R: Transfer,
+          C: FnMut(&mut Vec<u8>)
+{
+    let mut now = Instant::now();
+    let mut to_receiver = Vec::new();
+    let mut to_sender = Vec::new();
+    let mut sender_done = sender.process(&[], now, &mut to_receiver)? == Status::Complete;
+    let mut receiver_done = receiver.process(&[], now, &mut to_sender)? == Status::Complete;
+
+    for _ in 0..100_000 {
+        if sender_done && receiver_done {
+            return Ok(());
+        }
+        if to_receiver.is_empty() && to_sender.is_empty() {
+            let deadlines = [sender.deadline(), receiver.deadline()];
+            now = deadlines.iter().filter_map(|d| *d).min().expect("Nothing to wait for");
+        }
+
+        corrupt(&mut to_receiver);
+        let input = ::std::mem::replace(&mut to_receiver, Vec::new());
+        if !receiver_done {
+            receiver_done = receiver.process(&input, now, &mut to_sender)? == Status::Complete;
+        }
+        let input = ::std::mem::replace(&mut to_sender, Vec::new());
+        if !sender_done {
+            sender_done = sender.process(&input, now, &mut to_receiver)? == Status::Complete;
+        }
+    }
+    panic!("The transfer never finished");
+}
+
+/// Some data to transfer that doesn't repeat too soon.
+pub fn contents(len: usize) -> Vec<u8> {
+    (0..len as u32).map(|i| (i * 7 % 251) as u8).collect()
+}
+
+/// Returns a path in the temporary directory that is unique to this test process.
+pub fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("gattii-test-{}-{}", process::id(), name))
+}
+
+/// Creates an empty directory in the temporary directory that is unique to this test process.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = temp_path(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,151 @@
+//! Pseudo-terminals for running a `SerialThread` against, and the helpers for driving them.
+
+use std::io;
+use std::io::prelude::*;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Receiver;
+use std::time::Instant;
+
+use gattii::transfer::{Status, Transfer};
+use gattii::*;
+use serialport;
+use serialport::posix::TTYPort;
+
+use super::timeout;
+
+/// A pseudo-terminal pair. The `SerialThread` under test connects to the slave end by name while
+/// the test drives the master end directly.
+pub struct Pty {
+    pub master: TTYPort,
+    /// Kept open so that the slave end stays alive across connects and disconnects. It's also
+    /// used to inspect the terminal settings applied by the `SerialThread`.
+    pub slave: TTYPort,
+    pub name: String,
+}
+
+impl Pty {
+    pub fn new() -> Self {
+        let (master, mut slave) = TTYPort::pair().expect("Unable to create pseudo-terminal pair");
+        slave.set_exclusive(false).expect("Unable to make the slave terminal shareable");
+        let name = SerialPort::name(&slave).expect("Slave terminal has no name");
+        Pty { master, slave, name }
+    }
+
+    /// Writes `data` into the port from the device side.
+    pub fn write(&mut self, data: &[u8]) {
+        self.master.write_all(data).expect("Unable to write to master terminal");
+    }
+
+    /// Reads exactly `len` bytes that were sent by the `SerialThread`.
+    pub fn read(&mut self, len: usize) -> Vec<u8> {
+        let start = Instant::now();
+        let mut data = Vec::with_capacity(len);
+        let mut buf = [0u8; 1024];
+        while data.len() < len {
+            assert!(start.elapsed() < timeout(),
+                    "Timed out after reading {}/{} bytes",
+                    data.len(),
+                    len);
+            let max = (len - data.len()).min(buf.len());
+            if let Ok(n) = self.master.read(&mut buf[..max]) {
+                data.extend_from_slice(&buf[..n]);
+            }
+        }
+        data
+    }
+
+    /// Asserts that nothing else was sent by the `SerialThread`.
+    pub fn assert_idle(&mut self) {
+        let mut buf = [0u8; 16];
+        if let Ok(n) = self.master.read(&mut buf) {
+            assert_eq!(n, 0, "Unexpected data {:?}", &buf[..n]);
+        }
+    }
+}
+
+/// A backend that opens real ports but only lists the pseudo-terminals it was given, as they
+/// don't show up when enumerating the system's serial ports.
+#[derive(Clone)]
+pub struct PtyBackend {
+    ports: Arc<Mutex<Vec<PortInfo>>>,
+}
+
+impl PtyBackend {
+    pub fn new(pty: &Pty) -> Self {
+        PtyBackend { ports: Arc::new(Mutex::new(vec![PortInfo::new(pty.name.clone())])) }
+    }
+
+    /// Stops listing all ports, as if the device had been unplugged.
+    pub fn unplug(&self) {
+        self.ports.lock().unwrap().clear();
+    }
+}
+
+impl Backend for PtyBackend {
+    type Port = <SerialPortBackend as Backend>::Port;
+
+    fn open(&mut self, name: &str, settings: &SerialPortSettings) -> io::Result<Self::Port> {
+        SerialPortBackend.open(name, settings)
+    }
+
+    fn list_ports(&mut self) -> serialport::Result<Vec<PortInfo>> {
+        Ok(self.ports.lock().unwrap().clone())
+    }
+}
+
+/// Starts a `SerialThread` and connects it to the slave end of `pty`.
+pub fn connect(pty: &Pty, baud: u32) -> (SerialThread, PtyBackend) {
+    let backend = PtyBackend::new(pty);
+    let thread = SerialThread::with_backend(backend.clone(), || ());
+    thread.send_port_open_cmd(pty.name.clone(), baud.to_string()).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::OpenPortSuccess(ref name) if name == &pty.name => (),
+        r => panic!("Unexpected response {:?}", r),
+    }
+    (thread, backend)
+}
+
+/// Returns the next response from the port thread, skipping periodic port scan results.
+pub fn next_response(rx: &Receiver<SerialResponse>) -> SerialResponse {
+    let start = Instant::now();
+    loop {
+        let remaining = timeout().checked_sub(start.elapsed()).unwrap_or_default();
+        match rx.recv_timeout(remaining) {
+            Ok(SerialResponse::PortsFound(_)) => continue,
+            Ok(r) => return r,
+            Err(e) => panic!("No response from the port thread: {:?}", e),
+        }
+    }
+}
+
+/// Collects `len` bytes of received data from the port thread.
+pub fn receive_data(rx: &Receiver<SerialResponse>, len: usize) -> Vec<u8> {
+    let mut data = Vec::new();
+    while data.len() < len {
+        match next_response(rx) {
+            SerialResponse::Data(d) => data.extend(d),
+            r => panic!("Unexpected response {:?}", r),
+        }
+    }
+    data
+}
+
+/// Runs `transfer` on the device side of `pty` until it's complete.
+pub fn run_device<T: Transfer>(pty: &mut Pty, transfer: &mut T) {
+    let mut input = Vec::new();
+    let mut buf = [0u8; 2048];
+    let start = Instant::now();
+    loop {
+        assert!(start.elapsed() < timeout(), "The transfer never finished");
+        let mut output = Vec::new();
+        let status = transfer.process(&input, Instant::now(), &mut output).unwrap();
+        pty.write(&output);
+        if status == Status::Complete {
+            return;
+        }
+        input.clear();
+        if let Ok(n) = pty.master.read(&mut buf) {
+            input.extend_from_slice(&buf[..n]);
+        }
+    }
+}
@@ -0,0 +1,272 @@
+extern crate gattii;
+extern crate serialport;
+
+use std::io;
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+
+use gattii::*;
+
+fn next_response(rx: &Receiver<SerialResponse>) -> SerialResponse {
+    rx.recv_timeout(Duration::from_secs(2)).expect("No response from the port thread")
+}
+
+/// Opens the "loopback" port, returning the control lines reported after opening it.
+fn open(thread: &SerialThread, baud: &str) -> ControlLines {
+    thread.send_port_open_cmd("loopback".to_string(), baud.to_string()).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::OpenPortSuccess(name) => assert_eq!(name, "loopback"),
+        r => panic!("Unexpected response {:?}", r),
+    }
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::ControlLinesChanged(lines) => lines,
+        r => panic!("Unexpected response {:?}", r),
+    }
+}
+
+#[test]
+fn echoes_sent_data() {
+    let thread = SerialThread::with_backend(LoopbackBackend::default(), || ());
+    open(&thread, "115200");
+
+    thread.send_port_data_cmd(b"hello").unwrap();
+    let mut received = Vec::new();
+    while received.len() < 5 {
+        match next_response(&thread.from_port_chan_rx) {
+            SerialResponse::Data(d) => received.extend(d),
+            r => panic!("Unexpected response {:?}", r),
+        }
+    }
+    assert_eq!(received, b"hello");
+
+    thread.send_port_close_cmd().unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::DisconnectSuccess => (),
+        r => panic!("Unexpected response {:?}", r),
+    }
+}
+
+#[test]
+fn opening_missing_port_fails() {
+    let thread = SerialThread::with_backend(LoopbackBackend::default(), || ());
+    thread.send_port_open_cmd("missing".to_string(), "9600".to_string()).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::OpenPortError(SerialError::NoDevice(name)) => assert_eq!(name, "missing"),
+        r => panic!("Unexpected response {:?}", r),
+    }
+}
+
+#[test]
+fn opening_with_unsupported_baud_rate_fails() {
+    let thread = SerialThread::with_backend(LoopbackBackend::default(), || ());
+    thread.send_port_open_cmd("loopback".to_string(), "0".to_string()).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::OpenPortError(SerialError::UnsupportedSetting(s)) => {
+            assert!(s.starts_with("baud rate 0"))
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+}
+
+#[test]
+fn unsupported_setting_is_rejected() {
+    let thread = SerialThread::with_backend(LoopbackBackend::default(), || ());
+    open(&thread, "9600");
+
+    thread.send_port_change_baud_cmd(0).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::SettingsRejected { settings, error } => {
+            assert_eq!(settings.baud_rate, 9600);
+            match error {
+                SerialError::UnsupportedSetting(_) => (),
+                e => panic!("Unexpected error {:?}", e),
+            }
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    // The port is still usable afterwards
+    thread.send_port_data_cmd(b"ok").unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::Data(d) => assert_eq!(d, b"ok"),
+        r => panic!("Unexpected response {:?}", r),
+    }
+}
+
+#[test]
+fn setting_errors_keep_the_os_error() {
+    let e = serialport::Error::from(io::Error::from_raw_os_error(5));
+    match SerialError::from_setting_error("break", e) {
+        SerialError::Io { errno: Some(5), .. } => (),
+        e => panic!("Unexpected error {:?}", e),
+    }
+}
+
+#[test]
+fn setting_changes_are_acknowledged() {
+    let thread = SerialThread::with_backend(LoopbackBackend::default(), || ());
+
+    // Settings changed without an open port are acknowledged too, as they're used once it opens
+    thread.send_port_change_parity_cmd(Parity::Odd).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::SettingsApplied(settings) => assert_eq!(settings.parity, Parity::Odd),
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    open(&thread, "9600");
+
+    thread.send_port_change_data_bits_cmd(DataBits::Seven).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::SettingsApplied(settings) => {
+            assert_eq!(settings.baud_rate, 9600);
+            assert_eq!(settings.data_bits, DataBits::Seven);
+            assert_eq!(settings.parity, Parity::Odd);
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+}
+
+#[test]
+fn control_lines() {
+    let thread = SerialThread::with_backend(LoopbackBackend::default(), || ());
+    let lines = open(&thread, "9600");
+    assert!(lines.dtr && lines.rts);
+    assert!(lines.cts && lines.dsr && lines.cd);
+    assert!(!lines.ri);
+
+    thread.send_port_dtr_cmd(false).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::ControlLinesChanged(lines) => {
+            assert!(!lines.dtr && !lines.dsr && !lines.cd);
+            assert!(lines.rts && lines.cts);
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    thread.send_port_rts_cmd(false).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::ControlLinesChanged(lines) => assert!(!lines.rts && !lines.cts),
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    // The requested levels are kept when the port is reopened
+    thread.send_port_close_cmd().unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::DisconnectSuccess => (),
+        r => panic!("Unexpected response {:?}", r),
+    }
+    let lines = open(&thread, "9600");
+    assert!(!lines.dtr && !lines.rts && !lines.cts && !lines.dsr);
+}
+
+#[test]
+fn send_break() {
+    let thread = SerialThread::with_backend(LoopbackBackend::default(), || ());
+    open(&thread, "9600");
+
+    // A loopback port receives the break as a NUL byte
+    thread.send_break_cmd(Duration::from_millis(50)).unwrap();
+    let (mut sent, mut received) = (false, false);
+    while !(sent && received) {
+        match next_response(&thread.from_port_chan_rx) {
+            SerialResponse::BreakSent => sent = true,
+            SerialResponse::Data(d) => {
+                assert_eq!(d, [0]);
+                received = true;
+            }
+            r => panic!("Unexpected response {:?}", r),
+        }
+    }
+}
+
+#[test]
+fn sending_missing_file_fails() {
+    let thread = SerialThread::with_backend(LoopbackBackend::default(), || ());
+    open(&thread, "9600");
+
+    let path = std::env::temp_dir().join("gattii-test-missing-file");
+    thread.send_port_file_cmd(path.clone()).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::SendingFileError(SerialError::FileNotFound(p)) => assert_eq!(p, path),
+        r => panic!("Unexpected response {:?}", r),
+    }
+}
+
+#[test]
+fn changing_port_cancels_sending() {
+    let thread = SerialThread::with_backend(LoopbackBackend::new(vec!["loopback", "other"]), || ());
+    open(&thread, "115200");
+
+    // The transfer waits for a receiver to start it, which never happens on a loopback port
+    let path = std::env::temp_dir().join(format!("gattii-test-{}-change-port",
+                                                 std::process::id()));
+    std::fs::write(&path, b"firmware").unwrap();
+    thread.send_port_file_with_protocol_cmd(path.clone(), Protocol::XmodemCrc).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::SendingFileStarted => (),
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    thread.send_port_change_port_cmd("other".to_string()).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::SendingFileCanceled => (),
+        r => panic!("Unexpected response {:?}", r),
+    }
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::OpenPortSuccess(name) => assert_eq!(name, "other"),
+        r => panic!("Unexpected response {:?}", r),
+    }
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn disconnection_lists_remaining_ports() {
+    let adapter = PortInfo {
+        name: "/dev/ttyUSB0".to_string(),
+        vid: Some(0x0403),
+        pid: Some(0x6001),
+        serial_number: Some("A50285BI".to_string()),
+        manufacturer: Some("FTDI".to_string()),
+        product: Some("FT232R USB UART".to_string()),
+    };
+    let backend = LoopbackBackend::new(vec![PortInfo::new("loopback"), adapter.clone()]);
+    let thread = SerialThread::with_backend(backend.clone(), || ());
+    open(&thread, "115200");
+
+    backend.remove_port("loopback");
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::UnexpectedDisconnection(ports) => assert_eq!(ports, vec![adapter]),
+        r => panic!("Unexpected response {:?}", r),
+    }
+}
+
+#[test]
+fn port_labels() {
+    let mut port = PortInfo::new("/dev/ttyUSB0");
+    assert_eq!(port.label(), "/dev/ttyUSB0");
+    assert_eq!(port.description(), "/dev/ttyUSB0");
+
+    port.vid = Some(0x0403);
+    port.pid = Some(0x6001);
+    port.manufacturer = Some("FTDI".to_string());
+    assert_eq!(port.label(), "/dev/ttyUSB0 (FTDI)");
+
+    port.product = Some("FT232R USB UART".to_string());
+    port.serial_number = Some("A50285BI".to_string());
+    assert_eq!(port.label(), "/dev/ttyUSB0 (FT232R USB UART, A50285BI)");
+    assert_eq!(port.description(),
+               "/dev/ttyUSB0\nUSB ID: 0403:6001\nManufacturer: FTDI\nProduct: FT232R USB UART\n\
+                Serial number: A50285BI");
+}
+
+#[test]
+fn receiving_file_needs_protocol() {
+    let thread = SerialThread::with_backend(LoopbackBackend::default(), || ());
+    open(&thread, "115200");
+
+    thread.receive_port_file_cmd("unused".into(), Protocol::Raw).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::SendingFileError(SerialError::TransferFailed(_)) => (),
+        r => panic!("Unexpected response {:?}", r),
+    }
+}
@@ -0,0 +1,509 @@
+//! End-to-end tests of `SerialThread` using Linux pseudo-terminals as the serial device.
+
+#![cfg(target_os = "linux")]
+
+extern crate gattii;
+extern crate nix;
+extern crate serialport;
+
+mod common;
+
+use std::fs;
+use std::io::Write;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use gattii::*;
+
+use common::*;
+
+#[test]
+fn opening_missing_port_fails() {
+    let thread = SerialThread::new(|| ());
+    thread.send_port_open_cmd("/dev/ttyMISSING".to_string(), "9600".to_string()).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::OpenPortError(SerialError::NoDevice(name)) => {
+            assert_eq!(name, "/dev/ttyMISSING")
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+}
+
+#[test]
+fn opening_port_in_use_fails() {
+    // Exclusive access to a terminal doesn't keep out root
+    if nix::unistd::geteuid().is_root() {
+        return;
+    }
+    let mut pty = Pty::new();
+    pty.slave.set_exclusive(true).unwrap();
+    let thread = SerialThread::with_backend(PtyBackend::new(&pty), || ());
+    thread.send_port_open_cmd(pty.name.clone(), "9600".to_string()).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::OpenPortError(SerialError::PortBusy(name)) => assert_eq!(name, pty.name),
+        r => panic!("Unexpected response {:?}", r),
+    }
+}
+
+#[test]
+fn send_data() {
+    let mut pty = Pty::new();
+    let (thread, _) = connect(&pty, 115_200);
+
+    thread.send_port_data_cmd(b"hello\r\n").unwrap();
+    assert_eq!(pty.read(7), b"hello\r\n");
+}
+
+#[test]
+fn commands_are_handled_while_the_device_stops_reading() {
+    let mut pty = Pty::new();
+    let (thread, _) = connect(&pty, 115_200);
+
+    // Far more than the terminal buffers, so most of it has to wait until the device reads again
+    let contents: Vec<u8> = (0..1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+    thread.send_port_data_cmd(&contents).unwrap();
+    thread.send_cancel_file_cmd().unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::SendingFileCanceled => (),
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    // Everything is still sent once the device reads again
+    assert!(pty.read(contents.len()) == contents);
+}
+
+#[test]
+fn custom_baud_rates() {
+    // MIDI's rate isn't one of the standard ones
+    let mut pty = Pty::new();
+    let (thread, _) = connect(&pty, 31_250);
+
+    thread.send_port_change_baud_cmd(250_000).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::SettingsApplied(settings) => assert_eq!(settings.baud_rate, 250_000),
+        r => panic!("Unexpected response {:?}", r),
+    }
+    thread.send_port_data_cmd(b"dmx").unwrap();
+    assert_eq!(pty.read(3), b"dmx");
+}
+
+#[test]
+fn receive_data_from_device() {
+    let mut pty = Pty::new();
+    let (thread, _) = connect(&pty, 115_200);
+
+    pty.write(b"world");
+    assert_eq!(receive_data(&thread.from_port_chan_rx, 5), b"world");
+}
+
+#[test]
+fn receive_burst_without_loss() {
+    let pty = Pty::new();
+    let (thread, _) = connect(&pty, 921_600);
+
+    let contents: Vec<u8> = (0..256 * 1024u32).map(|i| (i % 253) as u8).collect();
+    let writer_contents = contents.clone();
+    let mut master = pty.master.try_clone().unwrap();
+    let writer = thread::spawn(move || master.write_all(&writer_contents).unwrap());
+
+    assert_eq!(receive_data(&thread.from_port_chan_rx, contents.len()), contents);
+    writer.join().unwrap();
+}
+
+#[test]
+fn send_file() {
+    let mut pty = Pty::new();
+    let (thread, _) = connect(&pty, 115_200);
+
+    let contents: Vec<u8> = (0..4096u32).map(|i| (i % 251) as u8).collect();
+    let path = temp_path("send_file");
+    fs::write(&path, &contents).unwrap();
+
+    thread.send_port_file_cmd(path.clone()).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::SendingFileStarted => (),
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    // Progress must be strictly increasing and the file must only be reported as complete once
+    // every byte has been written out.
+    let sent = pty.read(contents.len());
+    let mut last_progress = 0;
+    loop {
+        match next_response(&thread.from_port_chan_rx) {
+            SerialResponse::SendingFileProgress(p) => {
+                assert!(p.bytes > last_progress, "Progress went from {} to {:?}",
+                        last_progress, p);
+                assert_eq!(p.total, Some(contents.len() as u64));
+                last_progress = p.bytes;
+            }
+            SerialResponse::SendingFileComplete => break,
+            r => panic!("Unexpected response {:?}", r),
+        }
+    }
+    assert_eq!(last_progress, contents.len() as u64);
+    assert_eq!(sent, contents);
+    pty.assert_idle();
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn cancel_send_file() {
+    let mut pty = Pty::new();
+    // A low baud rate makes sure the file isn't finished before it's canceled.
+    let (thread, _) = connect(&pty, 9600);
+
+    let path = temp_path("cancel_send_file");
+    fs::write(&path, vec![b'x'; 10_000]).unwrap();
+
+    thread.send_port_file_cmd(path.clone()).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::SendingFileStarted => (),
+        r => panic!("Unexpected response {:?}", r),
+    }
+    pty.read(10);
+    thread.send_cancel_file_cmd().unwrap();
+    loop {
+        match next_response(&thread.from_port_chan_rx) {
+            SerialResponse::SendingFileProgress(_) => (),
+            SerialResponse::SendingFileCanceled => break,
+            r => panic!("Unexpected response {:?}", r),
+        }
+    }
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn send_queue() {
+    let mut pty = Pty::new();
+    let (thread, _) = connect(&pty, 115_200);
+
+    let first = temp_path("send_queue_1");
+    fs::write(&first, b"one\n").unwrap();
+    let second = temp_path("send_queue_2");
+    fs::write(&second, b"two\n").unwrap();
+    let files = vec![QueuedFile {
+                         path: first.clone(),
+                         protocol: Protocol::Raw,
+                     },
+                     QueuedFile {
+                         path: second.clone(),
+                         protocol: Protocol::Text,
+                     }];
+    let start = Instant::now();
+    thread.send_queue_cmd(files, Duration::from_millis(50), Repeat::Times(2)).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::SendingFileStarted => (),
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    // Each file is reported as it starts, and the queue only completes after the last pass
+    assert_eq!(pty.read(16), b"one\ntwo\none\ntwo\n");
+    let mut started = Vec::new();
+    loop {
+        match next_response(&thread.from_port_chan_rx) {
+            SerialResponse::SendingQueueProgress { index, count, pass, passes } => {
+                assert_eq!((count, passes), (2, Some(2)));
+                started.push((index, pass));
+            }
+            SerialResponse::SendingFileProgress(_) |
+            SerialResponse::SendingLineProgress { .. } => (),
+            SerialResponse::SendingFileComplete => break,
+            r => panic!("Unexpected response {:?}", r),
+        }
+    }
+    assert_eq!(started, [(0, 1), (1, 1), (0, 2), (1, 2)]);
+    assert!(start.elapsed() >= Duration::from_millis(150),
+            "The gaps took only {:?}",
+            start.elapsed());
+    pty.assert_idle();
+
+    fs::remove_file(&first).unwrap();
+    fs::remove_file(&second).unwrap();
+}
+
+#[test]
+fn cancel_send_queue_forever() {
+    let mut pty = Pty::new();
+    let (thread, _) = connect(&pty, 115_200);
+
+    let path = temp_path("cancel_send_queue");
+    fs::write(&path, b"ping\n").unwrap();
+    let files = vec![QueuedFile {
+                         path: path.clone(),
+                         protocol: Protocol::Raw,
+                     }];
+    thread.send_queue_cmd(files, Duration::from_millis(100), Repeat::Forever).unwrap();
+    assert_eq!(pty.read(15), b"ping\nping\nping\n");
+    thread.send_cancel_file_cmd().unwrap();
+    loop {
+        match next_response(&thread.from_port_chan_rx) {
+            SerialResponse::SendingQueueProgress { passes: None, .. } |
+            SerialResponse::SendingFileStarted |
+            SerialResponse::SendingFileProgress(_) => (),
+            SerialResponse::SendingFileCanceled => break,
+            r => panic!("Unexpected response {:?}", r),
+        }
+    }
+
+    // Nothing is sent once the queue is canceled
+    thread::sleep(Duration::from_millis(250));
+    pty.assert_idle();
+    assert!(thread.from_port_chan_rx.try_recv().is_err());
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn send_file_without_open_port() {
+    let thread = SerialThread::with_backend(LoopbackBackend::default(), || ());
+    thread.send_port_file_cmd(temp_path("no_port")).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::SendingFileError(SerialError::NoPortOpen) => (),
+        r => panic!("Unexpected response {:?}", r),
+    }
+}
+
+#[test]
+fn log_to_file() {
+    let mut pty = Pty::new();
+    let (thread, _) = connect(&pty, 115_200);
+
+    let path = temp_path("log_to_file");
+    thread.send_log_to_file_cmd(path.clone(), Default::default()).unwrap();
+    // Make sure logging has started before sending anything
+    thread.send_port_data_cmd(b"?").unwrap();
+    pty.read(1);
+
+    pty.write(b"logged data");
+    assert_eq!(receive_data(&thread.from_port_chan_rx, 11), b"logged data");
+
+    thread.send_cancel_log_to_file_cmd().unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::LoggingFileCanceled => (),
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    // Data received after logging stopped isn't logged
+    pty.write(b"!");
+    receive_data(&thread.from_port_chan_rx, 1);
+    assert_eq!(fs::read(&path).unwrap(), b"logged data");
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn log_to_file_as_hexdump() {
+    let mut pty = Pty::new();
+    let (thread, _) = connect(&pty, 115_200);
+
+    let path = temp_path("log_to_file_as_hexdump");
+    let options = LogOptions {
+        format: LogFormat::Hexdump,
+        ..Default::default()
+    };
+    thread.send_log_to_file_cmd(path.clone(), options).unwrap();
+    thread.send_port_data_cmd(b"?").unwrap();
+    pty.read(1);
+
+    pty.write(b"logged data");
+    assert_eq!(receive_data(&thread.from_port_chan_rx, 11), b"logged data");
+
+    // The incomplete last line is written once logging stops
+    thread.send_cancel_log_to_file_cmd().unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::LoggingFileCanceled => (),
+        r => panic!("Unexpected response {:?}", r),
+    }
+    assert_eq!(fs::read_to_string(&path).unwrap(),
+               "00000000  6c 6f 67 67 65 64 20 64  61 74 61                 |logged data|\n\
+                0000000b\n");
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn log_to_file_as_pcapng() {
+    let mut pty = Pty::new();
+    let (thread, _) = connect(&pty, 115_200);
+
+    let path = temp_path("log_to_file_as_pcapng");
+    let options = LogOptions {
+        format: LogFormat::Pcapng,
+        ..Default::default()
+    };
+    thread.send_log_to_file_cmd(path.clone(), options).unwrap();
+    thread.send_port_data_cmd(b"AT+CAPTURE").unwrap();
+    pty.read(10);
+    pty.write(b"captured");
+    assert_eq!(receive_data(&thread.from_port_chan_rx, 8), b"captured");
+    thread.send_cancel_log_to_file_cmd().unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::LoggingFileCanceled => (),
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    // The interface is described by the port's name and settings
+    let capture = fs::read(&path).unwrap();
+    let contains = |bytes: &[u8]| capture.windows(bytes.len()).any(|w| w == bytes);
+    assert_eq!(&capture[..4], &[0x0A, 0x0D, 0x0D, 0x0A]);
+    assert!(contains(pty.name.as_bytes()));
+    assert!(contains(b"115200 8N1"));
+    assert!(contains(b"captured"));
+    // Sent data is captured as well, without having to ask for it
+    assert!(contains(b"AT+CAPTURE"));
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn log_sent_data() {
+    let mut pty = Pty::new();
+    let (thread, _) = connect(&pty, 115_200);
+
+    let path = temp_path("log_sent_data");
+    let options = LogOptions {
+        format: LogFormat::Csv,
+        include_sent: true,
+        ..Default::default()
+    };
+    thread.send_log_to_file_cmd(path.clone(), options).unwrap();
+    thread.send_port_data_cmd(b"AT\r").unwrap();
+    assert_eq!(pty.read(3), b"AT\r");
+    pty.write(b"OK\r\n");
+    assert_eq!(receive_data(&thread.from_port_chan_rx, 4), b"OK\r\n");
+
+    // Files sent are logged as well
+    let file = temp_path("log_sent_data_file");
+    fs::write(&file, b"\x03").unwrap();
+    thread.send_port_file_cmd(file.clone()).unwrap();
+    assert_eq!(pty.read(1), b"\x03");
+    loop {
+        if let SerialResponse::SendingFileComplete = next_response(&thread.from_port_chan_rx) {
+            break;
+        }
+    }
+
+    thread.send_cancel_log_to_file_cmd().unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::LoggingFileCanceled => (),
+        r => panic!("Unexpected response {:?}", r),
+    }
+    let log = fs::read_to_string(&path).unwrap();
+    let rows: Vec<Vec<&str>> = log.lines()
+        .skip(1)
+        .map(|l| l.split(',').skip(1).collect())
+        .collect();
+    assert_eq!(rows,
+               vec![vec!["TX", "41 54 0d", "\"AT.\""],
+                    vec!["RX", "4f 4b 0d 0a", "\"OK..\""],
+                    vec!["TX", "03", "\".\""]]);
+
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(&file).unwrap();
+}
+
+#[test]
+fn change_settings() {
+    let pty = Pty::new();
+    let (thread, _) = connect(&pty, 115_200);
+
+    thread.send_port_change_flow_control_cmd(FlowControl::Software).unwrap();
+    thread.send_port_change_baud_cmd(57_600).unwrap();
+    thread.send_port_change_data_bits_cmd(DataBits::Seven).unwrap();
+    thread.send_port_change_parity_cmd(Parity::Even).unwrap();
+    thread.send_port_change_stop_bits_cmd(StopBits::Two).unwrap();
+
+    let mut settings = None;
+    for _ in 0..5 {
+        match next_response(&thread.from_port_chan_rx) {
+            SerialResponse::SettingsApplied(s) => settings = Some(s),
+            r => panic!("Unexpected response {:?}", r),
+        }
+    }
+    let settings = settings.unwrap();
+    assert_eq!(settings.baud_rate, 57_600);
+    assert_eq!(settings.data_bits, DataBits::Seven);
+    assert_eq!(settings.parity, Parity::Even);
+    assert_eq!(settings.stop_bits, StopBits::Two);
+    assert_eq!(settings.flow_control, FlowControl::Software);
+
+    // Linux pseudo-terminals ignore the baud rate and always force 8 data bits without parity,
+    // so only the remaining settings can be checked here.
+    assert_eq!(pty.slave.stop_bits().unwrap(), StopBits::Two);
+    assert_eq!(pty.slave.flow_control().unwrap(), FlowControl::Software);
+}
+
+#[test]
+fn send_break() {
+    let pty = Pty::new();
+    let (thread, _) = connect(&pty, 115_200);
+
+    let start = Instant::now();
+    thread.send_break_cmd(Duration::from_millis(100)).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::BreakSent => assert!(start.elapsed() >= Duration::from_millis(100)),
+        r => panic!("Unexpected response {:?}", r),
+    }
+}
+
+#[test]
+fn send_break_without_open_port() {
+    let thread = SerialThread::with_backend(LoopbackBackend::default(), || ());
+    thread.send_break_cmd(Duration::from_millis(100)).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::PortError(SerialError::NoPortOpen) => (),
+        r => panic!("Unexpected response {:?}", r),
+    }
+}
+
+#[test]
+fn disconnect() {
+    let mut pty = Pty::new();
+    let (thread, _) = connect(&pty, 115_200);
+
+    thread.send_port_close_cmd().unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::DisconnectSuccess => (),
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    // Nothing is sent or received after disconnecting
+    thread.send_port_data_cmd(b"ignored").unwrap();
+    pty.write(b"ignored");
+    thread::sleep(Duration::from_millis(100));
+    pty.assert_idle();
+    assert!(thread.from_port_chan_rx.try_recv().is_err());
+}
+
+#[test]
+fn unexpected_disconnection() {
+    let pty = Pty::new();
+    let (thread, backend) = connect(&pty, 115_200);
+
+    backend.unplug();
+    loop {
+        match thread.from_port_chan_rx.recv_timeout(Duration::from_secs(10)) {
+            Ok(SerialResponse::UnexpectedDisconnection(ports)) => {
+                assert!(ports.is_empty());
+                break;
+            }
+            Ok(SerialResponse::PortsFound(_)) => (),
+            r => panic!("Unexpected response {:?}", r),
+        }
+    }
+}
+
+#[test]
+fn list_ports_is_consistent() {
+    // Enumeration depends on the host, so only check that the results are sane.
+    if let Ok(mut ports) = list_ports() {
+        assert!(ports.iter().all(|p| !p.name.is_empty() && !p.label().is_empty()));
+        let len = ports.len();
+        ports.sort();
+        ports.dedup();
+        assert_eq!(ports.len(), len, "Duplicate ports found");
+    }
+}
@@ -0,0 +1,1444 @@
+//! Tests of the file transfer protocols other than XMODEM, connecting senders and receivers
+//! directly to each other and running them through a `SerialThread` on a pseudo-terminal.
+
+extern crate gattii;
+extern crate serialport;
+
+mod common;
+
+use std::fs;
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use gattii::transfer::hex::{Format, Image, RecordSender, Segment};
+use gattii::transfer::stk500::{self, Uploader};
+use gattii::transfer::stm32::{self, Flasher};
+use gattii::transfer::{kermit, xmodem, ymodem, zmodem};
+use gattii::transfer::pacer::{self, Pacer};
+use gattii::transfer::{BatchFile, RawSender, Status, TextSender, Transfer};
+use gattii::*;
+
+use common::{contents, run, temp_dir};
+
+fn batch(files: &[(&str, &[u8])]) -> Vec<BatchFile<Cursor<Vec<u8>>>> {
+    files.iter()
+        .map(|&(name, data)| {
+                 BatchFile {
+                     name: name.to_string(),
+                     file: Cursor::new(data.to_vec()),
+                     len: data.len() as u64,
+                 }
+             })
+        .collect()
+}
+
+#[test]
+fn ymodem_header() {
+    let header = ymodem::encode_header(Some(("image.bin", 70_000)));
+    assert_eq!(header.len(), 133);
+    assert_eq!(&header[..3], &[xmodem::SOH, 0, 0xFF]);
+    let (_, data) = xmodem::decode_block(&header, true).unwrap();
+    assert_eq!(ymodem::decode_header(data), ("image.bin".to_string(), Some(70_000)));
+
+    // Modification time and mode are ignored
+    assert_eq!(ymodem::decode_header(b"a\x00123 13041234567 100644\x00\x00"),
+               ("a".to_string(), Some(123)));
+    assert_eq!(ymodem::decode_header(b"a\x00\x00"), ("a".to_string(), None));
+
+    let end = ymodem::encode_header(None);
+    let (_, data) = xmodem::decode_block(&end, true).unwrap();
+    assert_eq!(ymodem::decode_header(data), (String::new(), None));
+
+    let long_name = "x".repeat(200);
+    assert_eq!(ymodem::encode_header(Some((&long_name, 1))).len(), 1029);
+}
+
+#[test]
+fn ymodem_batch() {
+    let dir = temp_dir("ymodem_batch");
+    let (first, second) = (contents(3000), contents(100));
+    let mut sender = ymodem::Sender::new(batch(&[("first.bin", &first),
+                                                 ("empty", &[]),
+                                                 ("second.txt", &second)]));
+    let mut receiver = ymodem::Receiver::new(&dir);
+    run(&mut sender, &mut receiver, |_| ()).unwrap();
+
+    // Files are cut to their size instead of being padded
+    assert_eq!(fs::read(dir.join("first.bin")).unwrap(), first);
+    assert_eq!(fs::read(dir.join("empty")).unwrap(), b"");
+    assert_eq!(fs::read(dir.join("second.txt")).unwrap(), second);
+
+    assert_eq!(sender.progress().percentage(), Some(100));
+    let file = receiver.progress().file.unwrap();
+    assert_eq!((file.name.as_str(), file.index, file.bytes), ("second.txt", 2, 100));
+    assert_eq!(receiver.progress().bytes, 3100);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn ymodem_corrupted_blocks_are_sent_again() {
+    let dir = temp_dir("ymodem_corrupted");
+    let data = contents(5000);
+    let mut sender = ymodem::Sender::new(batch(&[("a", &data), ("b", &data)]));
+    let mut receiver = ymodem::Receiver::new(&dir);
+    let mut blocks = 0;
+    run(&mut sender, &mut receiver, |output| if output.len() > 100 {
+            blocks += 1;
+            if blocks % 3 == 0 {
+                output[60] ^= 0x01;
+            } else if blocks % 4 == 0 {
+                output.truncate(100);
+            }
+        })
+        .unwrap();
+    assert_eq!(fs::read(dir.join("a")).unwrap(), data);
+    assert_eq!(fs::read(dir.join("b")).unwrap(), data);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn ymodem_stays_in_directory() {
+    let dir = temp_dir("ymodem_directory");
+    let mut sender = ymodem::Sender::new(batch(&[("../escaped", b"data")]));
+    let mut receiver = ymodem::Receiver::new(&dir);
+    run(&mut sender, &mut receiver, |_| ()).unwrap();
+    assert_eq!(fs::read(dir.join("escaped")).unwrap(), b"data");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn ymodem_send_through_port() {
+    use common::*;
+
+    let mut pty = Pty::new();
+    let (thread, _) = connect(&pty, 115_200);
+
+    let dir = temp_dir("ymodem_send");
+    let paths = vec![dir.join("one"), dir.join("two")];
+    let data = [contents(2000), contents(300)];
+    fs::write(&paths[0], &data[0]).unwrap();
+    fs::write(&paths[1], &data[1]).unwrap();
+    thread.send_port_files_cmd(paths, Protocol::Ymodem).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::SendingFileStarted => (),
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    let received = temp_dir("ymodem_send_received");
+    run_device(&mut pty, &mut ymodem::Receiver::new(&received));
+    assert_eq!(fs::read(received.join("one")).unwrap(), data[0]);
+    assert_eq!(fs::read(received.join("two")).unwrap(), data[1]);
+
+    // Every file is reported as it's started
+    let mut files = Vec::new();
+    loop {
+        match next_response(&thread.from_port_chan_rx) {
+            SerialResponse::SendingFileProgress(_) => (),
+            SerialResponse::SendingFileBatchProgress(file) => {
+                assert_eq!(file.count, Some(2));
+                if files.last() != Some(&file.name) {
+                    files.push(file.name);
+                }
+            }
+            SerialResponse::SendingFileComplete => break,
+            r => panic!("Unexpected response {:?}", r),
+        }
+    }
+    assert_eq!(files, ["one", "two"]);
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&received).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn ymodem_receive_through_port() {
+    use common::*;
+
+    let mut pty = Pty::new();
+    let (thread, _) = connect(&pty, 115_200);
+
+    let dir = temp_dir("ymodem_receive");
+    thread.receive_port_file_cmd(dir.clone(), Protocol::Ymodem).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::SendingFileStarted => (),
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    let data = contents(1500);
+    run_device(&mut pty, &mut ymodem::Sender::new(batch(&[("firmware.bin", &data)])));
+    loop {
+        match next_response(&thread.from_port_chan_rx) {
+            SerialResponse::SendingFileProgress(_) => (),
+            SerialResponse::SendingFileBatchProgress(ref file) if file.name == "firmware.bin" => (),
+            SerialResponse::SendingFileComplete => break,
+            r => panic!("Unexpected response {:?}", r),
+        }
+    }
+    assert_eq!(fs::read(dir.join("firmware.bin")).unwrap(), data);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn sending_several_files_needs_batch_protocol() {
+    let thread = SerialThread::with_backend(LoopbackBackend::default(), || ());
+    thread.send_port_open_cmd("loopback".to_string(), "115200".to_string()).unwrap();
+    let paths = vec![PathBuf::from("a"), PathBuf::from("b")];
+    thread.send_port_files_cmd(paths, Protocol::Xmodem1k).unwrap();
+    loop {
+        match thread.from_port_chan_rx.recv_timeout(Duration::from_secs(2)).unwrap() {
+            SerialResponse::SendingFileError(SerialError::TransferFailed(_)) => break,
+            SerialResponse::SendingFileError(e) => panic!("Unexpected error {:?}", e),
+            _ => (),
+        }
+    }
+}
+
+/// Decodes everything in `data`.
+fn zmodem_events(data: &[u8]) -> Vec<zmodem::Event> {
+    let mut decoder = zmodem::Decoder::default();
+    data.iter().filter_map(|b| decoder.decode(*b)).collect()
+}
+
+#[test]
+fn zmodem_crc32() {
+    assert_eq!(zmodem::crc32(b"123456789"), 0xCBF4_3926);
+    assert_eq!(zmodem::crc32(&[]), 0);
+}
+
+#[test]
+fn zmodem_headers() {
+    let header = zmodem::Header::with_position(zmodem::ZRPOS, 0x0102_0304);
+    assert_eq!(header.data, [4, 3, 2, 1]);
+    assert_eq!(header.position(), 0x0102_0304);
+    assert_eq!(zmodem::Header::new(zmodem::ZRQINIT).encode_hex(),
+               b"**\x18B00000000000000\r\x8a\x11".to_vec());
+    assert_eq!(zmodem_events(&header.encode_hex()), [zmodem::Event::Header(header)]);
+
+    // Binary headers are escaped
+    let header = zmodem::Header::with_position(zmodem::ZDATA, 0x1118);
+    for &crc32 in &[false, true] {
+        let encoded = header.encode_binary(crc32, false);
+        assert!(!encoded[3..].contains(&0x11));
+        assert_eq!(zmodem_events(&encoded), [zmodem::Event::Header(header)]);
+    }
+
+    let mut damaged = header.encode_binary(true, false);
+    damaged[4] ^= 0x01;
+    assert_eq!(zmodem_events(&damaged), [zmodem::Event::Corrupted]);
+}
+
+#[test]
+fn zmodem_data() {
+    let data: Vec<u8> = (0..=255).collect();
+    let header = zmodem::Header::with_position(zmodem::ZDATA, 0);
+    for &(crc32, escape_control) in &[(false, false), (true, false), (true, true)] {
+        let mut encoded = header.encode_binary(crc32, escape_control);
+        encoded.extend(zmodem::encode_data(&data, zmodem::ZCRCG, crc32, escape_control));
+        encoded.extend(zmodem::encode_data(b"end", zmodem::ZCRCE, crc32, escape_control));
+        if escape_control {
+            assert!(encoded.iter().all(|b| b & 0x60 != 0 || *b == zmodem::ZDLE));
+        }
+        assert_eq!(zmodem_events(&encoded),
+                   [zmodem::Event::Header(header),
+                    zmodem::Event::Data {
+                        data: data.clone(),
+                        end: zmodem::ZCRCG,
+                    },
+                    zmodem::Event::Data {
+                        data: b"end".to_vec(),
+                        end: zmodem::ZCRCE,
+                    }]);
+    }
+    assert_eq!(zmodem_events(&zmodem::CANCEL), [zmodem::Event::Canceled]);
+}
+
+#[test]
+fn zmodem_detector() {
+    let mut detector = zmodem::Detector::default();
+    assert!(!detector.detect(b"login: **"));
+    assert!(detector.detect(b"*\x18B00000000000000\r\x8a\x11"));
+    assert!(!detector.detect(b"**\x18B0100000023be50\r\x8a\x11"));
+    assert!(!detector.detect(b"*\x18A\x00"));
+}
+
+#[test]
+fn zmodem_batch() {
+    let dir = temp_dir("zmodem_batch");
+    let first = contents(10_000);
+    let second: Vec<u8> = (0..=255).cycle().take(3000).collect();
+    let mut sender = zmodem::Sender::new(batch(&[("first.bin", &first),
+                                                 ("empty", &[]),
+                                                 ("second.bin", &second)]),
+                                         false,
+                                         &SerialPortSettings::default());
+    let mut receiver = zmodem::Receiver::new(&dir, false);
+    run(&mut sender, &mut receiver, |_| ()).unwrap();
+
+    assert_eq!(fs::read(dir.join("first.bin")).unwrap(), first);
+    assert_eq!(fs::read(dir.join("empty")).unwrap(), b"");
+    assert_eq!(fs::read(dir.join("second.bin")).unwrap(), second);
+
+    assert_eq!(sender.progress().percentage(), Some(100));
+    let file = receiver.progress().file.unwrap();
+    assert_eq!((file.name.as_str(), file.index, file.bytes), ("second.bin", 2, 3000));
+    assert_eq!(receiver.progress().bytes, 13_000);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn zmodem_corrupted_data_is_sent_again() {
+    let dir = temp_dir("zmodem_corrupted");
+    let data = contents(50_000);
+    let mut sender = zmodem::Sender::new(batch(&[("a", &data), ("b", &data)]),
+                                         false,
+                                         &SerialPortSettings::default());
+    let mut receiver = zmodem::Receiver::new(&dir, false);
+    let mut chunks = 0;
+    run(&mut sender, &mut receiver, |output| if output.len() > 1000 {
+            chunks += 1;
+            if chunks % 3 == 0 {
+                output[900] ^= 0x04;
+            } else if chunks % 4 == 0 {
+                output.drain(500..700);
+            }
+        })
+        .unwrap();
+    assert_eq!(fs::read(dir.join("a")).unwrap(), data);
+    assert_eq!(fs::read(dir.join("b")).unwrap(), data);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn zmodem_resume() {
+    let dir = temp_dir("zmodem_resume");
+    let data = contents(5000);
+    fs::write(dir.join("partial"), &data[..2000]).unwrap();
+    fs::write(dir.join("complete"), &data).unwrap();
+
+    let mut sender = zmodem::Sender::new(batch(&[("partial", &data), ("complete", &data)]),
+                                         true,
+                                         &SerialPortSettings::default());
+    let mut receiver = zmodem::Receiver::new(&dir, false);
+    run(&mut sender, &mut receiver, |_| ()).unwrap();
+    assert_eq!(fs::read(dir.join("partial")).unwrap(), data);
+    assert_eq!(fs::read(dir.join("complete")).unwrap(), data);
+    // Only the missing part was sent
+    assert_eq!(receiver.progress().bytes, 3000);
+
+    // Without resuming, files are sent again from the start
+    fs::write(dir.join("partial"), b"old").unwrap();
+    let mut sender = zmodem::Sender::new(batch(&[("partial", &data)]),
+                                         false,
+                                         &SerialPortSettings::default());
+    let mut receiver = zmodem::Receiver::new(&dir, false);
+    run(&mut sender, &mut receiver, |_| ()).unwrap();
+    assert_eq!(fs::read(dir.join("partial")).unwrap(), data);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn zmodem_resume_without_length() {
+    let dir = temp_dir("zmodem_resume_no_length");
+    let data = contents(5000);
+    fs::write(dir.join("partial"), &data[..2000]).unwrap();
+    let mut receiver = zmodem::Receiver::new(&dir, true);
+    let now = Instant::now();
+    receiver.process(&[], now, &mut Vec::new()).unwrap();
+
+    // The header only has the file's name, so the rest is asked for rather than skipping it
+    let mut input = zmodem::Header::with_flags(zmodem::ZFILE, zmodem::ZCRESUM, 0)
+        .encode_binary(true, false);
+    input.extend(zmodem::encode_data(b"partial\x00", zmodem::ZCRCW, true, false));
+    let mut output = Vec::new();
+    receiver.process(&input, now, &mut output).unwrap();
+    assert_eq!(output, zmodem::Header::with_position(zmodem::ZRPOS, 2000).encode_hex());
+
+    let mut input = zmodem::Header::with_position(zmodem::ZDATA, 2000).encode_binary(true, false);
+    input.extend(zmodem::encode_data(&data[2000..], zmodem::ZCRCW, true, false));
+    input.extend(zmodem::Header::with_position(zmodem::ZEOF, 5000).encode_binary(true, false));
+    receiver.process(&input, now, &mut Vec::new()).unwrap();
+    assert_eq!(fs::read(dir.join("partial")).unwrap(), data);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn zmodem_data_without_a_file_is_dropped() {
+    let dir = temp_dir("zmodem_no_file");
+    let mut receiver = zmodem::Receiver::new(&dir, false);
+    let now = Instant::now();
+    receiver.process(&[], now, &mut Vec::new()).unwrap();
+
+    // The sender carries on with a file the receiver skipped
+    let mut input = zmodem::Header::with_position(zmodem::ZDATA, 0).encode_binary(true, false);
+    input.extend(zmodem::encode_data(&contents(100), zmodem::ZCRCG, true, false));
+    input.extend(zmodem::encode_data(&contents(100), zmodem::ZCRCW, true, false));
+    let mut output = Vec::new();
+    receiver.process(&input, now, &mut output).unwrap();
+    assert_eq!(receiver.progress().bytes, 0);
+    assert_eq!(output, b"");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn zmodem_receiver_cancels() {
+    let dir = temp_dir("zmodem_cancel");
+    let mut sender = zmodem::Sender::new(batch(&[("a", &contents(100_000))]),
+                                         false,
+                                         &SerialPortSettings::default());
+    let mut receiver = zmodem::Receiver::new(&dir, false);
+    let mut output = Vec::new();
+    let now = Instant::now();
+    sender.process(&[], now, &mut output).unwrap();
+    receiver.process(&output, now, &mut Vec::new()).unwrap();
+    let mut cancel = Vec::new();
+    receiver.cancel(&mut cancel);
+    match sender.process(&cancel, now, &mut Vec::new()) {
+        Err(SerialError::TransferFailed(_)) => (),
+        r => panic!("Unexpected result {:?}", r),
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn zmodem_send_through_port() {
+    use common::*;
+
+    let mut pty = Pty::new();
+    let (thread, _) = connect(&pty, 115_200);
+
+    let dir = temp_dir("zmodem_send");
+    let paths = vec![dir.join("one"), dir.join("two")];
+    let data = [contents(20_000), contents(300)];
+    fs::write(&paths[0], &data[0]).unwrap();
+    fs::write(&paths[1], &data[1]).unwrap();
+    thread.send_port_files_cmd(paths, Protocol::Zmodem { resume: false }).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::SendingFileStarted => (),
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    let received = temp_dir("zmodem_send_received");
+    run_device(&mut pty, &mut zmodem::Receiver::new(&received, false));
+    assert_eq!(fs::read(received.join("one")).unwrap(), data[0]);
+    assert_eq!(fs::read(received.join("two")).unwrap(), data[1]);
+    loop {
+        match next_response(&thread.from_port_chan_rx) {
+            SerialResponse::SendingFileProgress(_) |
+            SerialResponse::SendingFileBatchProgress(_) => (),
+            SerialResponse::SendingFileComplete => break,
+            r => panic!("Unexpected response {:?}", r),
+        }
+    }
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&received).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn zmodem_send_is_canceled_while_the_receiver_stops_reading() {
+    use std::io::Read;
+    use std::thread;
+    use common::*;
+
+    let mut pty = Pty::new();
+    let (serial_thread, _) = connect(&pty, 115_200);
+
+    let dir = temp_dir("zmodem_stalled");
+    let path = dir.join("large");
+    fs::write(&path, contents(1 << 20)).unwrap();
+    serial_thread.send_port_files_cmd(vec![path], Protocol::Zmodem { resume: false }).unwrap();
+    match next_response(&serial_thread.from_port_chan_rx) {
+        SerialResponse::SendingFileStarted => (),
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    // Receive until data is coming in, then stop reading so that the port fills up
+    let received = temp_dir("zmodem_stalled_received");
+    let mut receiver = zmodem::Receiver::new(&received, false);
+    let mut buf = [0u8; 2048];
+    let start = Instant::now();
+    while receiver.progress().bytes == 0 {
+        assert!(start.elapsed() < common::timeout(), "No data was received");
+        let n = pty.master.read(&mut buf).unwrap_or(0);
+        let mut output = Vec::new();
+        receiver.process(&buf[..n], Instant::now(), &mut output).unwrap();
+        pty.write(&output);
+    }
+    thread::sleep(Duration::from_secs(1));
+
+    serial_thread.send_cancel_file_cmd().unwrap();
+    loop {
+        match next_response(&serial_thread.from_port_chan_rx) {
+            SerialResponse::SendingFileProgress(_) |
+            SerialResponse::SendingFileBatchProgress(_) => (),
+            SerialResponse::SendingFileCanceled => break,
+            r => panic!("Unexpected response {:?}", r),
+        }
+    }
+
+    // The receiver is told once it reads again
+    let start = Instant::now();
+    loop {
+        assert!(start.elapsed() < common::timeout(), "The receiver wasn't canceled");
+        let n = pty.master.read(&mut buf).unwrap_or(0);
+        match receiver.process(&buf[..n], Instant::now(), &mut Vec::new()) {
+            Ok(_) => (),
+            Err(SerialError::TransferFailed(_)) => break,
+            Err(e) => panic!("Unexpected error {}", e),
+        }
+    }
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&received).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn zmodem_receive_through_port() {
+    use common::*;
+
+    let mut pty = Pty::new();
+    let (thread, _) = connect(&pty, 115_200);
+
+    // The sender starting is noticed while no transfer is running
+    let data = contents(5000);
+    let settings = port_settings(115_200, DataBits::Eight, Parity::None, StopBits::One);
+    let mut sender = zmodem::Sender::new(batch(&[("firmware.bin", &data)]), false, &settings);
+    let mut output = Vec::new();
+    sender.process(&[], Instant::now(), &mut output).unwrap();
+    pty.write(&output);
+    loop {
+        match next_response(&thread.from_port_chan_rx) {
+            SerialResponse::Data(_) => (),
+            SerialResponse::ZmodemDetected => break,
+            r => panic!("Unexpected response {:?}", r),
+        }
+    }
+
+    let dir = temp_dir("zmodem_receive");
+    thread.receive_port_file_cmd(dir.clone(), Protocol::Zmodem { resume: false }).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::SendingFileStarted => (),
+        r => panic!("Unexpected response {:?}", r),
+    }
+    run_device(&mut pty, &mut sender);
+    loop {
+        match next_response(&thread.from_port_chan_rx) {
+            SerialResponse::SendingFileProgress(_) => (),
+            SerialResponse::SendingFileBatchProgress(ref file) if file.name == "firmware.bin" => (),
+            SerialResponse::SendingFileComplete => break,
+            r => panic!("Unexpected response {:?}", r),
+        }
+    }
+    assert_eq!(fs::read(dir.join("firmware.bin")).unwrap(), data);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn kermit_packets() {
+    assert_eq!(kermit::crc16(b"123456789"), 0x2189);
+
+    // The example from the Kermit protocol manual
+    assert_eq!(kermit::encode_packet(0, kermit::NAK, &[], 1), b"\x01# N3\r".to_vec());
+
+    for check_type in 1..4 {
+        let packet = kermit::encode_packet(63, kermit::DATA, b"#M#Jhello", check_type);
+        assert_eq!(packet.len(), 14 + check_type as usize);
+        let mut reader = kermit::Reader::default();
+        let raw: Vec<_> = packet.iter().filter_map(|b| reader.read(*b)).collect();
+        assert_eq!(raw.len(), 1);
+        assert_eq!(kermit::decode_packet(&raw[0], check_type),
+                   Some(kermit::Packet {
+                            seq: 63,
+                            kind: kermit::DATA,
+                            data: b"#M#Jhello".to_vec(),
+                        }));
+
+        let mut damaged = raw[0].clone();
+        damaged[5] ^= 0x02;
+        assert_eq!(kermit::decode_packet(&damaged, check_type), None);
+    }
+}
+
+#[test]
+fn kermit_quoting() {
+    let data: Vec<u8> = (0..=255).collect();
+    for &eight_bit in &[None, Some(b'&')] {
+        let quoting = kermit::Quoting {
+            control: b'#',
+            eight_bit,
+        };
+        let encoded = quoting.encode(&data);
+        assert!(encoded.iter().all(|b| *b & 0x7F >= 32 && *b & 0x7F != 127));
+        if eight_bit.is_some() {
+            assert!(encoded.iter().all(|b| *b < 0x80));
+        }
+        assert_eq!(quoting.decode(&encoded), data);
+    }
+}
+
+#[test]
+fn kermit_batch() {
+    let dir = temp_dir("kermit_batch");
+    let first: Vec<u8> = (0..=255).cycle().take(5000).collect();
+    let second = contents(100);
+    let mut sender = kermit::Sender::new(batch(&[("first.bin", &first),
+                                                 ("empty", &[]),
+                                                 ("second.txt", &second)]));
+    let mut receiver = kermit::Receiver::new(&dir);
+    run(&mut sender, &mut receiver, |_| ()).unwrap();
+
+    assert_eq!(fs::read(dir.join("first.bin")).unwrap(), first);
+    assert_eq!(fs::read(dir.join("empty")).unwrap(), b"");
+    assert_eq!(fs::read(dir.join("second.txt")).unwrap(), second);
+
+    assert_eq!(sender.progress().percentage(), Some(100));
+    let file = receiver.progress().file.unwrap();
+    assert_eq!((file.name.as_str(), file.index, file.bytes, file.total),
+               ("second.txt", 2, 100, Some(100)));
+    assert_eq!(receiver.progress().bytes, 5100);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn kermit_damaged_packets_are_sent_again() {
+    let dir = temp_dir("kermit_damaged");
+    let data = contents(3000);
+    let mut sender = kermit::Sender::new(batch(&[("a", &data), ("../b", &data)]));
+    let mut receiver = kermit::Receiver::new(&dir);
+    let mut packets = 0;
+    run(&mut sender, &mut receiver, |output| if !output.is_empty() {
+            packets += 1;
+            if packets % 5 == 0 {
+                output[4] ^= 0x01;
+            } else if packets % 7 == 0 {
+                output.clear();
+            }
+        })
+        .unwrap();
+    assert_eq!(fs::read(dir.join("a")).unwrap(), data);
+    assert_eq!(fs::read(dir.join("b")).unwrap(), data);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn kermit_names_with_control_characters() {
+    let dir = temp_dir("kermit_control_name");
+    let data = contents(100);
+    let mut sender = kermit::Sender::new(batch(&[("carriage\rreturn", &data)]));
+    let mut receiver = kermit::Receiver::new(&dir);
+    run(&mut sender, &mut receiver, |_| ()).unwrap();
+    assert_eq!(fs::read(dir.join("carriage\rreturn")).unwrap(), data);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn kermit_receiver_cancels() {
+    let dir = temp_dir("kermit_cancel");
+    let mut sender = kermit::Sender::new(batch(&[("a", &contents(1000))]));
+    let mut receiver = kermit::Receiver::new(&dir);
+    let now = Instant::now();
+    let mut to_receiver = Vec::new();
+    let mut to_sender = Vec::new();
+    sender.process(&[], now, &mut to_receiver).unwrap();
+    receiver.process(&to_receiver, now, &mut to_sender).unwrap();
+    receiver.cancel(&mut to_sender);
+    match sender.process(&to_sender, now, &mut Vec::new()) {
+        Err(SerialError::TransferFailed(ref msg)) if msg.contains("Canceled") => (),
+        r => panic!("Unexpected result {:?}", r),
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn kermit_send_through_port() {
+    use common::*;
+
+    let mut pty = Pty::new();
+    let (thread, _) = connect(&pty, 115_200);
+
+    let dir = temp_dir("kermit_send");
+    let path = dir.join("settings.cfg");
+    let data = contents(2000);
+    fs::write(&path, &data).unwrap();
+    thread.send_port_file_with_protocol_cmd(path, Protocol::Kermit).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::SendingFileStarted => (),
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    let received = temp_dir("kermit_send_received");
+    run_device(&mut pty, &mut kermit::Receiver::new(&received));
+    assert_eq!(fs::read(received.join("settings.cfg")).unwrap(), data);
+    loop {
+        match next_response(&thread.from_port_chan_rx) {
+            SerialResponse::SendingFileProgress(_) |
+            SerialResponse::SendingFileBatchProgress(_) => (),
+            SerialResponse::SendingFileComplete => break,
+            r => panic!("Unexpected response {:?}", r),
+        }
+    }
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&received).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn kermit_receive_through_port() {
+    use common::*;
+
+    let mut pty = Pty::new();
+    let (thread, _) = connect(&pty, 115_200);
+
+    let dir = temp_dir("kermit_receive");
+    thread.receive_port_file_cmd(dir.clone(), Protocol::Kermit).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::SendingFileStarted => (),
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    let data = contents(1500);
+    run_device(&mut pty, &mut kermit::Sender::new(batch(&[("log.txt", &data)])));
+    loop {
+        match next_response(&thread.from_port_chan_rx) {
+            SerialResponse::SendingFileProgress(_) => (),
+            SerialResponse::SendingFileBatchProgress(ref file) if file.name == "log.txt" => (),
+            SerialResponse::SendingFileComplete => break,
+            r => panic!("Unexpected response {:?}", r),
+        }
+    }
+    assert_eq!(fs::read(dir.join("log.txt")).unwrap(), data);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+fn text_sender(text: &str, pacing: LinePacing) -> TextSender {
+    TextSender::new(Cursor::new(text.as_bytes().to_vec()), pacing).unwrap()
+}
+
+#[test]
+fn text_lines_are_paced() {
+    let pacing = LinePacing {
+        line_delay: Duration::from_millis(100),
+        ..Default::default()
+    };
+    let mut sender = text_sender("print(1)\r\n\nend", pacing);
+    assert_eq!(sender.progress().lines, Some((0, 3)));
+
+    let start = Instant::now();
+    let mut output = Vec::new();
+    assert_eq!(sender.process(&[], start, &mut output).unwrap(), Status::InProgress);
+    assert_eq!(output, b"print(1)\r\n");
+    assert_eq!(sender.progress().lines, Some((1, 3)));
+    assert_eq!(sender.deadline(), Some(start + Duration::from_millis(100)));
+
+    output.clear();
+    sender.process(&[], start + Duration::from_millis(50), &mut output).unwrap();
+    assert!(output.is_empty());
+    let now = sender.deadline().unwrap();
+    sender.process(&[], now, &mut output).unwrap();
+    assert_eq!(output, b"\n");
+    let now = sender.deadline().unwrap();
+    assert_eq!(sender.process(&[], now, &mut output).unwrap(), Status::InProgress);
+    assert_eq!(output, b"\nend");
+    assert_eq!(sender.progress().lines, Some((3, 3)));
+    assert_eq!(sender.progress().percentage(), Some(100));
+    let now = sender.deadline().unwrap();
+    assert_eq!(sender.process(&[], now, &mut output).unwrap(), Status::Complete);
+}
+
+#[test]
+fn text_characters_are_paced() {
+    let pacing = LinePacing {
+        char_delay: Duration::from_millis(5),
+        ..Default::default()
+    };
+    let mut sender = text_sender("ab\n", pacing);
+    let mut now = Instant::now();
+    let mut sent = Vec::new();
+    loop {
+        let mut output = Vec::new();
+        if sender.process(&[], now, &mut output).unwrap() == Status::Complete {
+            break;
+        }
+        assert!(output.len() <= 1);
+        sent.extend(output);
+        now = sender.deadline().unwrap();
+    }
+    assert_eq!(sent, b"ab\n");
+}
+
+#[test]
+fn text_waits_for_echo() {
+    let pacing = LinePacing {
+        wait_for: WaitFor::Echo,
+        ..Default::default()
+    };
+    let mut sender = text_sender("AT\r\nATI\r\n", pacing);
+    let now = Instant::now();
+    let mut output = Vec::new();
+    sender.process(&[], now, &mut output).unwrap();
+    assert_eq!(output, b"AT\r\n");
+
+    output.clear();
+    sender.process(b"A", now, &mut output).unwrap();
+    assert!(output.is_empty());
+    sender.process(b"T\r\nOK\r\n", now, &mut output).unwrap();
+    assert_eq!(output, b"ATI\r\n");
+    assert_eq!(sender.progress().lines, Some((1, 2)));
+
+    // Giving up if it doesn't come
+    let later = now + Duration::from_secs(60);
+    match sender.process(b"ERROR", later, &mut output) {
+        Err(SerialError::TransferFailed(ref msg)) if msg.contains("line 2") => (),
+        r => panic!("Unexpected result {:?}", r),
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn text_waits_for_prompt_through_port() {
+    use common::*;
+
+    let mut pty = Pty::new();
+    let (thread, _) = connect(&pty, 115_200);
+
+    let dir = temp_dir("text_prompt");
+    let path = dir.join("main.py");
+    fs::write(&path, "import os\nprint(os.uname())\n").unwrap();
+    let pacing = LinePacing {
+        wait_for: WaitFor::Prompt(">>> ".to_string()),
+        ..Default::default()
+    };
+    thread.send_line_pacing_cmd(pacing).unwrap();
+    thread.send_port_file_with_protocol_cmd(path, Protocol::Text).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::SendingFileStarted => (),
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    assert_eq!(pty.read(10), b"import os\n");
+    pty.assert_idle();
+    pty.write(b">>> ");
+    assert_eq!(pty.read(18), b"print(os.uname())\n");
+    pty.write(b"('micropython')\r\n>>> ");
+
+    // Everything received is shown, and every line is reported
+    let mut lines = Vec::new();
+    loop {
+        match next_response(&thread.from_port_chan_rx) {
+            SerialResponse::Data(_) |
+            SerialResponse::SendingFileProgress(_) => (),
+            SerialResponse::SendingLineProgress { sent, total } => lines.push((sent, total)),
+            SerialResponse::SendingFileComplete => break,
+            r => panic!("Unexpected response {:?}", r),
+        }
+    }
+    assert_eq!(lines, [(1, 2), (2, 2)]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+fn port_settings(baud_rate: u32, data_bits: DataBits, parity: Parity, stop_bits: StopBits)
+                 -> SerialPortSettings {
+    SerialPortSettings {
+        baud_rate,
+        data_bits,
+        flow_control: FlowControl::None,
+        parity,
+        stop_bits,
+        timeout: Duration::from_millis(1),
+    }
+}
+
+#[test]
+fn frame_bits() {
+    let settings = port_settings(9600, DataBits::Eight, Parity::None, StopBits::One);
+    assert_eq!(pacer::frame_bits(&settings), 10);
+    let settings = port_settings(9600, DataBits::Seven, Parity::Even, StopBits::Two);
+    assert_eq!(pacer::frame_bits(&settings), 11);
+}
+
+#[test]
+fn pacer_refills_at_its_rate() {
+    let start = Instant::now();
+    let mut pacer = Pacer::new(1000.0, start);
+    assert_eq!(pacer.capacity(), 10);
+    assert_eq!(pacer.available(start), 10);
+
+    pacer.consume(10);
+    assert_eq!(pacer.available(start), 0);
+    assert_eq!(pacer.ready_at(10), start + Duration::from_millis(10));
+    assert_eq!(pacer.available(start + Duration::from_millis(5)), 5);
+    // The bucket never holds more than its capacity
+    assert_eq!(pacer.available(start + Duration::from_secs(1)), 10);
+
+    // Sending more than is available has to be paid off before anything else is sent
+    let later = start + Duration::from_secs(1);
+    pacer.consume(30);
+    assert_eq!(pacer.available(later), 0);
+    assert_eq!(pacer.ready_at(10), later + Duration::from_millis(30));
+}
+
+#[test]
+fn raw_sends_at_the_port_rate() {
+    // 11 bits per byte at 11000 baud is 1000 bytes per second
+    let settings = port_settings(11_000, DataBits::Seven, Parity::Even, StopBits::Two);
+    raw_send_rate(&settings, None, 1000);
+}
+
+#[test]
+fn raw_sends_at_the_given_throughput() {
+    let settings = port_settings(115_200, DataBits::Eight, Parity::None, StopBits::One);
+    raw_send_rate(&settings, Some(500), 500);
+}
+
+/// Checks that sending a second's worth of data at `bytes_per_sec` with `settings` and
+/// `throughput` takes a second in simulated time.
+fn raw_send_rate(settings: &SerialPortSettings, throughput: Option<u32>, bytes_per_sec: usize) {
+    let contents = vec![0x55; bytes_per_sec];
+    let mut sender = RawSender::new(Cursor::new(contents.clone()),
+                                    contents.len() as u64,
+                                    settings,
+                                    throughput);
+    let start = Instant::now();
+    let mut now = start;
+    let mut sent = Vec::new();
+    while sender.process(&[], now, &mut sent).unwrap() == Status::InProgress {
+        now = sender.deadline().unwrap().max(now);
+    }
+    assert_eq!(sent, contents);
+    let elapsed = now - start;
+    assert!(elapsed >= Duration::from_millis(980) && elapsed <= Duration::from_millis(1020),
+            "Sending took {:?}",
+            elapsed);
+}
+
+/// An Intel HEX image with data at 0x08000000 and 0x08000010 and a start address.
+const INTEL_HEX: &str = ":020000040800F2\r
+:0400000001020304F2\r
+:020004000506EF\r
+:01001000AA45\r
+:0400000508000041AE\r
+:00000001FF\r
+";
+
+#[test]
+fn hex_parses_intel_hex() {
+    let image = Image::parse(INTEL_HEX.as_bytes()).unwrap();
+    assert_eq!(image.format, Format::IntelHex);
+    assert_eq!(image.records.len(), 6);
+    assert_eq!(image.records[1], b":0400000001020304F2");
+    assert_eq!(image.segments,
+               vec![Segment {
+                        address: 0x0800_0000,
+                        data: vec![1, 2, 3, 4, 5, 6],
+                    },
+                    Segment {
+                        address: 0x0800_0010,
+                        data: vec![0xaa],
+                    }]);
+    assert_eq!(image.start, Some(0x0800_0041));
+
+    let (address, binary) = image.to_binary(0xff).unwrap();
+    assert_eq!(address, 0x0800_0000);
+    let mut expected = vec![1, 2, 3, 4, 5, 6];
+    expected.extend_from_slice(&[0xff; 10]);
+    expected.push(0xaa);
+    assert_eq!(binary, expected);
+}
+
+#[test]
+fn hex_parses_srecords() {
+    let text = "S00600004844521B\nS1061000010203E3\n\nS104100404E3\nS9031000EC\n";
+    let image = Image::parse(text.as_bytes()).unwrap();
+    assert_eq!(image.format, Format::Srecord);
+    assert_eq!(image.records.len(), 4);
+    assert_eq!(image.segments,
+               vec![Segment {
+                        address: 0x1000,
+                        data: vec![1, 2, 3],
+                    },
+                    Segment {
+                        address: 0x1004,
+                        data: vec![4],
+                    }]);
+    assert_eq!(image.start, Some(0x1000));
+    assert_eq!(image.to_binary(0).unwrap(), (0x1000, vec![1, 2, 3, 0, 4]));
+}
+
+#[test]
+fn hex_rejects_invalid_files() {
+    let invalid = [
+        // A wrong checksum
+        (":020000040800F2\n:0400000001020304F3\n:00000001FF\n", "line 2: the checksum"),
+        (":0400000001020304F2\n", "end of file record is missing"),
+        (":0400000001020304F2\nS9031000EC\n", "mixed"),
+        (":04000000010203G4F2\n:00000001FF\n", "hex digits"),
+        (":0400000001020304F2\n:0400020001020304F0\n:00000001FF\n", "overlaps"),
+        (":00000001FF\n:0400000001020304F2\n", "after the last record"),
+        ("S1061000010203E4\n", "the checksum"),
+        ("hello\n", "line 1: not a record"),
+        ("", "no records"),
+    ];
+    for &(text, reason) in &invalid {
+        match Image::parse(text.as_bytes()) {
+            Err(SerialError::InvalidImage(ref msg)) if msg.contains(reason) => (),
+            r => panic!("Unexpected result {:?} for {:?}", r, text),
+        }
+    }
+}
+
+#[test]
+fn hex_records_wait_for_ack() {
+    let image = Image::parse(INTEL_HEX.as_bytes()).unwrap();
+    let mut sender = RecordSender::new(image, b'!');
+    let now = Instant::now();
+    let mut output = Vec::new();
+    sender.process(&[], now, &mut output).unwrap();
+    assert_eq!(output, b":020000040800F2\r\n");
+    assert_eq!(sender.progress().lines, Some((0, 6)));
+
+    output.clear();
+    sender.process(b"ok", now, &mut output).unwrap();
+    assert!(output.is_empty());
+    sender.process(b"!", now, &mut output).unwrap();
+    assert_eq!(output, b":0400000001020304F2\r\n");
+    assert_eq!(sender.progress().lines, Some((1, 6)));
+
+    // Giving up if it doesn't come
+    let later = now + Duration::from_secs(60);
+    match sender.process(&[], later, &mut output) {
+        Err(SerialError::TransferFailed(ref msg)) if msg.contains("record 2") => (),
+        r => panic!("Unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn hex_records_are_all_sent() {
+    let image = Image::parse(INTEL_HEX.as_bytes()).unwrap();
+    let mut sender = RecordSender::new(image, 0x06);
+    let now = Instant::now();
+    let mut sent = Vec::new();
+    let mut input = Vec::new();
+    while sender.process(&input, now, &mut sent).unwrap() == Status::InProgress {
+        input = vec![0x06];
+    }
+    assert_eq!(sent, INTEL_HEX.as_bytes());
+    assert_eq!(sender.progress().lines, Some((6, 6)));
+    assert_eq!(sender.progress().percentage(), Some(100));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn hex_image_sent_through_port() {
+    use common::*;
+
+    let mut pty = Pty::new();
+    let (thread, _) = connect(&pty, 115_200);
+    let dir = temp_dir("hex_image");
+
+    // Corrupt files are rejected before anything is sent
+    let path = dir.join("corrupt.hex");
+    fs::write(&path, INTEL_HEX.replace("EF", "EE")).unwrap();
+    thread.send_port_file_with_protocol_cmd(path, Protocol::HexImage).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::SendingFileError(SerialError::InvalidImage(ref msg))
+            if msg.contains("line 3") => (),
+        r => panic!("Unexpected response {:?}", r),
+    }
+    pty.assert_idle();
+
+    let path = dir.join("firmware.hex");
+    fs::write(&path, INTEL_HEX).unwrap();
+    thread.send_image_options_cmd(ImageOptions {
+                                      fill: 0,
+                                      ..Default::default()
+                                  })
+        .unwrap();
+    thread.send_port_file_with_protocol_cmd(path, Protocol::HexImage).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::SendingFileStarted => (),
+        r => panic!("Unexpected response {:?}", r),
+    }
+    let mut expected = vec![1, 2, 3, 4, 5, 6];
+    expected.extend_from_slice(&[0; 10]);
+    expected.push(0xaa);
+    assert_eq!(pty.read(expected.len()), expected);
+    loop {
+        match next_response(&thread.from_port_chan_rx) {
+            SerialResponse::SendingFileProgress(_) => (),
+            SerialResponse::SendingFileComplete => break,
+            r => panic!("Unexpected response {:?}", r),
+        }
+    }
+    pty.assert_idle();
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// An image with a segment that's not a whole number of words, and one that takes several blocks.
+fn stm32_segments() -> Vec<Segment> {
+    vec![Segment {
+             address: 0x0800_0000,
+             data: vec![1, 2, 3, 4, 5, 6],
+         },
+         Segment {
+             address: 0x0800_1000,
+             data: contents(600),
+         }]
+}
+
+#[test]
+fn stm32_flashes_image() {
+    for &extended_erase in &[true, false] {
+        let mut flasher = Flasher::new(stm32_segments(), Default::default());
+        let mut device = stm32::Simulator::new(0x2000);
+        device.extended_erase = extended_erase;
+        for b in &mut device.flash[..0x100] {
+            *b = 0;
+        }
+        run(&mut flasher, &mut device, |_| ()).unwrap();
+
+        assert_eq!(&device.flash[..8], &[1, 2, 3, 4, 5, 6, 0xff, 0xff]);
+        assert!(device.flash[8..0x1000].iter().all(|b| *b == 0xff));
+        assert_eq!(&device.flash[0x1000..0x1000 + 600], &contents(600)[..]);
+        assert!(device.flash[0x1000 + 600..].iter().all(|b| *b == 0xff));
+        assert_eq!(device.started, Some(0x0800_0000));
+
+        let progress = flasher.progress();
+        assert_eq!(progress.total, Some(2 * 608));
+        assert_eq!(progress.percentage(), Some(100));
+        assert_eq!(progress.stage, Some("Starting the application".to_string()));
+    }
+}
+
+#[test]
+fn stm32_verify_fails_without_erasing() {
+    let options = FlashOptions {
+        erase: false,
+        ..Default::default()
+    };
+    let mut flasher = Flasher::new(stm32_segments(), options);
+    let mut device = stm32::Simulator::new(0x2000);
+    device.flash[0x1002] = 0;
+    match run(&mut flasher, &mut device, |_| ()) {
+        Err(SerialError::TransferFailed(ref msg)) if msg.contains("0x08001002") => (),
+        r => panic!("Unexpected result {:?}", r),
+    }
+    assert_eq!(device.started, None);
+}
+
+#[test]
+fn stm32_rejected_command_fails() {
+    let segments = vec![Segment {
+                            address: 0x0900_0000,
+                            data: vec![0; 4],
+                        }];
+    let mut flasher = Flasher::new(segments, Default::default());
+    let mut device = stm32::Simulator::new(0x1000);
+    match run(&mut flasher, &mut device, |_| ()) {
+        Err(SerialError::TransferFailed(ref msg)) if msg.contains("rejected writing") => (),
+        r => panic!("Unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn stm32_sync_is_retried() {
+    let mut flasher = Flasher::new(stm32_segments(), Default::default());
+    let mut now = Instant::now();
+    let mut sent = Vec::new();
+    let result = loop {
+        match flasher.process(&[], now, &mut sent) {
+            Ok(Status::InProgress) => now = flasher.deadline().unwrap(),
+            r => break r,
+        }
+    };
+    match result {
+        Err(SerialError::TransferFailed(ref msg)) if msg.contains("synchronization") => (),
+        r => panic!("Unexpected result {:?}", r),
+    }
+    assert_eq!(sent, vec![stm32::SYNC; 5]);
+    assert_eq!(flasher.progress().stage, Some("Connecting to the bootloader".to_string()));
+}
+
+#[test]
+fn stm32_resets_into_bootloader() {
+    let options = FlashOptions {
+        reset: true,
+        go: false,
+        ..Default::default()
+    };
+    let mut flasher = Flasher::new(stm32_segments(), options);
+    let mut device = stm32::Simulator::new(0x2000);
+    let mut now = Instant::now();
+    let mut to_device = Vec::new();
+    let mut to_flasher = Vec::new();
+    let mut lines = Vec::new();
+    loop {
+        let status = flasher.process(&to_flasher, now, &mut to_device).unwrap();
+        let request = flasher.take_line_request();
+        if request != LineRequest::default() {
+            lines.push((request.dtr, request.rts, to_device.is_empty()));
+        }
+        if status == Status::Complete {
+            break;
+        }
+        to_flasher.clear();
+        device.process(&to_device, now, &mut to_flasher).unwrap();
+        to_device.clear();
+        if to_flasher.is_empty() {
+            now = flasher.deadline().unwrap();
+        }
+    }
+
+    // BOOT0 is raised during the reset before anything is sent, and lowered before resetting
+    // into the application
+    assert_eq!(lines,
+               vec![(Some(true), Some(true), true),
+                    (Some(false), None, true),
+                    (Some(true), Some(false), true),
+                    (Some(false), None, true)]);
+    assert_eq!(&device.flash[..4], &[1, 2, 3, 4]);
+    assert_eq!(device.started, None);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn stm32_flashed_through_port() {
+    use common::*;
+
+    let mut pty = Pty::new();
+    let (thread, _) = connect(&pty, 115_200);
+    let dir = temp_dir("stm32");
+
+    // Binary files are written at the address in the options
+    let path = dir.join("firmware.bin");
+    fs::write(&path, contents(300)).unwrap();
+    thread.send_flash_options_cmd(FlashOptions {
+                                      address: 0x0800_0400,
+                                      ..Default::default()
+                                  })
+        .unwrap();
+
+    // The bootloader only talks 8E1
+    thread.send_port_file_with_protocol_cmd(path.clone(), Protocol::Stm32).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::SendingFileError(SerialError::UnsupportedSetting(_)) => (),
+        r => panic!("Unexpected response {:?}", r),
+    }
+    pty.assert_idle();
+    thread.send_port_change_parity_cmd(Parity::Even).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::SettingsApplied(_) => (),
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    thread.send_port_file_with_protocol_cmd(path, Protocol::Stm32).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::SendingFileStarted => (),
+        r => panic!("Unexpected response {:?}", r),
+    }
+    let mut device = stm32::Simulator::new(0x1000);
+    run_device(&mut pty, &mut device);
+
+    let mut stages = Vec::new();
+    loop {
+        match next_response(&thread.from_port_chan_rx) {
+            SerialResponse::TransferStage(stage) => stages.push(stage),
+            SerialResponse::SendingFileProgress(_) => (),
+            SerialResponse::SendingFileComplete => break,
+            r => panic!("Unexpected response {:?}", r),
+        }
+    }
+    assert_eq!(stages.first().map(|s| s.as_str()), Some("Connecting to the bootloader"));
+    assert!(stages.contains(&"Connected to bootloader 3.1 on chip ID 0x413".to_string()));
+    assert_eq!(stages.last().map(|s| s.as_str()), Some("Starting the application"));
+    assert_eq!(&device.flash[0x400..0x400 + 300], &contents(300)[..]);
+    assert_eq!(device.started, Some(0x0800_0400));
+    pty.assert_idle();
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// An image of `data` at `address`, as read from an Intel HEX file.
+fn avr_image(address: u32, data: Vec<u8>) -> Image {
+    Image {
+        format: Format::IntelHex,
+        records: Vec::new(),
+        segments: vec![Segment {
+                           address,
+                           data,
+                       }],
+        start: None,
+    }
+}
+
+/// `data` at `address` as an Intel HEX file, with 16 bytes per record.
+fn intel_hex(address: u16, data: &[u8]) -> String {
+    let mut hex = String::new();
+    for (i, chunk) in data.chunks(16).enumerate() {
+        let offset = address + (i * 16) as u16;
+        let mut record = vec![chunk.len() as u8, (offset >> 8) as u8, offset as u8, 0];
+        record.extend_from_slice(chunk);
+        let sum = record.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        record.push(sum.wrapping_neg());
+        hex.push(':');
+        for b in record {
+            hex.push_str(&format!("{:02X}", b));
+        }
+        hex.push_str("\r\n");
+    }
+    hex.push_str(":00000001FF\r\n");
+    hex
+}
+
+#[test]
+fn stk500_uploads_image() {
+    let image = avr_image(0x10, contents(300));
+    let mut uploader = Uploader::new(&image, Default::default()).unwrap();
+    let mut device = stk500::Simulator::new(0x8000);
+    run(&mut uploader, &mut device, |_| ()).unwrap();
+
+    assert!(device.flash[..0x10].iter().all(|b| *b == 0xff));
+    assert_eq!(&device.flash[0x10..0x10 + 300], &contents(300)[..]);
+    assert!(device.flash[0x10 + 300..].iter().all(|b| *b == 0xff));
+    assert!(device.started);
+
+    let progress = uploader.progress();
+    assert_eq!(progress.total, Some(2 * 316));
+    assert_eq!(progress.percentage(), Some(100));
+    assert_eq!(progress.stage, Some("Starting the application".to_string()));
+}
+
+#[test]
+fn stk500_verify_fails_on_corrupted_page() {
+    let image = avr_image(0x10, contents(300));
+    let mut uploader = Uploader::new(&image, Default::default()).unwrap();
+    let mut device = stk500::Simulator::new(0x8000);
+    let mut corrupted = false;
+    let result = run(&mut uploader, &mut device, |output| {
+        // Flip a bit in the padding before the image in the first page
+        if !corrupted && output.first() == Some(&stk500::PROG_PAGE) {
+            corrupted = true;
+            output[10] ^= 1;
+        }
+    });
+    match result {
+        Err(SerialError::TransferFailed(ref msg)) if msg.contains("0x00006") => (),
+        r => panic!("Unexpected result {:?}", r),
+    }
+    assert!(!device.started);
+}
+
+#[test]
+fn stk500_failed_write_is_reported() {
+    let image = avr_image(0x1000, contents(16));
+    let mut uploader = Uploader::new(&image, Default::default()).unwrap();
+    let mut device = stk500::Simulator::new(0x800);
+    match run(&mut uploader, &mut device, |_| ()) {
+        Err(SerialError::TransferFailed(ref msg)) if msg.contains("failed writing a page") => (),
+        r => panic!("Unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn stk500_rejects_images_it_cant_address() {
+    let image = avr_image(0x1_fff0, contents(32));
+    match Uploader::new(&image, Default::default()) {
+        Err(SerialError::InvalidImage(ref msg)) if msg.contains("128 KiB") => (),
+        r => panic!("Unexpected result {:?}", r.is_ok()),
+    }
+}
+
+#[test]
+fn stk500_resets_and_retries_sync() {
+    let image = avr_image(0, contents(16));
+    let mut uploader = Uploader::new(&image, Default::default()).unwrap();
+    let mut now = Instant::now();
+    let mut sent = Vec::new();
+    let mut lines = Vec::new();
+    let result = loop {
+        let result = uploader.process(&[], now, &mut sent);
+        let request = uploader.take_line_request();
+        if request != LineRequest::default() {
+            lines.push((request.dtr, request.rts, sent.is_empty()));
+        }
+        match result {
+            Ok(Status::InProgress) => now = uploader.deadline().unwrap(),
+            r => break r,
+        }
+    };
+    match result {
+        Err(SerialError::TransferFailed(ref msg)) if msg.contains("synchronization") => (),
+        r => panic!("Unexpected result {:?}", r),
+    }
+
+    // DTR is released and asserted to reset the board before anything is sent
+    assert_eq!(lines, vec![(Some(false), None, true), (Some(true), None, true)]);
+    let sync = [stk500::GET_SYNC, stk500::CRC_EOP];
+    assert_eq!(sent, sync.iter().cycle().take(20).cloned().collect::<Vec<_>>());
+}
+
+#[test]
+fn stk500_ignores_late_replies_to_sync() {
+    let image = avr_image(0, contents(200));
+    let mut uploader = Uploader::new(&image, Default::default()).unwrap();
+    let mut device = stk500::Simulator::new(0x8000);
+    let mut held = Vec::new();
+    let mut syncs = 0;
+    run(&mut uploader, &mut device, |output| {
+        // The bootloader only gets the first sync command along with the retry, answering both
+        if output.first() == Some(&stk500::GET_SYNC) {
+            syncs += 1;
+            held.append(output);
+            if syncs > 1 {
+                output.append(&mut held);
+            }
+        }
+    })
+        .unwrap();
+
+    assert_eq!(syncs, 2);
+    assert_eq!(&device.flash[..200], &contents(200)[..]);
+    assert!(device.started);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn stk500_uploaded_through_port() {
+    use common::*;
+
+    let mut pty = Pty::new();
+    let (thread, _) = connect(&pty, 115_200);
+    let dir = temp_dir("stk500");
+
+    let path = dir.join("sketch.hex");
+    fs::write(&path, intel_hex(0, &contents(200))).unwrap();
+    thread.send_port_file_with_protocol_cmd(path, Protocol::Stk500).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::SendingFileStarted => (),
+        r => panic!("Unexpected response {:?}", r),
+    }
+    let mut device = stk500::Simulator::new(0x8000);
+    run_device(&mut pty, &mut device);
+
+    let mut stages = Vec::new();
+    loop {
+        match next_response(&thread.from_port_chan_rx) {
+            SerialResponse::TransferStage(stage) => stages.push(stage),
+            // Pseudo-terminals may not have a DTR line to pulse
+            SerialResponse::ControlLinesChanged(_) |
+            SerialResponse::PortError(_) |
+            SerialResponse::SendingFileProgress(_) => (),
+            SerialResponse::SendingFileComplete => break,
+            r => panic!("Unexpected response {:?}", r),
+        }
+    }
+    assert_eq!(stages.first().map(|s| s.as_str()), Some("Resetting the board"));
+    assert!(stages.contains(&"Connected to a device with signature 1E 95 0F".to_string()));
+    assert_eq!(stages.last().map(|s| s.as_str()), Some("Starting the application"));
+    assert_eq!(&device.flash[..200], &contents(200)[..]);
+    assert!(device.started);
+    pty.assert_idle();
+    fs::remove_dir_all(&dir).unwrap();
+}
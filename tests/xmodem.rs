@@ -0,0 +1,503 @@
+//! Tests of the XMODEM sender and receiver, connected directly to each other and through a
+//! `SerialThread` on a pseudo-terminal.
+
+extern crate gattii;
+extern crate serialport;
+
+mod common;
+
+use std::collections::VecDeque;
+use std::io::{self, Cursor, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use gattii::transfer::xmodem::{self, Receiver, Sender};
+use gattii::transfer::{Status, Transfer};
+use gattii::*;
+
+use common::{contents, run, temp_path};
+
+/// Pads `data` with SUB to a multiple of 128 bytes, as it's received.
+fn padded(data: &[u8]) -> Vec<u8> {
+    let mut padded = data.to_vec();
+    while padded.len() % 128 != 0 {
+        padded.push(xmodem::SUB);
+    }
+    padded
+}
+
+fn transfer(data: &[u8], send_protocol: Protocol, receive_protocol: Protocol) -> Vec<u8> {
+    let mut sender = Sender::new(Cursor::new(data.to_vec()), data.len() as u64, send_protocol);
+    let mut received = Vec::new();
+    {
+        let mut receiver = Receiver::new(&mut received, receive_protocol);
+        run(&mut sender, &mut receiver, |_| ()).unwrap();
+    }
+    assert_eq!(sender.progress().bytes, data.len() as u64);
+    assert_eq!(sender.progress().percentage(), Some(100));
+    received
+}
+
+#[test]
+fn crc16() {
+    assert_eq!(xmodem::crc16(b"123456789"), 0x31C3);
+    assert_eq!(xmodem::crc16(&[]), 0);
+}
+
+#[test]
+fn checksum() {
+    assert_eq!(xmodem::checksum(&[0xFF, 0x02, 0x10]), 0x11);
+}
+
+#[test]
+fn encode_block() {
+    let block = xmodem::encode_block(3, &[0x41; 128], false);
+    assert_eq!(block.len(), 132);
+    assert_eq!(&block[..3], &[xmodem::SOH, 3, 0xFC]);
+    assert_eq!(block[131], xmodem::checksum(&[0x41; 128]));
+
+    let block = xmodem::encode_block(255, &[0; 1024], true);
+    assert_eq!(block.len(), 1029);
+    assert_eq!(&block[..3], &[xmodem::STX, 255, 0]);
+}
+
+#[test]
+fn all_variants() {
+    let data = contents(5000);
+    for &protocol in &[Protocol::Xmodem, Protocol::XmodemCrc, Protocol::Xmodem1k] {
+        assert_eq!(transfer(&data, protocol, protocol), padded(&data), "{}", protocol);
+    }
+}
+
+#[test]
+fn sender_follows_receiver() {
+    // The receiver decides whether CRCs are used, and 1K blocks need them
+    let data = contents(3000);
+    assert_eq!(transfer(&data, Protocol::Xmodem, Protocol::XmodemCrc), padded(&data));
+    assert_eq!(transfer(&data, Protocol::Xmodem1k, Protocol::Xmodem), padded(&data));
+}
+
+#[test]
+fn block_numbers_wrap() {
+    let data = contents(300 * 128 + 5);
+    assert_eq!(transfer(&data, Protocol::XmodemCrc, Protocol::XmodemCrc), padded(&data));
+}
+
+#[test]
+fn empty_file() {
+    assert_eq!(transfer(&[], Protocol::XmodemCrc, Protocol::XmodemCrc), Vec::<u8>::new());
+}
+
+#[test]
+fn corrupted_blocks_are_sent_again() {
+    let data = contents(2000);
+    for &protocol in &[Protocol::Xmodem, Protocol::Xmodem1k] {
+        let mut sender = Sender::new(Cursor::new(data.clone()), data.len() as u64, protocol);
+        let mut received = Vec::new();
+        {
+            let mut receiver = Receiver::new(&mut received, protocol);
+            let mut blocks = 0;
+            run(&mut sender, &mut receiver, |output| {
+                    // Flip a bit in every third block and drop the end of every fifth, so that
+                    // the receiver has to time out.
+                    if output.len() > 100 {
+                        blocks += 1;
+                        if blocks % 3 == 0 {
+                            output[50] ^= 0x10;
+                        } else if blocks % 5 == 0 {
+                            output.truncate(100);
+                        }
+                    }
+                })
+                .unwrap();
+        }
+        assert_eq!(received, padded(&data), "{}", protocol);
+    }
+}
+
+#[test]
+fn start_requests_sent_before_the_first_block_arrived_are_ignored() {
+    let data = contents(1000);
+    let mut sender = Sender::new(Cursor::new(data.clone()), data.len() as u64, Protocol::XmodemCrc);
+    let mut received = Vec::new();
+    {
+        let mut receiver = Receiver::new(&mut received, Protocol::XmodemCrc);
+        let now = Instant::now();
+        receiver.process(&[], now, &mut Vec::new()).unwrap();
+
+        // The receiver asked again before the first block got to it
+        let mut to_receiver = Vec::new();
+        sender.process(b"CC", now, &mut to_receiver).unwrap();
+        assert_eq!(to_receiver.len(), 133);
+
+        // A block that has to be sent again only works out if the ACKs matched the blocks
+        let mut blocks = 0;
+        for _ in 0..100 {
+            if to_receiver.len() > 100 {
+                blocks += 1;
+                if blocks == 2 {
+                    to_receiver[50] ^= 0x10;
+                }
+            }
+            let mut to_sender = Vec::new();
+            let input = ::std::mem::replace(&mut to_receiver, Vec::new());
+            let received = receiver.process(&input, now, &mut to_sender).unwrap();
+            let sent = sender.process(&to_sender, now, &mut to_receiver).unwrap();
+            if received == Status::Complete && sent == Status::Complete {
+                break;
+            }
+        }
+    }
+    assert_eq!(received, padded(&data));
+}
+
+#[test]
+fn sender_gives_up() {
+    let data = contents(200);
+    let mut sender = Sender::new(Cursor::new(data.clone()), data.len() as u64, Protocol::Xmodem);
+    let mut received = Vec::new();
+    let mut receiver = Receiver::new(&mut received, Protocol::Xmodem);
+    let result = run(&mut sender, &mut receiver, |output| if output.len() > 100 {
+        output[10] ^= 1;
+    });
+    match result {
+        Err(SerialError::TransferFailed(_)) => (),
+        r => panic!("Unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn receiver_cancels() {
+    let data = contents(1000);
+    let mut sender = Sender::new(Cursor::new(data.clone()), data.len() as u64, Protocol::XmodemCrc);
+    let mut out = Vec::new();
+    let now = Instant::now();
+    sender.process(&[], now, &mut out).unwrap();
+    assert!(out.is_empty());
+    sender.process(b"C", now, &mut out).unwrap();
+    assert_eq!(out.len(), 133);
+
+    let mut cancel = Vec::new();
+    Receiver::new(Vec::new(), Protocol::XmodemCrc).cancel(&mut cancel);
+    match sender.process(&cancel, now, &mut out) {
+        Err(SerialError::TransferFailed(_)) => (),
+        r => panic!("Unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn sender_waits_for_receiver() {
+    let mut sender = Sender::new(Cursor::new(vec![1, 2, 3]), 3, Protocol::Xmodem);
+    let mut out = Vec::new();
+    let start = Instant::now();
+    sender.process(&[], start, &mut out).unwrap();
+    assert_eq!(sender.process(&[], start + Duration::from_secs(30), &mut out).unwrap(),
+               Status::InProgress);
+    assert!(sender.process(&[], start + Duration::from_secs(61), &mut out).is_err());
+    assert!(out.is_empty());
+}
+
+#[test]
+fn receiver_falls_back_to_checksums() {
+    let mut receiver = Receiver::new(Vec::new(), Protocol::XmodemCrc);
+    let mut out = Vec::new();
+    let mut now = Instant::now();
+    receiver.process(&[], now, &mut out).unwrap();
+    for _ in 0..4 {
+        now = receiver.deadline().unwrap();
+        receiver.process(&[], now, &mut out).unwrap();
+    }
+    assert_eq!(out, b"CCC\x15\x15");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn send_through_port() {
+    use common::*;
+    use std::fs;
+
+    let mut pty = Pty::new();
+    let (thread, _) = connect(&pty, 115_200);
+
+    let data = contents(3000);
+    let path = temp_path("xmodem_send");
+    fs::write(&path, &data).unwrap();
+    thread.send_port_file_with_protocol_cmd(path.clone(), Protocol::Xmodem1k).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::SendingFileStarted => (),
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    // Act as the receiving device on the other end of the port
+    let mut received = Vec::new();
+    {
+        let mut receiver = Receiver::new(&mut received, Protocol::Xmodem1k);
+        let mut input = Vec::new();
+        let mut buf = [0u8; 2048];
+        let start = Instant::now();
+        loop {
+            assert!(start.elapsed() < timeout(), "The transfer never finished");
+            let mut output = Vec::new();
+            let status = receiver.process(&input, Instant::now(), &mut output).unwrap();
+            pty.write(&output);
+            if status == Status::Complete {
+                break;
+            }
+            input.clear();
+            if let Ok(n) = pty.master.read(&mut buf) {
+                input.extend_from_slice(&buf[..n]);
+            }
+        }
+    }
+    assert_eq!(received, padded(&data));
+
+    loop {
+        match next_response(&thread.from_port_chan_rx) {
+            SerialResponse::SendingFileProgress(_) => (),
+            SerialResponse::SendingFileComplete => break,
+            r => panic!("Unexpected response {:?}", r),
+        }
+    }
+    fs::remove_file(&path).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn receive_through_port() {
+    use common::*;
+    use std::fs;
+
+    let mut pty = Pty::new();
+    let (thread, _) = connect(&pty, 115_200);
+
+    let path = temp_path("xmodem_receive");
+    thread.receive_port_file_cmd(path.clone(), Protocol::XmodemCrc).unwrap();
+    match next_response(&thread.from_port_chan_rx) {
+        SerialResponse::SendingFileStarted => (),
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    // Act as the sending device on the other end of the port
+    let data = contents(1000);
+    let mut sender = Sender::new(Cursor::new(data.clone()), data.len() as u64, Protocol::XmodemCrc);
+    let mut input = Vec::new();
+    let mut buf = [0u8; 256];
+    let start = Instant::now();
+    loop {
+        assert!(start.elapsed() < timeout(), "The transfer never finished");
+        let mut output = Vec::new();
+        let status = sender.process(&input, Instant::now(), &mut output).unwrap();
+        pty.write(&output);
+        if status == Status::Complete {
+            break;
+        }
+        input.clear();
+        if let Ok(n) = pty.master.read(&mut buf) {
+            input.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    // Nothing that's part of the transfer is shown as received data
+    loop {
+        match next_response(&thread.from_port_chan_rx) {
+            SerialResponse::SendingFileProgress(_) => (),
+            SerialResponse::SendingFileComplete => break,
+            r => panic!("Unexpected response {:?}", r),
+        }
+    }
+    assert_eq!(fs::read(&path).unwrap(), padded(&data));
+    fs::remove_file(&path).unwrap();
+}
+
+/// The receiving end of a `SlowPort`. It acknowledges each block as soon as the block starts
+/// arriving, long before the port has taken all of it.
+#[derive(Default)]
+struct EagerReceiver {
+    /// Everything the port took
+    received: Vec<u8>,
+    /// The most data the port thread ever offered the port at once
+    largest_write: usize,
+    /// What the receiver sent back, waiting to be read by the port thread
+    replies: VecDeque<u8>,
+    /// How far into the current block the port is
+    block_pos: usize,
+    /// When the transfer was last asked to start
+    last_request: Option<Instant>,
+}
+
+impl EagerReceiver {
+    fn take(&mut self, b: u8) {
+        self.received.push(b);
+        if self.block_pos > 0 {
+            self.block_pos = (self.block_pos + 1) % 1029;
+        } else if b == xmodem::STX {
+            self.replies.push_back(xmodem::ACK);
+            self.block_pos = 1;
+        } else if b == xmodem::EOT {
+            self.replies.push_back(xmodem::ACK);
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+struct SlowBackend {
+    receiver: Arc<Mutex<EagerReceiver>>,
+}
+
+impl Backend for SlowBackend {
+    type Port = SlowPort;
+
+    fn open(&mut self, _: &str, _: &SerialPortSettings) -> io::Result<SlowPort> {
+        Ok(SlowPort {
+               receiver: self.receiver.clone(),
+               writable: false,
+           })
+    }
+
+    fn list_ports(&mut self) -> serialport::Result<Vec<PortInfo>> {
+        Ok(vec![PortInfo::new("slow")])
+    }
+}
+
+/// A port that takes at most 64 bytes each time it becomes writable.
+struct SlowPort {
+    receiver: Arc<Mutex<EagerReceiver>>,
+    writable: bool,
+}
+
+impl Read for SlowPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut receiver = self.receiver.lock().unwrap();
+        let len = buf.len().min(receiver.replies.len());
+        for (dst, src) in buf.iter_mut().zip(receiver.replies.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for SlowPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.writable {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "The port is busy"));
+        }
+        self.writable = false;
+        let mut receiver = self.receiver.lock().unwrap();
+        receiver.largest_write = receiver.largest_write.max(buf.len());
+        let len = buf.len().min(64);
+        for &b in &buf[..len] {
+            receiver.take(b);
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Port for SlowPort {
+    fn name(&self) -> Option<String> {
+        Some("slow".to_string())
+    }
+
+    fn set_baud_rate(&mut self, _: u32) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_data_bits(&mut self, _: DataBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_flow_control(&mut self, _: FlowControl) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_parity(&mut self, _: Parity) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_stop_bits(&mut self, _: StopBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, _: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn write_data_terminal_ready(&mut self, _: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn wait(&mut self,
+            wakeup: &Wakeup,
+            timeout: Option<Duration>,
+            write: bool)
+            -> io::Result<Readiness> {
+        let interval = Duration::from_millis(100);
+        {
+            let mut receiver = self.receiver.lock().unwrap();
+            // Keep asking for the transfer to start until it does
+            if receiver.received.is_empty() &&
+               receiver.last_request.map_or(true, |t| t.elapsed() >= interval) {
+                receiver.replies.push_back(xmodem::CRC_REQUEST);
+                receiver.last_request = Some(Instant::now());
+            }
+            if write || !receiver.replies.is_empty() {
+                self.writable = write;
+                return Ok(Readiness {
+                              readable: !receiver.replies.is_empty(),
+                              writable: write,
+                          });
+            }
+        }
+        wakeup.wait(Some(timeout.map_or(interval, |t| t.min(interval))))?;
+        Ok(Readiness::default())
+    }
+}
+
+#[test]
+fn sender_waits_for_the_port_before_answering_the_receiver() {
+    use std::fs;
+
+    let backend = SlowBackend::default();
+    let thread = SerialThread::with_backend(backend.clone(), || ());
+    thread.send_port_open_cmd("slow".to_string(), "115200".to_string()).unwrap();
+
+    let data = contents(8 * 1024);
+    let path = temp_path("xmodem_slow");
+    fs::write(&path, &data).unwrap();
+    thread.send_port_file_with_protocol_cmd(path.clone(), Protocol::Xmodem1k).unwrap();
+    loop {
+        match thread.from_port_chan_rx.recv_timeout(Duration::from_secs(10)).unwrap() {
+            SerialResponse::SendingFileComplete => break,
+            SerialResponse::SendingFileError(e) => panic!("Sending failed: {}", e),
+            _ => (),
+        }
+    }
+    fs::remove_file(&path).unwrap();
+
+    // Each block only goes out once the one before it has, even though it was acknowledged
+    // before then
+    let receiver = backend.receiver.lock().unwrap();
+    assert_eq!(receiver.received.len(), 8 * 1029 + 1);
+    assert!(receiver.largest_write <= 1029,
+            "{} bytes were waiting to be sent",
+            receiver.largest_write);
+}